@@ -0,0 +1,699 @@
+use std::cell::RefCell;
+
+use anyhow::anyhow;
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{ApiError, ApiResult},
+    audit::{AuditAction, AuditActor, AuditLog, AuditOutcome},
+    billing::{Billing, Payer},
+    blocklist::KeyBlocklist,
+    clock,
+    config::Config,
+    handler::{
+        types::{
+            Account, AccountQuota, AccountUpdateRequest, AcmeServerError, GeneralRequest,
+            Identifier, JwkHeader, JwkPublicKey, KeyAuthorizationComputed, RawJwkPublicKey,
+            StoredAccount,
+        },
+        GenericError, R,
+    },
+    mem::{candid_storable, Repository},
+    policy,
+    tenant::{self, TenantRegistry},
+    thumbprint,
+};
+
+/// layout of [`AccountExport`], bumped on any incompatible change
+pub const EXPORT_VERSION: u32 = 1;
+/// accounts per export page, keeps a page well below the query response limit
+pub const MAX_EXPORT_PAGE: u32 = 1000;
+/// RFC 8555 §7.1.2
+const ACCOUNT_STATUSES: &[&str] = &["valid", "deactivated", "revoked"];
+/// account URLs are `<tenant base path>/acct/<id>`
+pub const ACCOUNT_PATH: &str = "/acct/";
+pub const NEW_ACCOUNT: &str = "/new-account";
+/// contacts one account may register
+pub const MAX_CONTACTS: usize = 4;
+/// RFC 5321 §4.5.3.1.3, the longest forward path
+const MAX_ADDRESS_LEN: usize = 254;
+
+thread_local! {
+    static ACCOUNTS: RefCell<AccountManager> = RefCell::new(AccountManager::init());
+}
+
+candid_storable!(StoredAccount);
+
+impl StoredAccount {
+    /// RFC 8555 §7.1.2, `url` is the account URL
+    pub fn to_acme(&self, url: &str) -> Account {
+        Account {
+            status: self.status.clone(),
+            contact: Some(self.contact.clone()),
+            terms_of_service_agreed: self.agreed_to_terms(),
+            orders: format!("{url}/orders"),
+            created_at: Some(self.created_at.clone()),
+            initial_ip: Some(self.initial_ip.clone()).filter(|ip| !ip.is_empty()),
+        }
+    }
+
+    /// whether the account can be used under `tenant`, accounts registered without a tenant only
+    /// outside of any
+    pub fn belongs_to(&self, tenant: Option<&str>) -> bool {
+        self.tenant.as_deref() == tenant
+    }
+
+    /// whether the account agreed to the current terms of service, if there are any
+    pub fn agreed_to_terms(&self) -> bool {
+        match Config::terms_of_service() {
+            Some(terms) => self.agreed_terms.as_deref() == Some(terms.version.as_str()),
+            None => true,
+        }
+    }
+}
+
+/// RFC 8555 §7.3.3, `userActionRequired` linking the terms to agree to
+fn terms_required(reason: &str) -> GenericError {
+    let mut err =
+        GenericError::forbidden(anyhow!("{reason}")).with_kind(AcmeServerError::UserActionRequired);
+
+    if let Some(terms) = Config::terms_of_service() {
+        err = err.with_link(&terms.url, "terms-of-service");
+    }
+
+    err
+}
+
+/// RFC 8555 §7.3, a contact is a `mailto:` URL naming a single address without hfields. The scheme
+/// and the domain are lowercased, the local part may be case sensitive and is kept as is.
+fn normalize_contact(contact: &str) -> R<String> {
+    let invalid = |reason: &str| {
+        GenericError::bad_request(anyhow!("contact {contact} {reason}"))
+            .with_kind(AcmeServerError::InvalidContact)
+    };
+
+    let Some((scheme, address)) = contact.trim().split_once(':') else {
+        return Err(invalid("is not a URL"));
+    };
+
+    if !scheme.eq_ignore_ascii_case("mailto") {
+        return Err(GenericError::bad_request(anyhow!(
+            "only mailto: contacts are supported, not {scheme}:"
+        ))
+        .with_kind(AcmeServerError::UnsupportedContact));
+    }
+
+    if address.contains('?') {
+        return Err(invalid("must not carry hfields"));
+    }
+
+    if address.contains(',') {
+        return Err(invalid("must name a single address"));
+    }
+
+    if address.len() > MAX_ADDRESS_LEN || !address.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(invalid("is not a valid address"));
+    }
+
+    let Some((local, domain)) = address.split_once('@') else {
+        return Err(invalid("has no domain"));
+    };
+
+    let domain = domain.to_ascii_lowercase();
+
+    if local.is_empty() || domain.contains('@') || domain.starts_with("*.") {
+        return Err(invalid("is not a valid address"));
+    }
+
+    policy::check_domain(&domain).map_err(|e| invalid(&format!("has an invalid domain: {e}")))?;
+
+    Ok(format!("mailto:{local}@{domain}"))
+}
+
+/// normalizes every contact and drops duplicates, the order is kept
+pub fn normalize_contacts(contacts: &[String]) -> R<Vec<String>> {
+    if contacts.len() > MAX_CONTACTS {
+        return Err(GenericError::bad_request(anyhow!(
+            "at most {MAX_CONTACTS} contacts can be registered"
+        ))
+        .with_kind(AcmeServerError::InvalidContact));
+    }
+
+    let mut normalized = Vec::new();
+
+    for contact in contacts {
+        let contact = normalize_contact(contact)?;
+
+        if !normalized.contains(&contact) {
+            normalized.push(contact);
+        }
+    }
+
+    Ok(normalized)
+}
+
+/// One page of accounts, in the JSON schema documented in the README. Pages are chained through
+/// `next` and each one can be imported on its own.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AccountExport {
+    pub version: u32,
+    /// base URL of the exporting server
+    pub source: String,
+    /// RFC 3339
+    pub exported_at: String,
+    pub accounts: Vec<ExportedAccount>,
+    /// RFC 8555 §7.1.4 objects that can be reused without validating again
+    pub authorizations: Vec<ExportedAuthorization>,
+    /// pass as `after` to fetch the following page, absent on the last one
+    pub next: Option<String>,
+}
+
+/// RFC 8555 §7.1.2 account, with the key it is bound to
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportedAccount {
+    pub id: String,
+    /// RFC 7517 JWK
+    pub key: JwkPublicKey,
+    pub contact: Vec<String>,
+    pub status: String,
+    /// RFC 3339
+    pub created_at: String,
+    /// tenant the account belongs to, absent for accounts registered outside of any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportedAuthorization {
+    /// id of the account in `accounts`
+    pub account: String,
+    pub identifier: Identifier,
+    pub status: String,
+    /// RFC 3339
+    pub expires: String,
+}
+
+/// memory marker for the thumbprint -> account id index
+pub struct AccountThumbprintIndex;
+
+/// Key of `thumbprint` in the thumbprint index and the id of a new account, prefixed with the
+/// tenant so the same key registers a separate account with every tenant.
+fn scoped_thumbprint(tenant: Option<&str>, thumbprint: &str) -> String {
+    match tenant {
+        Some(tenant) => format!("{tenant}.{thumbprint}"),
+        None => thumbprint.to_string(),
+    }
+}
+
+pub struct AccountManager {
+    accounts: Repository<String, StoredAccount>,
+    /// thumbprint of the current account key -> account id
+    by_thumbprint: Repository<String, String>,
+}
+
+impl AccountManager {
+    fn init() -> Self {
+        Self {
+            accounts: Repository::init::<Self>(),
+            by_thumbprint: Repository::init::<AccountThumbprintIndex>(),
+        }
+    }
+
+    pub fn get(id: &str) -> Option<StoredAccount> {
+        ACCOUNTS.with_borrow(|m| m.accounts.get(&id.to_string()))
+    }
+
+    /// The account of `key` under the current tenant. An account registered without a tenant is
+    /// never taken over by one, the same key registers a separate account there.
+    pub fn find_by_key(key: &RawJwkPublicKey) -> Option<StoredAccount> {
+        let thumbprint = key.thumbprint();
+        let tenant = tenant::current();
+
+        ACCOUNTS
+            .with_borrow(|m| {
+                m.by_thumbprint
+                    .get(&scoped_thumbprint(tenant.as_deref(), &thumbprint))
+                    // accounts bound to a tenant by earlier versions are still indexed without it
+                    .or_else(|| m.by_thumbprint.get(&thumbprint))
+                    .and_then(|id| m.accounts.get(&id))
+            })
+            .filter(|account| account.belongs_to(tenant.as_deref()))
+    }
+
+    /// RFC 8555 §7.3, a key reported as compromised is refused with `badPublicKey`
+    pub fn check_key(key: &RawJwkPublicKey) -> R<()> {
+        key.spki_der()
+            .and_then(|spki| KeyBlocklist::check(&spki))
+            .map_err(|e| GenericError::bad_request(e).with_kind(AcmeServerError::BadPublicKey))
+    }
+
+    /// Registers a new account for `key`, or returns the one already bound to it. While terms of
+    /// service are configured, new accounts have to agree to them.
+    pub fn create(
+        key: &RawJwkPublicKey,
+        contact: &[String],
+        terms_of_service_agreed: bool,
+    ) -> R<StoredAccount> {
+        Self::check_key(key)?;
+
+        let contact = normalize_contacts(contact)?;
+
+        if let Some(existing) = Self::find_by_key(key) {
+            return Ok(existing);
+        }
+
+        let terms = Config::terms_of_service();
+
+        if terms.is_some() && !terms_of_service_agreed {
+            return Err(terms_required(
+                "the terms of service must be agreed to with `termsOfServiceAgreed`",
+            ));
+        }
+
+        let thumbprint = key.thumbprint();
+        let tenant = tenant::current();
+        let id = scoped_thumbprint(tenant.as_deref(), &thumbprint);
+        let now = clock::now_rfc3339();
+
+        // the thumbprint at registration time doubles as the account id, it stays stable across
+        // key rollovers since lookups by key go through the index
+        let account = StoredAccount {
+            id: id.clone(),
+            public_key: key.to_jwk(),
+            contact,
+            status: "valid".to_string(),
+            created_at: now.clone(),
+            initial_ip: String::new(),
+            last_seen_ip: String::new(),
+            last_seen_at: now,
+            agreed_terms: terms.map(|t| t.version),
+            quota: None,
+            tenant,
+        };
+
+        ACCOUNTS.with_borrow_mut(|m| {
+            m.accounts.insert(account.id.clone(), account.clone());
+            m.by_thumbprint.insert(id, account.id.clone());
+        });
+
+        AuditLog::record(
+            AuditActor::Account {
+                id: account.id.clone(),
+                thumbprint,
+            },
+            AuditAction::AccountCreated,
+            vec![account.id.clone()],
+            AuditOutcome::Success,
+            None,
+        );
+
+        Ok(account)
+    }
+
+    /// RFC 8555 §7.3.3, accounts that did not agree to the current terms of service can't be used
+    /// until they do
+    pub fn check_terms(account: &StoredAccount) -> R<()> {
+        if account.agreed_to_terms() {
+            return Ok(());
+        }
+
+        Err(terms_required(
+            "the terms of service changed, agree to them by updating the account with `termsOfServiceAgreed`",
+        ))
+    }
+
+    /// RFC 8555 §7.3.2, replaces the contacts of an account. An update that does not agree to the
+    /// current terms of service is only accepted from accounts that already did.
+    pub fn apply_update(id: &str, update: &AccountUpdateRequest) -> R<StoredAccount> {
+        let contact = update
+            .contact
+            .as_deref()
+            .map(normalize_contacts)
+            .transpose()?;
+
+        let Some(mut account) = Self::get(id) else {
+            return Err(GenericError::bad_request(anyhow!("unknown account"))
+                .with_kind(AcmeServerError::AccountDoesNotExist));
+        };
+
+        match update.terms_of_service_agreed {
+            Some(true) => account.agreed_terms = Config::terms_of_service().map(|t| t.version),
+            _ => Self::check_terms(&account)?,
+        }
+
+        if let Some(contact) = contact {
+            account.contact = contact;
+        }
+
+        if let Some(payer) = &update.billing_payer {
+            let payer = Principal::from_text(payer).map_err(|e| {
+                GenericError::bad_request(anyhow!("`billingPayer` is not a principal: {e}"))
+            })?;
+
+            Billing::accept(Payer::Account(account.id.clone()), payer)
+                .map_err(|e| GenericError::bad_request(anyhow!("{e:?}")))?;
+        }
+
+        ACCOUNTS.with_borrow_mut(|m| m.accounts.insert(account.id.clone(), account.clone()));
+
+        AuditLog::record(
+            AuditActor::Account {
+                id: account.id.clone(),
+                thumbprint: thumbprint::compute(&account.public_key).unwrap_or_default(),
+            },
+            AuditAction::AccountUpdated,
+            vec![account.id.clone()],
+            AuditOutcome::Success,
+            None,
+        );
+
+        Ok(account)
+    }
+
+    /// a controller's override of the configured quota, `None` drops it again
+    pub fn set_quota(id: &str, quota: Option<AccountQuota>) -> ApiResult<StoredAccount> {
+        let account = ACCOUNTS
+            .with_borrow_mut(|m| m.accounts.update(&id.to_string(), |a| a.quota = quota))
+            .ok_or_else(|| ApiError::NotFound(format!("account {id}")))?;
+
+        AuditLog::record(
+            AuditActor::Principal(ic_cdk::caller()),
+            AuditAction::AccountUpdated,
+            vec![account.id.clone()],
+            AuditOutcome::Success,
+            None,
+        );
+
+        Ok(account)
+    }
+
+    /// the URL of account `id` under the tenant the request was sent to, its `kid`
+    pub fn url(id: &str) -> String {
+        let origin = Config::base_url();
+        let resource = format!("{}{id}", ACCOUNT_PATH.trim_start_matches('/'));
+
+        match tenant::current().and_then(|t| TenantRegistry::get(&t)) {
+            Some(tenant) => tenant.url(&origin, &resource),
+            None => format!("{origin}/{resource}"),
+        }
+    }
+
+    /// the account id carried by a `kid`, which is the account URL
+    pub fn id_from_kid(kid: &str) -> &str {
+        kid.trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+    }
+
+    /// the account a `kid` names, only under the tenant it belongs to
+    pub fn resolve_kid(kid: &str) -> anyhow::Result<(StoredAccount, RawJwkPublicKey)> {
+        let account = Self::get(Self::id_from_kid(kid))
+            .filter(|account| account.belongs_to(tenant::current().as_deref()))
+            .ok_or_else(|| anyhow!("unknown account"))?;
+
+        if account.status != "valid" {
+            return Err(anyhow!("account is {}", account.status));
+        }
+
+        let key = RawJwkPublicKey::from_jwk(&account.public_key)?;
+
+        anyhow::Ok((account, key))
+    }
+
+    /// the key a request has to be verified with, along with its account when sent with `kid`
+    pub fn authenticate(
+        header: &JwkHeader,
+    ) -> anyhow::Result<(Option<StoredAccount>, RawJwkPublicKey)> {
+        match (&header.jwk, &header.kid) {
+            (Some(jwk), None) => anyhow::Ok((Self::find_by_key(jwk), jwk.clone())),
+            (None, Some(kid)) => {
+                let (account, key) = Self::resolve_kid(kid)?;
+
+                anyhow::Ok((Some(account), key))
+            }
+            _ => Err(anyhow!("exactly one of `jwk` or `kid` must be present")),
+        }
+    }
+
+    /// the key that verifiably signed a JWS body and its account, `None` for a body the key it
+    /// names did not sign
+    fn verified(raw_body: &[u8]) -> Option<(Option<StoredAccount>, RawJwkPublicKey)> {
+        let req = serde_json::from_slice::<GeneralRequest>(raw_body).ok()?;
+        let header = req.jwk_header().ok()?;
+        let (account, key) = Self::authenticate(&header).ok()?;
+
+        req.is_signed_by(&header, &key).then_some((account, key))
+    }
+
+    /// the registered account whose key verifiably signed a JWS body, `None` for anything else,
+    /// so a forged `kid` is never attributed to the account it names
+    pub fn from_jws(raw_body: &[u8]) -> Option<StoredAccount> {
+        Self::verified(raw_body)?.0
+    }
+
+    /// Who verifiably signed a JWS body: the id of its account, or the id the account of an
+    /// embedded `jwk` gets when it registers. `None` for a body the key it names did not sign.
+    pub fn signer(raw_body: &[u8]) -> Option<String> {
+        let (account, key) = Self::verified(raw_body)?;
+
+        Some(match account {
+            Some(account) => account.id,
+            None => scoped_thumbprint(tenant::current().as_deref(), &key.thumbprint()),
+        })
+    }
+
+    /// records where the account that signed `raw_body` was last seen from, and first seen from
+    /// if nothing was recorded yet
+    pub fn seen(raw_body: &[u8], ip: &str) {
+        let Some(mut account) = Self::from_jws(raw_body) else {
+            return;
+        };
+
+        if account.initial_ip.is_empty() {
+            account.initial_ip = ip.to_string();
+        }
+        account.last_seen_ip = ip.to_string();
+        account.last_seen_at = clock::now_rfc3339();
+
+        ACCOUNTS.with_borrow_mut(|m| m.accounts.insert(account.id.clone(), account));
+    }
+
+    pub fn key_authorization(
+        account: &StoredAccount,
+        token: &str,
+    ) -> anyhow::Result<KeyAuthorizationComputed> {
+        thumbprint::key_authorization(token, &account.public_key)
+    }
+
+    /// Accounts after `after` in id order. Authorizations are always empty, domains are validated
+    /// on every issuance here so there are none worth carrying over.
+    pub fn export(after: Option<String>, limit: u32) -> AccountExport {
+        let limit = limit.clamp(1, MAX_EXPORT_PAGE) as usize;
+
+        let mut accounts = ACCOUNTS.with_borrow(|m| {
+            let page = match &after {
+                Some(after) => m
+                    .accounts
+                    .range(after.clone()..)
+                    .skip_while(|(id, _)| id == after)
+                    .take(limit + 1)
+                    .collect::<Vec<_>>(),
+                None => m.accounts.iter().take(limit + 1).collect(),
+            };
+
+            page.into_iter()
+                .map(|(_, account)| ExportedAccount {
+                    id: account.id,
+                    key: account.public_key,
+                    contact: account.contact,
+                    status: account.status,
+                    created_at: account.created_at,
+                    tenant: account.tenant,
+                })
+                .collect::<Vec<_>>()
+        });
+
+        // the extra account only tells whether there is another page
+        let next = (accounts.len() > limit).then(|| {
+            accounts.truncate(limit);
+            accounts[limit - 1].id.clone()
+        });
+
+        AccountExport {
+            version: EXPORT_VERSION,
+            source: Config::base_url(),
+            exported_at: clock::now_rfc3339(),
+            accounts,
+            authorizations: Vec::new(),
+            next,
+        }
+    }
+
+    /// Registers the accounts of an export, returns how many were added. Accounts whose id or key
+    /// is already known are skipped, so a page can be imported again after a failure. Imported
+    /// authorizations are dropped, every name is validated again before issuance.
+    ///
+    /// Each added account is audited, a rejected page is audited once.
+    pub fn import(export: AccountExport) -> anyhow::Result<u64> {
+        let imported = Self::_import(export);
+
+        if imported.is_err() {
+            AuditLog::record(
+                AuditActor::Principal(ic_cdk::caller()),
+                AuditAction::AccountImported,
+                Vec::new(),
+                AuditOutcome::of(&imported),
+                None,
+            );
+        }
+
+        imported
+    }
+
+    fn _import(export: AccountExport) -> anyhow::Result<u64> {
+        if export.version != EXPORT_VERSION {
+            return Err(anyhow!(
+                "unsupported export version {}, expected {EXPORT_VERSION}",
+                export.version
+            ));
+        }
+
+        // everything is checked before anything is stored, a bad page leaves no partial import
+        let accounts = export
+            .accounts
+            .into_iter()
+            .map(|account| {
+                let key = RawJwkPublicKey::from_jwk(&account.key)
+                    .map_err(|e| anyhow!("account {}: {e}", account.id))?;
+
+                if !ACCOUNT_STATUSES.contains(&account.status.as_str()) {
+                    return Err(anyhow!(
+                        "account {} has unknown status {}",
+                        account.id,
+                        account.status
+                    ));
+                }
+
+                clock::parse_rfc3339(&account.created_at)
+                    .map_err(|e| anyhow!("account {}: {e}", account.id))?;
+
+                if let Some(tenant) = &account.tenant {
+                    TenantRegistry::get(tenant).ok_or_else(|| {
+                        anyhow!("account {}: unknown tenant {tenant}", account.id)
+                    })?;
+                }
+
+                anyhow::Ok((account, key))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let now = clock::now_rfc3339();
+        let mut added = Vec::new();
+
+        ACCOUNTS.with_borrow_mut(|m| {
+            for (account, key) in accounts {
+                let thumbprint = scoped_thumbprint(account.tenant.as_deref(), &key.thumbprint());
+
+                if m.accounts.contains(&account.id) || m.by_thumbprint.contains(&thumbprint) {
+                    continue;
+                }
+
+                m.by_thumbprint.insert(thumbprint, account.id.clone());
+                added.push(account.id.clone());
+                m.accounts.insert(
+                    account.id.clone(),
+                    StoredAccount {
+                        id: account.id,
+                        public_key: key.to_jwk(),
+                        contact: account.contact,
+                        status: account.status,
+                        created_at: account.created_at,
+                        initial_ip: String::new(),
+                        last_seen_ip: String::new(),
+                        last_seen_at: now.clone(),
+                        agreed_terms: None,
+                        quota: None,
+                        tenant: account.tenant,
+                    },
+                );
+            }
+        });
+
+        let imported = added.len() as u64;
+
+        for id in added {
+            AuditLog::record(
+                AuditActor::Principal(ic_cdk::caller()),
+                AuditAction::AccountImported,
+                vec![id],
+                AuditOutcome::Success,
+                None,
+            );
+        }
+
+        anyhow::Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(contacts: &[&str]) -> Option<Vec<String>> {
+        let contacts = contacts.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+
+        normalize_contacts(&contacts).ok()
+    }
+
+    #[test]
+    fn lowercases_scheme_and_domain_but_not_the_local_part() {
+        assert_eq!(
+            normalized(&[" MAILTO:Admin@Example.ORG "]),
+            Some(vec!["mailto:Admin@example.org".to_string()])
+        );
+    }
+
+    #[test]
+    fn drops_duplicates_and_keeps_the_order() {
+        assert_eq!(
+            normalized(&[
+                "mailto:b@example.org",
+                "mailto:a@example.org",
+                "mailto:b@EXAMPLE.org",
+            ]),
+            Some(vec![
+                "mailto:b@example.org".to_string(),
+                "mailto:a@example.org".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn refuses_anything_but_a_single_mailto_address() {
+        for contact in [
+            "tel:+41000000000",
+            "admin@example.org",
+            "mailto:admin@example.org?subject=hi",
+            "mailto:a@example.org,b@example.org",
+            "mailto:admin",
+            "mailto:@example.org",
+            "mailto:admin@*.example.org",
+            "mailto:admin@localhost",
+            "mailto:ad min@example.org",
+        ] {
+            assert_eq!(normalized(&[contact]), None, "{contact}");
+        }
+    }
+
+    #[test]
+    fn caps_the_number_of_contacts() {
+        let contacts = (0..=MAX_CONTACTS)
+            .map(|i| format!("mailto:{i}@example.org"))
+            .collect::<Vec<_>>();
+
+        assert!(normalize_contacts(&contacts[..MAX_CONTACTS]).is_ok());
+        assert!(normalize_contacts(&contacts).is_err());
+    }
+}
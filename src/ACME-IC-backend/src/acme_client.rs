@@ -0,0 +1,728 @@
+use std::{cell::Cell, time::Duration};
+
+use anyhow::anyhow;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use candid::CandidType;
+use ic_cdk::api::management_canister::http_request::{HttpHeader, HttpMethod};
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    api::{ApiError, ApiResult},
+    cert_manager::{CertificateManager, CertificateOwner, IssuedCertificate},
+    challenge::{self, ChallengeValidator, Dns01},
+    client::{
+        self,
+        environment::{ClientEnvironments, ClientProfile, Environment},
+        OrderPlan, ServerLimits,
+    },
+    clock,
+    handler::types::{Directory, JwkPublicKey},
+    media,
+    outcall::{self, Outcall, Transform},
+    thumbprint, trace,
+};
+
+/// a PEM chain or a large order is well below this
+const ACME_MAX_RESPONSE_BYTES: u64 = 64 * 1024;
+/// unused cycles are refunded by the management canister
+const ACME_OUTCALL_CYCLES: u128 = 2_000_000_000;
+/// background polls of an order the CA is still validating or signing, each one is an outcall
+const MAX_POLLS: u32 = 10;
+/// wait before polling a CA that sent no `Retry-After`
+const DEFAULT_POLL_DELAY: Duration = Duration::from_secs(3);
+/// longest wait between two polls, whatever `Retry-After` asks for
+const MAX_POLL_DELAY: Duration = Duration::from_secs(60);
+/// the CA's headers client mode reads, the relay's own ones differ between replicas
+const KEPT_HEADERS: &[&str] = &[
+    "content-type",
+    "link",
+    "location",
+    "replay-nonce",
+    "retry-after",
+];
+
+thread_local! {
+    /// background polls made for the current client order
+    static POLLS: Cell<u32> = const { Cell::new(0) };
+    /// whether a background poll is scheduled, timers don't survive upgrades
+    static SCHEDULED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A TXT record `start_client_order` asks to publish before the order can be completed.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DnsChallenge {
+    pub domain: String,
+    /// `_acme-challenge.<domain>`
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RemoteDirectory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct RemoteOrder {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RemoteIdentifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct RemoteAuthorization {
+    identifier: RemoteIdentifier,
+    status: String,
+    challenges: Vec<RemoteChallenge>,
+}
+
+#[derive(Deserialize)]
+struct RemoteChallenge {
+    r#type: String,
+    url: String,
+    token: String,
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct RemoteProblem {
+    r#type: String,
+    detail: Option<String>,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<HttpHeader>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value.as_str())
+    }
+
+    fn json<T: for<'de> Deserialize<'de>>(&self) -> anyhow::Result<T> {
+        serde_json::from_slice(&self.body)
+            .map_err(|e| anyhow!("unexpected answer from the CA: {e}"))
+    }
+
+    fn problem(&self) -> Option<RemoteProblem> {
+        serde_json::from_slice(&self.body).ok()
+    }
+
+    /// RFC 8555 §7.4, how long the CA asked to wait before polling again, in delay-seconds
+    fn retry_after(&self) -> Duration {
+        self.header("Retry-After")
+            .and_then(|v| v.trim().parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_POLL_DELAY)
+            .min(MAX_POLL_DELAY)
+    }
+}
+
+/// where a run against the client order stopped
+enum Progress {
+    /// the PEM chain of the certificate
+    Issued(String),
+    /// the CA is still validating or signing, it is polled again after the delay
+    Waiting(Duration),
+}
+
+/// RFC 7518 §6.2.1 public JWK of a P-256 account key
+fn es256_jwk(key: &SigningKey) -> JwkPublicKey {
+    let point = key.verifying_key().to_encoded_point(false);
+
+    JwkPublicKey {
+        kty: "EC".to_string(),
+        crv: Some("P-256".to_string()),
+        // uncompressed points always carry both coordinates
+        x: point.x().map(|x| BASE64_URL_SAFE_NO_PAD.encode(x)),
+        y: point.y().map(|y| BASE64_URL_SAFE_NO_PAD.encode(y)),
+        n: None,
+        e: None,
+    }
+}
+
+/// RFC 3986 §2.1, everything but unreserved characters
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// One run of client mode against the active environment.
+///
+/// Outcalls are made by every replica of the subnet. Sent straight to the CA, each replica would
+/// spend the same nonce and all but one would fail, so requests go through the profile's relay
+/// instead: `<method> <relay>?url=<CA URL>` with the body and the `Idempotency-Key` header. The
+/// relay forwards each key once and answers every replica with the CA's response.
+struct Session {
+    env: Environment,
+    relay: String,
+    key: SigningKey,
+    jwk: JwkPublicKey,
+    directory: RemoteDirectory,
+    /// what the directory says the CA accepts in one order
+    limits: ServerLimits,
+    account_url: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Session {
+    async fn open(envs: &ClientEnvironments) -> anyhow::Result<(Self, ClientProfile)> {
+        let profile = envs
+            .profile
+            .clone()
+            .ok_or_else(|| anyhow!("no client profile is configured"))?;
+        let relay = profile
+            .relay
+            .clone()
+            .ok_or_else(|| anyhow!("client mode needs a relay"))?;
+        let env = envs.active;
+        let directory_url = ClientEnvironments::active_directory()
+            .ok_or_else(|| anyhow!("no client profile is configured"))?;
+
+        let key = ClientEnvironments::account_key(env)
+            .await
+            .map_err(|e| anyhow!("{e:?}"))?;
+        let jwk = es256_jwk(&key);
+
+        let resp = Self::send(
+            &relay,
+            HttpMethod::GET,
+            &directory_url,
+            None,
+            format!("directory-{}", clock::now_nanos()),
+        )
+        .await?;
+        let directory = resp.json::<RemoteDirectory>()?;
+        let limits = resp
+            .json::<Directory>()
+            .map(|d| ServerLimits::from_directory(&d))
+            .unwrap_or_default();
+
+        let session = Self {
+            env,
+            relay,
+            key,
+            jwk,
+            directory,
+            limits,
+            // a new account key drops the account registered under the old one
+            account_url: ClientEnvironments::get().state(env).account_url.clone(),
+            nonce: None,
+        };
+
+        anyhow::Ok((session, profile))
+    }
+
+    async fn send(
+        relay: &str,
+        method: HttpMethod,
+        url: &str,
+        body: Option<Vec<u8>>,
+        idempotency_key: String,
+    ) -> anyhow::Result<Response> {
+        let separator = if relay.contains('?') { '&' } else { '?' };
+        let mut headers = vec![
+            HttpHeader {
+                name: "Idempotency-Key".to_string(),
+                value: idempotency_key,
+            },
+            HttpHeader {
+                name: "Accept".to_string(),
+                value: format!("{}, {}", media::JSON, media::PEM_CHAIN),
+            },
+        ];
+
+        if body.is_some() {
+            headers.push(HttpHeader {
+                name: "Content-Type".to_string(),
+                value: media::JOSE_JSON.to_string(),
+            });
+        }
+
+        // the relay answers every replica alike, only its own headers are dropped
+        let outcall = Outcall {
+            url: format!("{relay}{separator}url={}", percent_encode(url)),
+            method,
+            headers,
+            body,
+            max_response_bytes: ACME_MAX_RESPONSE_BYTES,
+            cycles: ACME_OUTCALL_CYCLES,
+            transform: Transform::Body {
+                headers: KEPT_HEADERS.iter().map(|h| h.to_string()).collect(),
+            },
+        };
+
+        let resp = outcall::send(outcall)
+            .await
+            .map_err(|e| anyhow!("request to {url} failed: {e}"))?;
+
+        anyhow::Ok(Response {
+            status: outcall::status(&resp),
+            headers: resp.headers,
+            body: resp.body,
+        })
+    }
+
+    async fn nonce(&mut self) -> anyhow::Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return anyhow::Ok(nonce);
+        }
+
+        let resp = Self::send(
+            &self.relay,
+            HttpMethod::HEAD,
+            &self.directory.new_nonce,
+            None,
+            format!("nonce-{}", clock::now_nanos()),
+        )
+        .await?;
+
+        resp.header("Replay-Nonce")
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("the CA handed out no nonce"))
+    }
+
+    /// RFC 8555 §6.2, a JWS signed with the threshold account key. `payload` is `None` for a
+    /// POST-as-GET.
+    async fn jws(
+        &mut self,
+        url: &str,
+        payload: Option<&serde_json::Value>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let mut protected = json!({
+            "alg": "ES256",
+            "nonce": self.nonce().await?,
+            "url": url,
+        });
+
+        match &self.account_url {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = json!(self.jwk),
+        }
+
+        let protected = BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(&protected)?);
+        let payload = match payload {
+            Some(payload) => BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(payload)?),
+            None => String::new(),
+        };
+
+        // deterministic (RFC 6979), every replica signs alike. JWS wants the fixed size
+        // `r || s`, RFC 7518 §3.4
+        let signature: Signature = self.key.sign(format!("{protected}.{payload}").as_bytes());
+
+        anyhow::Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": BASE64_URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+        }))
+    }
+
+    /// A signed POST, sent again once with a fresh nonce when the CA refused the nonce. Problem
+    /// documents are returned as errors.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&serde_json::Value>,
+    ) -> anyhow::Result<Response> {
+        let mut retried = false;
+
+        loop {
+            let jws = self.jws(url, payload).await?;
+            let key = jws["signature"].as_str().unwrap_or_default().to_string();
+
+            let resp = Self::send(
+                &self.relay,
+                HttpMethod::POST,
+                url,
+                Some(serde_json::to_vec(&jws)?),
+                key,
+            )
+            .await?;
+
+            self.nonce = resp.header("Replay-Nonce").map(str::to_string);
+
+            if resp.status < 400 {
+                return anyhow::Ok(resp);
+            }
+
+            let problem = resp.problem();
+
+            if !retried
+                && problem
+                    .as_ref()
+                    .is_some_and(|p| p.r#type.ends_with(":badNonce"))
+            {
+                retried = true;
+                continue;
+            }
+
+            return Err(match problem {
+                Some(p) => anyhow!("{url}: {} {}", p.r#type, p.detail.unwrap_or_default()),
+                None => anyhow!("{url} answered with HTTP {}", resp.status),
+            });
+        }
+    }
+
+    /// RFC 8555 §7.3, registers the account of the environment the first time
+    async fn account(&mut self, contact: &[String]) -> anyhow::Result<()> {
+        if self.account_url.is_some() {
+            return anyhow::Ok(());
+        }
+
+        let contact = contact
+            .iter()
+            .map(|c| match c.starts_with("mailto:") {
+                true => c.clone(),
+                false => format!("mailto:{c}"),
+            })
+            .collect::<Vec<_>>();
+
+        let url = self.directory.new_account.clone();
+        let resp = self
+            .post(
+                &url,
+                Some(&json!({ "termsOfServiceAgreed": true, "contact": contact })),
+            )
+            .await?;
+
+        let account_url = resp
+            .header("Location")
+            .ok_or_else(|| anyhow!("the CA returned no account URL"))?
+            .to_string();
+
+        ClientEnvironments::set_account_url(self.env, account_url.clone())
+            .map_err(|e| anyhow!("{e:?}"))?;
+        self.account_url = Some(account_url);
+
+        anyhow::Ok(())
+    }
+
+    /// POST-as-GET of `url`, with how long the CA asked to wait before fetching it again
+    async fn poll<T: for<'de> Deserialize<'de>>(
+        &mut self,
+        url: &str,
+    ) -> anyhow::Result<(T, Duration)> {
+        let resp = self.post(url, None).await?;
+
+        anyhow::Ok((resp.json()?, resp.retry_after()))
+    }
+
+    async fn authorization(&mut self, url: &str) -> anyhow::Result<RemoteAuthorization> {
+        anyhow::Ok(self.poll(url).await?.0)
+    }
+
+    /// the dns-01 challenge of `authz` with its key authorization
+    fn dns01<'a>(
+        &self,
+        authz: &'a RemoteAuthorization,
+    ) -> anyhow::Result<(&'a RemoteChallenge, String)> {
+        let challenge = authz
+            .challenges
+            .iter()
+            .find(|c| c.r#type == "dns-01")
+            .ok_or_else(|| anyhow!("{} offers no dns-01 challenge", authz.identifier.value))?;
+
+        let key_authorization = thumbprint::key_authorization(&challenge.token, &self.jwk)?;
+
+        anyhow::Ok((challenge, key_authorization.key_authorization))
+    }
+}
+
+/// the profile's domains checked against local policy and the CA's `limits`, and against
+/// `csr_der` when given
+fn plan(
+    profile: &ClientProfile,
+    limits: &ServerLimits,
+    csr_der: Option<&[u8]>,
+) -> ApiResult<OrderPlan> {
+    let plan = client::plan_order(&profile.domains, limits, csr_der);
+
+    if !plan.is_ready() {
+        let reasons = plan
+            .rejected
+            .iter()
+            .map(|r| format!("{}: {}", r.identifier, r.reason))
+            .chain(plan.problems.iter().cloned())
+            .collect::<Vec<_>>();
+
+        return Err(ApiError::InvalidArgument(reasons.join("; ")));
+    }
+
+    Ok(plan)
+}
+
+fn failed(env: Environment, e: anyhow::Error) -> ApiError {
+    let _ = ClientEnvironments::record_failure(env, e.to_string());
+
+    ApiError::Internal(e.to_string())
+}
+
+/// Places an order for the profile's domains, registering the account first if needed, and returns
+/// the dns-01 records to publish before [`complete_order`].
+pub async fn start_order() -> ApiResult<Vec<DnsChallenge>> {
+    let envs = ClientEnvironments::get();
+    let env = envs.active;
+
+    if envs.profile.is_none() {
+        return Err(ApiError::NotFound("client profile".to_string()));
+    }
+
+    let (mut session, profile) = Session::open(&envs).await.map_err(|e| failed(env, e))?;
+    let plan = plan(&profile, &session.limits, None)?;
+
+    _start_order(&mut session, &profile, plan.identifiers)
+        .await
+        .map_err(|e| failed(env, e))
+}
+
+async fn _start_order(
+    session: &mut Session,
+    profile: &ClientProfile,
+    identifiers: Vec<String>,
+) -> anyhow::Result<Vec<DnsChallenge>> {
+    session.account(&profile.contact).await?;
+
+    let identifiers = identifiers
+        .iter()
+        .map(|value| json!({ "type": "dns", "value": value }))
+        .collect::<Vec<_>>();
+
+    let url = session.directory.new_order.clone();
+    let resp = session
+        .post(&url, Some(&json!({ "identifiers": identifiers })))
+        .await?;
+
+    let order_url = resp
+        .header("Location")
+        .ok_or_else(|| anyhow!("the CA returned no order URL"))?
+        .to_string();
+    let order = resp.json::<RemoteOrder>()?;
+
+    ClientEnvironments::set_order_url(session.env, Some(order_url))
+        .map_err(|e| anyhow!("{e:?}"))?;
+
+    let mut records = Vec::new();
+
+    for url in &order.authorizations {
+        let authz = session.authorization(url).await?;
+
+        if authz.status == "valid" {
+            continue;
+        }
+
+        let (_, key_authorization) = session.dns01(&authz)?;
+
+        records.push(DnsChallenge {
+            domain: authz.identifier.value.clone(),
+            name: challenge::dns01_record_name(&authz.identifier.value),
+            value: challenge::dns01_txt_value(&key_authorization),
+        });
+    }
+
+    anyhow::Ok(records)
+}
+
+/// Answers the challenges of the order placed by [`start_order`] once its records resolve,
+/// finalizes it with `csr_der` and archives the certificate. While the CA is still validating or
+/// signing, the call fails with `Unavailable` and the order is completed in the background,
+/// polled as the CA's `Retry-After` asks. Called again, it returns the certificate once archived.
+pub async fn complete_order(csr_der: Vec<u8>) -> ApiResult<IssuedCertificate> {
+    let envs = ClientEnvironments::get();
+    let env = envs.active;
+    let state = envs.state(env);
+
+    let Some(order_url) = state.order_url.clone() else {
+        // completed in the background since the last call
+        return state
+            .certificate_serial
+            .and_then(CertificateManager::get)
+            .ok_or_else(|| ApiError::NotFound("pending client order".to_string()));
+    };
+
+    if envs.profile.is_none() {
+        return Err(ApiError::NotFound("client profile".to_string()));
+    }
+
+    if SCHEDULED.get() {
+        return Err(ApiError::Unavailable {
+            message: format!("{order_url} is being completed in the background"),
+            retry_after_secs: DEFAULT_POLL_DELAY.as_secs(),
+        });
+    }
+
+    let (mut session, profile) = Session::open(&envs).await.map_err(|e| failed(env, e))?;
+    plan(&profile, &session.limits, Some(&csr_der))?;
+
+    let progress = _complete_order(&mut session, &order_url, &csr_der)
+        .await
+        .map_err(|e| failed(env, e))?;
+
+    match progress {
+        Progress::Issued(pem_chain) => archive(env, &pem_chain),
+        Progress::Waiting(delay) => {
+            ClientEnvironments::set_pending_csr(env, csr_der)?;
+            POLLS.set(0);
+            schedule(env, delay);
+
+            Err(ApiError::Unavailable {
+                message: format!("{order_url} is still pending, it is completed in the background"),
+                retry_after_secs: delay.as_secs().max(1),
+            })
+        }
+    }
+}
+
+/// imports `pem_chain` like any imported certificate and closes the client order
+fn archive(env: Environment, pem_chain: &str) -> ApiResult<IssuedCertificate> {
+    let cert =
+        CertificateManager::import(pem_chain, CertificateOwner::Canister(ic_cdk::id()), None)
+            .map_err(|e| failed(env, e))?;
+
+    ClientEnvironments::record_success(env, cert.serial)?;
+
+    Ok(cert)
+}
+
+/// polls the client order of `env` again after `delay`, as long as it is still the one waiting
+fn schedule(env: Environment, delay: Duration) {
+    SCHEDULED.set(true);
+
+    ic_cdk_timers::set_timer(delay, move || {
+        SCHEDULED.set(false);
+
+        ic_cdk::spawn(async move {
+            if let Err(e) = follow_up(env).await {
+                trace::log(&format!("{e:?}"));
+            }
+        })
+    });
+}
+
+async fn follow_up(env: Environment) -> ApiResult<()> {
+    let envs = ClientEnvironments::get();
+    let state = envs.state(env);
+
+    // completed, replaced by a new order or switched to another environment meanwhile
+    let (Some(order_url), Some(csr_der)) = (state.order_url.clone(), state.pending_csr.clone())
+    else {
+        return Ok(());
+    };
+
+    if envs.active != env {
+        return Ok(());
+    }
+
+    let (mut session, _) = Session::open(&envs).await.map_err(|e| failed(env, e))?;
+
+    match _complete_order(&mut session, &order_url, &csr_der)
+        .await
+        .map_err(|e| failed(env, e))?
+    {
+        Progress::Issued(pem_chain) => archive(env, &pem_chain).map(|_| ()),
+        Progress::Waiting(delay) if POLLS.get() < MAX_POLLS => {
+            POLLS.set(POLLS.get() + 1);
+            schedule(env, delay);
+
+            Ok(())
+        }
+        Progress::Waiting(_) => Err(failed(
+            env,
+            anyhow!("{order_url} is still pending after {MAX_POLLS} polls"),
+        )),
+    }
+}
+
+async fn _complete_order(
+    session: &mut Session,
+    order_url: &str,
+    csr_der: &[u8],
+) -> anyhow::Result<Progress> {
+    let (mut order, mut delay) = session.poll::<RemoteOrder>(order_url).await?;
+
+    if order.status == "pending" {
+        for url in &order.authorizations {
+            let authz = session.authorization(url).await?;
+
+            if authz.status != "pending" {
+                continue;
+            }
+
+            let (challenge, key_authorization) = session.dns01(&authz)?;
+
+            // answered already, the CA is validating it
+            if challenge.status != "pending" {
+                continue;
+            }
+
+            // a challenge answered before its record resolves fails for good
+            Dns01
+                .validate(
+                    &authz.identifier.value,
+                    &challenge.token,
+                    &key_authorization,
+                )
+                .await
+                .map_err(|e| anyhow!("{e}, publish the record first"))?;
+
+            let challenge_url = challenge.url.clone();
+            session.post(&challenge_url, Some(&json!({}))).await?;
+        }
+
+        for url in &order.authorizations {
+            let (authz, retry_after) = session.poll::<RemoteAuthorization>(url).await?;
+
+            match authz.status.as_str() {
+                "valid" => {}
+                "pending" => return anyhow::Ok(Progress::Waiting(retry_after)),
+                status => return Err(anyhow!("{url} turned {status}")),
+            }
+        }
+
+        (order, delay) = session.poll(order_url).await?;
+    }
+
+    if order.status == "ready" {
+        let finalize = order.finalize.clone();
+        let csr = BASE64_URL_SAFE_NO_PAD.encode(csr_der);
+        let resp = session
+            .post(&finalize, Some(&json!({ "csr": csr })))
+            .await?;
+
+        (order, delay) = (resp.json()?, resp.retry_after());
+    }
+
+    let certificate = match (order.status.as_str(), order.certificate) {
+        ("valid", Some(certificate)) => certificate,
+        ("processing", _) => return anyhow::Ok(Progress::Waiting(delay)),
+        (status, _) => return Err(anyhow!("{order_url} is {status}")),
+    };
+
+    let resp = session.post(&certificate, None).await?;
+
+    String::from_utf8(resp.body)
+        .map(Progress::Issued)
+        .map_err(|_| anyhow!("the certificate is not a PEM chain"))
+}
@@ -0,0 +1,23 @@
+use candid::CandidType;
+use serde::Deserialize;
+
+/// Version of the Candid service exposed by this canister.
+///
+/// Bump the minor version when methods or optional fields are added, and the major version on any
+/// breaking change to an existing method, so operator tooling can detect what it is talking to.
+pub const API_VERSION: &str = "2.4.0";
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ApiError {
+    Unauthorized,
+    InvalidArgument(String),
+    NotFound(String),
+    Internal(String),
+    /// the call was refused for now and can be made again after `retry_after_secs`
+    Unavailable {
+        message: String,
+        retry_after_secs: u64,
+    },
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
@@ -0,0 +1,267 @@
+use std::{cell::RefCell, fmt::Debug, time::Duration};
+
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+use crate::{
+    api::{ApiError, ApiResult},
+    clock,
+    mem::{candid_storable, Repository},
+    source, trace,
+};
+
+/// entries per exported page, keeps a page well below the query response limit
+pub const MAX_AUDIT_PAGE: u32 = 1000;
+/// how often entries past the retention are rotated out
+const ROTATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// entries removed per rotation, the rest follow in the next round
+const ROTATED_PER_ROUND: usize = 10_000;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+thread_local! {
+    static AUDIT: RefCell<AuditLog> = RefCell::new(AuditLog::init());
+    static RETENTION: RefCell<AuditRetention> = RefCell::new(AuditRetention::default());
+}
+
+/// Who caused an audited event.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AuditActor {
+    /// an ACME account, with the thumbprint of the key that signed the request
+    Account { id: String, thumbprint: String },
+    /// a canister consumer or a controller
+    Principal(Principal),
+    /// whoever holds the key of a certificate, proven by signing with it
+    CertificateKey { spki_hash: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditAction {
+    CertificateIssued,
+    CertificateImported,
+    CertificateRevoked,
+    AccountCreated,
+    AccountImported,
+    AccountUpdated,
+    KeyBlocked,
+    KeyRotationStarted,
+    KeyRotationCompleted,
+    KeyRotationCancelled,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AuditOutcome {
+    Success,
+    Failure(String),
+}
+
+impl AuditOutcome {
+    pub fn of<T, E: Debug>(result: &Result<T, E>) -> Self {
+        match result {
+            Ok(_) => Self::Success,
+            Err(e) => Self::Failure(format!("{e:?}")),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub sequence: u64,
+    /// IC time in nanoseconds
+    pub timestamp: u64,
+    pub actor: AuditActor,
+    pub action: AuditAction,
+    /// names on the certificate, or the id of the account
+    pub identifiers: Vec<String>,
+    pub outcome: AuditOutcome,
+    pub serial: Option<u64>,
+    /// client address of the ACME request behind the event, as forwarded by the boundary node
+    pub source: Option<String>,
+    /// correlation id of the HTTP request behind the event, its `X-Request-Id`
+    pub request_id: Option<String>,
+}
+
+candid_storable!(AuditEntry);
+
+/// How long entries are kept, whichever bound is hit first rotates out the oldest ones.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuditRetention {
+    pub max_entries: u64,
+    pub max_age_days: u32,
+}
+
+impl Default for AuditRetention {
+    fn default() -> Self {
+        // two years, as the CA/Browser Forum baseline requirements ask of audit logs
+        Self {
+            max_entries: 1_000_000,
+            max_age_days: 2 * 365,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    /// pass as `start` to fetch the following page, absent on the last one
+    pub next: Option<u64>,
+    /// sequence of the oldest entry still kept, anything before it was rotated out
+    pub oldest: Option<u64>,
+}
+
+/// Append-only history of issuance, revocation and account events, keyed by a sequence that
+/// only grows. Entries are never changed, only the oldest ones are rotated out once they fall
+/// outside the [`AuditRetention`].
+pub struct AuditLog {
+    entries: Repository<u64, AuditEntry>,
+}
+
+impl AuditLog {
+    fn init() -> Self {
+        Self {
+            entries: Repository::init::<Self>(),
+        }
+    }
+
+    /// removes up to `limit` entries past the retention, the newest entry is always kept so the
+    /// sequence carries on from it
+    fn _rotate(&mut self, retention: &AuditRetention, now: u64, limit: usize) {
+        let cutoff = now.saturating_sub(retention.max_age_days as u64 * NANOS_PER_DAY);
+        let excess = self.entries.len().saturating_sub(retention.max_entries);
+
+        let expired = self
+            .entries
+            .iter()
+            .take(limit)
+            .enumerate()
+            .take_while(|(i, (_, e))| (*i as u64) < excess || e.timestamp < cutoff)
+            .map(|(_, (sequence, _))| sequence)
+            .collect::<Vec<_>>();
+
+        for sequence in expired {
+            if self.entries.len() <= 1 {
+                break;
+            }
+
+            self.entries.remove(&sequence);
+        }
+    }
+
+    pub fn record(
+        actor: AuditActor,
+        action: AuditAction,
+        identifiers: Vec<String>,
+        outcome: AuditOutcome,
+        serial: Option<u64>,
+    ) {
+        let retention = RETENTION.with_borrow(|r| r.clone());
+        let now = clock::now_nanos();
+
+        AUDIT.with_borrow_mut(|a| {
+            let sequence = a.entries.last().map(|(s, _)| s + 1).unwrap_or(0);
+
+            a.entries.insert(
+                sequence,
+                AuditEntry {
+                    sequence,
+                    timestamp: now,
+                    actor,
+                    action,
+                    identifiers,
+                    outcome,
+                    serial,
+                    source: source::current(),
+                    request_id: trace::current(),
+                },
+            );
+
+            // keeps the entry bound without waiting for the next rotation round
+            a._rotate(&retention, now, 1);
+        })
+    }
+
+    /// up to `limit` entries from sequence `start` on, oldest first
+    pub fn page(start: u64, limit: u32) -> ApiResult<AuditPage> {
+        if limit == 0 || limit > MAX_AUDIT_PAGE {
+            return Err(ApiError::InvalidArgument(format!(
+                "limit must be between 1 and {MAX_AUDIT_PAGE}"
+            )));
+        }
+
+        AUDIT.with_borrow(|a| {
+            let mut entries = a
+                .entries
+                .range(start..)
+                .map(|(_, e)| e)
+                .take(limit as usize + 1)
+                .collect::<Vec<_>>();
+
+            let next = if entries.len() > limit as usize {
+                entries.pop().map(|e| e.sequence)
+            } else {
+                None
+            };
+
+            Ok(AuditPage {
+                entries,
+                next,
+                oldest: a.entries.iter().next().map(|(s, _)| s),
+            })
+        })
+    }
+
+    pub fn retention() -> AuditRetention {
+        RETENTION.with_borrow(|r| r.clone())
+    }
+
+    pub fn set_retention(retention: AuditRetention) -> ApiResult<()> {
+        if retention.max_entries == 0 || retention.max_age_days == 0 {
+            return Err(ApiError::InvalidArgument(
+                "audit retention must keep at least one entry for at least one day".to_string(),
+            ));
+        }
+
+        RETENTION.with_borrow_mut(|r| *r = retention);
+
+        Ok(())
+    }
+}
+
+fn rotate() {
+    let retention = AuditLog::retention();
+
+    AUDIT.with_borrow_mut(|a| a._rotate(&retention, clock::now_nanos(), ROTATED_PER_ROUND))
+}
+
+/// A trap rolls back everything the call changed, audit entries included, so a panic is written
+/// to the canister log instead, which outlives the rollback, with who and what caused it. Replaces
+/// the hook `ic_cdk` sets up, has to be installed again after every upgrade.
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("panic");
+        let location = info
+            .location()
+            .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+            .unwrap_or_default();
+
+        let line = format!(
+            "audit: panicked at {location}: {message} (caller {}, source {}, request {})",
+            ic_cdk::caller(),
+            source::current().as_deref().unwrap_or("-"),
+            trace::current().as_deref().unwrap_or("-"),
+        );
+
+        ic_cdk::println!("{line}");
+        ic_cdk::trap(&line);
+    }));
+}
+
+/// has to be called again after every upgrade
+pub fn start_rotation() {
+    ic_cdk_timers::set_timer_interval(ROTATION_INTERVAL, rotate);
+}
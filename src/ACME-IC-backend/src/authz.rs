@@ -0,0 +1,398 @@
+use std::cell::RefCell;
+
+use anyhow::anyhow;
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::{
+    capability,
+    challenge::{self, CANISTER_TOKEN},
+    clock,
+    config::Config,
+    handler::types::{AcmeServerError, Authorization, Challenge, ChallengeType, Error, Identifier},
+    jobs::JobQueue,
+    load_shed::{LoadShedder, Queue},
+    mem::{candid_storable, Repository},
+    order::OrderManager,
+    token::TokenStore,
+};
+
+pub const AUTHZ_PATH: &str = "/authz/";
+pub const CHALLENGE_PATH: &str = "/challenge/";
+
+thread_local! {
+    static AUTHORIZATIONS: RefCell<AuthorizationStore> = RefCell::new(AuthorizationStore::init());
+}
+
+/// RFC 8555 §7.1.6 challenge status
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationStatus {
+    Pending,
+    Processing,
+    Valid,
+    Invalid,
+}
+
+impl ValidationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Processing => "processing",
+            Self::Valid => "valid",
+            Self::Invalid => "invalid",
+        }
+    }
+}
+
+/// Validation of one name of a queued order. A failed attempt is retried by the job worker with
+/// exponential backoff until `challenge_attempts` ran out.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuthorizationState {
+    pub order: u64,
+    pub index: u32,
+    pub identifier: String,
+    pub status: ValidationStatus,
+    pub attempts: u32,
+    /// next attempt of a `processing` validation, IC time in nanoseconds
+    pub retry_at: Option<u64>,
+    /// why the last attempt failed
+    pub error: Option<String>,
+    /// the challenge that proved control, `None` when canister orders need no proof
+    pub validated_by: Option<ChallengeType>,
+    pub validated_at: Option<u64>,
+    /// the challenge the account responded to, canister orders attempt every offered one
+    pub challenge: Option<ChallengeType>,
+}
+
+candid_storable!(AuthorizationState);
+
+impl AuthorizationState {
+    fn problem(&self) -> Option<Error> {
+        self.error.as_ref().map(|detail| Error {
+            r#type: AcmeServerError::InvalidChallenge.urn().to_string(),
+            title: "Forbidden".to_string(),
+            detail: detail.clone(),
+            status: 403,
+            instance: None,
+            limit: None,
+            reset: None,
+            field: None,
+        })
+    }
+
+    pub fn url(&self) -> String {
+        let resource = format!("{AUTHZ_PATH}{}/{}", self.order, self.index);
+
+        format!("{}{}", Config::base_url(), capability::path(&resource))
+    }
+
+    fn challenge_url(&self, kind: ChallengeType) -> String {
+        let resource = format!(
+            "{CHALLENGE_PATH}{}/{}/{}",
+            self.order,
+            self.index,
+            kind.as_str()
+        );
+
+        format!("{}{}", Config::base_url(), capability::path(&resource))
+    }
+
+    /// RFC 8555 §7.1.3, a wildcard is authorized at its base domain and only through dns-01
+    pub fn is_wildcard(&self) -> bool {
+        self.identifier.starts_with("*.")
+    }
+
+    /// refuses `kind` unless the identifier is offered it, e.g. http-01 for a wildcard
+    pub fn check_offered(&self, kind: ChallengeType) -> anyhow::Result<()> {
+        if challenge::offered(&self.identifier).contains(&kind) {
+            return anyhow::Ok(());
+        }
+
+        match self.is_wildcard() {
+            true => Err(anyhow!(
+                "wildcard identifiers can only be validated with dns-01, not {}",
+                kind.as_str()
+            )),
+            false => Err(anyhow!(
+                "{} is not offered for {}",
+                kind.as_str(),
+                self.identifier
+            )),
+        }
+    }
+
+    /// Every offered challenge is attempted for canister orders, so they share the status of the
+    /// validation. Once valid, only the one that proved control is. An account only ever has the
+    /// challenge it responded to attempted, the others stay `pending`.
+    pub fn to_challenge(&self, kind: ChallengeType) -> Challenge {
+        let picked = self.challenge.or(self
+            .validated_by
+            .filter(|_| self.status == ValidationStatus::Valid));
+        let status = match picked {
+            Some(picked) if picked != kind => ValidationStatus::Pending,
+            _ => self.status,
+        };
+
+        Challenge {
+            r#type: kind.as_str().to_string(),
+            url: self.challenge_url(kind),
+            token: TokenStore::get(self.order, self.index)
+                .map(|t| t.token)
+                .unwrap_or_else(|| CANISTER_TOKEN.to_string()),
+            status: status.as_str().to_string(),
+            validated: self
+                .validated_at
+                .filter(|_| status == ValidationStatus::Valid)
+                .map(clock::rfc3339),
+            error: self
+                .problem()
+                .filter(|_| status != ValidationStatus::Valid && picked.is_none_or(|p| p == kind)),
+        }
+    }
+
+    /// RFC 8555 §7.1.4, an authorization stays `pending` while its challenge is processed
+    pub fn to_acme(&self) -> Authorization {
+        let status = match self.status {
+            ValidationStatus::Valid => "valid",
+            ValidationStatus::Invalid => "invalid",
+            ValidationStatus::Pending | ValidationStatus::Processing => "pending",
+        };
+
+        Authorization {
+            status: status.to_string(),
+            expires: OrderManager::get(self.order).map(|o| clock::rfc3339(o.expires_at)),
+            identifier: Identifier {
+                r#type: "dns".to_string(),
+                value: self
+                    .identifier
+                    .strip_prefix("*.")
+                    .unwrap_or(&self.identifier)
+                    .to_string(),
+            },
+            challenges: challenge::offered(&self.identifier)
+                .into_iter()
+                .map(|kind| self.to_challenge(kind))
+                .collect(),
+            wildcard: self.is_wildcard().then_some(true),
+        }
+    }
+
+    /// seconds until the next attempt, the `Retry-After` of a `processing` validation
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        let retry_at = self
+            .retry_at
+            .filter(|_| self.status == ValidationStatus::Processing)?;
+
+        Some(retry_at.saturating_sub(clock::now_nanos()) / 1_000_000_000)
+    }
+}
+
+/// `<order>/<index>` of an authorization
+pub fn authz_ref(resource: &str) -> Option<(u64, u32)> {
+    let (order, index) = resource.split_once('/')?;
+
+    Some((order.parse().ok()?, index.parse().ok()?))
+}
+
+/// `<order>/<index>/<type>` of a challenge
+pub fn challenge_ref(resource: &str) -> Option<(u64, u32, ChallengeType)> {
+    let (authz, kind) = resource.rsplit_once('/')?;
+    let (order, index) = authz_ref(authz)?;
+    let kind = [
+        ChallengeType::Http01,
+        ChallengeType::Dns01,
+        ChallengeType::TlsAlpn01,
+    ]
+    .into_iter()
+    .find(|t| t.as_str() == kind)?;
+
+    Some((order, index, kind))
+}
+
+/// memory marker for the validations accounts asked for, with when each is attempted next
+pub struct PendingValidations;
+
+/// Per-name validation state of orders, keyed by order and the index of the name. Canister orders
+/// are validated by their job, the challenges accounts respond to are attempted by the job worker
+/// from [`PendingValidations`].
+pub struct AuthorizationStore {
+    authorizations: Repository<(u64, u32), AuthorizationState>,
+    pending: Repository<(u64, u32), u64>,
+}
+
+impl AuthorizationStore {
+    fn init() -> Self {
+        Self {
+            authorizations: Repository::init::<Self>(),
+            pending: Repository::init::<PendingValidations>(),
+        }
+    }
+
+    /// drops the validation from those the worker attempts, once it is done either way
+    fn settle(order: u64, index: u32) {
+        let settled =
+            AUTHORIZATIONS.with_borrow_mut(|a| a.pending.remove(&(order, index)).is_some());
+
+        if settled {
+            LoadShedder::drained(Queue::Validation);
+        }
+    }
+
+    fn update(order: u64, index: u32, f: impl FnOnce(&mut AuthorizationState)) {
+        AUTHORIZATIONS.with_borrow_mut(|a| a.authorizations.update(&(order, index), f));
+    }
+
+    /// a `pending` validation for every name of `order`, each with a fresh challenge token that
+    /// expires at `expires_at`
+    pub fn open(order: u64, domains: &[String], expires_at: u64) -> anyhow::Result<()> {
+        for index in 0..domains.len() as u32 {
+            TokenStore::issue(order, index, expires_at)?;
+        }
+
+        AUTHORIZATIONS.with_borrow_mut(|a| {
+            for (index, domain) in domains.iter().enumerate() {
+                a.authorizations.insert(
+                    (order, index as u32),
+                    AuthorizationState {
+                        order,
+                        index: index as u32,
+                        identifier: domain.clone(),
+                        status: ValidationStatus::Pending,
+                        attempts: 0,
+                        retry_at: None,
+                        error: None,
+                        validated_by: None,
+                        validated_at: None,
+                        challenge: None,
+                    },
+                );
+            }
+        });
+
+        anyhow::Ok(())
+    }
+
+    pub fn get(order: u64, index: u32) -> Option<AuthorizationState> {
+        AUTHORIZATIONS.with_borrow(|a| a.authorizations.get(&(order, index)))
+    }
+
+    pub fn for_order(order: u64) -> Vec<AuthorizationState> {
+        AUTHORIZATIONS.with_borrow(|a| {
+            a.authorizations
+                .range((order, 0)..=(order, u32::MAX))
+                .map(|(_, state)| state)
+                .collect()
+        })
+    }
+
+    /// counts an attempt, returns how many were made
+    pub fn attempt(order: u64, index: u32) -> u32 {
+        let mut attempts = 0;
+
+        Self::update(order, index, |s| {
+            s.status = ValidationStatus::Processing;
+            s.attempts += 1;
+            s.retry_at = None;
+            attempts = s.attempts;
+        });
+
+        attempts
+    }
+
+    pub fn validated(order: u64, index: u32, by: Option<ChallengeType>) {
+        Self::update(order, index, |s| {
+            s.status = ValidationStatus::Valid;
+            s.error = None;
+            s.validated_by = by;
+            s.validated_at = Some(clock::now_nanos());
+        });
+        Self::settle(order, index);
+    }
+
+    /// `retry_at` keeps the validation `processing`, without it the validation turns `invalid`
+    pub fn failed(order: u64, index: u32, error: String, retry_at: Option<u64>) {
+        Self::update(order, index, |s| {
+            s.status = match retry_at {
+                Some(_) => ValidationStatus::Processing,
+                None => ValidationStatus::Invalid,
+            };
+            s.error = Some(error);
+            s.retry_at = retry_at;
+        });
+
+        match retry_at {
+            Some(at) => AUTHORIZATIONS.with_borrow_mut(|a| {
+                a.pending.update(&(order, index), |due| *due = at);
+            }),
+            None => Self::settle(order, index),
+        }
+    }
+
+    /// RFC 8555 §7.5.1, the account asks for `kind` to be validated. Only a `pending`
+    /// authorization starts a validation, the worker attempts it in its next round.
+    pub fn respond(
+        order: u64,
+        index: u32,
+        kind: ChallengeType,
+    ) -> anyhow::Result<AuthorizationState> {
+        let state = Self::get(order, index).ok_or_else(|| anyhow!("no such authorization"))?;
+
+        if state.status != ValidationStatus::Pending {
+            return anyhow::Ok(state);
+        }
+
+        let now = clock::now_nanos();
+
+        Self::update(order, index, |s| {
+            s.status = ValidationStatus::Processing;
+            s.challenge = Some(kind);
+            s.retry_at = Some(now);
+        });
+        AUTHORIZATIONS.with_borrow_mut(|a| a.pending.insert((order, index), now));
+        LoadShedder::enqueued(Queue::Validation);
+
+        Self::get(order, index).ok_or_else(|| anyhow!("no such authorization"))
+    }
+
+    /// at most `limit` validations accounts asked for that are due at `now`
+    pub fn due(now: u64, limit: usize) -> Vec<AuthorizationState> {
+        AUTHORIZATIONS.with_borrow(|a| {
+            a.pending
+                .iter()
+                .filter(|(_, due)| *due <= now)
+                .filter_map(|(key, _)| a.authorizations.get(&key))
+                .take(limit)
+                .collect()
+        })
+    }
+
+    /// how many validations accounts asked for are still to be attempted
+    pub fn pending_len() -> u64 {
+        AUTHORIZATIONS.with_borrow(|a| a.pending.len())
+    }
+
+    /// RFC 8555 §7.5.1, a POST to a challenge of a validation waiting for its next attempt runs
+    /// that attempt in the next worker round. It still counts against `challenge_attempts`.
+    pub fn trigger(order: u64, index: u32) -> anyhow::Result<AuthorizationState> {
+        let state = Self::get(order, index).ok_or_else(|| anyhow!("no such authorization"))?;
+
+        if state.retry_at.is_none() || state.status != ValidationStatus::Processing {
+            return anyhow::Ok(state);
+        }
+
+        let now = clock::now_nanos();
+        let responded = AUTHORIZATIONS.with_borrow_mut(|a| {
+            a.pending
+                .update(&(order, index), |due| *due = now)
+                .is_some()
+        });
+
+        if !responded {
+            JobQueue::requeue(order).map_err(|e| anyhow!("{e:?}"))?;
+        }
+
+        Self::update(order, index, |s| s.retry_at = Some(now));
+
+        Self::get(order, index).ok_or_else(|| anyhow!("no such authorization"))
+    }
+}
@@ -0,0 +1,502 @@
+use std::cell::RefCell;
+
+use candid::{CandidType, Nat, Principal};
+use icrc_ledger_types::{
+    icrc1::{
+        account::Account,
+        transfer::{Memo, TransferArg, TransferError},
+    },
+    icrc2::transfer_from::{TransferFromArgs, TransferFromError},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::{ApiError, ApiResult},
+    clock,
+    config::Config,
+    mem::{candid_storable, Repository},
+};
+
+thread_local! {
+    static BILLING: RefCell<Billing> = RefCell::new(Billing::init());
+}
+
+/// Fee charged per certificate when set, through ICRC-2 `icrc2_transfer_from` on `ledger`.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BillingConfig {
+    /// ICRC-2 ledger the fee is paid on
+    pub ledger: Principal,
+    /// in the ledger's base units, the ledger's own transfer fee comes on top
+    pub fee: u64,
+}
+
+/// An ICRC-1 account, owner and optional 32 byte subaccount.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LedgerAccount {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl LedgerAccount {
+    fn to_icrc(&self) -> ApiResult<Account> {
+        let subaccount = self
+            .subaccount
+            .as_ref()
+            .map(|s| {
+                <[u8; 32]>::try_from(s.as_slice())
+                    .map_err(|_| ApiError::InvalidArgument("a subaccount has 32 bytes".to_string()))
+            })
+            .transpose()?;
+
+        Ok(Account {
+            owner: self.owner,
+            subaccount,
+        })
+    }
+}
+
+/// Who certificate fees are billed to, a canister consumer or an ACME account.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Payer {
+    Principal(Principal),
+    /// an ACME account by id, it has no ledger account of its own and only pays through the one
+    /// it accepted
+    Account(String),
+}
+
+candid_storable!(Payer);
+
+impl Payer {
+    /// the ledger account fees come from when no other payer was accepted
+    fn default_account(&self) -> ApiResult<LedgerAccount> {
+        match self {
+            Self::Principal(owner) => Ok(LedgerAccount {
+                owner: *owner,
+                subaccount: None,
+            }),
+            Self::Account(id) => Err(ApiError::InvalidArgument(format!(
+                "account {id} has no payer, a principal has to link one with `link_billing_account`"
+            ))),
+        }
+    }
+}
+
+/// Who pays for the certificates of one consumer, the consumer's own default account unless the
+/// consumer accepted another payer.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct BillingProfile {
+    pub payer: Option<LedgerAccount>,
+    /// offered by a payer with `link_billing_account`, pays only once the consumer accepts it
+    pub proposed_payer: Option<LedgerAccount>,
+    /// set by a controller, certificates are issued without a fee
+    pub exempt: bool,
+}
+
+candid_storable!(BillingProfile);
+
+/// A fee taken for one issuance, kept until the certificate it paid for is signed so a retried
+/// signature is not charged again.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Payment {
+    pub payer: LedgerAccount,
+    pub ledger: Principal,
+    pub amount: u64,
+    pub block_index: Nat,
+    pub paid_at: u64,
+    /// the certificate the fee went to, `None` while it is being signed
+    pub serial: Option<u64>,
+    /// block of the transfer that gave the fee back, the issuance failed for good
+    pub refunded: Option<Nat>,
+}
+
+candid_storable!(Payment);
+
+/// One transfer to the ledger, kept until the ledger answered it. Every retry sends the same
+/// `created_at_time` and memo, so the ledger answers a transfer that went through with
+/// `Duplicate`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Attempt {
+    pub created_at_time: u64,
+    pub memo: Vec<u8>,
+}
+
+candid_storable!(Attempt);
+
+/// payment key of a queued order
+pub fn order_key(order: u64) -> String {
+    format!("order:{order}")
+}
+
+/// payments waiting to be refunded are kept under this prefix
+const REFUND_PREFIX: &str = "refund:";
+/// refunds tried per worker round
+const REFUNDS_PER_ROUND: usize = 10;
+
+/// memory markers for billing profiles, payments and charges in flight
+pub struct BillingProfiles;
+pub struct BillingPayments;
+pub struct BillingAttempts;
+
+/// Billing profiles by consumer and payments by the issuance they paid for, e.g. `order:<id>`.
+pub struct Billing {
+    profiles: Repository<Payer, BillingProfile>,
+    payments: Repository<String, Payment>,
+    /// transfers whose outcome is not known yet, by the payment they are for, `refund:` prefixed
+    /// for refunds
+    attempts: Repository<String, Attempt>,
+}
+
+impl Billing {
+    fn init() -> Self {
+        Self {
+            profiles: Repository::init::<BillingProfiles>(),
+            payments: Repository::init::<BillingPayments>(),
+            attempts: Repository::init::<BillingAttempts>(),
+        }
+    }
+
+    pub fn profile(consumer: &Payer) -> BillingProfile {
+        BILLING
+            .with_borrow(|b| b.profiles.get(consumer))
+            .unwrap_or_default()
+    }
+
+    fn update_profile(consumer: Payer, f: impl FnOnce(&mut BillingProfile)) -> BillingProfile {
+        let mut profile = Self::profile(&consumer);
+        f(&mut profile);
+
+        BILLING.with_borrow_mut(|b| b.profiles.insert(consumer, profile.clone()));
+
+        profile
+    }
+
+    /// `payer` offers to take over the fees of `consumer` from `subaccount`, the payer has to be
+    /// the caller as it is the one whose approval is drawn on. Nothing changes until the consumer
+    /// accepts, see [`Self::accept`].
+    pub fn link(
+        consumer: Payer,
+        payer: Principal,
+        subaccount: Option<Vec<u8>>,
+    ) -> ApiResult<BillingProfile> {
+        if payer == Principal::anonymous() {
+            return Err(ApiError::Unauthorized);
+        }
+
+        let account = LedgerAccount {
+            owner: payer,
+            subaccount,
+        };
+        account.to_icrc()?;
+
+        Ok(Self::update_profile(consumer, |p| {
+            p.proposed_payer = Some(account)
+        }))
+    }
+
+    /// the consumer, who has to be the caller or the account that signed the request, accepts the
+    /// payer `payer` proposed
+    pub fn accept(consumer: Payer, payer: Principal) -> ApiResult<BillingProfile> {
+        let profile = Self::profile(&consumer);
+
+        let Some(proposed) = profile.proposed_payer.filter(|p| p.owner == payer) else {
+            return Err(ApiError::NotFound(format!(
+                "no payer {payer} proposed for {consumer:?}"
+            )));
+        };
+
+        Ok(Self::update_profile(consumer, |p| {
+            p.payer = Some(proposed);
+            p.proposed_payer = None;
+        }))
+    }
+
+    /// back to the consumer paying for itself, either side may unlink, a pending proposal is
+    /// withdrawn as well
+    pub fn unlink(consumer: Payer, caller: Principal) -> ApiResult<BillingProfile> {
+        let profile = Self::profile(&consumer);
+        let is = |payer: &Option<LedgerAccount>| payer.as_ref().is_some_and(|p| p.owner == caller);
+
+        let allowed = consumer == Payer::Principal(caller)
+            || is(&profile.payer)
+            || is(&profile.proposed_payer)
+            || ic_cdk::api::is_controller(&caller);
+
+        if !allowed {
+            return Err(ApiError::Unauthorized);
+        }
+
+        Ok(Self::update_profile(consumer, |p| {
+            p.payer = None;
+            p.proposed_payer = None;
+        }))
+    }
+
+    pub fn set_exempt(consumer: Payer, exempt: bool) -> BillingProfile {
+        Self::update_profile(consumer, |p| p.exempt = exempt)
+    }
+
+    pub fn payment(key: &str) -> Option<Payment> {
+        BILLING.with_borrow(|b| b.payments.get(&key.to_string()))
+    }
+
+    /// Takes the configured fee for the issuance `key` from the payer of `consumer`, before it is
+    /// signed. Nothing is charged without billing, for exempt consumers or when `key` was paid
+    /// already. A missing approval fails the request, an unreachable ledger is worth a retry.
+    pub async fn charge(consumer: &Payer, key: &str) -> ApiResult<()> {
+        let Some(config) = Config::billing() else {
+            return Ok(());
+        };
+
+        let profile = Self::profile(consumer);
+
+        if profile.exempt || Self::payment(key).is_some() {
+            return Ok(());
+        }
+
+        let payer = match profile.payer {
+            Some(payer) => payer,
+            None => consumer.default_account()?,
+        };
+
+        let attempt = Self::attempt(key);
+
+        let args = TransferFromArgs {
+            spender_subaccount: None,
+            from: payer.to_icrc()?,
+            to: Account {
+                owner: ic_cdk::id(),
+                subaccount: None,
+            },
+            amount: Nat::from(config.fee),
+            fee: None,
+            memo: Some(Memo::from(attempt.memo)),
+            created_at_time: Some(attempt.created_at_time),
+        };
+
+        let (result,): (Result<Nat, TransferFromError>,) =
+            ic_cdk::call(config.ledger, "icrc2_transfer_from", (args,))
+                .await
+                .map_err(|(code, msg)| {
+                    ApiError::Internal(format!("icrc2_transfer_from failed: {code:?} {msg}"))
+                })?;
+
+        let block_index = match result {
+            Ok(block_index) => block_index,
+            // the first attempt went through, its reply was lost
+            Err(TransferFromError::Duplicate { duplicate_of }) => duplicate_of,
+            // whether the first attempt went through can't be told anymore, trying anew could
+            // take the fee twice. The attempt stays for reconciliation by its memo.
+            Err(TransferFromError::TooOld) => {
+                return Err(ApiError::InvalidArgument(format!(
+                    "the fee transfer for {key} is older than the ledger's deduplication window"
+                )))
+            }
+            Err(e) => {
+                // the ledger refused the transfer, nothing was taken
+                Self::forget_attempt(key);
+
+                return Err(match e {
+                    TransferFromError::InsufficientAllowance { .. }
+                    | TransferFromError::InsufficientFunds { .. }
+                    | TransferFromError::BadFee { .. } => ApiError::InvalidArgument(format!(
+                        "the fee of {} could not be taken from {}: {e}",
+                        config.fee, payer.owner
+                    )),
+                    e => ApiError::Internal(format!("the ledger refused the fee: {e}")),
+                });
+            }
+        };
+
+        let payment = Payment {
+            payer,
+            ledger: config.ledger,
+            amount: config.fee,
+            block_index,
+            paid_at: clock::now_nanos(),
+            serial: None,
+            refunded: None,
+        };
+
+        BILLING.with_borrow_mut(|b| {
+            b.payments.insert(key.to_string(), payment);
+            b.attempts.remove(&key.to_string());
+        });
+
+        Ok(())
+    }
+
+    /// the transfer tried for `key`, recorded before the first call so a reply lost after the
+    /// transfer went through doesn't turn the retry into a second transfer
+    fn attempt(key: &str) -> Attempt {
+        BILLING.with_borrow_mut(|b| {
+            b.attempts.get(&key.to_string()).unwrap_or_else(|| {
+                let attempt = Attempt {
+                    created_at_time: clock::now_nanos(),
+                    // names the payment the transfer is for on the ledger
+                    memo: Sha256::digest(key.as_bytes()).to_vec(),
+                };
+                b.attempts.insert(key.to_string(), attempt.clone());
+                attempt
+            })
+        })
+    }
+
+    fn forget_attempt(key: &str) {
+        BILLING.with_borrow_mut(|b| b.attempts.remove(&key.to_string()));
+    }
+
+    /// records the certificate the fee for `key` paid for, a later issuance under the same key
+    /// is charged again
+    pub fn settle(key: &str, serial: u64) {
+        BILLING.with_borrow_mut(|b| {
+            let Some(mut payment) = b.payments.remove(&key.to_string()) else {
+                return;
+            };
+
+            payment.serial = Some(serial);
+            b.payments.insert(format!("{key}/{serial}"), payment);
+        })
+    }
+
+    /// Gives the fee for `key` back, the issuance it paid for failed for good. The payment waits
+    /// under `refund:` until [`Self::refund_pending`] transferred it.
+    pub fn refund(key: &str) {
+        BILLING.with_borrow_mut(|b| {
+            if let Some(payment) = b.payments.remove(&key.to_string()) {
+                b.payments.insert(format!("{REFUND_PREFIX}{key}"), payment);
+            }
+        })
+    }
+
+    /// transfers the refunds that are waiting, called every worker round. A refund the ledger
+    /// doesn't take is tried again in the next one.
+    pub async fn refund_pending() {
+        let pending = BILLING.with_borrow(|b| {
+            b.payments
+                .range(REFUND_PREFIX.to_string()..)
+                .take_while(|(key, _)| key.starts_with(REFUND_PREFIX))
+                .take(REFUNDS_PER_ROUND)
+                .collect::<Vec<_>>()
+        });
+
+        for (key, payment) in pending {
+            if let Err(e) = Self::transfer_refund(&key, payment).await {
+                ic_cdk::println!("refund of {key} failed: {e:?}");
+            }
+        }
+    }
+
+    async fn transfer_refund(key: &str, mut payment: Payment) -> ApiResult<()> {
+        let attempt = Self::attempt(key);
+
+        let args = TransferArg {
+            from_subaccount: None,
+            to: payment.payer.to_icrc()?,
+            fee: None,
+            created_at_time: Some(attempt.created_at_time),
+            memo: Some(Memo::from(attempt.memo)),
+            amount: Nat::from(payment.amount),
+        };
+
+        let (result,): (Result<Nat, TransferError>,) =
+            ic_cdk::call(payment.ledger, "icrc1_transfer", (args,))
+                .await
+                .map_err(|(code, msg)| {
+                    ApiError::Internal(format!("icrc1_transfer failed: {code:?} {msg}"))
+                })?;
+
+        let block_index = match result {
+            Ok(block_index) => block_index,
+            Err(TransferError::Duplicate { duplicate_of }) => duplicate_of,
+            // whether the first attempt went through can't be told anymore, see `charge`. The
+            // payment leaves the queue, kept with its attempt for reconciliation.
+            Err(TransferError::TooOld) => {
+                BILLING.with_borrow_mut(|b| {
+                    b.payments.remove(&key.to_string());
+                    b.payments.insert(
+                        format!("{}/unrefunded", &key[REFUND_PREFIX.len()..]),
+                        payment,
+                    );
+                });
+
+                return Err(ApiError::Internal(
+                    "the refund is older than the ledger's deduplication window".to_string(),
+                ));
+            }
+            Err(e) => {
+                Self::forget_attempt(key);
+
+                return Err(ApiError::Internal(format!(
+                    "the ledger refused the refund: {e}"
+                )));
+            }
+        };
+
+        payment.refunded = Some(block_index);
+
+        BILLING.with_borrow_mut(|b| {
+            b.payments.remove(&key.to_string());
+            b.attempts.remove(&key.to_string());
+            b.payments
+                .insert(format!("{}/refunded", &key[REFUND_PREFIX.len()..]), payment);
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paid(key: &str) {
+        let payment = Payment {
+            payer: LedgerAccount {
+                owner: Principal::anonymous(),
+                subaccount: None,
+            },
+            ledger: Principal::management_canister(),
+            amount: 10,
+            block_index: Nat::from(1u8),
+            paid_at: 0,
+            serial: None,
+            refunded: None,
+        };
+
+        BILLING.with_borrow_mut(|b| b.payments.insert(key.to_string(), payment));
+    }
+
+    #[test]
+    fn settling_moves_the_payment_to_its_certificate() {
+        paid("order:1");
+        Billing::settle("order:1", 7);
+
+        assert!(Billing::payment("order:1").is_none());
+        assert_eq!(Billing::payment("order:1/7").unwrap().serial, Some(7));
+    }
+
+    #[test]
+    fn refunds_wait_until_transferred() {
+        paid("order:2");
+        Billing::refund("order:2");
+
+        assert!(Billing::payment("order:2").is_none());
+        assert!(Billing::payment("refund:order:2").is_some());
+
+        // nothing was paid for it
+        Billing::refund("order:3");
+        assert!(Billing::payment("refund:order:3").is_none());
+    }
+
+    #[test]
+    fn accounts_only_pay_through_a_linked_payer() {
+        let owner = Principal::management_canister();
+
+        assert_eq!(
+            Payer::Principal(owner).default_account().unwrap().owner,
+            owner
+        );
+        assert!(Payer::Account("a".to_string()).default_account().is_err());
+    }
+}
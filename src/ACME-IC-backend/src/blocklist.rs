@@ -0,0 +1,85 @@
+use std::cell::RefCell;
+
+use anyhow::anyhow;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use candid::CandidType;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    clock,
+    mem::{candid_storable, Repository},
+};
+
+thread_local! {
+    static BLOCKLIST: RefCell<KeyBlocklist> = RefCell::new(KeyBlocklist::init());
+}
+
+/// A key whose compromise was reported. It can't register an account or be certified again.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockedKey {
+    /// see [`spki_hash`]
+    pub spki_hash: String,
+    pub blocked_at: u64,
+    /// the certificate the compromise was reported for
+    pub reported_with: Option<u64>,
+}
+
+candid_storable!(BlockedKey);
+
+/// SHA-256 of a DER `SubjectPublicKeyInfo`, base64url encoded. Account keys and certificate keys
+/// are compared by it.
+pub fn spki_hash(spki_der: &[u8]) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(spki_der))
+}
+
+/// Compromised keys, keyed by [`spki_hash`].
+pub struct KeyBlocklist {
+    keys: Repository<String, BlockedKey>,
+}
+
+impl KeyBlocklist {
+    fn init() -> Self {
+        Self {
+            keys: Repository::init::<Self>(),
+        }
+    }
+
+    /// a key that is already blocked keeps its first report
+    pub fn block(spki_hash: &str, reported_with: Option<u64>) -> BlockedKey {
+        BLOCKLIST.with_borrow_mut(|b| {
+            if let Some(existing) = b.keys.get(&spki_hash.to_string()) {
+                return existing;
+            }
+
+            let blocked = BlockedKey {
+                spki_hash: spki_hash.to_string(),
+                blocked_at: clock::now_nanos(),
+                reported_with,
+            };
+
+            b.keys.insert(blocked.spki_hash.clone(), blocked.clone());
+
+            blocked
+        })
+    }
+
+    pub fn is_blocked(spki_hash: &str) -> bool {
+        BLOCKLIST.with_borrow(|b| b.keys.contains(&spki_hash.to_string()))
+    }
+
+    /// fails for a key that was reported as compromised
+    pub fn check(spki_der: &[u8]) -> anyhow::Result<()> {
+        if Self::is_blocked(&spki_hash(spki_der)) {
+            return Err(anyhow!(
+                "the key was reported as compromised and can't be used again"
+            ));
+        }
+
+        anyhow::Ok(())
+    }
+
+    pub fn list() -> Vec<BlockedKey> {
+        BLOCKLIST.with_borrow(|b| b.keys.values().collect())
+    }
+}
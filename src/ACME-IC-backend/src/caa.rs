@@ -0,0 +1,289 @@
+use anyhow::anyhow;
+
+use crate::dns::{self, RecordType};
+
+/// a single CAA resource record, RFC 8659 §4.1
+#[derive(Debug, Clone)]
+pub struct CaaRecord {
+    pub flags: u8,
+    pub tag: String,
+    pub value: String,
+}
+
+impl CaaRecord {
+    /// parses the presentation format returned by the resolver, e.g. `0 issue "letsencrypt.org"`
+    pub fn parse(data: &str) -> anyhow::Result<Self> {
+        let mut parts = data.splitn(3, ' ');
+
+        let flags = parts
+            .next()
+            .and_then(|f| f.parse::<u8>().ok())
+            .ok_or_else(|| anyhow!("invalid CAA flags in `{data}`"))?;
+        let tag = parts
+            .next()
+            .ok_or_else(|| anyhow!("missing CAA tag in `{data}`"))?
+            .to_ascii_lowercase();
+        let value = dns::unquote_txt(parts.next().unwrap_or_default());
+
+        anyhow::Ok(Self { flags, tag, value })
+    }
+
+    /// the issuer domain of an `issue`/`issuewild` value, without its parameters
+    pub fn issuer(&self) -> &str {
+        self.value.split(';').next().unwrap_or_default().trim()
+    }
+}
+
+/// RFC 8659 §4.1, the issuer critical flag
+const CRITICAL: u8 = 0x80;
+
+/// property tags this CA understands, a critical property with any other tag forbids issuance
+const KNOWN_TAGS: &[&str] = &[
+    "issue",
+    "issuewild",
+    "iodef",
+    "contactemail",
+    "contactphone",
+    "issuemail",
+];
+
+/// `domain` and each of its ancestors up to but not including the root, RFC 8659 §3. A wildcard
+/// is looked up at the domain it covers.
+fn tree(domain: &str) -> Vec<&str> {
+    let base = domain
+        .strip_prefix("*.")
+        .unwrap_or(domain)
+        .trim_end_matches('.');
+
+    std::iter::once(base)
+        .chain(base.match_indices('.').map(|(i, _)| &base[i + 1..]))
+        .collect()
+}
+
+/// Applies the relevant RRset of `domain` to an issuance by one of `identities`, RFC 8659 §4.
+///
+/// A wildcard is governed by `issuewild` when the set has any and falls back to `issue`
+/// otherwise, `issuewild` never applies to other names. A set without the governing property
+/// puts no restriction on issuance.
+fn evaluate(domain: &str, records: &[CaaRecord], identities: &[String]) -> anyhow::Result<()> {
+    if let Some(unknown) = records
+        .iter()
+        .find(|r| r.flags & CRITICAL != 0 && !KNOWN_TAGS.contains(&r.tag.as_str()))
+    {
+        return Err(anyhow!(
+            "CAA records for {domain} carry the unknown critical property `{}`",
+            unknown.tag
+        ));
+    }
+
+    let with_tag = |tag: &str| records.iter().filter(|r| r.tag == tag).collect::<Vec<_>>();
+
+    let mut governing = Vec::new();
+    if domain.starts_with("*.") {
+        governing = with_tag("issuewild");
+    }
+    if governing.is_empty() {
+        governing = with_tag("issue");
+    }
+
+    // no issue property means any CA may issue
+    if governing.is_empty() {
+        return anyhow::Ok(());
+    }
+
+    let permitted = governing.iter().any(|r| {
+        identities
+            .iter()
+            .any(|id| r.issuer().eq_ignore_ascii_case(id))
+    });
+
+    if !permitted {
+        return Err(anyhow!(
+            "CAA records for {domain} forbid issuance by this CA"
+        ));
+    }
+
+    anyhow::Ok(())
+}
+
+/// the first non-empty CAA RRset climbing from `domain` towards the root, RFC 8659 §3
+async fn relevant_records(domain: &str) -> anyhow::Result<Vec<CaaRecord>> {
+    for name in tree(domain) {
+        let records = dns::resolve(name, RecordType::Caa)
+            .await?
+            .iter()
+            .map(|data| CaaRecord::parse(data))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if !records.is_empty() {
+            return anyhow::Ok(records);
+        }
+    }
+
+    anyhow::Ok(Vec::new())
+}
+
+/// whether the CAA records relevant to `domain` allow one of `identities` to issue for it
+pub async fn check(domain: &str, identities: &[String]) -> anyhow::Result<()> {
+    let records = relevant_records(domain).await?;
+
+    evaluate(domain, &records, identities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CA: &str = "ic.encrypt.icp";
+
+    fn records(set: &[&str]) -> Vec<CaaRecord> {
+        set.iter().map(|r| CaaRecord::parse(r).unwrap()).collect()
+    }
+
+    fn allows(domain: &str, set: &[&str]) -> bool {
+        evaluate(domain, &records(set), &[CA.to_string()]).is_ok()
+    }
+
+    /// the RRset [`relevant_records`] settles on, with `zone` standing in for the resolver
+    fn climb(domain: &str, zone: &[(&str, &[&str])]) -> Vec<CaaRecord> {
+        tree(domain)
+            .into_iter()
+            .find_map(|name| {
+                zone.iter()
+                    .find(|(owner, set)| *owner == name && !set.is_empty())
+                    .map(|(_, set)| records(set))
+            })
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn parses_presentation_format() {
+        let record =
+            CaaRecord::parse(r#"0 ISSUE "letsencrypt.org; validationmethods=dns-01""#).unwrap();
+
+        assert_eq!(record.flags, 0);
+        assert_eq!(record.tag, "issue");
+        assert_eq!(record.issuer(), "letsencrypt.org");
+        assert!(CaaRecord::parse("issue letsencrypt.org").is_err());
+    }
+
+    #[test]
+    fn issue_names_the_permitted_cas() {
+        // google.com
+        assert!(!allows("google.com", &[r#"0 issue "pki.goog""#]));
+        // github.com
+        let github = [
+            r#"0 issue "digicert.com""#,
+            r#"0 issue "letsencrypt.org""#,
+            r#"0 issue "sectigo.com""#,
+            r#"0 issuewild "digicert.com""#,
+            r#"0 issuewild "sectigo.com""#,
+        ];
+        assert!(!allows("github.com", &github));
+
+        let with_us = [
+            r#"0 issue "pki.goog""#,
+            r#"0 issue "IC.encrypt.icp; account=42""#,
+        ];
+        assert!(allows("example.org", &with_us));
+        // RFC 8659 §4.2, an empty issuer forbids every CA
+        assert!(!allows("example.org", &[r#"0 issue ";""#]));
+    }
+
+    #[test]
+    fn a_set_without_issue_restricts_nothing() {
+        assert!(allows("example.org", &[]));
+        assert!(allows(
+            "example.org",
+            &[r#"0 iodef "mailto:security@example.org""#]
+        ));
+    }
+
+    #[test]
+    fn issuewild_governs_wildcards_only() {
+        let set = [
+            r#"0 issue "ic.encrypt.icp""#,
+            r#"0 issuewild ";""#,
+            r#"0 iodef "mailto:security@example.org""#,
+        ];
+        assert!(allows("www.example.org", &set));
+        assert!(!allows("*.example.org", &set));
+
+        // the inverse, only wildcards may be issued by this CA
+        let set = [
+            r#"0 issue "digicert.com""#,
+            r#"0 issuewild "ic.encrypt.icp""#,
+        ];
+        assert!(!allows("www.example.org", &set));
+        assert!(allows("*.example.org", &set));
+    }
+
+    #[test]
+    fn wildcards_fall_back_to_issue() {
+        let set = [
+            r#"0 issue "letsencrypt.org""#,
+            r#"0 issue "ic.encrypt.icp""#,
+        ];
+        assert!(allows("*.example.org", &set));
+        assert!(!allows("*.example.org", &[r#"0 issue "letsencrypt.org""#]));
+    }
+
+    #[test]
+    fn unknown_critical_properties_forbid_issuance() {
+        // RFC 8659 §4.5
+        let set = [r#"0 issue "ic.encrypt.icp""#, r#"128 tbs "Unknown""#];
+        assert!(!allows("example.org", &set));
+
+        let set = [r#"0 issue "ic.encrypt.icp""#, r#"0 tbs "Unknown""#];
+        assert!(allows("example.org", &set));
+
+        let set = [
+            r#"0 issue "ic.encrypt.icp""#,
+            r#"128 issue "ic.encrypt.icp""#,
+        ];
+        assert!(allows("example.org", &set));
+
+        // a critical property the CA knows doesn't stand in the way
+        let set = [
+            r#"0 issue "ic.encrypt.icp""#,
+            r#"128 iodef "mailto:security@example.org""#,
+        ];
+        assert!(allows("example.org", &set));
+    }
+
+    #[test]
+    fn tree_climbs_to_the_tld() {
+        assert_eq!(
+            tree("www.shop.example.org."),
+            [
+                "www.shop.example.org",
+                "shop.example.org",
+                "example.org",
+                "org"
+            ]
+        );
+        assert_eq!(tree("*.example.org"), ["example.org", "org"]);
+    }
+
+    #[test]
+    fn the_closest_non_empty_set_is_relevant() {
+        let zone: &[(&str, &[&str])] = &[
+            ("shop.example.org", &[]),
+            ("example.org", &[r#"0 issue "ic.encrypt.icp""#]),
+            ("org", &[r#"0 issue ";""#]),
+        ];
+
+        let set = climb("www.shop.example.org", zone);
+        assert_eq!(set.len(), 1);
+        assert!(evaluate("www.shop.example.org", &set, &[CA.to_string()]).is_ok());
+
+        // a set closer to the name replaces its parent's entirely
+        let zone: &[(&str, &[&str])] = &[
+            ("www.example.org", &[r#"0 issue "pki.goog""#]),
+            ("example.org", &[r#"0 issue "ic.encrypt.icp""#]),
+        ];
+        let set = climb("www.example.org", zone);
+        assert!(evaluate("www.example.org", &set, &[CA.to_string()]).is_err());
+        assert!(climb("example.com", zone).is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+use std::{cell::RefCell, time::Duration};
+
+use anyhow::anyhow;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use candid::CandidType;
+use hmac::{Hmac, Mac};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::StableCell;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{
+    mem::{candid_storable, Mem, Memory},
+    trace,
+};
+
+/// bytes of the HMAC a token keeps, 128 bits can't be guessed
+const TOKEN_BYTES: usize = 16;
+/// a failed `raw_rand` is tried again after this long
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+thread_local! {
+    static SECRET: RefCell<StableCell<CapabilitySecret, Memory>> = RefCell::new(
+        Mem::cell::<CapabilitySecret, _>(CapabilitySecret::default()),
+    );
+}
+
+/// HMAC key the tokens of capability URLs are derived with, drawn from `raw_rand` once
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct CapabilitySecret(Vec<u8>);
+
+candid_storable!(CapabilitySecret);
+
+fn secret() -> Vec<u8> {
+    SECRET.with_borrow(|cell| cell.get().0.clone())
+}
+
+fn mac(secret: &[u8], resource: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(resource.as_bytes());
+
+    mac
+}
+
+/// `resource`, e.g. `/order/12`, followed by `.` and its token so its URL can't be guessed from
+/// the id, bare until the secret is drawn
+pub fn path(resource: &str) -> String {
+    let secret = secret();
+
+    if secret.is_empty() {
+        return resource.to_string();
+    }
+
+    let tag = mac(&secret, resource).finalize().into_bytes();
+
+    format!(
+        "{resource}.{}",
+        BASE64_URL_SAFE_NO_PAD.encode(&tag[..TOKEN_BYTES])
+    )
+}
+
+/// the resource of a capability `path`, `None` when its token is missing or wrong
+pub fn verify(path: &str) -> Option<&str> {
+    let (resource, token) = path.rsplit_once('.')?;
+    let token = BASE64_URL_SAFE_NO_PAD.decode(token).ok()?;
+    let secret = secret();
+
+    if secret.is_empty() || token.len() != TOKEN_BYTES {
+        return None;
+    }
+
+    // constant time, the token is attacker controlled
+    mac(&secret, resource).verify_truncated_left(&token).ok()?;
+
+    Some(resource)
+}
+
+async fn draw() -> anyhow::Result<()> {
+    if !secret().is_empty() {
+        return anyhow::Ok(());
+    }
+
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|(code, msg)| anyhow!("failed to draw the capability secret: {code:?} {msg}"))?;
+
+    SECRET.with_borrow_mut(|cell| {
+        // tokens handed out under a secret must stay valid
+        if cell.get().0.is_empty() {
+            cell.set(CapabilitySecret(bytes))
+                .map_err(|e| anyhow!("failed to store the capability secret: {e:?}"))?;
+        }
+
+        anyhow::Ok(())
+    })
+}
+
+/// draws the secret right after install, has to be called again after every upgrade in case it
+/// never was
+pub fn start() {
+    schedule(Duration::ZERO);
+}
+
+fn schedule(delay: Duration) {
+    ic_cdk_timers::set_timer(delay, || {
+        ic_cdk::spawn(async {
+            if let Err(e) = draw().await {
+                trace::log(&e.to_string());
+                schedule(RETRY_DELAY);
+            }
+        })
+    });
+}
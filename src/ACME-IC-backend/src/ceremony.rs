@@ -0,0 +1,179 @@
+use std::cell::RefCell;
+
+use anyhow::anyhow;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use candid::{CandidType, Principal};
+use ic_stable_structures::StableCell;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x509_cert::der::{DecodePem, Encode};
+
+use crate::{
+    clock,
+    key::AcmeKey,
+    mem::{candid_storable, Mem, Memory, Repository},
+    router, trace,
+};
+
+pub const CEREMONY_PATH: &str = "/ceremony.json";
+
+thread_local! {
+    static TRANSCRIPT: RefCell<CeremonyTranscript> = RefCell::new(CeremonyTranscript::init());
+    static SIGNED: RefCell<StableCell<SignedTranscript, Memory>> = RefCell::new(
+        Mem::cell::<SignedTranscript, _>(SignedTranscript::default()),
+    );
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CeremonyKind {
+    RootCreated,
+    IntermediateCreated,
+    /// a root key certified by another root during a rotation
+    CrossSigned,
+}
+
+/// One trust anchor creation, everything an auditor needs to re-derive and check it.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CeremonyEntry {
+    pub sequence: u64,
+    pub kind: CeremonyKind,
+    pub subject: String,
+    pub serial: u64,
+    /// tECDSA derivation path of the certificate's key, each component base64url encoded
+    pub derivation_path: Vec<String>,
+    /// SEC1 public key from the certificate, base64url encoded
+    pub public_key: String,
+    /// SHA-256 over the DER certificate, base64url encoded
+    pub certificate_sha256: String,
+    /// IC time in nanoseconds
+    pub created_at: u64,
+    pub initiator: Principal,
+}
+
+candid_storable!(CeremonyEntry);
+
+/// The transcript as a detached-signature document, `payload` is the exact JSON that was signed.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SignedTranscript {
+    /// number of entries covered, lags behind the transcript until the background signing ran
+    pub entries: u64,
+    pub payload: String,
+    /// ecdsa-with-SHA256 over `payload` by the current root key, DER encoded then base64url
+    pub signature: String,
+    /// SEC1 key verifying `signature`, base64url encoded
+    pub signing_key: String,
+    pub signed_at: u64,
+}
+
+candid_storable!(SignedTranscript);
+
+/// Append-only record of every root and intermediate this canister created.
+pub struct CeremonyTranscript {
+    entries: Repository<u64, CeremonyEntry>,
+}
+
+impl CeremonyTranscript {
+    fn init() -> Self {
+        Self {
+            entries: Repository::init::<Self>(),
+        }
+    }
+
+    /// appends the creation of `pem`, issued to `key`, and re-signs the document in the background
+    pub fn record(kind: CeremonyKind, key: &AcmeKey, serial: u64, pem: &str) -> anyhow::Result<()> {
+        let cert = x509_cert::Certificate::from_pem(pem)?;
+        let tbs = &cert.tbs_certificate;
+
+        let public_key = tbs
+            .subject_public_key_info
+            .subject_public_key
+            .as_bytes()
+            .ok_or_else(|| anyhow!("certificate key is not octet aligned"))?;
+
+        TRANSCRIPT.with_borrow_mut(|t| {
+            let sequence = t.entries.last().map(|(s, _)| s + 1).unwrap_or(0);
+
+            t.entries.insert(
+                sequence,
+                CeremonyEntry {
+                    sequence,
+                    kind,
+                    subject: tbs.subject.to_string(),
+                    serial,
+                    derivation_path: key
+                        .derivation_path()
+                        .iter()
+                        .map(|c| BASE64_URL_SAFE_NO_PAD.encode(c))
+                        .collect(),
+                    public_key: BASE64_URL_SAFE_NO_PAD.encode(public_key),
+                    certificate_sha256: BASE64_URL_SAFE_NO_PAD
+                        .encode(Sha256::digest(cert.to_der()?)),
+                    created_at: clock::now_nanos(),
+                    initiator: ic_cdk::caller(),
+                },
+            );
+
+            anyhow::Ok(())
+        })?;
+
+        sign_in_background();
+
+        anyhow::Ok(())
+    }
+
+    pub fn entries() -> Vec<CeremonyEntry> {
+        TRANSCRIPT.with_borrow(|t| t.entries.values().collect())
+    }
+}
+
+/// signs the current transcript with the root key, replacing the served document
+pub async fn sign() -> anyhow::Result<SignedTranscript> {
+    let entries = CeremonyTranscript::entries();
+    let payload = serde_json::to_string(&entries)?;
+
+    let root = AcmeKey::issuer();
+    let signing_key = root.public_key().await?;
+    let signature = root.sign(payload.as_bytes()).await?;
+
+    let signed = SignedTranscript {
+        entries: entries.len() as u64,
+        payload,
+        signature: BASE64_URL_SAFE_NO_PAD.encode(signature.as_bytes()),
+        signing_key: BASE64_URL_SAFE_NO_PAD.encode(signing_key.to_encoded_point(false).as_bytes()),
+        signed_at: clock::now_nanos(),
+    };
+
+    SIGNED.with_borrow_mut(|cell| {
+        // a signing round that started later may already have covered more entries
+        if cell.get().entries > signed.entries {
+            return anyhow::Ok(());
+        }
+
+        cell.set(signed.clone())
+            .map_err(|e| anyhow!("failed to store the signed transcript: {e:?}"))?;
+
+        anyhow::Ok(())
+    })?;
+
+    router::certify_resources();
+
+    anyhow::Ok(signed)
+}
+
+fn sign_in_background() {
+    ic_cdk::spawn(async {
+        if let Err(e) = sign().await {
+            trace::log(&format!("signing the ceremony transcript failed: {e}"));
+        }
+    });
+}
+
+/// the last signed transcript, `None` before any trust anchor was created
+pub fn signed() -> Option<SignedTranscript> {
+    SIGNED.with_borrow(|cell| {
+        let signed = cell.get();
+
+        (signed.entries > 0).then(|| signed.clone())
+    })
+}
@@ -1,27 +1,892 @@
-use std::ops::Add;
+use std::{cell::RefCell, ops::Add};
 
+use anyhow::anyhow;
+use candid::{CandidType, Principal};
 use ic_stable_structures::StableCell;
-use x509_cert::name::Name;
+use serde::Deserialize;
+use x509_cert::{
+    der::{pem::LineEnding, Encode, EncodePem},
+    ext::pkix::{name::GeneralName, AuthorityKeyIdentifier, SubjectAltName, SubjectKeyIdentifier},
+    name::Name,
+    spki::SubjectPublicKeyInfoOwned,
+};
 
-use crate::{key::AcmeKey, mem::Memory};
+use crate::{
+    audit::{AuditAction, AuditActor, AuditLog, AuditOutcome},
+    blocklist,
+    ceremony::{CeremonyKind, CeremonyTranscript},
+    clock,
+    config::Config,
+    csr::Csr,
+    ct::{self, Embedding},
+    events::Events,
+    key::{AcmeKey, Certificate, ROOT_SERIAL_NUMBER},
+    mem::{candid_storable, Mem, Memory, Repository},
+    metrics, policy,
+    profile::Issuance,
+    quota::AccountQuotas,
+    revocation::RevocationRegistry,
+    trace,
+};
+
+/// resource below a tenant's base path that searches the certificates of an account by domain
+pub const CERTIFICATE_SEARCH: &str = "/certificates";
+/// most certificates a [`CertificateManager::search`] returns
+pub const MAX_SEARCH_RESULTS: usize = 100;
+/// most index entries a search over every owner reads, a short suffix can name most of the archive
+const MAX_SEARCH_SCAN: usize = 10_000;
+
+thread_local! {
+    static CERTIFICATES: RefCell<CertificateManager> = RefCell::new(CertificateManager::init());
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CertificateOwner {
+    Account(String),
+    Canister(Principal),
+}
+
+impl CertificateOwner {
+    /// the owner as the first part of an index key
+    fn index_key(&self) -> String {
+        match self {
+            Self::Account(id) => format!("account/{id}"),
+            Self::Canister(principal) => format!("canister/{principal}"),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IssuedCertificate {
+    pub serial: u64,
+    pub domains: Vec<String>,
+    /// leaf followed by the issuing root
+    pub pem_chain: String,
+    pub not_before: u64,
+    pub not_after: u64,
+    pub issued_at: u64,
+    pub owner: CertificateOwner,
+    /// set for certificates issued elsewhere and imported, `serial` is then only an archive id
+    pub imported: Option<ImportedFrom>,
+    /// subject key identifier of the leaf
+    pub key_id: Option<Vec<u8>>,
+    /// subject key identifier of the certificate that signed the leaf, from its authority key
+    /// identifier, the chain is rebuilt by following it
+    pub issuer_key_id: Option<Vec<u8>>,
+}
+
+/// where a certificate stands, as a search reports it
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CertificateStatus {
+    Valid,
+    Expired,
+    Revoked,
+}
+
+impl CertificateStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Valid => "valid",
+            Self::Expired => "expired",
+            Self::Revoked => "revoked",
+        }
+    }
+}
+
+/// A certificate found by [`CertificateManager::search`], without its chain.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CertificateMatch {
+    pub serial: u64,
+    pub domains: Vec<String>,
+    pub owner: CertificateOwner,
+    pub status: CertificateStatus,
+    pub not_before: u64,
+    pub not_after: u64,
+    pub imported: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ImportedFrom {
+    /// issuer DN as it appears in the certificate
+    pub issuer: String,
+    /// serial number assigned by that issuer, hex encoded
+    pub serial: String,
+    /// HTTPS endpoint told when the certificate is about to expire
+    pub notify_url: Option<String>,
+    pub expiry_notified: bool,
+}
+
+impl IssuedCertificate {
+    fn leaf(&self) -> anyhow::Result<x509_cert::Certificate> {
+        x509_cert::Certificate::load_pem_chain(self.pem_chain.as_bytes())?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("the chain holds no certificate"))
+    }
+
+    /// DER of the leaf, as a revocation request carries it
+    pub fn leaf_der(&self) -> anyhow::Result<Vec<u8>> {
+        anyhow::Ok(self.leaf()?.to_der()?)
+    }
+
+    /// DER `SubjectPublicKeyInfo` of the leaf
+    pub fn leaf_spki(&self) -> anyhow::Result<Vec<u8>> {
+        let leaf = self.leaf()?;
+
+        anyhow::Ok(leaf.tbs_certificate.subject_public_key_info.to_der()?)
+    }
+
+    pub fn key_hash(&self) -> anyhow::Result<String> {
+        anyhow::Ok(blocklist::spki_hash(&self.leaf_spki()?))
+    }
+
+    /// `keyIdentifier` of the leaf's authority key identifier, names the key that signed it
+    pub fn authority_key_id(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.issuer_key_id.is_some() {
+            return anyhow::Ok(self.issuer_key_id.clone());
+        }
+
+        anyhow::Ok(key_ids(&self.leaf()?)?.authority)
+    }
+
+    /// revoked once revoked here, expired past `not_after`, imported certificates can only expire
+    pub fn status(&self, now: u64) -> CertificateStatus {
+        if self.imported.is_none() && RevocationRegistry::get(self.serial).is_some() {
+            return CertificateStatus::Revoked;
+        }
+
+        match self.not_after <= now {
+            true => CertificateStatus::Expired,
+            false => CertificateStatus::Valid,
+        }
+    }
+
+    pub fn to_match(&self, now: u64) -> CertificateMatch {
+        CertificateMatch {
+            serial: self.serial,
+            domains: self.domains.clone(),
+            owner: self.owner.clone(),
+            status: self.status(now),
+            not_before: self.not_before,
+            not_after: self.not_after,
+            imported: self.imported.is_some(),
+        }
+    }
+
+    /// records the key identifiers of the leaf, see [`Self::key_id`] and [`Self::issuer_key_id`]
+    fn with_key_ids(mut self) -> anyhow::Result<Self> {
+        let ids = key_ids(&self.leaf()?)?;
+        (self.key_id, self.issuer_key_id) = (ids.subject, ids.authority);
+
+        anyhow::Ok(self)
+    }
+}
+
+candid_storable!(IssuedCertificate);
+
+/// Another path from the issuer of leaves to a root, e.g. a cross-sign by an established root or
+/// the previous intermediate after a rotation. It is offered as an alternate of every leaf whose
+/// issuer is the subject of its first certificate.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IssuerChain {
+    pub id: u64,
+    /// subject DN of the first certificate, the issuer a leaf has to name
+    pub issuer: String,
+    /// PEM certificates without the leaf, each followed by the one that signed it
+    pub pem: String,
+    pub added_at: u64,
+    /// subject key identifier of the first certificate, a leaf's issuer key id has to match it
+    pub key_id: Option<Vec<u8>>,
+}
+
+candid_storable!(IssuerChain);
+
+/// one leaf of a [`CertificateManager::issue_batch`]
+pub struct LeafRequest {
+    pub domains: Vec<String>,
+    pub public_key: SubjectPublicKeyInfoOwned,
+    pub owner: CertificateOwner,
+    pub issuance: Issuance,
+}
+
+/// The leaves of a batch, or why one couldn't be signed, in the order they were requested.
+pub struct SignedBatch {
+    pub results: Vec<anyhow::Result<IssuedCertificate>>,
+    /// drop of the cycle balance while the batch was signed, calls running meanwhile included
+    pub cycles: u128,
+}
+
+/// memory markers for issued certificates, their indexes, the cached root and alternate chains
+pub struct CertificateStore;
+pub struct RootCertificateCell;
+pub struct CertificateDomainIndex;
+pub struct CertificateExpiryIndex;
+pub struct ImportedCertificateIndex;
+pub struct IssuerChainStore;
+pub struct CertificateKeyIndex;
+pub struct CertificateSuffixIndex;
+pub struct CertificateOwnerSuffixIndex;
 
 pub struct CertificateManager {
     serial_number_registry: StableCell<u64, Memory>,
+    certificates: Repository<u64, IssuedCertificate>,
+    root_pem: StableCell<String, Memory>,
+    by_domain: Repository<(String, u64), ()>,
+    by_expiry: Repository<(u64, u64), ()>,
+    /// `{issuer}/{serial}` of every imported certificate, so the same one is not archived twice
+    imported: Repository<String, u64>,
+    chains: Repository<u64, IssuerChain>,
+    /// [`blocklist::spki_hash`] of the leaf key and serial
+    by_key: Repository<(String, u64), ()>,
+    /// names with their labels reversed, `org.example.www`, so a domain and every name below it
+    /// are one range
+    by_suffix: Repository<(String, u64), ()>,
+    /// [`Self::by_suffix`] below the owner's [`CertificateOwner::index_key`], so a search of one
+    /// owner only reads its own names
+    by_owner_suffix: Repository<(String, String, u64), ()>,
 }
 
 impl CertificateManager {
+    fn init() -> Self {
+        Self {
+            serial_number_registry: Mem::cell::<Self, _>(ROOT_SERIAL_NUMBER + 1),
+            certificates: Repository::init::<CertificateStore>(),
+            root_pem: Mem::cell::<RootCertificateCell, _>(String::new()),
+            by_domain: Repository::init::<CertificateDomainIndex>(),
+            by_expiry: Repository::init::<CertificateExpiryIndex>(),
+            imported: Repository::init::<ImportedCertificateIndex>(),
+            chains: Repository::init::<IssuerChainStore>(),
+            by_key: Repository::init::<CertificateKeyIndex>(),
+            by_suffix: Repository::init::<CertificateSuffixIndex>(),
+            by_owner_suffix: Repository::init::<CertificateOwnerSuffixIndex>(),
+        }
+    }
+
+    fn _index(&mut self, cert: &IssuedCertificate) {
+        for domain in &cert.domains {
+            self.by_domain.insert((domain.clone(), cert.serial), ());
+            self.by_suffix.insert((reversed(domain), cert.serial), ());
+            self.by_owner_suffix
+                .insert((cert.owner.index_key(), reversed(domain), cert.serial), ());
+        }
+
+        self.by_expiry.insert((cert.not_after, cert.serial), ());
+
+        if let Some(imported) = &cert.imported {
+            self.imported.insert(
+                format!("{}/{}", imported.issuer, imported.serial),
+                cert.serial,
+            );
+        }
+
+        // a chain that doesn't parse was never issued here and can't be revoked by key anyway
+        if let Ok(hash) = cert.key_hash() {
+            self.by_key.insert((hash, cert.serial), ());
+        }
+    }
+
+    fn _store(&mut self, cert: IssuedCertificate) {
+        self._index(&cert);
+        self.certificates.insert(cert.serial, cert);
+    }
+
     fn _inc_serial_number(&mut self) -> u64 {
         let current = self.serial_number_registry.get().to_owned();
 
-        self.serial_number_registry.set(current.add(1)).unwrap();
+        if let Err(e) = self.serial_number_registry.set(current.add(1)) {
+            Mem::write_failed("the serial number", e);
+        }
 
         current.to_owned()
     }
 
-    pub fn generate_cert(&mut self, domain: Name) -> String {
-        let serial_number = self._inc_serial_number();
+    /// the root certificate, created and recorded in the ceremony transcript on first use
+    pub async fn root_pem() -> anyhow::Result<String> {
+        let pem = CERTIFICATES.with_borrow(|m| m.root_pem.get().to_owned());
+
+        if !pem.is_empty() {
+            return anyhow::Ok(pem);
+        }
+
+        let pem = Certificate::build_root().await?;
+
+        // another call may have stored a root while this one was waiting for its signature
+        let (stored, created) = CERTIFICATES.with_borrow_mut(|m| {
+            if !m.root_pem.get().is_empty() {
+                return anyhow::Ok((m.root_pem.get().to_owned(), false));
+            }
+
+            m.root_pem
+                .set(pem.clone())
+                .map_err(|e| anyhow!("failed to store the root certificate: {e:?}"))?;
+
+            anyhow::Ok((pem, true))
+        })?;
+
+        if created {
+            if let Err(e) = CeremonyTranscript::record(
+                CeremonyKind::RootCreated,
+                &AcmeKey::issuer(),
+                ROOT_SERIAL_NUMBER,
+                &stored,
+            ) {
+                trace::log(&format!("failed to record the root ceremony: {e}"));
+            }
+        }
+
+        anyhow::Ok(stored)
+    }
+
+    /// subject of the root new leaves are chained to, `None` until it is created
+    pub fn root_name() -> Option<Name> {
+        let pem = CERTIFICATES.with_borrow(|m| m.root_pem.get().to_owned());
+
+        // there is no root before the first issuance, and parsing an empty chain panics
+        if pem.is_empty() {
+            return None;
+        }
+
+        x509_cert::Certificate::load_pem_chain(pem.as_bytes())
+            .ok()?
+            .into_iter()
+            .next()
+            .map(|root| root.tbs_certificate.subject)
+    }
+
+    /// takes a fresh serial, e.g. for a CA certificate issued outside [`CertificateManager::issue`]
+    pub fn next_serial() -> u64 {
+        CERTIFICATES.with_borrow_mut(|m| m._inc_serial_number())
+    }
+
+    /// makes `pem` the root new leaves are chained to, once issuance switched to its key
+    pub fn replace_root(pem: &str) -> anyhow::Result<()> {
+        CERTIFICATES.with_borrow_mut(|m| {
+            m.root_pem
+                .set(pem.to_string())
+                .map_err(|e| anyhow!("failed to store the root certificate: {e:?}"))?;
+
+            anyhow::Ok(())
+        })
+    }
+
+    /// signs a leaf for `domains` over `public_key` and stores it under a fresh serial, logging it
+    /// as a precertificate first when Certificate Transparency is enabled
+    pub async fn issue(
+        domains: Vec<String>,
+        public_key: SubjectPublicKeyInfoOwned,
+        owner: CertificateOwner,
+        issuance: &Issuance,
+    ) -> anyhow::Result<IssuedCertificate> {
+        let (issuer, root_pem) = Self::issuer_for(issuance).await?;
+        let serial = Self::next_serial();
+
+        let request = LeafRequest {
+            domains,
+            public_key,
+            owner,
+            issuance: issuance.clone(),
+        };
+        let cert = Self::build(&issuer, &root_pem, serial, request).await?;
+
+        Self::commit(vec![cert.clone()]);
+
+        anyhow::Ok(cert)
+    }
+
+    /// Signs a leaf for each request at once, the threshold signatures are awaited concurrently
+    /// instead of one after the other. The leaves that were signed are stored even when others
+    /// failed, serials taken by a failed leaf stay unused.
+    pub async fn issue_batch(requests: Vec<LeafRequest>) -> anyhow::Result<SignedBatch> {
+        let mut issuers = Vec::new();
+
+        for request in &requests {
+            issuers.push(Self::issuer_for(&request.issuance).await?);
+        }
+
+        let balance = ic_cdk::api::canister_balance128();
+
+        let builds = requests
+            .into_iter()
+            .zip(&issuers)
+            .map(|(request, (issuer, root_pem))| {
+                let serial = Self::next_serial();
+
+                Self::build(issuer, root_pem, serial, request)
+            });
+
+        let results = futures::future::join_all(builds).await;
+
+        let signed = results
+            .iter()
+            .filter_map(|r| r.as_ref().ok().cloned())
+            .collect::<Vec<_>>();
+        let signed_count = signed.len() as u64;
+        Self::commit(signed);
+
+        let batch = SignedBatch {
+            results,
+            cycles: balance.saturating_sub(ic_cdk::api::canister_balance128()),
+        };
+        metrics::batch_signed(signed_count, batch.cycles);
+
+        anyhow::Ok(batch)
+    }
+
+    /// the key and root that sign for `issuance`, those of its tenant when it names one
+    async fn issuer_for(issuance: &Issuance) -> anyhow::Result<(AcmeKey, String)> {
+        match &issuance.tenant {
+            Some(tenant) => anyhow::Ok((tenant.issuer_key(), tenant.root_pem().await?)),
+            None => anyhow::Ok((AcmeKey::issuer(), Self::root_pem().await?)),
+        }
+    }
+
+    async fn build(
+        issuer: &AcmeKey,
+        root_pem: &str,
+        serial: u64,
+        request: LeafRequest,
+    ) -> anyhow::Result<IssuedCertificate> {
+        let LeafRequest {
+            domains,
+            public_key,
+            owner,
+            issuance,
+        } = request;
+
+        let subject = Certificate::leaf_subject(&domains)?;
+        let (not_before, not_after) = (issuance.not_before, issuance.not_after);
+        let validity = Certificate::validity(not_before, not_after)?;
+        let policy = Config::ct();
+
+        // the precertificate and the final leaf must only differ in their CT extension
+        let ct = if policy.enabled {
+            let precert = Certificate::build_leaf(
+                issuer,
+                serial,
+                subject.clone(),
+                public_key.clone(),
+                validity,
+                &domains,
+                &issuance.profile,
+                &Embedding::Poison,
+            )
+            .await?;
+
+            let required = ct::required_scts(&policy, not_before, not_after);
+            ct::embedding(&policy, serial, &precert, root_pem, required).await?
+        } else {
+            Embedding::None
+        };
+
+        let leaf = Certificate::build_leaf(
+            issuer,
+            serial,
+            subject,
+            public_key,
+            validity,
+            &domains,
+            &issuance.profile,
+            &ct,
+        )
+        .await?;
+
+        anyhow::Ok(
+            IssuedCertificate {
+                serial,
+                domains,
+                pem_chain: format!("{leaf}{root_pem}"),
+                not_before,
+                not_after,
+                issued_at: clock::now_nanos(),
+                owner,
+                imported: None,
+                key_id: None,
+                issuer_key_id: None,
+            }
+            .with_key_ids()?,
+        )
+    }
+
+    /// stores signed leaves in one go, without an await in between
+    fn commit(certificates: Vec<IssuedCertificate>) {
+        CERTIFICATES.with_borrow_mut(|m| {
+            for cert in &certificates {
+                m._store(cert.clone());
+            }
+        });
+
+        for cert in &certificates {
+            metrics::certificate_issued();
+            AccountQuotas::certificate_issued(cert);
+            Events::issued(cert);
+        }
+    }
+
+    /// Archives a certificate issued elsewhere, e.g. obtained in client mode, next to the ones
+    /// this CA issued. `pem_chain` is stored as given, leaf first, and is not verified.
+    pub fn import(
+        pem_chain: &str,
+        owner: CertificateOwner,
+        notify_url: Option<String>,
+    ) -> anyhow::Result<IssuedCertificate> {
+        let imported = Self::archive(pem_chain, owner, notify_url);
+
+        AuditLog::record(
+            AuditActor::Principal(ic_cdk::caller()),
+            AuditAction::CertificateImported,
+            imported
+                .as_ref()
+                .map(|c| c.domains.clone())
+                .unwrap_or_default(),
+            AuditOutcome::of(&imported),
+            imported.as_ref().ok().map(|c| c.serial),
+        );
+
+        imported
+    }
+
+    fn archive(
+        pem_chain: &str,
+        owner: CertificateOwner,
+        notify_url: Option<String>,
+    ) -> anyhow::Result<IssuedCertificate> {
+        let chain = x509_cert::Certificate::load_pem_chain(pem_chain.as_bytes())?;
+        let leaf = chain
+            .first()
+            .ok_or_else(|| anyhow!("the chain holds no certificate"))?;
+        let tbs = &leaf.tbs_certificate;
+
+        let mut domains = match tbs.get::<SubjectAltName>()? {
+            Some((_, san)) => san
+                .0
+                .into_iter()
+                .filter_map(|name| match name {
+                    GeneralName::DnsName(name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        // certificates without SANs name their domain in the CN only
+        if domains.is_empty() {
+            domains = Csr::common_names(&tbs.subject)?;
+        }
+
+        if domains.is_empty() {
+            return Err(anyhow!("the certificate names no domain"));
+        }
+
+        let domains = policy::normalize_domains(&domains);
+        let not_before = tbs.validity.not_before.to_unix_duration().as_nanos() as u64;
+        let not_after = tbs.validity.not_after.to_unix_duration().as_nanos() as u64;
+
+        if not_after <= clock::now_nanos() {
+            return Err(anyhow!("the certificate has already expired"));
+        }
+
+        let issuer = tbs.issuer.to_string();
+        let serial = tbs
+            .serial_number
+            .as_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        let key = format!("{issuer}/{serial}");
+
+        let ids = key_ids(leaf)?;
+
+        CERTIFICATES.with_borrow_mut(|m| {
+            if let Some(id) = m.imported.get(&key) {
+                return Err(anyhow!("the certificate was already imported as {id}"));
+            }
+
+            let cert = IssuedCertificate {
+                serial: m._inc_serial_number(),
+                domains,
+                pem_chain: pem_chain.to_string(),
+                not_before,
+                not_after,
+                issued_at: clock::now_nanos(),
+                owner,
+                imported: Some(ImportedFrom {
+                    issuer,
+                    serial,
+                    notify_url,
+                    expiry_notified: false,
+                }),
+                key_id: ids.subject,
+                issuer_key_id: ids.authority,
+            };
+
+            m._store(cert.clone());
+
+            anyhow::Ok(cert)
+        })
+    }
+
+    pub fn get(serial: u64) -> Option<IssuedCertificate> {
+        CERTIFICATES.with_borrow(|m| m.certificates.get(&serial))
+    }
+
+    /// every archived certificate naming `domain`, issued or imported
+    pub fn for_domain(domain: &str) -> Vec<IssuedCertificate> {
+        let domain = domain.to_ascii_lowercase();
+
+        CERTIFICATES.with_borrow(|m| {
+            m.by_domain
+                .range((domain.clone(), 0)..=(domain, u64::MAX))
+                .filter_map(|((_, serial), _)| m.certificates.get(&serial))
+                .collect()
+        })
+    }
+
+    /// Archived certificates naming `domain`, and with `subdomains` also those naming any name
+    /// below it, wildcards included. Only those of `owner` when given. Newest first, at most
+    /// [`MAX_SEARCH_RESULTS`]. A search over every owner reads at most [`MAX_SEARCH_SCAN`] names.
+    pub fn search(
+        domain: &str,
+        subdomains: bool,
+        owner: Option<&CertificateOwner>,
+    ) -> Vec<IssuedCertificate> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let exact = reversed(&domain);
+        let below = format!("{exact}.");
+        let matches = |name: &String| *name == exact || (subdomains && name.starts_with(&below));
+
+        CERTIFICATES.with_borrow(|m| {
+            let mut serials = match owner {
+                Some(owner) => {
+                    let owner = owner.index_key();
+
+                    m.by_owner_suffix
+                        .range((owner.clone(), exact.clone(), 0)..)
+                        .map(|((o, name, serial), _)| (o, name, serial))
+                        .take_while(|(o, name, _)| *o == owner && name.starts_with(&exact))
+                        .filter(|(_, name, _)| matches(name))
+                        .map(|(_, _, serial)| serial)
+                        .collect::<Vec<_>>()
+                }
+                None => m
+                    .by_suffix
+                    .range((exact.clone(), 0)..)
+                    .take(MAX_SEARCH_SCAN)
+                    .map(|((name, serial), _)| (name, serial))
+                    .take_while(|(name, _)| name.starts_with(&exact))
+                    .filter(|(name, _)| matches(name))
+                    .map(|(_, serial)| serial)
+                    .collect::<Vec<_>>(),
+            };
+
+            serials.sort_unstable_by(|a, b| b.cmp(a));
+            serials.dedup();
+
+            serials
+                .into_iter()
+                .take(MAX_SEARCH_RESULTS)
+                .filter_map(|serial| m.certificates.get(&serial))
+                .collect()
+        })
+    }
+
+    /// every archived certificate for the key with [`blocklist::spki_hash`] `hash`
+    pub fn with_key(hash: &str) -> Vec<IssuedCertificate> {
+        CERTIFICATES.with_borrow(|m| {
+            m.by_key
+                .range((hash.to_string(), 0)..=(hash.to_string(), u64::MAX))
+                .filter_map(|((_, serial), _)| m.certificates.get(&serial))
+                .collect()
+        })
+    }
+
+    /// certificates that are still valid but expire before `before`, soonest first
+    pub fn expiring(before: u64) -> Vec<IssuedCertificate> {
+        let now = clock::now_nanos();
+
+        CERTIFICATES.with_borrow(|m| {
+            m.by_expiry
+                .range((now, 0)..(before, 0))
+                .filter_map(|((_, serial), _)| m.certificates.get(&serial))
+                .collect()
+        })
+    }
+
+    /// records that the expiry warning for imported certificate `serial` went out
+    pub fn mark_expiry_notified(serial: u64) {
+        CERTIFICATES.with_borrow_mut(|m| {
+            m.certificates.update(&serial, |cert| {
+                if let Some(imported) = &mut cert.imported {
+                    imported.expiry_notified = true;
+                }
+            })
+        });
+    }
+
+    /// registers an alternate chain, `pem` starts with the certificate that issues the leaves
+    pub fn add_chain(pem: &str) -> anyhow::Result<IssuerChain> {
+        let chain = x509_cert::Certificate::load_pem_chain(pem.as_bytes())?;
+        let first = chain
+            .first()
+            .ok_or_else(|| anyhow!("the chain holds no certificate"))?;
+
+        for pair in chain.windows(2) {
+            let (child, parent) = (&pair[0].tbs_certificate, &pair[1].tbs_certificate);
+
+            if child.issuer != parent.subject {
+                return Err(anyhow!(
+                    "{} is not issued by {}, the chain is out of order",
+                    child.subject,
+                    parent.subject
+                ));
+            }
+        }
+
+        let ders = chain_der(pem)?;
+
+        CERTIFICATES.with_borrow_mut(|m| {
+            for existing in m.chains.values() {
+                if chain_der(&existing.pem)? == ders {
+                    return Err(anyhow!("the chain was already added as {}", existing.id));
+                }
+            }
+
+            let chain = IssuerChain {
+                id: m.chains.last().map(|(id, _)| id + 1).unwrap_or(1),
+                issuer: first.tbs_certificate.subject.to_string(),
+                pem: pem.to_string(),
+                added_at: clock::now_nanos(),
+                key_id: key_ids(first)?.subject,
+            };
+
+            m.chains.insert(chain.id, chain.clone());
+
+            anyhow::Ok(chain)
+        })
+    }
+
+    pub fn remove_chain(id: u64) -> Option<IssuerChain> {
+        CERTIFICATES.with_borrow_mut(|m| m.chains.remove(&id))
+    }
+
+    pub fn chains() -> Vec<IssuerChain> {
+        CERTIFICATES.with_borrow(|m| m.chains.values().collect())
+    }
+
+    /// Every PEM chain `cert` can be downloaded as, the one it was issued or imported with first.
+    /// The others pair the leaf with each added chain for its issuer and key, RFC 8555 §7.4.2.
+    pub fn chain_set(cert: &IssuedCertificate) -> anyhow::Result<Vec<String>> {
+        let chain = x509_cert::Certificate::load_pem_chain(cert.pem_chain.as_bytes())?;
+        let leaf = chain
+            .first()
+            .ok_or_else(|| anyhow!("the chain holds no certificate"))?;
+        let issuer = leaf.tbs_certificate.issuer.to_string();
+        let authority_key = cert.authority_key_id()?;
+
+        let issued_with = chain[1..]
+            .iter()
+            .map(|c| c.to_der())
+            .collect::<Result<Vec<_>, _>>()?;
+        let leaf_pem = leaf.to_pem(LineEnding::LF)?;
+
+        let mut set = vec![cert.pem_chain.clone()];
+
+        for alternate in CERTIFICATES.with_borrow(|m| m.chains.values().collect::<Vec<_>>()) {
+            if alternate.issuer == issuer
+                && issues_key(&alternate, authority_key.as_deref())
+                && chain_der(&alternate.pem)? != issued_with
+            {
+                set.push(format!("{leaf_pem}{}", alternate.pem));
+            }
+        }
+
+        anyhow::Ok(set)
+    }
+
+    /// builds the domain, suffix, expiry and key indexes for certificates stored before they
+    /// existed
+    pub fn index_existing() -> anyhow::Result<()> {
+        CERTIFICATES.with_borrow_mut(|m| {
+            let certificates = m.certificates.values().collect::<Vec<_>>();
+
+            for cert in &certificates {
+                m._index(cert);
+            }
+        });
+
+        anyhow::Ok(())
+    }
+
+    /// records the key identifiers of certificates stored before they were kept
+    pub fn link_existing() -> anyhow::Result<()> {
+        CERTIFICATES.with_borrow_mut(|m| {
+            for cert in m.certificates.values().collect::<Vec<_>>() {
+                if cert.key_id.is_none() && cert.issuer_key_id.is_none() {
+                    m.certificates.insert(cert.serial, cert.with_key_ids()?);
+                }
+            }
+
+            anyhow::Ok(())
+        })
+    }
+
+    /// records the key identifier of chains added before it was kept
+    pub fn link_existing_chains() -> anyhow::Result<()> {
+        CERTIFICATES.with_borrow_mut(|m| {
+            for mut chain in m.chains.values().collect::<Vec<_>>() {
+                let first = x509_cert::Certificate::load_pem_chain(chain.pem.as_bytes())?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| anyhow!("chain {} holds no certificate", chain.id))?;
+
+                chain.key_id = key_ids(&first)?.subject;
+                m.chains.insert(chain.id, chain);
+            }
+
+            anyhow::Ok(())
+        })
+    }
+}
+
+/// The key identifiers a certificate carries, either may be missing from certificates issued
+/// elsewhere.
+struct KeyIds {
+    /// the subject key identifier
+    subject: Option<Vec<u8>>,
+    /// the `keyIdentifier` of the authority key identifier
+    authority: Option<Vec<u8>>,
+}
+
+/// subject key identifier and the `keyIdentifier` of the authority key identifier of `cert`
+fn key_ids(cert: &x509_cert::Certificate) -> anyhow::Result<KeyIds> {
+    let tbs = &cert.tbs_certificate;
+    let subject = tbs
+        .get::<SubjectKeyIdentifier>()?
+        .map(|(_, ski)| ski.0.as_bytes().to_vec());
+    let authority = tbs
+        .get::<AuthorityKeyIdentifier>()?
+        .and_then(|(_, aki)| aki.key_identifier)
+        .map(|id| id.as_bytes().to_vec());
+
+    anyhow::Ok(KeyIds { subject, authority })
+}
+
+/// `www.example.org` as `org.example.www`
+fn reversed(domain: &str) -> String {
+    domain.rsplit('.').collect::<Vec<_>>().join(".")
+}
+
+fn chain_der(pem: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+    x509_cert::Certificate::load_pem_chain(pem.as_bytes())?
+        .iter()
+        .map(|c| anyhow::Ok(c.to_der()?))
+        .collect()
+}
 
-        let key = AcmeKey::new(domain, serial_number);
-        crate::key::Certificate::new(key).build()
+/// Whether the first certificate of `chain` holds the key `authority_key` names. Roots rotated
+/// under the same name are told apart by key, certificates without identifiers match by name.
+fn issues_key(chain: &IssuerChain, authority_key: Option<&[u8]>) -> bool {
+    match (&chain.key_id, authority_key) {
+        (Some(key_id), Some(authority_key)) => key_id == authority_key,
+        _ => true,
     }
 }
@@ -0,0 +1,235 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use anyhow::anyhow;
+use ic_http_certification::{
+    utils::add_v2_certificate_header, DefaultCelBuilder, DefaultResponseCertification,
+    HttpCertification, HttpCertificationPath, HttpCertificationTree, HttpCertificationTreeEntry,
+    HttpResponse, CERTIFICATE_EXPRESSION_HEADER_NAME,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    ceremony::CEREMONY_PATH,
+    compression::{self, Encoding},
+    crl::{CRL_PATH, VERSIONED_CRL_PATH},
+};
+
+/// headers that differ between otherwise identical responses, certifying them would mean
+/// re-certifying on every request
+pub const VOLATILE_HEADERS: &[&str] = &["Replay-Nonce", "Retry-After", "Date", "Cache-Control"];
+
+/// Which response headers a certified resource commits to. The certificate expression header is
+/// always certified.
+pub enum CertifiedHeaders {
+    /// only these
+    Only(&'static [&'static str]),
+    /// every header but these
+    Excluding(&'static [&'static str]),
+}
+
+/// Resources served with a certified response, anything else is served with certification
+/// skipped. Each entry becomes its own CEL expression, so a resource only certifies the headers a
+/// client acts on and the tree holds one leaf per resource. An entry ending in `/` covers every
+/// resource below it.
+const CERTIFIED: &[(&str, CertifiedHeaders)] = &[
+    (
+        CRL_PATH,
+        CertifiedHeaders::Only(&["Content-Type", "Content-Encoding"]),
+    ),
+    (
+        VERSIONED_CRL_PATH,
+        CertifiedHeaders::Only(&["Content-Type", "Content-Encoding"]),
+    ),
+    (CEREMONY_PATH, CertifiedHeaders::Excluding(VOLATILE_HEADERS)),
+];
+
+thread_local! {
+    static CERTIFICATION: RefCell<Certification> = RefCell::new(Certification::default());
+}
+
+/// One coding of a certified resource, `None` is the body as it is.
+struct Variant {
+    encoding: Option<Encoding>,
+    entry: HttpCertificationTreeEntry<'static>,
+    response: HttpResponse<'static>,
+    /// SHA-256 of the body, hashed once when it is certified
+    body_sha256: Vec<u8>,
+}
+
+/// A piece of a certified body, see [`chunk`].
+pub struct Chunk {
+    pub bytes: Vec<u8>,
+    /// length of the whole body
+    pub len: usize,
+    pub body_sha256: Vec<u8>,
+}
+
+/// Kept on the heap, [`crate::router::certify_resources`] rebuilds it after an upgrade.
+#[derive(Default)]
+struct Certification {
+    tree: HttpCertificationTree,
+    /// the certified responses of each resource, one per coding it is served in
+    responses: HashMap<String, Vec<Variant>>,
+}
+
+fn skip_entry() -> HttpCertificationTreeEntry<'static> {
+    HttpCertificationTreeEntry::new(
+        HttpCertificationPath::wildcard(""),
+        HttpCertification::skip(),
+    )
+}
+
+fn commit(tree: &HttpCertificationTree) {
+    ic_cdk::api::set_certified_data(&tree.root_hash());
+}
+
+/// certifies that every resource without its own entry is deliberately served uncertified
+pub fn init() {
+    CERTIFICATION.with_borrow_mut(|c| {
+        c.tree.insert(&skip_entry());
+        commit(&c.tree);
+    })
+}
+
+/// Replaces the certified responses of `path`, only callable from an update, a timer or an
+/// upgrade hook since it sets the canister's certified data. Large bodies are certified once per
+/// coding, so a compressed response verifies as well.
+pub fn certify(path: &str, response: HttpResponse<'static>) -> anyhow::Result<()> {
+    let headers = CERTIFIED
+        .iter()
+        .find(|(p, _)| *p == path || (p.ends_with('/') && path.starts_with(p)))
+        .map(|(_, headers)| headers)
+        .ok_or_else(|| anyhow!("{path} is not a certified resource"))?;
+
+    let certification = match headers {
+        CertifiedHeaders::Only(names) => {
+            DefaultResponseCertification::certified_response_headers(*names)
+        }
+        CertifiedHeaders::Excluding(names) => {
+            DefaultResponseCertification::response_header_exclusions(*names)
+        }
+    };
+    let expression = DefaultCelBuilder::response_only_certification()
+        .with_response_certification(certification)
+        .build();
+
+    let variants = compression::variants(response)
+        .into_iter()
+        .map(|(encoding, mut response)| {
+            response.add_header((
+                CERTIFICATE_EXPRESSION_HEADER_NAME.to_string(),
+                expression.to_string(),
+            ));
+
+            let entry = HttpCertificationTreeEntry::new(
+                HttpCertificationPath::exact(path.to_string()),
+                HttpCertification::response_only(&expression, &response, None)?,
+            );
+
+            anyhow::Ok(Variant {
+                encoding,
+                entry,
+                body_sha256: Sha256::digest(response.body()).to_vec(),
+                response,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    CERTIFICATION.with_borrow_mut(|c| {
+        for previous in c.responses.remove(path).unwrap_or_default() {
+            c.tree.delete(&previous.entry);
+        }
+
+        for variant in &variants {
+            c.tree.insert(&variant.entry);
+        }
+
+        c.responses.insert(path.to_string(), variants);
+        commit(&c.tree);
+    });
+
+    anyhow::Ok(())
+}
+
+/// the coding of `path` that `accept_encoding` prefers, or the first one
+fn variant<'a>(
+    c: &'a Certification,
+    path: &str,
+    accept_encoding: Option<&str>,
+) -> Option<&'a Variant> {
+    let wanted = compression::negotiate(accept_encoding);
+    let variants = c.responses.get(path)?;
+
+    variants
+        .iter()
+        .find(|v| v.encoding == wanted)
+        .or_else(|| variants.first())
+}
+
+/// the certified response of `path` in the coding `accept_encoding` prefers, with its witness
+/// attached, `None` if it has none
+pub fn certified(path: &str, accept_encoding: Option<&str>) -> Option<HttpResponse<'static>> {
+    let data_certificate = ic_cdk::api::data_certificate()?;
+
+    CERTIFICATION.with_borrow(|c| {
+        let variant = variant(c, path, accept_encoding)?;
+        let witness = c.tree.witness(&variant.entry, path).ok()?;
+        let mut response = variant.response.clone();
+
+        add_v2_certificate_header(
+            &data_certificate,
+            &mut response,
+            &witness,
+            &variant.entry.path.to_expr_path(),
+        );
+
+        Some(response)
+    })
+}
+
+/// `len` bytes from `offset` of the body [`certified`] serves for `path`, sliced from the stored
+/// response. `None` if `path` has no certified response.
+pub fn chunk(
+    path: &str,
+    accept_encoding: Option<&str>,
+    offset: usize,
+    len: usize,
+) -> Option<Chunk> {
+    CERTIFICATION.with_borrow(|c| {
+        let variant = variant(c, path, accept_encoding)?;
+        let body = variant.response.body();
+        let start = offset.min(body.len());
+        let end = body.len().min(start + len);
+
+        Some(Chunk {
+            bytes: body[start..end].to_vec(),
+            len: body.len(),
+            body_sha256: variant.body_sha256.clone(),
+        })
+    })
+}
+
+/// attaches the proof that `url` is served uncertified on purpose
+pub fn skipped(url: &str, mut response: HttpResponse<'static>) -> HttpResponse<'static> {
+    let Some(data_certificate) = ic_cdk::api::data_certificate() else {
+        return response;
+    };
+
+    let entry = skip_entry();
+    let Ok(witness) = CERTIFICATION.with_borrow(|c| c.tree.witness(&entry, url)) else {
+        return response;
+    };
+
+    response.add_header((
+        CERTIFICATE_EXPRESSION_HEADER_NAME.to_string(),
+        DefaultCelBuilder::skip_certification().to_string(),
+    ));
+    add_v2_certificate_header(
+        &data_certificate,
+        &mut response,
+        &witness,
+        &entry.path.to_expr_path(),
+    );
+
+    response
+}
@@ -0,0 +1,252 @@
+use anyhow::anyhow;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use candid::Principal;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use x509_cert::{
+    der::{
+        asn1::{ObjectIdentifier, OctetString},
+        Decode,
+    },
+    ext::pkix::{name::GeneralName, SubjectAltName},
+};
+
+use crate::{
+    config::Config,
+    dns::{self, RecordType},
+    handler::types::ChallengeType,
+    metrics, prober,
+};
+
+/// RFC 8737 §3, `id-pe-acmeIdentifier`
+const ACME_IDENTIFIER: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.5.5.7.1.31");
+/// stands in for the challenge token of canister consumers, see [`canister_key_authorization`]
+pub const CANISTER_TOKEN: &str = "ic-principal";
+
+/// Proves control of an identifier for one challenge type, RFC 8555 §8.
+pub trait ChallengeValidator {
+    async fn validate(
+        &self,
+        identifier: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> anyhow::Result<()>;
+}
+
+pub struct Http01;
+pub struct Dns01;
+pub struct TlsAlpn01;
+
+/// TXT record value proving control of a domain for `key_authorization`, RFC 8555 §8.4
+pub fn dns01_txt_value(key_authorization: &str) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(key_authorization.as_bytes()))
+}
+
+/// Canister consumers have no account key, their principal stands in for the key authorization.
+pub fn canister_key_authorization(principal: &Principal) -> String {
+    key_authorization(CANISTER_TOKEN, principal)
+}
+
+/// `token` followed by the principal that stands in for the account key, RFC 8555 §8.1
+fn key_authorization(token: &str, principal: &Principal) -> String {
+    format!("{token}.{principal}")
+}
+
+/// constant time, what a server presents is compared against a secret it must not learn bytewise
+fn matches(found: &[u8], expected: &[u8]) -> bool {
+    found.ct_eq(expected).into()
+}
+
+/// `_acme-challenge` name for `domain`, wildcards are validated at their base domain
+pub fn dns01_record_name(domain: &str) -> String {
+    let base = domain.strip_prefix("*.").unwrap_or(domain);
+
+    format!("_acme-challenge.{base}")
+}
+
+/// SHA-256 of the key authorization, the `acmeIdentifier` of a tls-alpn-01 certificate, RFC 8737 §3
+pub fn tls_alpn01_digest(key_authorization: &str) -> Vec<u8> {
+    Sha256::digest(key_authorization.as_bytes()).to_vec()
+}
+
+impl ChallengeValidator for Http01 {
+    async fn validate(
+        &self,
+        identifier: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> anyhow::Result<()> {
+        let path = format!("/.well-known/acme-challenge/{token}");
+        let body = prober::http_resource(identifier, &path).await?;
+
+        // RFC 8555 §8.3, trailing whitespace is tolerated
+        if !matches(body.trim_ascii_end(), key_authorization.as_bytes()) {
+            return Err(anyhow!(
+                "http://{identifier}{path} does not hold the key authorization"
+            ));
+        }
+
+        anyhow::Ok(())
+    }
+}
+
+impl ChallengeValidator for Dns01 {
+    async fn validate(
+        &self,
+        identifier: &str,
+        _: &str,
+        key_authorization: &str,
+    ) -> anyhow::Result<()> {
+        let expected = dns01_txt_value(key_authorization);
+        let name = dns01_record_name(identifier);
+
+        let found = dns::resolve(&name, RecordType::Txt)
+            .await?
+            .iter()
+            .any(|data| matches(dns::unquote_txt(data).as_bytes(), expected.as_bytes()));
+
+        if !found {
+            return Err(anyhow!("no matching TXT record at {name}"));
+        }
+
+        anyhow::Ok(())
+    }
+}
+
+impl ChallengeValidator for TlsAlpn01 {
+    /// RFC 8737 §3, the validation certificate names the identifier alone and carries the digest
+    /// of the key authorization in a critical `acmeIdentifier` extension
+    async fn validate(
+        &self,
+        identifier: &str,
+        _: &str,
+        key_authorization: &str,
+    ) -> anyhow::Result<()> {
+        let cert = prober::tls_alpn_certificate(identifier).await?;
+        let tbs = &cert.tbs_certificate;
+
+        let names = match tbs.get::<SubjectAltName>()? {
+            Some((_, san)) => san.0,
+            None => Vec::new(),
+        };
+
+        match names.as_slice() {
+            [GeneralName::DnsName(name)] if name.as_str().eq_ignore_ascii_case(identifier) => {}
+            _ => {
+                return Err(anyhow!(
+                    "the validation certificate must name {identifier} alone"
+                ))
+            }
+        }
+
+        let extension = tbs
+            .extensions
+            .iter()
+            .flatten()
+            .find(|e| e.extn_id == ACME_IDENTIFIER)
+            .ok_or_else(|| anyhow!("the validation certificate has no acmeIdentifier extension"))?;
+
+        if !extension.critical {
+            return Err(anyhow!("the acmeIdentifier extension must be critical"));
+        }
+
+        let value = OctetString::from_der(extension.extn_value.as_bytes())
+            .map_err(|_| anyhow!("the acmeIdentifier extension is malformed"))?;
+
+        if !matches(value.as_bytes(), &tls_alpn01_digest(key_authorization)) {
+            return Err(anyhow!(
+                "the acmeIdentifier does not match the key authorization"
+            ));
+        }
+
+        anyhow::Ok(())
+    }
+}
+
+/// one attempt with the validator registered for `kind`
+async fn attempt(
+    kind: ChallengeType,
+    identifier: &str,
+    token: &str,
+    key_authorization: &str,
+) -> anyhow::Result<()> {
+    match kind {
+        ChallengeType::Http01 => Http01.validate(identifier, token, key_authorization).await,
+        ChallengeType::Dns01 => Dns01.validate(identifier, token, key_authorization).await,
+        ChallengeType::TlsAlpn01 => {
+            TlsAlpn01
+                .validate(identifier, token, key_authorization)
+                .await
+        }
+    }
+}
+
+/// The challenges `identifier` is offered, in the order the policy prefers them. Those that need
+/// the prober are left out while none is configured. Wildcards are only ever offered dns-01,
+/// RFC 8555 §7.1.3, whatever a policy restored from before that was validated says.
+pub fn offered(identifier: &str) -> Vec<ChallengeType> {
+    let policy = Config::challenge_policy();
+    let wildcard = identifier.starts_with("*.");
+    let types = match wildcard {
+        true => policy.wildcards,
+        false => policy.names,
+    };
+
+    types
+        .into_iter()
+        .filter(|t| !wildcard || *t == ChallengeType::Dns01)
+        .filter(|t| *t == ChallengeType::Dns01 || prober::configured())
+        .collect()
+}
+
+/// One attempt of `kind` for `identifier`, a type the identifier is not offered fails right away.
+/// Retries are scheduled by whoever asked for the validation.
+pub async fn validate(
+    kind: ChallengeType,
+    identifier: &str,
+    token: &str,
+    key_authorization: &str,
+) -> anyhow::Result<()> {
+    if !offered(identifier).contains(&kind) {
+        return Err(anyhow!("{} is not offered for {identifier}", kind.as_str()));
+    }
+
+    let result = attempt(kind, identifier, token, key_authorization).await;
+
+    metrics::challenge_validated(kind.as_str(), result.is_ok());
+
+    result
+}
+
+/// Canister consumers don't pick a challenge, any one offered for `domain` proves control and is
+/// returned. The http-01 resource is served at `token`, the token of the authorization or
+/// [`CANISTER_TOKEN`] outside of one.
+///
+/// dns-01 and tls-alpn-01 are not bound to the token. Their values only depend on the principal,
+/// so a consumer publishes them once, ahead of any order, and automatic renewals find them without
+/// the consumer's help. As long as such a record stands it proves control for every order of the
+/// principal, removing it is the only way to withdraw that proof.
+pub async fn verify_canister(
+    caller: &Principal,
+    domain: &str,
+    token: &str,
+) -> anyhow::Result<ChallengeType> {
+    let mut failures = Vec::new();
+
+    for kind in offered(domain) {
+        let authorization = match kind {
+            ChallengeType::Http01 => key_authorization(token, caller),
+            ChallengeType::Dns01 | ChallengeType::TlsAlpn01 => canister_key_authorization(caller),
+        };
+
+        match validate(kind, domain, token, &authorization).await {
+            Ok(()) => return anyhow::Ok(kind),
+            Err(e) => failures.push(format!("{}: {e}", kind.as_str())),
+        }
+    }
+
+    Err(anyhow!(
+        "no challenge proved control of {domain}: {}",
+        failures.join("; ")
+    ))
+}
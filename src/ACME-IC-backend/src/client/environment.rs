@@ -0,0 +1,294 @@
+use std::cell::RefCell;
+
+use candid::CandidType;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::StableCell;
+use p256::ecdsa::SigningKey;
+use serde::Deserialize;
+
+use crate::{
+    api::{ApiError, ApiResult},
+    clock,
+    mem::{candid_storable, Mem, Memory, Repository},
+    policy,
+};
+
+thread_local! {
+    static ACCOUNT_KEYS: RefCell<Repository<String, Vec<u8>>> =
+        RefCell::new(Repository::init::<ClientAccountKeys>());
+    static ENVIRONMENTS: RefCell<StableCell<ClientEnvironments, Memory>> = RefCell::new(
+        Mem::cell::<ClientEnvironments, _>(ClientEnvironments::default()),
+    );
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Environment {
+    Staging,
+    Production,
+}
+
+impl Environment {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Staging => "staging",
+            Self::Production => "production",
+        }
+    }
+}
+
+/// What client mode requests, identical for both environments apart from the directory.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ClientProfile {
+    pub staging_directory: String,
+    pub production_directory: String,
+    pub domains: Vec<String>,
+    pub contact: Vec<String>,
+    /// HTTPS relay every request to the CA goes through, see [`crate::acme_client`]
+    pub relay: Option<String>,
+}
+
+impl ClientProfile {
+    fn validate(&self) -> ApiResult<()> {
+        for url in [&self.staging_directory, &self.production_directory]
+            .into_iter()
+            .chain(&self.relay)
+        {
+            if !url.starts_with("https://") {
+                return Err(ApiError::InvalidArgument(format!(
+                    "{url} must be an https URL"
+                )));
+            }
+        }
+
+        if self.domains.is_empty() {
+            return Err(ApiError::InvalidArgument(
+                "at least one domain is required".to_string(),
+            ));
+        }
+
+        for domain in policy::normalize_domains(&self.domains) {
+            policy::check_domain(&domain).map_err(|e| ApiError::InvalidArgument(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// memory marker for the P-256 account keys of client mode, keyed by environment
+pub struct ClientAccountKeys;
+
+/// Account and progress of client mode against one environment, never shared between the two.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct EnvironmentState {
+    pub account_url: Option<String>,
+    /// order placed by `start_client_order` that has not completed yet
+    pub order_url: Option<String>,
+    /// CSR the order is finalized with while it is completed in the background
+    pub pending_csr: Option<Vec<u8>>,
+    /// serial of the certificate the last completed order was archived as
+    pub certificate_serial: Option<u64>,
+    /// profile revision the full flow last completed for
+    pub validated_revision: Option<u64>,
+    pub last_success_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ClientEnvironments {
+    pub active: Environment,
+    pub profile: Option<ClientProfile>,
+    /// bumped on every profile change so an old staging run cannot vouch for a new profile
+    pub revision: u64,
+    pub staging: EnvironmentState,
+    pub production: EnvironmentState,
+}
+
+impl Default for ClientEnvironments {
+    fn default() -> Self {
+        Self {
+            active: Environment::Staging,
+            profile: None,
+            revision: 0,
+            staging: EnvironmentState::default(),
+            production: EnvironmentState::default(),
+        }
+    }
+}
+
+candid_storable!(ClientEnvironments);
+
+impl ClientEnvironments {
+    fn update<T>(f: impl FnOnce(&mut Self) -> ApiResult<T>) -> ApiResult<T> {
+        ENVIRONMENTS.with_borrow_mut(|cell| {
+            let mut envs = cell.get().clone();
+            let out = f(&mut envs)?;
+
+            cell.set(envs)
+                .map_err(|e| ApiError::Internal(format!("{e:?}")))?;
+
+            Ok(out)
+        })
+    }
+
+    pub fn state(&self, env: Environment) -> &EnvironmentState {
+        match env {
+            Environment::Staging => &self.staging,
+            Environment::Production => &self.production,
+        }
+    }
+
+    fn state_mut(&mut self, env: Environment) -> &mut EnvironmentState {
+        match env {
+            Environment::Staging => &mut self.staging,
+            Environment::Production => &mut self.production,
+        }
+    }
+
+    pub fn get() -> Self {
+        ENVIRONMENTS.with_borrow(|cell| cell.get().clone())
+    }
+
+    /// replaces the profile and sends client mode back to staging until it is validated again
+    pub fn configure(profile: ClientProfile) -> ApiResult<()> {
+        profile.validate()?;
+
+        Self::update(|envs| {
+            envs.profile = Some(profile);
+            envs.revision += 1;
+            envs.active = Environment::Staging;
+
+            Ok(())
+        })
+    }
+
+    /// directory client mode currently talks to
+    pub fn active_directory() -> Option<String> {
+        ENVIRONMENTS.with_borrow(|cell| {
+            let envs = cell.get();
+            let profile = envs.profile.as_ref()?;
+
+            Some(match envs.active {
+                Environment::Staging => profile.staging_directory.clone(),
+                Environment::Production => profile.production_directory.clone(),
+            })
+        })
+    }
+
+    /// Account key for `env`, each environment registers its own account. Threshold ECDSA only
+    /// signs over secp256k1, which Let's Encrypt refuses, so this is a P-256 key drawn from
+    /// `raw_rand` on first use and kept by the canister. An account registered under an earlier
+    /// key is registered again.
+    pub async fn account_key(env: Environment) -> ApiResult<SigningKey> {
+        let stored = || ACCOUNT_KEYS.with_borrow(|k| k.get(&env.as_str().to_string()));
+        let decode = |secret: Vec<u8>| {
+            SigningKey::from_slice(&secret)
+                .map_err(|e| ApiError::Internal(format!("invalid client account key: {e}")))
+        };
+
+        if let Some(secret) = stored() {
+            return decode(secret);
+        }
+
+        let (secret,) = raw_rand().await.map_err(|(code, msg)| {
+            ApiError::Internal(format!(
+                "failed to draw a client account key: {code:?} {msg}"
+            ))
+        })?;
+
+        // another call may have drawn one while this one waited
+        if let Some(secret) = stored() {
+            return decode(secret);
+        }
+
+        let key = decode(secret.clone())?;
+        ACCOUNT_KEYS.with_borrow_mut(|k| k.insert(env.as_str().to_string(), secret));
+
+        Self::update(|envs| {
+            let state = envs.state_mut(env);
+            state.account_url = None;
+            state.order_url = None;
+            state.pending_csr = None;
+
+            Ok(())
+        })?;
+
+        Ok(key)
+    }
+
+    pub fn set_account_url(env: Environment, account_url: String) -> ApiResult<()> {
+        Self::update(|envs| {
+            envs.state_mut(env).account_url = Some(account_url);
+
+            Ok(())
+        })
+    }
+
+    /// a new order drops whatever the previous one left to complete
+    pub fn set_order_url(env: Environment, order_url: Option<String>) -> ApiResult<()> {
+        Self::update(|envs| {
+            let state = envs.state_mut(env);
+            state.order_url = order_url;
+            state.pending_csr = None;
+
+            Ok(())
+        })
+    }
+
+    pub fn set_pending_csr(env: Environment, csr_der: Vec<u8>) -> ApiResult<()> {
+        Self::update(|envs| {
+            envs.state_mut(env).pending_csr = Some(csr_der);
+
+            Ok(())
+        })
+    }
+
+    /// marks the full flow, up to downloading the certificate `serial`, as completed for `env`
+    pub fn record_success(env: Environment, serial: u64) -> ApiResult<()> {
+        Self::update(|envs| {
+            let revision = envs.revision;
+            let state = envs.state_mut(env);
+
+            state.order_url = None;
+            state.pending_csr = None;
+            state.certificate_serial = Some(serial);
+            state.validated_revision = Some(revision);
+            state.last_success_at = Some(clock::now_nanos());
+            state.last_error = None;
+
+            Ok(())
+        })
+    }
+
+    pub fn record_failure(env: Environment, error: String) -> ApiResult<()> {
+        Self::update(|envs| {
+            envs.state_mut(env).last_error = Some(error);
+
+            Ok(())
+        })
+    }
+
+    /// switches the current profile to production once staging has validated it
+    pub fn promote() -> ApiResult<()> {
+        Self::update(|envs| {
+            if envs.profile.is_none() {
+                return Err(ApiError::NotFound("client profile".to_string()));
+            }
+
+            if envs.active == Environment::Production {
+                return Err(ApiError::InvalidArgument(
+                    "client mode already runs against production".to_string(),
+                ));
+            }
+
+            if envs.staging.validated_revision != Some(envs.revision) {
+                return Err(ApiError::InvalidArgument(
+                    "the current profile has not completed a staging run yet".to_string(),
+                ));
+            }
+
+            envs.active = Environment::Production;
+
+            Ok(())
+        })
+    }
+}
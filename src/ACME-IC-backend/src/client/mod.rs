@@ -0,0 +1,112 @@
+pub mod environment;
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::{csr::Csr, handler::types::Directory, policy};
+
+/// identifiers per order assumed for servers that do not advertise a limit, Let's Encrypt's cap
+pub const DEFAULT_MAX_IDENTIFIERS: u32 = 100;
+
+/// What the target ACME server accepts in a single order.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ServerLimits {
+    pub max_identifiers: u32,
+    pub allow_wildcards: bool,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self {
+            max_identifiers: DEFAULT_MAX_IDENTIFIERS,
+            allow_wildcards: true,
+        }
+    }
+}
+
+impl ServerLimits {
+    pub fn from_directory(directory: &Directory) -> Self {
+        let max_identifiers = directory
+            .meta
+            .as_ref()
+            .and_then(|m| m.max_identifiers)
+            .unwrap_or(DEFAULT_MAX_IDENTIFIERS);
+
+        Self {
+            max_identifiers,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RejectedIdentifier {
+    pub identifier: String,
+    pub reason: String,
+}
+
+/// Outcome of checking a planned certificate before any order is created.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct OrderPlan {
+    /// normalized identifiers that passed every check
+    pub identifiers: Vec<String>,
+    pub rejected: Vec<RejectedIdentifier>,
+    /// problems with the plan as a whole, e.g. too many identifiers
+    pub problems: Vec<String>,
+}
+
+impl OrderPlan {
+    /// whether an order for this plan can be created without being refused outright
+    pub fn is_ready(&self) -> bool {
+        !self.identifiers.is_empty() && self.rejected.is_empty() && self.problems.is_empty()
+    }
+}
+
+/// Checks `domains`, and the CSR that is going to finalize the order if already known, against
+/// local policy and `limits`, so oversized or invalid SAN sets never cost an order.
+pub fn plan_order(domains: &[String], limits: &ServerLimits, csr_der: Option<&[u8]>) -> OrderPlan {
+    let mut plan = OrderPlan::default();
+
+    for domain in policy::normalize_domains(domains) {
+        let checked = policy::check_domain(&domain).and_then(|_| {
+            if domain.starts_with("*.") && !limits.allow_wildcards {
+                return Err(anyhow::anyhow!("the server does not issue wildcards"));
+            }
+
+            anyhow::Ok(())
+        });
+
+        match checked {
+            Ok(_) => plan.identifiers.push(domain),
+            Err(e) => plan.rejected.push(RejectedIdentifier {
+                identifier: domain,
+                reason: e.to_string(),
+            }),
+        }
+    }
+
+    let requested = plan.identifiers.len() + plan.rejected.len();
+
+    if requested == 0 {
+        plan.problems.push("no identifiers requested".to_string());
+    }
+
+    if requested > limits.max_identifiers as usize {
+        plan.problems.push(format!(
+            "{requested} identifiers requested, the server accepts at most {}",
+            limits.max_identifiers
+        ));
+    }
+
+    if let Some(der) = csr_der {
+        match Csr::from_der(der) {
+            Ok(csr) if csr.domains != plan.identifiers => plan
+                .problems
+                .push("CSR names do not match the planned identifiers".to_string()),
+            Ok(_) => {}
+            Err(e) => plan.problems.push(format!("invalid CSR: {e}")),
+        }
+    }
+
+    plan
+}
@@ -0,0 +1,33 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::anyhow;
+
+use x509_cert::der::DateTime;
+
+pub fn now_nanos() -> u64 {
+    ic_cdk::api::time()
+}
+
+/// RFC 3339 / ISO 8601 rendering of a unix timestamp in nanoseconds, as used in ACME resources
+pub fn rfc3339(nanos: u64) -> String {
+    DateTime::from_unix_duration(Duration::from_nanos(nanos))
+        .map(|t| t.to_string())
+        .unwrap_or_default()
+}
+
+pub fn now_rfc3339() -> String {
+    rfc3339(now_nanos())
+}
+
+/// unix nanoseconds of an RFC 3339 UTC timestamp such as ACME's `notBefore`, fractional seconds are
+/// truncated
+pub fn parse_rfc3339(s: &str) -> anyhow::Result<u64> {
+    let whole = match s.split_once('.') {
+        Some((seconds, fraction)) if fraction.ends_with('Z') => format!("{seconds}Z"),
+        _ => s.to_string(),
+    };
+
+    let time = DateTime::from_str(&whole).map_err(|_| anyhow!("{s} is not a UTC RFC 3339 time"))?;
+
+    anyhow::Ok(time.unix_duration().as_nanos() as u64)
+}
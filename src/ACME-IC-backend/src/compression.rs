@@ -0,0 +1,286 @@
+use std::io::Write;
+
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use ic_http_certification::{HttpResponse, HttpResponseBuilder};
+
+use crate::media;
+
+/// smaller bodies fit a packet either way, compressing them only costs cycles
+pub const MIN_COMPRESSED_BYTES: usize = 1024;
+
+/// RFC 9110 §8.4.1 content codings this server produces
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    /// the zlib format, RFC 9110 §8.4.1.2
+    Deflate,
+}
+
+impl Encoding {
+    /// preferred first when a client accepts both equally
+    pub const ALL: [Self; 2] = [Self::Gzip, Self::Deflate];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    fn compress(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Self::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// The coding an `Accept-Encoding` header prefers, RFC 9110 §12.5.3. `None` leaves the body as
+/// it is, also when the header is missing.
+pub fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+    let codings = accept_encoding?
+        .split(',')
+        .map(|coding| {
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            (name, quality)
+        })
+        .collect::<Vec<_>>();
+
+    let quality = |encoding: Encoding| {
+        let aliases: &[&str] = match encoding {
+            Encoding::Gzip => &["gzip", "x-gzip"],
+            Encoding::Deflate => &["deflate"],
+        };
+
+        codings
+            .iter()
+            .find(|(name, _)| aliases.contains(&name.as_str()))
+            .or_else(|| codings.iter().find(|(name, _)| name == "*"))
+            .map(|(_, q)| *q)
+            .unwrap_or(0.0)
+    };
+
+    Encoding::ALL
+        .into_iter()
+        .map(|encoding| (encoding, quality(encoding)))
+        .filter(|(_, q)| *q > 0.0)
+        .fold(
+            None,
+            |best: Option<(Encoding, f32)>, (encoding, q)| match best {
+                Some((_, best_q)) if best_q >= q => best,
+                _ => Some((encoding, q)),
+            },
+        )
+        .map(|(encoding, _)| encoding)
+}
+
+/// whether `response` is served in more than one coding
+fn compressible(response: &HttpResponse) -> bool {
+    response.body().len() >= MIN_COMPRESSED_BYTES
+        && media::header(response.headers(), "Content-Encoding").is_none()
+}
+
+fn rebuild(
+    response: &HttpResponse,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+) -> HttpResponse<'static> {
+    HttpResponseBuilder::new()
+        .with_status_code(response.status_code())
+        .with_headers(headers)
+        .with_body(body)
+        .with_upgrade(false)
+        .build()
+}
+
+/// the headers of `response` with `Accept-Encoding` added to its `Vary`
+fn varied(response: &HttpResponse) -> Vec<(String, String)> {
+    let mut headers = response.headers().to_vec();
+
+    match headers
+        .iter_mut()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Vary"))
+    {
+        Some((_, vary)) => vary.push_str(", Accept-Encoding"),
+        None => headers.push(("Vary".to_string(), "Accept-Encoding".to_string())),
+    }
+
+    headers
+}
+
+/// `response` in `encoding`, `None` when that doesn't make it any smaller
+fn encoded(
+    response: &HttpResponse,
+    headers: &[(String, String)],
+    encoding: Encoding,
+) -> Option<HttpResponse<'static>> {
+    let body = encoding.compress(response.body()).ok()?;
+
+    if body.len() >= response.body().len() {
+        return None;
+    }
+
+    let mut headers = headers.to_vec();
+    headers.push((
+        "Content-Encoding".to_string(),
+        encoding.as_str().to_string(),
+    ));
+
+    Some(rebuild(response, headers, body))
+}
+
+/// `response` as is, followed by one response per coding that shrinks it. Every one of them varies
+/// on `Accept-Encoding`, so each can be certified on its own.
+pub fn variants(response: HttpResponse<'static>) -> Vec<(Option<Encoding>, HttpResponse<'static>)> {
+    if !compressible(&response) {
+        return vec![(None, response)];
+    }
+
+    let headers = varied(&response);
+    let mut variants = Encoding::ALL
+        .into_iter()
+        .filter_map(|encoding| Some((Some(encoding), encoded(&response, &headers, encoding)?)))
+        .collect::<Vec<_>>();
+
+    variants.insert(
+        0,
+        (None, rebuild(&response, headers, response.body().to_vec())),
+    );
+
+    variants
+}
+
+/// `response` in the coding `accept_encoding` prefers, for responses that are not certified ahead
+/// of time
+pub fn negotiated(
+    accept_encoding: Option<&str>,
+    response: HttpResponse<'static>,
+) -> HttpResponse<'static> {
+    if !compressible(&response) {
+        return response;
+    }
+
+    let headers = varied(&response);
+
+    negotiate(accept_encoding)
+        .and_then(|encoding| encoded(&response, &headers, encoding))
+        .unwrap_or_else(|| rebuild(&response, headers, response.body().to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use ic_http_certification::StatusCode;
+
+    use super::*;
+
+    fn response(body: Vec<u8>) -> HttpResponse<'static> {
+        HttpResponseBuilder::new()
+            .with_status_code(StatusCode::OK)
+            .with_headers(vec![("Content-Type".to_string(), "text/plain".to_string())])
+            .with_body(body)
+            .build()
+    }
+
+    fn decompressed(encoding: Encoding, body: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        match encoding {
+            Encoding::Gzip => GzDecoder::new(body).read_to_end(&mut out),
+            Encoding::Deflate => ZlibDecoder::new(body).read_to_end(&mut out),
+        }
+        .unwrap();
+
+        out
+    }
+
+    #[test]
+    fn negotiates_by_quality() {
+        assert_eq!(negotiate(None), None);
+        assert_eq!(negotiate(Some("identity")), None);
+        assert_eq!(negotiate(Some("gzip, deflate")), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate(Some("gzip;q=0.5, deflate")),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(negotiate(Some("X-GZIP")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(Some("br, *;q=0.1")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(Some("*, gzip;q=0")), Some(Encoding::Deflate));
+    }
+
+    #[test]
+    fn small_bodies_are_left_alone() {
+        let variants = variants(response(b"short".to_vec()));
+
+        assert_eq!(variants.len(), 1);
+        assert_eq!(media::header(variants[0].1.headers(), "Vary"), None);
+    }
+
+    #[test]
+    fn every_variant_varies_and_decompresses_to_the_body() {
+        let body = "a line repeated until it is worth compressing\n"
+            .repeat(64)
+            .into_bytes();
+
+        let variants = variants(response(body.clone()));
+
+        assert_eq!(
+            variants.iter().map(|(e, _)| *e).collect::<Vec<_>>(),
+            [None, Some(Encoding::Gzip), Some(Encoding::Deflate)]
+        );
+
+        for (encoding, variant) in variants {
+            assert_eq!(
+                media::header(variant.headers(), "Vary"),
+                Some("Accept-Encoding")
+            );
+
+            match encoding {
+                Some(encoding) => {
+                    assert_eq!(
+                        media::header(variant.headers(), "Content-Encoding"),
+                        Some(encoding.as_str())
+                    );
+                    assert_eq!(decompressed(encoding, variant.body()), body);
+                }
+                None => assert_eq!(variant.body(), body.as_slice()),
+            }
+        }
+    }
+
+    #[test]
+    fn an_existing_vary_is_extended() {
+        let mut response = response(vec![b'x'; MIN_COMPRESSED_BYTES]);
+        response.add_header(("Vary".to_string(), "Accept".to_string()));
+
+        let negotiated = negotiated(Some("deflate"), response);
+
+        assert_eq!(
+            media::header(negotiated.headers(), "Vary"),
+            Some("Accept, Accept-Encoding")
+        );
+        assert_eq!(
+            media::header(negotiated.headers(), "Content-Encoding"),
+            Some("deflate")
+        );
+    }
+}
@@ -0,0 +1,542 @@
+use std::{cell::RefCell, time::Duration};
+
+use candid::Principal;
+
+use crate::{
+    api::{ApiError, ApiResult},
+    billing::BillingConfig,
+    handler::types::{
+        AccountQuota, CertificateProfile, ChallengePolicy, ChallengeType, CtPolicy, DirectoryMeta,
+        DirectoryPricing, IssuerName, KeyPurpose, RateLimit, RevocationWindows, SctFailureMode,
+        SctRequirement, ServerConfig, SubjectPolicy, TermsOfService,
+    },
+    issuance::MAX_SANS,
+    jobs::MAX_ATTEMPTS,
+    order::ORDER_LIFETIME,
+    profile::CLASSIC,
+};
+
+/// RSA account keys shorter than this are never accepted, regardless of configuration
+pub const MIN_RSA_KEY_BITS_FLOOR: u32 = 2048;
+/// the CA/Browser Forum baseline requirements cap CRL and OCSP validity at 10 days
+const MAX_REVOCATION_VALIDITY_SECS: u64 = 10 * 24 * 60 * 60;
+/// anything shorter costs a threshold signature every few minutes
+const MIN_REVOCATION_VALIDITY_SECS: u64 = 60 * 60;
+/// the longest merge delay RFC 6962 logs are commonly operated with
+const MAX_CT_MERGE_DELAY_SECS: u64 = 24 * 60 * 60;
+/// a JWS around a CSR with a 4096-bit RSA key and a full set of names stays well below this
+pub const DEFAULT_MAX_REQUEST_BYTES: u64 = 64 * 1024;
+/// enough for the largest JWS this server accepts
+const MIN_MAX_REQUEST_BYTES: u64 = 16 * 1024;
+/// the ingress message limit of the IC
+const MAX_MAX_REQUEST_BYTES: u64 = 2 * 1024 * 1024;
+/// covers a client retrying after a timeout or a crash
+pub const DEFAULT_ORDER_REUSE_WINDOW_SECS: u64 = 60 * 60;
+/// Let's Encrypt suggests renewing 90 day certificates with 30 days left
+pub const DEFAULT_RENEWAL_THRESHOLD_DAYS: u32 = 30;
+/// no profile issues for longer than a year
+const MAX_RENEWAL_THRESHOLD_DAYS: u32 = 365;
+/// clients whose clocks run up to an hour ahead accept a certificate right after it was issued
+pub const DEFAULT_CLOCK_SKEW_SECS: u64 = 60 * 60;
+/// a certificate shouldn't claim to be valid from long before its names were validated
+const MAX_CLOCK_SKEW_SECS: u64 = 24 * 60 * 60;
+/// RFC 5280 upper bounds of the issuer name attributes
+pub const MAX_COMMON_NAME_LEN: usize = 64;
+const MAX_ORGANIZATION_LEN: usize = 64;
+
+thread_local! {
+    static CONFIG: RefCell<ServerConfig> = RefCell::new(ServerConfig::default());
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: 60,
+            accounts_per_hour: 10,
+            challenges_per_hour: 60,
+            certificates_per_week: 50,
+        }
+    }
+}
+
+impl Default for AccountQuota {
+    fn default() -> Self {
+        Self {
+            max_pending_orders: 300,
+            certificates_per_domain_per_week: 50,
+            max_active_certificates: 10_000,
+        }
+    }
+}
+
+impl Default for RevocationWindows {
+    fn default() -> Self {
+        Self {
+            crl_validity_secs: 7 * 24 * 60 * 60,
+            crl_refresh_interval_secs: 24 * 60 * 60,
+            ocsp_validity_secs: 4 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl RevocationWindows {
+    fn validate(&self) -> ApiResult<()> {
+        let bounds = MIN_REVOCATION_VALIDITY_SECS..=MAX_REVOCATION_VALIDITY_SECS;
+
+        if !bounds.contains(&self.crl_validity_secs) || !bounds.contains(&self.ocsp_validity_secs) {
+            return Err(ApiError::InvalidArgument(format!(
+                "CRL and OCSP validity must be between {MIN_REVOCATION_VALIDITY_SECS} and {MAX_REVOCATION_VALIDITY_SECS} seconds"
+            )));
+        }
+
+        // relying parties need at least one refresh interval to pick up the next CRL
+        if self.crl_refresh_interval_secs < MIN_REVOCATION_VALIDITY_SECS
+            || self.crl_refresh_interval_secs > self.crl_validity_secs / 2
+        {
+            return Err(ApiError::InvalidArgument(format!(
+                "the CRL refresh interval must be between {MIN_REVOCATION_VALIDITY_SECS} seconds and half the CRL validity"
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for CtPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            logs: Vec::new(),
+            // the lifetime tiers browsers apply to embedded SCTs
+            requirements: vec![
+                SctRequirement {
+                    max_lifetime_days: 180,
+                    min_scts: 2,
+                },
+                SctRequirement {
+                    max_lifetime_days: 398,
+                    min_scts: 3,
+                },
+            ],
+            on_failure: SctFailureMode::FailIssuance,
+        }
+    }
+}
+
+impl CtPolicy {
+    fn validate(&self) -> ApiResult<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        if self.requirements.is_empty() {
+            return Err(ApiError::InvalidArgument(
+                "CT needs at least one SCT requirement".to_string(),
+            ));
+        }
+
+        let needed = self
+            .requirements
+            .iter()
+            .map(|r| r.min_scts)
+            .max()
+            .unwrap_or(0);
+
+        if needed as usize > self.logs.len() {
+            return Err(ApiError::InvalidArgument(format!(
+                "CT requires up to {needed} SCTs but only {} logs are configured",
+                self.logs.len()
+            )));
+        }
+
+        for log in &self.logs {
+            if !log.url.starts_with("https://") || !log.url.ends_with('/') {
+                return Err(ApiError::InvalidArgument(format!(
+                    "CT log {} needs an https URL ending in '/'",
+                    log.name
+                )));
+            }
+
+            if log.max_merge_delay_secs == 0 || log.max_merge_delay_secs > MAX_CT_MERGE_DELAY_SECS {
+                return Err(ApiError::InvalidArgument(format!(
+                    "CT log {} must merge within {MAX_CT_MERGE_DELAY_SECS} seconds",
+                    log.name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// the profiles next to `classic` when `ServerConfig.profiles` is `None`
+fn default_profiles() -> Vec<CertificateProfile> {
+    vec![
+        CertificateProfile {
+            name: "shortlived".to_string(),
+            description: "7-day TLS server certificates without revocation pointers".to_string(),
+            validity_days: 7,
+            revocation_pointers: false,
+            key_purposes: vec![KeyPurpose::ServerAuth],
+        },
+        CertificateProfile {
+            name: "client-auth".to_string(),
+            description: "TLS client certificates, e.g. for mutual TLS".to_string(),
+            validity_days: 90,
+            revocation_pointers: true,
+            key_purposes: vec![KeyPurpose::ClientAuth],
+        },
+    ]
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            port: 443,
+            hostname: "localhost".to_string(),
+            ca_key_path: String::new(),
+            ca_cert_path: String::new(),
+            data_dir: String::new(),
+            challenge_timeout: 30,
+            challenge_attempts: 3,
+            cert_validity_days: 365,
+            rate_limit: RateLimit::default(),
+            min_rsa_key_bits: MIN_RSA_KEY_BITS_FLOOR,
+            caa_identities: vec!["ic.encrypt.icp".to_string()],
+            require_dns01_for_canisters: true,
+            profiles: None,
+            revocation: None,
+            ct: None,
+            max_request_bytes: Some(DEFAULT_MAX_REQUEST_BYTES),
+            challenge_prober: None,
+            challenges: None,
+            terms_of_service: None,
+            account_quota: None,
+            strict_payloads: None,
+            order_reuse_window_secs: Some(DEFAULT_ORDER_REUSE_WINDOW_SECS),
+            billing: None,
+            renewal_threshold_days: Some(DEFAULT_RENEWAL_THRESHOLD_DAYS),
+            issuer_name: None,
+            subject_policy: None,
+            legacy_get: None,
+            clock_skew_secs: Some(DEFAULT_CLOCK_SKEW_SECS),
+        }
+    }
+}
+
+impl Default for IssuerName {
+    fn default() -> Self {
+        Self {
+            common_name: "ic.encrypt.icp".to_string(),
+            organization: None,
+            country: None,
+        }
+    }
+}
+
+impl IssuerName {
+    fn validate(&self) -> ApiResult<()> {
+        if self.common_name.is_empty() || self.common_name.chars().count() > MAX_COMMON_NAME_LEN {
+            return Err(ApiError::InvalidArgument(format!(
+                "the issuer common name must have between 1 and {MAX_COMMON_NAME_LEN} characters"
+            )));
+        }
+
+        if self
+            .organization
+            .as_ref()
+            .is_some_and(|o| o.is_empty() || o.chars().count() > MAX_ORGANIZATION_LEN)
+        {
+            return Err(ApiError::InvalidArgument(format!(
+                "the issuer organization must have between 1 and {MAX_ORGANIZATION_LEN} characters"
+            )));
+        }
+
+        if self
+            .country
+            .as_ref()
+            .is_some_and(|c| c.len() != 2 || !c.chars().all(|c| c.is_ascii_uppercase()))
+        {
+            return Err(ApiError::InvalidArgument(
+                "the issuer country must be a two letter ISO 3166 code, e.g. CH".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Config;
+
+impl Config {
+    pub fn get() -> ServerConfig {
+        CONFIG.with_borrow(|c| c.clone())
+    }
+
+    pub fn with<T>(f: impl FnOnce(&ServerConfig) -> T) -> T {
+        CONFIG.with_borrow(f)
+    }
+
+    /// public origin of the canister, prefix of every URL it hands out
+    pub fn base_url() -> String {
+        Self::with(|c| match c.port {
+            443 => format!("https://{}", c.hostname),
+            port => format!("https://{}:{port}", c.hostname),
+        })
+    }
+
+    /// every profile orders can pick, `classic` follows `cert_validity_days` unless configured
+    pub fn profiles() -> Vec<CertificateProfile> {
+        Self::with(|c| {
+            let mut profiles = c.profiles.clone().unwrap_or_else(default_profiles);
+
+            if !profiles.iter().any(|p| p.name == CLASSIC) {
+                profiles.insert(
+                    0,
+                    CertificateProfile {
+                        name: CLASSIC.to_string(),
+                        description: "TLS server and client certificates with revocation pointers"
+                            .to_string(),
+                        validity_days: c.cert_validity_days,
+                        revocation_pointers: true,
+                        key_purposes: vec![KeyPurpose::ServerAuth, KeyPurpose::ClientAuth],
+                    },
+                );
+            }
+
+            profiles
+        })
+    }
+
+    pub fn challenge_policy() -> ChallengePolicy {
+        Self::with(|c| c.challenges.clone().unwrap_or_default())
+    }
+
+    pub fn terms_of_service() -> Option<TermsOfService> {
+        Self::with(|c| c.terms_of_service.clone())
+    }
+
+    pub fn account_quota() -> AccountQuota {
+        Self::with(|c| c.account_quota.unwrap_or_default())
+    }
+
+    pub fn max_request_bytes() -> u64 {
+        Self::with(|c| c.max_request_bytes.unwrap_or(DEFAULT_MAX_REQUEST_BYTES))
+    }
+
+    pub fn strict_payloads() -> bool {
+        Self::with(|c| c.strict_payloads.unwrap_or(false))
+    }
+
+    pub fn order_reuse_window() -> Duration {
+        Self::with(|c| {
+            Duration::from_secs(
+                c.order_reuse_window_secs
+                    .unwrap_or(DEFAULT_ORDER_REUSE_WINDOW_SECS),
+            )
+        })
+    }
+
+    pub fn billing() -> Option<BillingConfig> {
+        Self::with(|c| c.billing.clone())
+    }
+
+    pub fn renewal_threshold() -> Duration {
+        let days = Self::with(|c| {
+            c.renewal_threshold_days
+                .unwrap_or(DEFAULT_RENEWAL_THRESHOLD_DAYS)
+        });
+
+        Duration::from_secs(days as u64 * 24 * 60 * 60)
+    }
+
+    pub fn issuer_name() -> IssuerName {
+        Self::with(|c| c.issuer_name.clone().unwrap_or_default())
+    }
+
+    pub fn legacy_get() -> bool {
+        Self::with(|c| c.legacy_get.unwrap_or(false))
+    }
+
+    /// how far notBefore of new certificates lies before their issuance
+    pub fn clock_skew() -> Duration {
+        Self::with(|c| Duration::from_secs(c.clock_skew_secs.unwrap_or(DEFAULT_CLOCK_SKEW_SECS)))
+    }
+
+    pub fn subject_policy() -> SubjectPolicy {
+        Self::with(|c| c.subject_policy.unwrap_or(SubjectPolicy::CommonName))
+    }
+
+    /// the configured revocation windows, the defaults when none are
+    pub fn revocation() -> RevocationWindows {
+        Self::with(|c| c.revocation.clone().unwrap_or_default())
+    }
+
+    /// the configured CT policy, off when there is none
+    pub fn ct() -> CtPolicy {
+        Self::with(|c| c.ct.clone().unwrap_or_default())
+    }
+
+    /// `name`, or the `classic` profile when the order did not pick one
+    pub fn profile(name: Option<&str>) -> Option<CertificateProfile> {
+        let name = name.unwrap_or(CLASSIC);
+
+        Self::profiles().into_iter().find(|p| p.name == name)
+    }
+
+    /// the `meta` object of every directory served by this canister
+    pub fn directory_meta() -> DirectoryMeta {
+        let profiles = Self::profiles()
+            .into_iter()
+            .map(|p| (p.name, p.description))
+            .collect();
+
+        Self::with(|c| DirectoryMeta {
+            terms_of_service: c.terms_of_service.as_ref().map(|t| t.url.clone()),
+            website: None,
+            caa_identities: Some(c.caa_identities.clone()),
+            external_account_required: None,
+            max_identifiers: Some(MAX_SANS as u32),
+            profiles: Some(profiles),
+            pricing: c.billing.as_ref().map(|b| DirectoryPricing {
+                ledger: b.ledger.to_text(),
+                fee: b.fee,
+            }),
+        })
+    }
+
+    pub fn set(config: ServerConfig) -> ApiResult<()> {
+        if config.min_rsa_key_bits < MIN_RSA_KEY_BITS_FLOOR {
+            return Err(ApiError::InvalidArgument(format!(
+                "min_rsa_key_bits must be at least {MIN_RSA_KEY_BITS_FLOOR}"
+            )));
+        }
+
+        let profiles = config.profiles.clone().unwrap_or_default();
+
+        if config.cert_validity_days == 0 || profiles.iter().any(|p| p.validity_days == 0) {
+            return Err(ApiError::InvalidArgument(
+                "certificate validity must be at least one day".to_string(),
+            ));
+        }
+
+        if profiles
+            .iter()
+            .any(|p| p.name.is_empty() || p.key_purposes.is_empty())
+        {
+            return Err(ApiError::InvalidArgument(
+                "profiles need a name and at least one key purpose".to_string(),
+            ));
+        }
+
+        if let Some(revocation) = &config.revocation {
+            revocation.validate()?;
+        }
+
+        if let Some(ct) = &config.ct {
+            ct.validate()?;
+        }
+
+        if let Some(max) = config.max_request_bytes {
+            if !(MIN_MAX_REQUEST_BYTES..=MAX_MAX_REQUEST_BYTES).contains(&max) {
+                return Err(ApiError::InvalidArgument(format!(
+                    "max_request_bytes must be between {MIN_MAX_REQUEST_BYTES} and {MAX_MAX_REQUEST_BYTES}"
+                )));
+            }
+        }
+
+        // an order can't be handed out again once it expired anyway
+        if config
+            .order_reuse_window_secs
+            .is_some_and(|w| w > ORDER_LIFETIME.as_secs())
+        {
+            return Err(ApiError::InvalidArgument(format!(
+                "order_reuse_window_secs must be at most {}",
+                ORDER_LIFETIME.as_secs()
+            )));
+        }
+
+        if config
+            .renewal_threshold_days
+            .is_some_and(|d| !(1..=MAX_RENEWAL_THRESHOLD_DAYS).contains(&d))
+        {
+            return Err(ApiError::InvalidArgument(format!(
+                "renewal_threshold_days must be between 1 and {MAX_RENEWAL_THRESHOLD_DAYS}"
+            )));
+        }
+
+        if config
+            .clock_skew_secs
+            .is_some_and(|s| s > MAX_CLOCK_SKEW_SECS)
+        {
+            return Err(ApiError::InvalidArgument(format!(
+                "clock_skew_secs must be at most {MAX_CLOCK_SKEW_SECS}"
+            )));
+        }
+
+        if let Some(name) = &config.issuer_name {
+            name.validate()?;
+        }
+
+        if let Some(billing) = &config.billing {
+            if billing.ledger == Principal::anonymous() || billing.fee == 0 {
+                return Err(ApiError::InvalidArgument(
+                    "billing needs a ledger and a fee above 0".to_string(),
+                ));
+            }
+        }
+
+        if let Some(prober) = &config.challenge_prober {
+            let host = prober.strip_prefix("https://").unwrap_or_default();
+
+            if host.is_empty() || host.starts_with(['/', '?']) || host.contains('@') {
+                return Err(ApiError::InvalidArgument(
+                    "challenge_prober must be an https URL".to_string(),
+                ));
+            }
+        }
+
+        // a job is parked once a step failed that often, retried validations included
+        if config.challenge_attempts == 0 || config.challenge_attempts as u32 > MAX_ATTEMPTS {
+            return Err(ApiError::InvalidArgument(format!(
+                "challenge_attempts must be between 1 and {MAX_ATTEMPTS}"
+            )));
+        }
+
+        if let Some(policy) = &config.challenges {
+            if policy.names.is_empty() || policy.wildcards.is_empty() {
+                return Err(ApiError::InvalidArgument(
+                    "every identifier needs at least one challenge".to_string(),
+                ));
+            }
+
+            if policy.wildcards.iter().any(|t| *t != ChallengeType::Dns01) {
+                return Err(ApiError::InvalidArgument(
+                    "wildcards can only be validated with dns-01".to_string(),
+                ));
+            }
+        }
+
+        if let Some(terms) = &config.terms_of_service {
+            if !terms.url.starts_with("https://") || terms.version.trim().is_empty() {
+                return Err(ApiError::InvalidArgument(
+                    "the terms of service need an https URL and a version".to_string(),
+                ));
+            }
+        }
+
+        let mut names = profiles.iter().map(|p| &p.name).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+
+        if names.len() != profiles.len() {
+            return Err(ApiError::InvalidArgument(
+                "profile names must be unique".to_string(),
+            ));
+        }
+
+        CONFIG.with_borrow_mut(|c| *c = config);
+
+        Ok(())
+    }
+}
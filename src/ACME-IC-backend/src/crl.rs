@@ -0,0 +1,380 @@
+use std::{
+    cell::{Cell, RefCell},
+    time::Duration,
+};
+
+use candid::CandidType;
+use der::{
+    asn1::{BitString, Ia5String, OctetString, Uint, UtcTime},
+    Encode,
+};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::StableCell;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use x509_cert::{
+    crl::{CertificateList, RevokedCert, TbsCertList},
+    ext::{
+        pkix::{
+            crl::{dp::DistributionPoint, CrlDistributionPoints, CrlNumber},
+            name::{DistributionPointName, GeneralName},
+            AuthorityKeyIdentifier,
+        },
+        AsExtension,
+    },
+    name::Name,
+    serial_number::SerialNumber,
+    time::Time,
+    Version,
+};
+
+use crate::{
+    cert_manager::CertificateManager,
+    clock,
+    config::Config,
+    key::{AcmeKey, INITIAL_KEY_VERSION},
+    mem::{candid_storable, Mem, Memory, Repository},
+    revocation::{Revocation, RevocationRegistry},
+    rotation::KeyRotations,
+    router,
+    tenant::TenantRegistry,
+    trace,
+};
+
+/// CRL of the first root key version, which every leaf issued before the first rotation points to
+pub const CRL_PATH: &str = "/crl.der";
+/// CRLs of the later root key versions, `/crl/<version>.der`, and of every tenant key,
+/// `/crl/<tenant>/<version>.der`
+pub const VERSIONED_CRL_PATH: &str = "/crl/";
+
+thread_local! {
+    /// the CRL served at [`CRL_PATH`]
+    static CRL: RefCell<StableCell<SignedCrl, Memory>> = RefCell::new(
+        Mem::cell::<SignedCrl, _>(SignedCrl::default()),
+    );
+    /// the CRLs served below [`VERSIONED_CRL_PATH`]
+    static VERSIONED: RefCell<Repository<u32, SignedCrl>> =
+        RefCell::new(Repository::init::<IssuerCrls>());
+    /// the CRLs of the tenant keys, by tenant id and key version
+    static TENANT_CRLS: RefCell<Repository<(String, u32), SignedCrl>> =
+        RefCell::new(Repository::init::<TenantCrls>());
+    /// highest CRLNumber handed to an in-flight refresh
+    static LAST_RESERVED_NUMBER: Cell<u64> = const { Cell::new(0) };
+    static REFRESH_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct SignedCrl {
+    /// monotonically increasing CRLNumber, RFC 5280 §5.2.3
+    pub number: u64,
+    pub this_update: u64,
+    pub next_update: u64,
+    pub der: Vec<u8>,
+}
+
+/// memory markers for the CRLs of the root key versions after the first and of the tenant keys
+pub struct IssuerCrls;
+pub struct TenantCrls;
+
+candid_storable!(SignedCrl);
+
+/// where the CRL of root key `version` is served
+pub fn path(version: u32) -> String {
+    match version {
+        INITIAL_KEY_VERSION => CRL_PATH.to_string(),
+        version => format!("{VERSIONED_CRL_PATH}{version}.der"),
+    }
+}
+
+/// where the CRL of version `version` of tenant `id`'s key is served
+pub fn tenant_path(id: &str, version: u32) -> String {
+    format!("{VERSIONED_CRL_PATH}{id}/{version}.der")
+}
+
+/// where leaves signed by `issuer` point relying parties for the CRL
+pub fn distribution_url(issuer: &AcmeKey) -> String {
+    let path = match issuer.tenant() {
+        Some(id) => tenant_path(id, issuer.version()),
+        None => path(issuer.version()),
+    };
+
+    format!("{}{path}", Config::base_url())
+}
+
+/// CRLDistributionPoints extension advertising [`distribution_url`]
+pub fn distribution_points(issuer: &AcmeKey) -> anyhow::Result<CrlDistributionPoints> {
+    let uri = GeneralName::UniformResourceIdentifier(Ia5String::new(&distribution_url(issuer))?);
+
+    anyhow::Ok(CrlDistributionPoints(vec![DistributionPoint {
+        distribution_point: Some(DistributionPointName::FullName(vec![uri])),
+        reasons: None,
+        crl_issuer: None,
+    }]))
+}
+
+fn utc_time(nanos: u64) -> anyhow::Result<Time> {
+    anyhow::Ok(Time::UtcTime(UtcTime::from_unix_duration(
+        Duration::from_nanos(nanos),
+    )?))
+}
+
+/// concurrent refreshes (timer and revocation) must never sign two CRLs with the same number
+fn reserve_number() -> u64 {
+    let served = VERSIONED
+        .with_borrow(|crls| crls.values().map(|crl| crl.number).max())
+        .unwrap_or_default()
+        .max(CRL.with_borrow(|cell| cell.get().number))
+        .max(
+            TENANT_CRLS
+                .with_borrow(|crls| crls.values().map(|crl| crl.number).max())
+                .unwrap_or_default(),
+        );
+
+    LAST_RESERVED_NUMBER.with(|last| {
+        let next = last.get().max(served) + 1;
+        last.set(next);
+
+        next
+    })
+}
+
+fn stored(version: u32) -> Option<SignedCrl> {
+    let crl = match version {
+        INITIAL_KEY_VERSION => CRL.with_borrow(|cell| cell.get().clone()),
+        version => VERSIONED.with_borrow(|crls| crls.get(&version))?,
+    };
+
+    (!crl.der.is_empty()).then_some(crl)
+}
+
+/// the latest signed CRL of root key `version`, DER encoded
+pub fn current(version: u32) -> Option<Vec<u8>> {
+    stored(version).map(|crl| crl.der)
+}
+
+fn stored_for_tenant(id: &str, version: u32) -> Option<SignedCrl> {
+    TENANT_CRLS.with_borrow(|crls| crls.get(&(id.to_string(), version)))
+}
+
+/// every tenant key a CRL is served for, as (tenant id, key version)
+pub fn tenant_versions() -> Vec<(String, u32)> {
+    TENANT_CRLS.with_borrow(|crls| crls.iter().map(|(id, _)| id).collect())
+}
+
+/// the latest signed CRL of version `version` of tenant `id`'s key, DER encoded
+pub fn tenant_current(id: &str, version: u32) -> Option<Vec<u8>> {
+    stored_for_tenant(id, version).map(|crl| crl.der)
+}
+
+/// every root key version a CRL is served for
+pub fn versions() -> Vec<u32> {
+    let versioned = VERSIONED.with_borrow(|crls| crls.iter().map(|(v, _)| v).collect::<Vec<_>>());
+
+    stored(INITIAL_KEY_VERSION)
+        .map(|_| INITIAL_KEY_VERSION)
+        .into_iter()
+        .chain(versioned)
+        .collect()
+}
+
+/// Builds and signs a CRL for every root key version and every tenant key that issued leaves.
+/// Each one only lists the revoked leaves of its own key, leaves issued before they named their
+/// issuer's key count as the first root key version's.
+pub async fn refresh() -> anyhow::Result<()> {
+    let revoked = RevocationRegistry::list()
+        .into_iter()
+        .map(|r| {
+            let key_id = CertificateManager::get(r.serial)
+                .and_then(|cert| cert.authority_key_id().ok().flatten());
+
+            (r, key_id)
+        })
+        .collect::<Vec<_>>();
+
+    for version in KeyRotations::issuer_versions() {
+        let key = AcmeKey::new_root().with_version(version);
+        let key_id = key_identifier(&key).await?;
+
+        let listed = revoked
+            .iter()
+            .filter(|(_, issuer)| match issuer {
+                Some(issuer) => *issuer == key_id,
+                None => version == INITIAL_KEY_VERSION,
+            })
+            .map(|(r, _)| r);
+
+        let signed = sign(
+            &key,
+            KeyRotations::issuer_name(version),
+            key_id.clone(),
+            listed,
+        )
+        .await?;
+        store(version, signed)?;
+    }
+
+    for tenant in TenantRegistry::list() {
+        for version in tenant.key_versions() {
+            let key = tenant.key(version);
+            let key_id = key_identifier(&key).await?;
+
+            let listed = revoked
+                .iter()
+                .filter(|(_, issuer)| issuer.as_ref() == Some(&key_id))
+                .map(|(r, _)| r);
+
+            let signed = sign(&key, tenant.issuer_name(version), key_id.clone(), listed).await?;
+            store_for_tenant(&tenant.id, version, signed);
+        }
+    }
+
+    router::certify_resources();
+
+    anyhow::Ok(())
+}
+
+/// RFC 5280 §4.2.1.2 method 1 over `key`, what the leaves it signed name it by
+async fn key_identifier(key: &AcmeKey) -> anyhow::Result<Vec<u8>> {
+    let public_key = key.public_key().await?;
+
+    anyhow::Ok(Sha1::digest(public_key.to_encoded_point(false).as_bytes()).to_vec())
+}
+
+async fn sign<'a>(
+    issuer_key: &AcmeKey,
+    issuer: Name,
+    key_identifier: Vec<u8>,
+    revoked: impl Iterator<Item = &'a Revocation>,
+) -> anyhow::Result<SignedCrl> {
+    let number = reserve_number();
+    let this_update = clock::now_nanos();
+    let validity = Duration::from_secs(Config::revocation().crl_validity_secs);
+    let next_update = this_update + validity.as_nanos() as u64;
+
+    let revoked = revoked
+        .map(|r| {
+            anyhow::Ok(RevokedCert {
+                serial_number: SerialNumber::from(r.serial),
+                revocation_date: utc_time(r.revoked_at)?,
+                crl_entry_extensions: Some(vec![r.crl_reason().to_extension(&issuer, &[])?]),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let aki = AuthorityKeyIdentifier {
+        key_identifier: Some(OctetString::new(key_identifier)?),
+        authority_cert_issuer: None,
+        authority_cert_serial_number: None,
+    };
+    let crl_number = CrlNumber(Uint::new(&number.to_be_bytes())?);
+
+    let tbs_cert_list = TbsCertList {
+        version: Version::V2,
+        signature: AcmeKey::signature_algorithm(),
+        issuer: issuer.clone(),
+        this_update: utc_time(this_update)?,
+        next_update: Some(utc_time(next_update)?),
+        revoked_certificates: (!revoked.is_empty()).then_some(revoked),
+        crl_extensions: Some(vec![
+            aki.to_extension(&issuer, &[])?,
+            crl_number.to_extension(&issuer, &[])?,
+        ]),
+    };
+
+    let signature = issuer_key.sign(&tbs_cert_list.to_der()?).await?;
+
+    let der = CertificateList {
+        tbs_cert_list,
+        signature_algorithm: AcmeKey::signature_algorithm(),
+        signature: BitString::from_bytes(signature.as_bytes())?,
+    }
+    .to_der()?;
+
+    anyhow::Ok(SignedCrl {
+        number,
+        this_update,
+        next_update,
+        der,
+    })
+}
+
+/// keeps `signed` as the CRL of version `version` of tenant `id`'s key
+fn store_for_tenant(id: &str, version: u32, signed: SignedCrl) {
+    // a refresh that started later already replaced this one
+    if stored_for_tenant(id, version).is_some_and(|crl| crl.number > signed.number) {
+        return;
+    }
+
+    TENANT_CRLS.with_borrow_mut(|crls| crls.insert((id.to_string(), version), signed));
+}
+
+/// keeps `signed` as the CRL of root key `version`
+fn store(version: u32, signed: SignedCrl) -> anyhow::Result<()> {
+    // a refresh that started later already replaced this one
+    if stored(version).is_some_and(|crl| crl.number > signed.number) {
+        return anyhow::Ok(());
+    }
+
+    if version != INITIAL_KEY_VERSION {
+        VERSIONED.with_borrow_mut(|crls| crls.insert(version, signed));
+
+        return anyhow::Ok(());
+    }
+
+    CRL.with_borrow_mut(|cell| {
+        cell.set(signed)
+            .map_err(|e| anyhow::anyhow!("failed to store CRL: {e:?}"))?;
+
+        anyhow::Ok(())
+    })
+}
+
+/// re-signs in the background, a failed round is retried on the next tick
+pub fn refresh_in_background() {
+    ic_cdk::spawn(async {
+        if let Err(e) = refresh().await {
+            trace::log(&format!("CRL refresh failed: {e}"));
+        }
+    });
+}
+
+/// keeps thisUpdate/nextUpdate valid, has to be called again after every upgrade and whenever the
+/// configured windows change
+pub fn start_refresh_timer() {
+    let interval = Duration::from_secs(Config::revocation().crl_refresh_interval_secs);
+
+    if let Some(previous) = REFRESH_TIMER.take() {
+        ic_cdk_timers::clear_timer(previous);
+    }
+
+    ic_cdk_timers::set_timer(Duration::ZERO, refresh_in_background);
+    REFRESH_TIMER.set(Some(ic_cdk_timers::set_timer_interval(
+        interval,
+        refresh_in_background,
+    )));
+}
+
+/// validity of the CRL of the issuing key as (number, thisUpdate, nextUpdate), `None` before the
+/// first one
+pub fn current_window() -> Option<(u64, u64, u64)> {
+    stored(KeyRotations::active_version()).map(|crl| (crl.number, crl.this_update, crl.next_update))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_key_has_its_own_crl() {
+        assert_eq!(path(INITIAL_KEY_VERSION), CRL_PATH);
+        assert_eq!(path(2), "/crl/2.der");
+        assert_eq!(tenant_path("a", 1), "/crl/a/1.der");
+
+        let root = AcmeKey::new_root().with_version(2);
+        let tenant = AcmeKey::new_root().with_tenant("a").with_version(2);
+
+        assert!(distribution_url(&root).ends_with("/crl/2.der"));
+        assert!(distribution_url(&tenant).ends_with("/crl/a/2.der"));
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::anyhow;
+use k256::pkcs8::DecodePublicKey;
+use signature::Verifier;
+use x509_cert::{
+    der::{
+        oid::{db::rfc4519::CN, AssociatedOid, ObjectIdentifier},
+        Decode, Encode,
+    },
+    ext::pkix::{
+        name::{DirectoryString, GeneralName},
+        SubjectAltName,
+    },
+    name::Name,
+    request::{CertReq, ExtensionReq},
+    spki::SubjectPublicKeyInfoOwned,
+};
+
+const ECDSA_WITH_SHA_256: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.2");
+const ECDSA_WITH_SHA_384: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3");
+const SHA_256_WITH_RSA_ENCRYPTION: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.11");
+const ED25519: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+const SECP256R1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7");
+const SECP256K1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.132.0.10");
+const SECP384R1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.132.0.34");
+
+/// A PKCS#10 request whose self-signature has been checked.
+#[derive(Debug, Clone)]
+pub struct Csr {
+    pub public_key: SubjectPublicKeyInfoOwned,
+    /// the subject CN followed by every dNSName SAN, lowercased and deduplicated
+    pub domains: Vec<String>,
+}
+
+impl Csr {
+    pub fn from_der(der: &[u8]) -> anyhow::Result<Self> {
+        let req = CertReq::from_der(der).map_err(|e| anyhow!("malformed CSR: {e}"))?;
+
+        Self::verify_signature(&req)?;
+
+        let mut domains = Self::common_names(&req.info.subject)?;
+        domains.extend(Self::san_domains(&req)?);
+
+        let domains = crate::policy::normalize_domains(&domains);
+
+        anyhow::Ok(Self {
+            public_key: req.info.public_key,
+            domains,
+        })
+    }
+
+    /// proof of possession, RFC 2986 §4.2
+    fn verify_signature(req: &CertReq) -> anyhow::Result<()> {
+        let msg = req.info.to_der()?;
+        let sig = req
+            .signature
+            .as_bytes()
+            .ok_or_else(|| anyhow!("CSR signature is not octet aligned"))?;
+        let spki = &req.info.public_key;
+        let spki_der = spki.to_der()?;
+        let alg = req.algorithm.oid;
+
+        let curve = spki
+            .algorithm
+            .parameters
+            .as_ref()
+            .and_then(|p| p.decode_as::<ObjectIdentifier>().ok());
+
+        let verified = if alg == ECDSA_WITH_SHA_384 {
+            if curve != Some(SECP384R1) {
+                return Err(anyhow!("unsupported CSR key curve"));
+            }
+
+            let key = p384::ecdsa::VerifyingKey::from_public_key_der(&spki_der)?;
+            let sig = p384::ecdsa::DerSignature::try_from(sig)?;
+
+            key.verify(&msg, &sig).is_ok()
+        } else if alg == ECDSA_WITH_SHA_256 {
+            match curve {
+                Some(c) if c == SECP256R1 => {
+                    let key = p256::ecdsa::VerifyingKey::from_public_key_der(&spki_der)?;
+                    let sig = p256::ecdsa::DerSignature::try_from(sig)?;
+
+                    key.verify(&msg, &sig).is_ok()
+                }
+                Some(c) if c == SECP256K1 => {
+                    let key = k256::ecdsa::VerifyingKey::from_public_key_der(&spki_der)?;
+                    let sig = k256::ecdsa::DerSignature::try_from(sig)?;
+
+                    key.verify(&msg, &sig).is_ok()
+                }
+                _ => return Err(anyhow!("unsupported CSR key curve")),
+            }
+        } else if alg == SHA_256_WITH_RSA_ENCRYPTION {
+            let key = rsa::RsaPublicKey::from_public_key_der(&spki_der)?;
+            let key = rsa::pkcs1v15::VerifyingKey::<sha2::Sha256>::new(key);
+            let sig = rsa::pkcs1v15::Signature::try_from(sig)?;
+
+            key.verify(&msg, &sig).is_ok()
+        } else if alg == ED25519 {
+            let raw = spki
+                .subject_public_key
+                .as_bytes()
+                .and_then(|b| <[u8; 32]>::try_from(b).ok())
+                .ok_or_else(|| anyhow!("invalid Ed25519 CSR key"))?;
+            let key = ed25519_dalek::VerifyingKey::from_bytes(&raw)?;
+            let sig = ed25519_dalek::Signature::from_slice(sig)?;
+
+            key.verify_strict(&msg, &sig).is_ok()
+        } else {
+            return Err(anyhow!("unsupported CSR signature algorithm {alg}"));
+        };
+
+        if !verified {
+            return Err(anyhow!("CSR signature does not verify"));
+        }
+
+        anyhow::Ok(())
+    }
+
+    pub fn common_names(subject: &Name) -> anyhow::Result<Vec<String>> {
+        let mut names = Vec::new();
+
+        for atv in subject.0.iter().flat_map(|rdn| rdn.0.iter()) {
+            if atv.oid != CN {
+                continue;
+            }
+
+            let value = match DirectoryString::from_der(&atv.value.to_der()?)? {
+                DirectoryString::PrintableString(s) => s.to_string(),
+                DirectoryString::TeletexString(s) => s.to_string(),
+                DirectoryString::Utf8String(s) => s,
+            };
+
+            names.push(value);
+        }
+
+        anyhow::Ok(names)
+    }
+
+    fn san_domains(req: &CertReq) -> anyhow::Result<Vec<String>> {
+        let mut names = Vec::new();
+
+        let requested = req
+            .info
+            .attributes
+            .iter()
+            .filter(|attr| attr.oid == ExtensionReq::OID)
+            .flat_map(|attr| attr.values.iter());
+
+        for value in requested {
+            let ExtensionReq(extensions) = ExtensionReq::from_der(&value.to_der()?)?;
+
+            for ext in extensions
+                .iter()
+                .filter(|e| e.extn_id == SubjectAltName::OID)
+            {
+                let SubjectAltName(general_names) =
+                    SubjectAltName::from_der(ext.extn_value.as_bytes())?;
+
+                for name in general_names {
+                    match name {
+                        GeneralName::DnsName(dns) => names.push(dns.to_string()),
+                        _ => return Err(anyhow!("only dNSName SANs are supported")),
+                    }
+                }
+            }
+        }
+
+        anyhow::Ok(names)
+    }
+}
@@ -0,0 +1,351 @@
+use std::cell::RefCell;
+
+use anyhow::anyhow;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use candid::CandidType;
+use ic_cdk::api::management_canister::http_request::{HttpHeader, HttpMethod};
+use serde::{Deserialize, Serialize};
+use x509_cert::{
+    der::{
+        asn1::{Null, ObjectIdentifier, OctetString},
+        oid::AssociatedOid,
+        DecodePem, Encode, Length, Writer,
+    },
+    ext::{AsExtension, Extension},
+    name::Name,
+};
+
+use crate::{
+    clock,
+    handler::types::{CtLog, CtPolicy, SctFailureMode},
+    mem::{candid_storable, Repository},
+    outcall::{self, Outcall, Transform},
+    trace,
+};
+
+/// RFC 6962 §3.1, marks a certificate as a precertificate no client will accept
+const CT_PRECERT_POISON: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.11129.2.4.3");
+/// RFC 6962 §3.3, the embedded `SignedCertificateTimestampList`
+const CT_PRECERT_SCTS: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.11129.2.4.2");
+/// an SCT is a few hundred bytes of JSON
+const CT_MAX_RESPONSE_BYTES: u64 = 4 * 1024;
+/// unused cycles are refunded by the management canister
+const CT_OUTCALL_CYCLES: u128 = 10_000_000_000;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// how far ahead of the canister's clock a log may stamp an SCT
+const MAX_SCT_CLOCK_SKEW_MS: u64 = 5 * 60 * 1_000;
+
+thread_local! {
+    static RECEIPTS: RefCell<CtReceiptStore> = RefCell::new(CtReceiptStore::init());
+}
+
+/// What a leaf carries for Certificate Transparency.
+pub enum Embedding {
+    None,
+    /// the precertificate submitted to the logs
+    Poison,
+    Scts(SctList),
+}
+
+pub struct PrecertificatePoison;
+
+impl AssociatedOid for PrecertificatePoison {
+    const OID: ObjectIdentifier = CT_PRECERT_POISON;
+}
+
+impl Encode for PrecertificatePoison {
+    fn encoded_len(&self) -> x509_cert::der::Result<Length> {
+        Null.encoded_len()
+    }
+
+    fn encode(&self, encoder: &mut impl Writer) -> x509_cert::der::Result<()> {
+        Null.encode(encoder)
+    }
+}
+
+impl AsExtension for PrecertificatePoison {
+    fn critical(&self, _: &Name, _: &[Extension]) -> bool {
+        true
+    }
+}
+
+/// One submission of a precertificate to a log, kept for audit whether or not it returned an SCT.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CtReceipt {
+    pub serial: u64,
+    pub log: String,
+    pub url: String,
+    pub submitted_at: u64,
+    /// base64 log id of the returned SCT
+    pub log_id: Option<String>,
+    /// the SCT's timestamp, milliseconds since the Unix epoch
+    pub sct_timestamp: Option<u64>,
+    /// whether the SCT made it into the final certificate
+    pub embedded: bool,
+    pub error: Option<String>,
+}
+
+candid_storable!(CtReceipt);
+
+/// CT submissions keyed by serial and the position of the log in the submission order.
+pub struct CtReceiptStore {
+    receipts: Repository<(u64, u32), CtReceipt>,
+}
+
+impl CtReceiptStore {
+    fn init() -> Self {
+        Self {
+            receipts: Repository::init::<Self>(),
+        }
+    }
+
+    fn record(receipts: Vec<CtReceipt>) {
+        RECEIPTS.with_borrow_mut(|r| {
+            for (index, receipt) in receipts.into_iter().enumerate() {
+                r.receipts.insert((receipt.serial, index as u32), receipt);
+            }
+        })
+    }
+
+    pub fn for_certificate(serial: u64) -> Vec<CtReceipt> {
+        RECEIPTS.with_borrow(|r| {
+            r.receipts
+                .range((serial, 0)..=(serial, u32::MAX))
+                .map(|(_, receipt)| receipt)
+                .collect()
+        })
+    }
+}
+
+/// TLS encoded `SignedCertificateTimestampList`, wrapped in an OCTET STRING as RFC 6962 asks.
+pub struct SctList(OctetString);
+
+impl AssociatedOid for SctList {
+    const OID: ObjectIdentifier = CT_PRECERT_SCTS;
+}
+
+impl Encode for SctList {
+    fn encoded_len(&self) -> x509_cert::der::Result<Length> {
+        self.0.encoded_len()
+    }
+
+    fn encode(&self, encoder: &mut impl Writer) -> x509_cert::der::Result<()> {
+        self.0.encode(encoder)
+    }
+}
+
+impl AsExtension for SctList {
+    fn critical(&self, _: &Name, _: &[Extension]) -> bool {
+        false
+    }
+}
+
+#[derive(Serialize)]
+struct AddChainRequest {
+    chain: Vec<String>,
+}
+
+/// RFC 6962 §4.1 `add-pre-chain` response
+#[derive(Deserialize)]
+struct AddChainResponse {
+    sct_version: u8,
+    id: String,
+    timestamp: u64,
+    extensions: String,
+    signature: String,
+}
+
+struct Sct {
+    version: u8,
+    log_id: Vec<u8>,
+    timestamp: u64,
+    extensions: Vec<u8>,
+    /// `DigitallySigned`, already TLS encoded by the log
+    signature: Vec<u8>,
+}
+
+impl Sct {
+    fn to_tls(&self) -> Vec<u8> {
+        let mut out = vec![self.version];
+        out.extend(&self.log_id);
+        out.extend(self.timestamp.to_be_bytes());
+        out.extend((self.extensions.len() as u16).to_be_bytes());
+        out.extend(&self.extensions);
+        out.extend(&self.signature);
+
+        out
+    }
+}
+
+fn sct_list(scts: &[Sct]) -> anyhow::Result<SctList> {
+    let mut list = Vec::new();
+
+    for sct in scts {
+        let sct = sct.to_tls();
+        list.extend((sct.len() as u16).to_be_bytes());
+        list.extend(sct);
+    }
+
+    let mut out = (list.len() as u16).to_be_bytes().to_vec();
+    out.extend(list);
+
+    anyhow::Ok(SctList(OctetString::new(out)?))
+}
+
+/// SCTs the policy wants for a certificate valid from `not_before` to `not_after`
+pub fn required_scts(policy: &CtPolicy, not_before: u64, not_after: u64) -> u32 {
+    let lifetime_days = (not_after - not_before).div_ceil(NANOS_PER_DAY);
+
+    policy
+        .requirements
+        .iter()
+        .find(|r| r.max_lifetime_days as u64 >= lifetime_days)
+        .or(policy.requirements.last())
+        .map(|r| r.min_scts)
+        .unwrap_or(0)
+}
+
+/// Every replica submits the same chain, which only reaches consensus with logs that hand out the
+/// same SCT for a resubmitted chain, as RFC 6962 §4.1 allows. A log that does not just counts as
+/// a failed submission.
+async fn submit(log: &CtLog, chain: &[Vec<u8>]) -> anyhow::Result<Sct> {
+    let body = serde_json::to_vec(&AddChainRequest {
+        chain: chain.iter().map(|c| BASE64_STANDARD.encode(c)).collect(),
+    })?;
+
+    // logs answer differently on every replica only in their headers, the SCT has to agree
+    let outcall = Outcall {
+        url: format!("{}ct/v1/add-pre-chain", log.url),
+        method: HttpMethod::POST,
+        headers: vec![HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        }],
+        body: Some(body),
+        max_response_bytes: CT_MAX_RESPONSE_BYTES,
+        cycles: CT_OUTCALL_CYCLES,
+        transform: Transform::body(),
+    };
+
+    let resp = outcall::send(outcall)
+        .await
+        .map_err(|e| anyhow!("CT log {} failed: {e}", log.name))?;
+
+    if outcall::status(&resp) != 200 {
+        return Err(anyhow!(
+            "CT log {} answered with HTTP {}",
+            log.name,
+            resp.status
+        ));
+    }
+
+    let resp = serde_json::from_slice::<AddChainResponse>(&resp.body)?;
+    let log_id = BASE64_STANDARD.decode(resp.id)?;
+
+    if log_id.len() != 32 {
+        return Err(anyhow!("CT log {} returned a malformed log id", log.name));
+    }
+
+    check_timestamp(log, resp.timestamp)?;
+
+    anyhow::Ok(Sct {
+        version: resp.sct_version,
+        log_id,
+        timestamp: resp.timestamp,
+        extensions: BASE64_STANDARD.decode(resp.extensions)?,
+        signature: BASE64_STANDARD.decode(resp.signature)?,
+    })
+}
+
+/// The log promises to merge the precertificate within `max_merge_delay_secs` of the SCT's
+/// timestamp. A precertificate is new on every issuance, so an SCT stamped before that window or
+/// in the future doesn't hold the log to its promise.
+fn check_timestamp(log: &CtLog, timestamp_ms: u64) -> anyhow::Result<()> {
+    let now_ms = clock::now_nanos() / 1_000_000;
+    let oldest = now_ms.saturating_sub(log.max_merge_delay_secs * 1_000);
+
+    if !(oldest..=now_ms + MAX_SCT_CLOCK_SKEW_MS).contains(&timestamp_ms) {
+        return Err(anyhow!(
+            "CT log {} stamped its SCT at {timestamp_ms} ms, outside its merge window",
+            log.name
+        ));
+    }
+
+    anyhow::Ok(())
+}
+
+/// Submits `precert_pem`, issued by `issuer_pem`, to the configured logs and decides what the
+/// final certificate embeds according to the policy. A receipt of every submission is stored under
+/// `serial`, also when issuance fails for lack of SCTs.
+pub async fn embedding(
+    policy: &CtPolicy,
+    serial: u64,
+    precert_pem: &str,
+    issuer_pem: &str,
+    required: u32,
+) -> anyhow::Result<Embedding> {
+    let chain = [precert_pem, issuer_pem]
+        .iter()
+        .map(|pem| anyhow::Ok(x509_cert::Certificate::from_pem(pem)?.to_der()?))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut scts = Vec::new();
+    let mut receipts = Vec::new();
+
+    for log in &policy.logs {
+        if scts.len() >= required as usize {
+            break;
+        }
+
+        let mut receipt = CtReceipt {
+            serial,
+            log: log.name.clone(),
+            url: log.url.clone(),
+            submitted_at: clock::now_nanos(),
+            log_id: None,
+            sct_timestamp: None,
+            embedded: false,
+            error: None,
+        };
+
+        match submit(log, &chain).await {
+            Ok(sct) => {
+                receipt.log_id = Some(BASE64_STANDARD.encode(&sct.log_id));
+                receipt.sct_timestamp = Some(sct.timestamp);
+                scts.push(sct);
+            }
+            Err(e) => {
+                trace::log(&e.to_string());
+                receipt.error = Some(e.to_string());
+            }
+        }
+
+        receipts.push(receipt);
+    }
+
+    let embedding = if scts.len() >= required as usize {
+        for receipt in receipts.iter_mut().filter(|r| r.log_id.is_some()) {
+            receipt.embedded = true;
+        }
+
+        sct_list(&scts).map(Embedding::Scts)
+    } else {
+        match policy.on_failure {
+            SctFailureMode::FailIssuance => Err(anyhow!(
+                "only {} of {required} CT logs returned an SCT",
+                scts.len()
+            )),
+            SctFailureMode::IssueWithoutScts => {
+                trace::log(&format!(
+                    "issuing without SCTs, only {} of {required} logs answered",
+                    scts.len()
+                ));
+                anyhow::Ok(Embedding::None)
+            }
+        }
+    };
+
+    CtReceiptStore::record(receipts);
+
+    embedding
+}
@@ -0,0 +1,190 @@
+use std::cell::RefCell;
+
+use candid::CandidType;
+use ic_stable_structures::StableLog;
+use serde::Deserialize;
+
+use crate::{
+    account::AccountManager,
+    api::{ApiError, ApiResult},
+    clock,
+    handler::types::GeneralRequest,
+    mem::{candid_storable, Mem, Memory, Repository},
+};
+
+/// capture windows are bounded so a forgotten toggle cannot fill stable memory
+const MAX_CAPTURE_WINDOW_SECS: u64 = 24 * 60 * 60;
+/// recording stops once the log holds this many entries, until it is cleared
+const MAX_CAPTURED_ENTRIES: u64 = 100_000;
+/// longest body or trace kept per entry
+const MAX_CAPTURED_BYTES: usize = 16 * 1024;
+/// most entries one call to [`DebugCapture::entries`] returns, keeps the reply within the query
+/// response limit
+const MAX_ENTRIES_PER_PAGE: u64 = 100;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+thread_local! {
+    static CAPTURE: RefCell<DebugCapture> = RefCell::new(DebugCapture::init());
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum CaptureKind {
+    Request,
+    JwsHeader,
+    Response,
+    Trace,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CaptureEntry {
+    pub timestamp: u64,
+    pub account_id: String,
+    pub kind: CaptureKind,
+    pub data: String,
+}
+
+candid_storable!(CaptureEntry);
+
+/// memory markers for the capture log
+pub struct DebugCaptureIndex;
+pub struct DebugCaptureData;
+
+/// Verbose request/response capture for individual accounts, used to debug client interop issues
+/// without raising log volume for everyone.
+pub struct DebugCapture {
+    /// account id -> end of the capture window, in nanoseconds
+    windows: Repository<String, u64>,
+    log: StableLog<CaptureEntry, Memory, Memory>,
+}
+
+impl DebugCapture {
+    fn init() -> Self {
+        Self {
+            windows: Repository::init::<Self>(),
+            log: StableLog::init(
+                Mem::memory_for::<DebugCaptureIndex>(),
+                Mem::memory_for::<DebugCaptureData>(),
+            )
+            .expect("debug capture log initialization must succeed"),
+        }
+    }
+
+    fn _is_active(&self, account_id: &str) -> bool {
+        match self.windows.get(&account_id.to_string()) {
+            Some(until) => clock::now_nanos() < until,
+            None => false,
+        }
+    }
+
+    fn _record(&self, account_id: &str, kind: CaptureKind, mut data: String) {
+        if self.log.len() >= MAX_CAPTURED_ENTRIES {
+            return;
+        }
+
+        if data.len() > MAX_CAPTURED_BYTES {
+            let mut end = MAX_CAPTURED_BYTES;
+            while !data.is_char_boundary(end) {
+                end -= 1;
+            }
+            data.truncate(end);
+        }
+
+        let entry = CaptureEntry {
+            timestamp: clock::now_nanos(),
+            account_id: account_id.to_string(),
+            kind,
+            data,
+        };
+
+        // capture is best effort and must never fail the request being captured
+        let _ = self.log.append(&entry);
+    }
+
+    /// starts capturing `account_id` for `duration_secs`, returns the end of the window
+    pub fn enable(account_id: String, duration_secs: u64) -> ApiResult<u64> {
+        if duration_secs == 0 || duration_secs > MAX_CAPTURE_WINDOW_SECS {
+            return Err(ApiError::InvalidArgument(format!(
+                "capture window must be between 1 and {MAX_CAPTURE_WINDOW_SECS} seconds"
+            )));
+        }
+
+        if AccountManager::get(&account_id).is_none() {
+            return Err(ApiError::NotFound(format!("account {account_id}")));
+        }
+
+        let until = clock::now_nanos() + duration_secs * NANOS_PER_SEC;
+
+        CAPTURE.with_borrow_mut(|c| c.windows.insert(account_id, until));
+
+        Ok(until)
+    }
+
+    pub fn disable(account_id: &str) {
+        CAPTURE.with_borrow_mut(|c| c.windows.remove(&account_id.to_string()));
+    }
+
+    /// records a validation trace, `trace` is only evaluated when the account is being captured
+    pub fn trace(account_id: &str, trace: impl FnOnce() -> String) {
+        CAPTURE.with_borrow(|c| {
+            if c._is_active(account_id) {
+                c._record(account_id, CaptureKind::Trace, trace());
+            }
+        })
+    }
+
+    pub fn record(account_id: &str, kind: CaptureKind, data: String) {
+        CAPTURE.with_borrow(|c| {
+            if c._is_active(account_id) {
+                c._record(account_id, kind, data);
+            }
+        })
+    }
+
+    /// captures an incoming JWS body and its protected header when it was sent by an account under
+    /// capture, returns that account id so the response can be captured as well
+    pub fn capture_request(raw_body: &[u8]) -> Option<String> {
+        if CAPTURE.with_borrow(|c| c.windows.is_empty()) {
+            return None;
+        }
+
+        let req = serde_json::from_slice::<GeneralRequest>(raw_body).ok()?;
+        let header = req.jwk_header().ok()?;
+        let account_id = AccountManager::from_jws(raw_body)?.id;
+
+        CAPTURE.with_borrow(|c| {
+            if !c._is_active(&account_id) {
+                return None;
+            }
+
+            c._record(
+                &account_id,
+                CaptureKind::Request,
+                String::from_utf8_lossy(raw_body).into_owned(),
+            );
+            c._record(&account_id, CaptureKind::JwsHeader, format!("{header:?}"));
+
+            Some(account_id)
+        })
+    }
+
+    /// up to `limit` entries from index `start` on, at most [`MAX_ENTRIES_PER_PAGE`]
+    pub fn entries(account_id: Option<String>, start: u64, limit: u64) -> Vec<CaptureEntry> {
+        CAPTURE.with_borrow(|c| {
+            (start..c.log.len())
+                .filter_map(|idx| c.log.get(idx))
+                .filter(|e| account_id.as_ref().is_none_or(|id| &e.account_id == id))
+                .take(limit.min(MAX_ENTRIES_PER_PAGE) as usize)
+                .collect()
+        })
+    }
+
+    pub fn clear() {
+        CAPTURE.with_borrow_mut(|c| {
+            c.log = StableLog::new(
+                Mem::memory_for::<DebugCaptureIndex>(),
+                Mem::memory_for::<DebugCaptureData>(),
+            );
+        })
+    }
+}
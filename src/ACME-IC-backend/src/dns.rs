@@ -0,0 +1,107 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::outcall::{self, Outcall, Transform};
+
+/// DNS-over-HTTPS JSON API used for every lookup, see https://developers.google.com/speed/public-dns/docs/doh/json
+const DOH_ENDPOINT: &str = "https://dns.google/resolve";
+const DOH_MAX_RESPONSE_BYTES: u64 = 16 * 1024;
+/// unused cycles are refunded by the management canister
+const DOH_OUTCALL_CYCLES: u128 = 1_000_000_000;
+/// answers kept of a response, far more than any TXT or CAA set a validation looks at
+const MAX_ANSWERS: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub enum RecordType {
+    Txt = 16,
+    Caa = 257,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DohAnswer {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub rtype: u16,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DohResponse {
+    #[serde(rename = "Status")]
+    pub status: u32,
+    #[serde(rename = "Answer", default)]
+    pub answer: Vec<DohAnswer>,
+}
+
+/// Replicas see different TTLs, flags and casings for the same lookup, keep only what consensus
+/// needs: the status code and the deduplicated, sorted answers with lowercased names and without
+/// TTLs. A body that isn't a DNS JSON response becomes empty.
+pub fn normalize(body: &[u8]) -> Vec<u8> {
+    let Ok(mut resp) = serde_json::from_slice::<DohResponse>(body) else {
+        return Vec::new();
+    };
+
+    for answer in &mut resp.answer {
+        answer.name.make_ascii_lowercase();
+    }
+
+    resp.answer.sort();
+    resp.answer.dedup();
+    resp.answer.truncate(MAX_ANSWERS);
+
+    serde_json::to_vec(&resp).unwrap_or_default()
+}
+
+/// record data of every `rtype` record at `name`, following CNAMEs the way the resolver does
+pub async fn resolve(name: &str, rtype: RecordType) -> anyhow::Result<Vec<String>> {
+    let outcall = Outcall::get(
+        format!("{DOH_ENDPOINT}?name={name}&type={}", rtype as u16),
+        DOH_MAX_RESPONSE_BYTES,
+        DOH_OUTCALL_CYCLES,
+        Transform::Doh,
+    )
+    .with_header("Accept", "application/dns-json");
+
+    let resp = outcall::send(outcall)
+        .await
+        .map_err(|e| anyhow!("DNS lookup for {name} failed: {e}"))?;
+
+    if outcall::status(&resp) != 200 {
+        return Err(anyhow!(
+            "DNS lookup for {name} failed with HTTP {}",
+            resp.status
+        ));
+    }
+
+    let resp = serde_json::from_slice::<DohResponse>(&resp.body)
+        .map_err(|_| anyhow!("malformed DNS response for {name}"))?;
+
+    // 0 = NOERROR, 3 = NXDOMAIN, both simply mean "no records" to callers
+    if resp.status != 0 && resp.status != 3 {
+        return Err(anyhow!(
+            "DNS lookup for {name} failed with rcode {}",
+            resp.status
+        ));
+    }
+
+    anyhow::Ok(
+        resp.answer
+            .into_iter()
+            .filter(|a| a.rtype == rtype as u16)
+            .map(|a| a.data)
+            .collect(),
+    )
+}
+
+/// TXT data as returned by the resolver is quoted and may be split into several strings
+pub fn unquote_txt(data: &str) -> String {
+    if !data.contains('"') {
+        return data.to_string();
+    }
+
+    data.split('"')
+        .enumerate()
+        .filter(|(i, _)| i % 2 == 1)
+        .map(|(_, s)| s)
+        .collect::<String>()
+}
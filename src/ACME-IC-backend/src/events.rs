@@ -0,0 +1,484 @@
+use std::{cell::RefCell, time::Duration};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use candid::{CandidType, Principal};
+use hmac::{Hmac, Mac};
+use ic_cdk::api::management_canister::http_request::HttpHeader;
+use ic_stable_structures::StableCell;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    api::{ApiError, ApiResult},
+    cert_manager::{CertificateManager, CertificateOwner, IssuedCertificate},
+    clock,
+    jobs::JobState,
+    mem::{candid_storable, Mem, Memory, Repository},
+    pickup,
+    revocation::{Revocation, RevocationRegistry},
+};
+
+/// subscriptions a single owner may register
+const MAX_SUBSCRIPTIONS_PER_OWNER: usize = 10;
+/// shorter shared secrets are too easy to guess
+const MIN_SECRET_BYTES: usize = 16;
+/// deliveries attempted per worker round
+const DELIVERIES_PER_ROUND: usize = 16;
+
+/// below a tenant's base path, where ACME accounts manage their own subscriptions
+pub const SUBSCRIPTIONS_PATH: &str = "/subscriptions";
+
+thread_local! {
+    static EVENTS: RefCell<Events> = RefCell::new(Events::init());
+}
+
+/// The variant names are the Candid encoding of stored subscriptions and of the interface, the
+/// shared prefix stays.
+#[allow(clippy::enum_variant_names)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    CertificateIssued,
+    CertificateRevoked,
+    /// the certificate entered the expiry window, sent once per certificate
+    CertificateExpiring,
+}
+
+impl EventKind {
+    /// `type` of the JSON event
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::CertificateIssued => "certificate.issued",
+            Self::CertificateRevoked => "certificate.revoked",
+            Self::CertificateExpiring => "certificate.expiring",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        [
+            Self::CertificateIssued,
+            Self::CertificateRevoked,
+            Self::CertificateExpiring,
+        ]
+        .into_iter()
+        .find(|k| k.as_str() == kind)
+    }
+}
+
+/// An HTTPS endpoint the lifecycle events of one owner's certificates are POSTed to.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StoredSubscription {
+    pub id: u64,
+    pub owner: CertificateOwner,
+    pub url: String,
+    /// HMAC-SHA256 key of the `webhook-signature` header, never handed out again
+    pub secret: Vec<u8>,
+    pub events: Vec<EventKind>,
+    pub created_at: u64,
+}
+
+candid_storable!(StoredSubscription);
+
+/// [`StoredSubscription`] without its secret.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Subscription {
+    pub id: u64,
+    pub owner: CertificateOwner,
+    pub url: String,
+    pub events: Vec<EventKind>,
+    pub created_at: u64,
+}
+
+impl From<StoredSubscription> for Subscription {
+    fn from(s: StoredSubscription) -> Self {
+        Self {
+            id: s.id,
+            owner: s.owner,
+            url: s.url,
+            events: s.events,
+            created_at: s.created_at,
+        }
+    }
+}
+
+/// One event on its way to one subscription. Deliveries that failed [`crate::jobs::MAX_ATTEMPTS`]
+/// times are dead letters, kept until a controller redelivers or drops them.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EventDelivery {
+    pub id: u64,
+    pub subscription: u64,
+    pub kind: EventKind,
+    /// the JSON event, the same bytes on every attempt
+    pub body: Vec<u8>,
+    pub state: JobState,
+}
+
+candid_storable!(EventDelivery);
+
+/// body of every event
+#[derive(Serialize, Debug)]
+struct Event<'a> {
+    id: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// RFC 3339
+    created_at: String,
+    data: EventData<'a>,
+}
+
+#[derive(Serialize, Debug)]
+struct EventData<'a> {
+    serial: u64,
+    domains: &'a [String],
+    /// RFC 3339
+    not_after: String,
+    /// RFC 5280 §5.3.1 reason code of a revocation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<u8>,
+}
+
+/// memory markers for the subscriptions, the deliveries, the event counter and the certificates
+/// whose expiry was announced
+pub struct SubscriptionStore;
+pub struct EventDeliveryQueue;
+pub struct EventSequence;
+pub struct ExpiryAnnouncements;
+
+/// Subscriptions to certificate lifecycle events and the queue of signed deliveries to them.
+pub struct Events {
+    subscriptions: Repository<u64, StoredSubscription>,
+    deliveries: Repository<u64, EventDelivery>,
+    sequence: StableCell<u64, Memory>,
+    announced: Repository<u64, ()>,
+}
+
+impl Events {
+    fn init() -> Self {
+        Self {
+            subscriptions: Repository::init::<SubscriptionStore>(),
+            deliveries: Repository::init::<EventDeliveryQueue>(),
+            sequence: Mem::cell::<EventSequence, _>(0),
+            announced: Repository::init::<ExpiryAnnouncements>(),
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        let id = self.sequence.get() + 1;
+        if let Err(e) = self.sequence.set(id) {
+            Mem::write_failed("the event sequence", e);
+        }
+
+        id
+    }
+
+    /// registers `url` for `events` of `owner`'s certificates, the caller has to be the owner
+    /// unless it is a controller. ACME accounts register theirs with a JWS at
+    /// [`SUBSCRIPTIONS_PATH`].
+    pub fn subscribe(
+        caller: Principal,
+        owner: Option<CertificateOwner>,
+        url: String,
+        secret: String,
+        events: Vec<EventKind>,
+    ) -> ApiResult<Subscription> {
+        let owner = owner.unwrap_or(CertificateOwner::Canister(caller));
+
+        if owner != CertificateOwner::Canister(caller) && !ic_cdk::api::is_controller(&caller) {
+            return Err(ApiError::Unauthorized);
+        }
+
+        Self::register(owner, url, secret, events)
+    }
+
+    /// registers `url` for `events` of `owner`'s certificates, the owner is already authenticated
+    pub fn register(
+        owner: CertificateOwner,
+        url: String,
+        secret: String,
+        events: Vec<EventKind>,
+    ) -> ApiResult<Subscription> {
+        pickup::check_notify_url(&url).map_err(|e| ApiError::InvalidArgument(e.to_string()))?;
+
+        if secret.len() < MIN_SECRET_BYTES {
+            return Err(ApiError::InvalidArgument(format!(
+                "the secret needs at least {MIN_SECRET_BYTES} bytes"
+            )));
+        }
+
+        if events.is_empty() {
+            return Err(ApiError::InvalidArgument(
+                "subscribe to at least one event".to_string(),
+            ));
+        }
+
+        EVENTS.with_borrow_mut(|e| {
+            let registered = e
+                .subscriptions
+                .values()
+                .filter(|s| s.owner == owner)
+                .count();
+
+            if registered >= MAX_SUBSCRIPTIONS_PER_OWNER {
+                return Err(ApiError::InvalidArgument(format!(
+                    "at most {MAX_SUBSCRIPTIONS_PER_OWNER} subscriptions per owner"
+                )));
+            }
+
+            let subscription = StoredSubscription {
+                id: e.next_id(),
+                owner,
+                url,
+                secret: secret.into_bytes(),
+                events,
+                created_at: clock::now_nanos(),
+            };
+
+            e.subscriptions
+                .insert(subscription.id, subscription.clone());
+
+            Ok(subscription.into())
+        })
+    }
+
+    /// drops the subscription and whatever is still queued for it
+    pub fn unsubscribe(caller: Principal, id: u64) -> ApiResult<()> {
+        if ic_cdk::api::is_controller(&caller) {
+            return Self::remove(id, |_| true);
+        }
+
+        Self::remove(id, |owner| *owner == CertificateOwner::Canister(caller))
+    }
+
+    /// [`Self::unsubscribe`] for a subscription of `owner`, those of others are unknown
+    pub fn cancel(owner: &CertificateOwner, id: u64) -> ApiResult<()> {
+        Self::remove(id, |o| o == owner).map_err(|e| match e {
+            ApiError::Unauthorized => ApiError::NotFound(format!("subscription {id}")),
+            e => e,
+        })
+    }
+
+    fn remove(id: u64, allowed: impl Fn(&CertificateOwner) -> bool) -> ApiResult<()> {
+        EVENTS.with_borrow_mut(|e| {
+            let subscription = e
+                .subscriptions
+                .get(&id)
+                .ok_or_else(|| ApiError::NotFound(format!("subscription {id}")))?;
+
+            if !allowed(&subscription.owner) {
+                return Err(ApiError::Unauthorized);
+            }
+
+            e.subscriptions.remove(&id);
+
+            let queued = e
+                .deliveries
+                .values()
+                .filter(|d| d.subscription == id)
+                .map(|d| d.id)
+                .collect::<Vec<_>>();
+
+            for delivery in queued {
+                e.deliveries.remove(&delivery);
+            }
+
+            Ok(())
+        })
+    }
+
+    /// the subscriptions of `owner`
+    pub fn of(owner: &CertificateOwner) -> Vec<Subscription> {
+        EVENTS.with_borrow(|e| {
+            e.subscriptions
+                .values()
+                .filter(|s| s.owner == *owner)
+                .map(Subscription::from)
+                .collect()
+        })
+    }
+
+    /// the caller's subscriptions, every subscription for a controller
+    pub fn list(caller: Principal) -> Vec<Subscription> {
+        let all = ic_cdk::api::is_controller(&caller);
+
+        EVENTS.with_borrow(|e| {
+            e.subscriptions
+                .values()
+                .filter(|s| all || s.owner == CertificateOwner::Canister(caller))
+                .map(Subscription::from)
+                .collect()
+        })
+    }
+
+    /// queues `kind` for every subscription of the certificate's owner that asked for it
+    fn publish(kind: EventKind, cert: &IssuedCertificate, reason: Option<u8>) {
+        EVENTS.with_borrow_mut(|e| {
+            let subscribed = e
+                .subscriptions
+                .values()
+                .filter(|s| s.owner == cert.owner && s.events.contains(&kind))
+                .collect::<Vec<_>>();
+
+            for subscription in subscribed {
+                let id = e.next_id();
+                let event = Event {
+                    id: format!("evt_{id}"),
+                    kind: kind.as_str(),
+                    created_at: clock::now_rfc3339(),
+                    data: EventData {
+                        serial: cert.serial,
+                        domains: &cert.domains,
+                        not_after: clock::rfc3339(cert.not_after),
+                        reason,
+                    },
+                };
+
+                let Ok(body) = serde_json::to_vec(&event) else {
+                    continue;
+                };
+
+                e.deliveries.insert(
+                    id,
+                    EventDelivery {
+                        id,
+                        subscription: subscription.id,
+                        kind,
+                        body,
+                        state: JobState::new(clock::now_nanos()),
+                    },
+                );
+            }
+        })
+    }
+
+    pub fn issued(cert: &IssuedCertificate) {
+        Self::publish(EventKind::CertificateIssued, cert, None);
+    }
+
+    pub fn revoked(cert: &IssuedCertificate, revocation: &Revocation) {
+        Self::publish(EventKind::CertificateRevoked, cert, Some(revocation.reason));
+    }
+
+    /// announces `cert` entering the expiry window, only the first time
+    pub fn expiring(cert: &IssuedCertificate) {
+        let first = EVENTS.with_borrow_mut(|e| e.announced.insert(cert.serial, ()).is_none());
+
+        if first {
+            Self::publish(EventKind::CertificateExpiring, cert, None);
+        }
+    }
+
+    /// forgets the announcements of certificates that expired, were revoked or are gone, they are
+    /// never due again
+    pub fn prune_announced(now: u64) {
+        EVENTS.with_borrow_mut(|e| {
+            let gone = e
+                .announced
+                .iter()
+                .map(|(serial, _)| serial)
+                .filter(|serial| {
+                    CertificateManager::get(*serial).is_none_or(|c| c.not_after <= now)
+                        || RevocationRegistry::get(*serial).is_some()
+                })
+                .collect::<Vec<_>>();
+
+            for serial in gone {
+                e.announced.remove(&serial);
+            }
+        })
+    }
+
+    /// deliveries that ran out of attempts
+    pub fn dead_letters() -> Vec<EventDelivery> {
+        EVENTS.with_borrow(|e| e.deliveries.values().filter(|d| d.state.parked()).collect())
+    }
+
+    /// gives a dead letter a fresh set of attempts
+    pub fn redeliver(id: u64) -> ApiResult<()> {
+        EVENTS
+            .with_borrow_mut(|e| e.deliveries.update(&id, |d| d.state.requeue()))
+            .map(|_| ())
+            .ok_or_else(|| ApiError::NotFound(format!("delivery {id}")))
+    }
+
+    pub fn drop_dead_letter(id: u64) -> ApiResult<()> {
+        EVENTS
+            .with_borrow_mut(|e| e.deliveries.remove(&id))
+            .map(|_| ())
+            .ok_or_else(|| ApiError::NotFound(format!("delivery {id}")))
+    }
+}
+
+/// Standard Webhooks headers: `webhook-signature` is `v1,` and the base64 HMAC-SHA256 over
+/// `{webhook-id}.{webhook-timestamp}.{body}` keyed with the subscription's secret.
+fn signature_headers(secret: &[u8], id: &str, body: &[u8]) -> Vec<HttpHeader> {
+    let timestamp = Duration::from_nanos(clock::now_nanos())
+        .as_secs()
+        .to_string();
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(format!("{id}.{timestamp}.").as_bytes());
+    mac.update(body);
+    let signature = BASE64_STANDARD.encode(mac.finalize().into_bytes());
+
+    [
+        ("webhook-id", id.to_string()),
+        ("webhook-timestamp", timestamp),
+        ("webhook-signature", format!("v1,{signature}")),
+    ]
+    .into_iter()
+    .map(|(name, value)| HttpHeader {
+        name: name.to_string(),
+        value,
+    })
+    .collect()
+}
+
+async fn deliver(delivery: EventDelivery) {
+    let subscription = EVENTS.with_borrow_mut(|e| {
+        let subscription = e.subscriptions.get(&delivery.subscription);
+
+        if subscription.is_some() {
+            e.deliveries.update(&delivery.id, |d| d.state.attempt());
+        } else {
+            e.deliveries.remove(&delivery.id);
+        }
+
+        subscription
+    });
+
+    let Some(subscription) = subscription else {
+        return;
+    };
+
+    let id = format!("evt_{}", delivery.id);
+    let headers = signature_headers(&subscription.secret, &id, &delivery.body);
+
+    let delivered =
+        pickup::post_webhook(&subscription.url, id, headers, delivery.body.clone()).await;
+
+    EVENTS.with_borrow_mut(|e| match delivered {
+        Ok(()) => {
+            e.deliveries.remove(&delivery.id);
+        }
+        Err(err) => {
+            e.deliveries.update(&delivery.id, |d| {
+                d.state.failed(err.to_string(), clock::now_nanos())
+            });
+        }
+    });
+}
+
+/// sends the deliveries that are due, called from the job worker's round
+pub async fn deliver_due() {
+    let now = clock::now_nanos();
+    let due = EVENTS.with_borrow(|e| {
+        e.deliveries
+            .values()
+            .filter(|d| d.state.due(now))
+            .take(DELIVERIES_PER_ROUND)
+            .collect::<Vec<_>>()
+    });
+
+    for delivery in due {
+        deliver(delivery).await;
+    }
+}
@@ -0,0 +1,387 @@
+use std::{cell::RefCell, time::Duration};
+
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{ApiError, ApiResult},
+    cert_manager::{CertificateManager, CertificateOwner, IssuedCertificate},
+    clock,
+    config::Config,
+    events::Events,
+    handler::types::{RenewalInfo, SuggestedWindow},
+    issuance,
+    mem::{candid_storable, Repository},
+    order::OrderManager,
+    pickup,
+    profile::IssuanceOptions,
+    revocation::RevocationRegistry,
+    token::TokenStore,
+    trace,
+};
+
+/// RFC 9773 `renewalInfo` resource, followed by the certificate's `<AKI>.<serial>` id
+pub const RENEWAL_INFO_PATH: &str = "/renewal-info/";
+/// RFC 9773 §4.3 suggests polling a few times a day
+pub const RENEWAL_INFO_RETRY_AFTER: Duration = Duration::from_secs(6 * 60 * 60);
+/// a revoked certificate is due for renewal right away, in a window this long
+const REVOKED_WINDOW: Duration = Duration::from_secs(60 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+thread_local! {
+    static EXPIRY: RefCell<ExpiryMonitor> = RefCell::new(ExpiryMonitor::init());
+}
+
+/// body POSTed to an imported certificate's `notify_url`
+#[derive(Serialize, Debug)]
+struct ExpiryNotification<'a> {
+    certificate: u64,
+    domains: &'a [String],
+    issuer: &'a str,
+    /// RFC 3339
+    not_after: String,
+}
+
+/// A certificate inside its renewal window, kept until it expires or is revoked.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ExpiringCertificate {
+    pub serial: u64,
+    pub domains: Vec<String>,
+    pub owner: CertificateOwner,
+    pub not_after: u64,
+    /// when the check found it inside the window
+    pub marked_at: u64,
+    /// the order opened to renew it for an owner with auto-renew
+    pub renewal_order: Option<u64>,
+    /// why no renewal order could be opened, tried again on the next check
+    pub renewal_error: Option<String>,
+}
+
+candid_storable!(ExpiringCertificate);
+
+/// What a certificate of a consumer with auto-renew was requested with, ordered again as is.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RenewalRequest {
+    pub csr_der: Vec<u8>,
+    pub profile: Option<String>,
+    pub notify_url: Option<String>,
+    /// renewed under the tenant that signed the certificate
+    pub tenant: Option<String>,
+}
+
+candid_storable!(RenewalRequest);
+
+/// memory markers for the expiring certificates, the consumers with auto-renew and the requests
+/// their certificates are renewed with
+pub struct ExpiringIndex;
+pub struct AutoRenewConsumers;
+pub struct RenewalRequests;
+
+/// The certificates due for renewal, and what it takes to renew them for consumers that opted in.
+pub struct ExpiryMonitor {
+    expiring: Repository<u64, ExpiringCertificate>,
+    auto_renew: Repository<Principal, ()>,
+    requests: Repository<u64, RenewalRequest>,
+}
+
+impl ExpiryMonitor {
+    fn init() -> Self {
+        Self {
+            expiring: Repository::init::<ExpiringIndex>(),
+            auto_renew: Repository::init::<AutoRenewConsumers>(),
+            requests: Repository::init::<RenewalRequests>(),
+        }
+    }
+
+    /// every certificate the check found inside its renewal window, soonest to expire first
+    pub fn expiring() -> Vec<ExpiringCertificate> {
+        let mut expiring = EXPIRY.with_borrow(|e| e.expiring.values().collect::<Vec<_>>());
+        expiring.sort_by_key(|c| (c.not_after, c.serial));
+
+        expiring
+    }
+
+    pub fn auto_renew(consumer: &Principal) -> bool {
+        EXPIRY.with_borrow(|e| e.auto_renew.contains(consumer))
+    }
+
+    /// certificates issued to `consumer` from now on are ordered again once they are due
+    pub fn set_auto_renew(consumer: Principal, enabled: bool) -> ApiResult<()> {
+        if consumer == Principal::anonymous() {
+            return Err(ApiError::Unauthorized);
+        }
+
+        EXPIRY.with_borrow_mut(|e| {
+            if enabled {
+                e.auto_renew.insert(consumer, ());
+            } else {
+                e.auto_renew.remove(&consumer);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// keeps the request of a certificate issued to a consumer with auto-renew
+    pub fn remember(cert: &IssuedCertificate, request: RenewalRequest) {
+        let CertificateOwner::Canister(consumer) = &cert.owner else {
+            return;
+        };
+
+        if Self::auto_renew(consumer) {
+            EXPIRY.with_borrow_mut(|e| e.requests.insert(cert.serial, request));
+        }
+    }
+
+    /// drops what is no longer due for renewal, expired or revoked certificates
+    fn prune(now: u64) {
+        EXPIRY.with_borrow_mut(|e| {
+            let gone = e
+                .expiring
+                .values()
+                .filter(|c| c.not_after <= now || RevocationRegistry::get(c.serial).is_some())
+                .map(|c| c.serial)
+                .collect::<Vec<_>>();
+
+            for serial in gone {
+                e.expiring.remove(&serial);
+                e.requests.remove(&serial);
+            }
+        })
+    }
+
+    /// whether `cert` was marked just now
+    fn mark(cert: &IssuedCertificate, now: u64) -> bool {
+        EXPIRY.with_borrow_mut(|e| {
+            if e.expiring.contains(&cert.serial) {
+                return false;
+            }
+
+            e.expiring.insert(
+                cert.serial,
+                ExpiringCertificate {
+                    serial: cert.serial,
+                    domains: cert.domains.clone(),
+                    owner: cert.owner.clone(),
+                    not_after: cert.not_after,
+                    marked_at: now,
+                    renewal_order: None,
+                    renewal_error: None,
+                },
+            );
+
+            true
+        })
+    }
+
+    /// Opens an order for every marked certificate of a consumer with auto-renew, with the request
+    /// it was issued for. The order goes through validation and billing like any other.
+    fn renew() {
+        let due = Self::expiring()
+            .into_iter()
+            .filter(|c| c.renewal_order.is_none())
+            // renewed by the consumer itself in the meantime
+            .filter(|c| OrderManager::replacement(c.serial).is_none())
+            .filter_map(|c| {
+                let CertificateOwner::Canister(consumer) = &c.owner else {
+                    return None;
+                };
+                let consumer = *consumer;
+                let request = EXPIRY.with_borrow(|e| e.requests.get(&c.serial))?;
+
+                Self::auto_renew(&consumer).then_some((consumer, c, request))
+            })
+            .collect::<Vec<_>>();
+
+        for (consumer, cert, request) in due {
+            // the renewal is linked to its predecessor and spared the rate limit
+            let options = IssuanceOptions {
+                profile: request.profile,
+                not_before: None,
+                not_after: None,
+                replaces: CertificateManager::get(cert.serial).and_then(|c| cert_id(&c)),
+                tenant: request.tenant,
+            };
+
+            let opened = issuance::submit_order(
+                consumer,
+                cert.domains,
+                request.csr_der,
+                request.notify_url,
+                options,
+            );
+
+            EXPIRY.with_borrow_mut(|e| {
+                e.expiring.update(&cert.serial, |c| match &opened {
+                    Ok(order) => {
+                        c.renewal_order = Some(order.id);
+                        c.renewal_error = None;
+                    }
+                    Err(err) => c.renewal_error = Some(format!("{err:?}")),
+                })
+            });
+        }
+    }
+}
+
+/// certificates expiring within `days`, soonest first
+pub fn expiring_within(days: u32) -> Vec<IssuedCertificate> {
+    let window = Duration::from_secs(days as u64 * 24 * 60 * 60);
+
+    CertificateManager::expiring(clock::now_nanos() + window.as_nanos() as u64)
+}
+
+/// Start and end of the window `cert` should be renewed in, in nanoseconds. It opens the renewal
+/// threshold before expiry, or at two thirds of the lifetime of shorter lived certificates, and
+/// closes halfway to expiry.
+pub fn renewal_window(cert: &IssuedCertificate) -> (u64, u64) {
+    let lifetime = cert.not_after.saturating_sub(cert.not_before);
+    let lead = (Config::renewal_threshold().as_nanos() as u64).min(lifetime / 3);
+
+    (cert.not_after - lead, cert.not_after - lead / 2)
+}
+
+/// RFC 9773 §4.1 certificate id, the base64url `keyIdentifier` of the authority key identifier
+/// and the base64url serial number joined by a dot
+fn parse_cert_id(cert_id: &str) -> Option<(Vec<u8>, u64)> {
+    let (key_id, serial) = cert_id.split_once('.')?;
+    let key_id = BASE64_URL_SAFE_NO_PAD.decode(key_id).ok()?;
+    let serial = BASE64_URL_SAFE_NO_PAD.decode(serial).ok()?;
+
+    // DER integer content, positive serials with the top bit set start with a zero byte
+    let start = serial.iter().position(|b| *b != 0).unwrap_or(serial.len());
+    let serial = &serial[start..];
+
+    if serial.len() > 8 {
+        return None;
+    }
+
+    let mut bytes = [0u8; 8];
+    bytes[8 - serial.len()..].copy_from_slice(serial);
+
+    Some((key_id, u64::from_be_bytes(bytes)))
+}
+
+/// RFC 9773 §4.1 certificate id of `cert`, `None` for certificates without an authority key
+/// identifier
+pub fn cert_id(cert: &IssuedCertificate) -> Option<String> {
+    let key_id = cert.authority_key_id().ok()??;
+
+    // DER integer content, a leading zero byte keeps serials with the top bit set positive
+    let bytes = cert.serial.to_be_bytes();
+    let start = bytes
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(bytes.len() - 1);
+    let mut serial = bytes[start..].to_vec();
+
+    if serial[0] & 0x80 != 0 {
+        serial.insert(0, 0);
+    }
+
+    Some(format!(
+        "{}.{}",
+        BASE64_URL_SAFE_NO_PAD.encode(key_id),
+        BASE64_URL_SAFE_NO_PAD.encode(serial)
+    ))
+}
+
+/// the certificate this CA issued that `cert_id` names, imported ones have no id here
+pub fn certificate(cert_id: &str) -> Option<IssuedCertificate> {
+    let (key_id, serial) = parse_cert_id(cert_id)?;
+    let cert = CertificateManager::get(serial).filter(|c| c.imported.is_none())?;
+
+    (cert.authority_key_id().ok()?? == key_id).then_some(cert)
+}
+
+/// The suggested renewal window of the certificate `cert_id` names, `None` for certificates this
+/// CA did not issue. Revoked certificates are due right away.
+pub fn renewal_info(cert_id: &str) -> Option<RenewalInfo> {
+    let cert = certificate(cert_id)?;
+
+    let (start, end) = match RevocationRegistry::get(cert.serial) {
+        Some(revocation) => (
+            revocation.revoked_at,
+            revocation.revoked_at + REVOKED_WINDOW.as_nanos() as u64,
+        ),
+        None => renewal_window(&cert),
+    };
+
+    Some(RenewalInfo {
+        suggested_window: SuggestedWindow {
+            start: clock::rfc3339(start),
+            end: clock::rfc3339(end),
+        },
+    })
+}
+
+async fn notify(cert: IssuedCertificate) -> anyhow::Result<()> {
+    let Some(imported) = &cert.imported else {
+        return anyhow::Ok(());
+    };
+    let Some(notify_url) = &imported.notify_url else {
+        return anyhow::Ok(());
+    };
+
+    let body = serde_json::to_vec(&ExpiryNotification {
+        certificate: cert.serial,
+        domains: &cert.domains,
+        issuer: &imported.issuer,
+        not_after: clock::rfc3339(cert.not_after),
+    })?;
+
+    pickup::post_webhook(
+        notify_url,
+        format!("certificate-{}-expiring", cert.serial),
+        Vec::new(),
+        body,
+    )
+    .await?;
+
+    CertificateManager::mark_expiry_notified(cert.serial);
+
+    anyhow::Ok(())
+}
+
+/// Marks the certificates that entered their renewal window and opens the auto-renew orders.
+/// Subscribers hear about issued certificates once they are marked. Imported certificates are
+/// announced to their `notify_url` once each, a failed webhook is retried on the next run.
+/// Expired challenge tokens are forgotten on the way.
+async fn check() {
+    let now = clock::now_nanos();
+    ExpiryMonitor::prune(now);
+    Events::prune_announced(now);
+    TokenStore::prune(now);
+
+    let due = CertificateManager::expiring(now + Config::renewal_threshold().as_nanos() as u64)
+        .into_iter()
+        .filter(|cert| renewal_window(cert).0 <= now)
+        .filter(|cert| RevocationRegistry::get(cert.serial).is_none())
+        .collect::<Vec<_>>();
+
+    for cert in &due {
+        if ExpiryMonitor::mark(cert, now) && cert.imported.is_none() {
+            Events::expiring(cert);
+        }
+    }
+
+    ExpiryMonitor::renew();
+
+    let unannounced = due
+        .into_iter()
+        .filter(|cert| matches!(&cert.imported, Some(i) if !i.expiry_notified));
+
+    for cert in unannounced {
+        let serial = cert.serial;
+
+        if let Err(e) = notify(cert).await {
+            trace::log(&format!("expiry warning for certificate {serial}: {e}"));
+        }
+    }
+}
+
+/// has to be called again after every upgrade
+pub fn start_timer() {
+    ic_cdk_timers::set_timer_interval(CHECK_INTERVAL, || ic_cdk::spawn(check()));
+}
@@ -0,0 +1,108 @@
+use anyhow::anyhow;
+use ic_http_certification::StatusCode;
+
+use super::{
+    types::{Account, AccountUpdateRequest, AcmeServerError, GeneralRequest, NewAccountRequest},
+    GenericError, HandleOutcome,
+};
+use crate::{
+    account::{AccountManager, ACCOUNT_PATH, NEW_ACCOUNT},
+    maintenance::Maintenance,
+};
+
+handler! {
+    /// RFC 8555 §7.3, signed with the `jwk` of the new account. A key that is already registered
+    /// gets its account back with `200`, unless it was reported as compromised since. With
+    /// `onlyReturnExisting` an unknown key gets `accountDoesNotExist` instead of a new account.
+    pub struct NewAccount(POST NEW_ACCOUNT, directory = "newAccount");
+
+    fn handle(req: GeneralRequest) -> R<HandleOutcome<Account>> {
+        let header = req.jwk_header()?;
+
+        let Some(key) = &header.jwk else {
+            return Err(GenericError::bad_request(anyhow!(
+                "new accounts must be signed with `jwk`"
+            )));
+        };
+
+        if !header.url.ends_with(NEW_ACCOUNT) {
+            return Err(GenericError::bad_request(anyhow!(
+                "`url` must be the newAccount URL"
+            )));
+        }
+
+        req.verify(&header, key)?;
+        AccountManager::check_key(key)?;
+
+        let payload = req.payload::<NewAccountRequest>()?;
+
+        let (account, status_code) = match AccountManager::find_by_key(key) {
+            Some(existing) => (existing, StatusCode::OK),
+            None if payload.only_return_existing => {
+                return Err(GenericError::bad_request(anyhow!(
+                    "no account is registered for this key"
+                ))
+                .with_kind(AcmeServerError::AccountDoesNotExist));
+            }
+            None => {
+                // existing accounts can still be looked up while new ones are refused
+                Maintenance::admit()?;
+
+                (
+                    AccountManager::create(
+                        key,
+                        payload.contact.as_deref().unwrap_or_default(),
+                        payload.terms_of_service_agreed,
+                    )?,
+                    StatusCode::CREATED,
+                )
+            }
+        };
+
+        let url = AccountManager::url(&account.id);
+
+        Ok(HandleOutcome {
+            data: account.to_acme(&url),
+            status_code,
+            headers: vec![("Location".to_string(), url)],
+        })
+    }
+}
+
+handler! {
+    /// RFC 8555 §7.3.2, POST to an account URL. It must be signed with `kid` set to the URL it is
+    /// sent to, so an account only ever updates itself. An empty payload fetches the account.
+    pub struct UpdateAccount(POST ACCOUNT_PATH);
+
+    fn handle(req: GeneralRequest) -> R<HandleOutcome<Account>> {
+        let header = req.jwk_header()?;
+
+        let Some(kid) = header.kid.as_deref() else {
+            return Err(GenericError::bad_request(anyhow!(
+                "account requests must be signed with `kid`"
+            )));
+        };
+
+        if kid != header.url {
+            return Err(GenericError::forbidden(anyhow!(
+                "`kid` must be the account URL the request is sent to"
+            )));
+        }
+
+        let (account, key) = AccountManager::resolve_kid(kid).map_err(GenericError::forbidden)?;
+        req.verify(&header, &key)?;
+
+        let account = match req.payload.is_empty() {
+            true => account,
+            false => {
+                AccountManager::apply_update(&account.id, &req.payload::<AccountUpdateRequest>()?)?
+            }
+        };
+
+        Ok(HandleOutcome {
+            data: account.to_acme(kid),
+            status_code: StatusCode::OK,
+            headers: Vec::new(),
+        })
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::anyhow;
+use ic_http_certification::StatusCode;
+
+use super::{
+    types::{
+        AcmeServerError, Authorization, Challenge, EmptyRequest, GeneralRequest, Order,
+        StoredAccount,
+    },
+    GenericError, HandleOutcome, R,
+};
+use crate::{
+    account::AccountManager,
+    authz::{self, AuthorizationStore, ValidationStatus, AUTHZ_PATH, CHALLENGE_PATH},
+    capability,
+    cert_manager::{CertificateManager, CertificateOwner},
+    config::Config,
+    media,
+    order::{OrderManager, CERTIFICATE_PATH, ORDER_PATH},
+    router,
+    tenant::{self, TenantRegistry},
+};
+
+/// RFC 8555 §6.3, a POST-as-GET is signed with `kid` over an empty payload. Returns the account
+/// that signed it and what follows `prefix` in the capability URL it names.
+fn authenticate(req: &GeneralRequest, prefix: &str) -> R<(StoredAccount, String)> {
+    let signed = signer(req, prefix)?;
+
+    if !req.payload.is_empty() {
+        return Err(GenericError::bad_request(anyhow!(
+            "POST-as-GET requests carry an empty payload"
+        )));
+    }
+
+    Ok(signed)
+}
+
+/// [`authenticate`] for a POST to a capability URL whatever its payload. An account that has not
+/// agreed to the current terms of service is refused.
+pub(super) fn signer(req: &GeneralRequest, prefix: &str) -> R<(StoredAccount, String)> {
+    let header = req.jwk_header()?;
+
+    let Some(kid) = header.kid.as_deref() else {
+        return Err(GenericError::bad_request(anyhow!(
+            "POST-as-GET requests must be signed with `kid`"
+        )));
+    };
+
+    // the resource lives outside any tenant, the account is looked up under the one its URL names
+    let tenant = kid
+        .strip_prefix(&Config::base_url())
+        .and_then(|path| TenantRegistry::resolve(router::path(path)))
+        .map(|t| t.id);
+    let (account, key) = tenant::scoped(tenant, || AccountManager::resolve_kid(kid))
+        .map_err(GenericError::forbidden)?;
+    req.verify(&header, &key)?;
+    AccountManager::check_terms(&account)?;
+
+    let resource = header
+        .url
+        .strip_prefix(&Config::base_url())
+        .map(router::path)
+        .and_then(capability::verify)
+        .and_then(|path| path.strip_prefix(prefix))
+        .ok_or_else(|| GenericError::not_found(anyhow!("no such resource: {}", header.url)))?;
+
+    Ok((account, resource.to_string()))
+}
+
+/// resources of other owners are as unknown as missing ones
+pub(super) fn owned_by(owner: &CertificateOwner, account: &StoredAccount) -> bool {
+    *owner == CertificateOwner::Account(account.id.clone())
+}
+
+handler! {
+    /// RFC 8555 §7.1.3, POST-as-GET of an order owned by the signing account
+    pub struct FetchOrder(POST ORDER_PATH);
+
+    fn handle(req: GeneralRequest) -> R<HandleOutcome<Order>> {
+        let (account, resource) = authenticate(&req, ORDER_PATH)?;
+
+        let order = resource
+            .parse()
+            .ok()
+            .and_then(OrderManager::get)
+            .filter(|o| owned_by(&o.owner, &account))
+            .ok_or_else(|| {
+                GenericError::not_found(anyhow!("no such order"))
+                    .with_kind(AcmeServerError::OrderNotFound)
+            })?;
+
+        let mut headers = vec![("Location".to_string(), order.url())];
+
+        if let Some(secs) = order.retry_after_secs() {
+            headers.push(("Retry-After".to_string(), secs.to_string()));
+        }
+
+        Ok(HandleOutcome {
+            data: order.to_acme(),
+            status_code: StatusCode::OK,
+            headers,
+        })
+    }
+}
+
+handler! {
+    /// RFC 8555 §7.5, POST-as-GET of an authorization of an order owned by the signing account
+    pub struct FetchAuthorization(POST AUTHZ_PATH);
+
+    fn handle(req: GeneralRequest) -> R<HandleOutcome<Authorization>> {
+        let (account, resource) = authenticate(&req, AUTHZ_PATH)?;
+
+        let state = authz::authz_ref(&resource)
+            .filter(|(order, _)| {
+                OrderManager::get(*order).is_some_and(|o| owned_by(&o.owner, &account))
+            })
+            .and_then(|(order, index)| AuthorizationStore::get(order, index))
+            .ok_or_else(|| GenericError::not_found(anyhow!("no such authorization")))?;
+
+        let headers = state
+            .retry_after_secs()
+            .map(|secs| ("Retry-After".to_string(), secs.to_string()))
+            .into_iter()
+            .collect();
+
+        Ok(HandleOutcome {
+            data: state.to_acme(),
+            status_code: StatusCode::OK,
+            headers,
+        })
+    }
+}
+
+handler! {
+    /// RFC 8555 §7.5.1, a POST of `{}` signed by the account that owns the order asks for the
+    /// challenge to be validated, runs a scheduled retry right away. A POST-as-GET only fetches it.
+    /// Only the first challenge an account responds to is attempted for its authorization, one that
+    /// isn't offered for the identifier, like http-01 for a wildcard, is refused.
+    pub struct RespondChallenge(POST CHALLENGE_PATH, sheddable = true);
+
+    fn handle(req: GeneralRequest) -> R<HandleOutcome<Challenge>> {
+        let (account, resource) = signer(&req, CHALLENGE_PATH)?;
+
+        let (order, index, kind) = authz::challenge_ref(&resource)
+            .filter(|(order, _, _)| {
+                OrderManager::get(*order).is_some_and(|o| owned_by(&o.owner, &account))
+            })
+            .ok_or_else(|| GenericError::not_found(anyhow!("no such challenge")))?;
+
+        let state = AuthorizationStore::get(order, index)
+            .ok_or_else(|| GenericError::not_found(anyhow!("no such challenge")))?;
+        state.check_offered(kind).map_err(GenericError::bad_request)?;
+
+        let state = match req.payload.is_empty() {
+            true => state,
+            false => {
+                req.payload::<EmptyRequest>()?;
+
+                match state.status {
+                    ValidationStatus::Pending => AuthorizationStore::respond(order, index, kind),
+                    _ => AuthorizationStore::trigger(order, index),
+                }
+                .map_err(GenericError::not_found)?
+            }
+        };
+
+        let mut headers = vec![("Link".to_string(), format!("<{}>;rel=\"up\"", state.url()))];
+        headers.extend(
+            state
+                .retry_after_secs()
+                .map(|secs| ("Retry-After".to_string(), secs.to_string())),
+        );
+
+        Ok(HandleOutcome {
+            data: state.to_challenge(kind),
+            status_code: StatusCode::OK,
+            headers,
+        })
+    }
+}
+
+handler! {
+    /// RFC 8555 §7.4.2, POST-as-GET download of a PEM chain of a certificate owned by the signing
+    /// account, or of its DER leaf alone when `Accept` asks for it. Every other chain of its set
+    /// is linked as `alternate`.
+    pub struct FetchCertificate(POST CERTIFICATE_PATH);
+
+    fn handle(req: GeneralRequest) -> R<HandleOutcome<(&'static str, Vec<u8>)>> {
+        let (account, resource) = authenticate(&req, CERTIFICATE_PATH)?;
+
+        let (cert, index) = router::chain_ref(&resource)
+            .and_then(|(serial, index)| Some((CertificateManager::get(serial)?, index)))
+            .filter(|(cert, _)| cert.imported.is_none() && owned_by(&cert.owner, &account))
+            .ok_or_else(|| {
+                GenericError::not_found(anyhow!("no such certificate"))
+                    .with_kind(AcmeServerError::CertificateNotFound)
+            })?;
+
+        let media_type = media::negotiate(
+            media::accept().as_deref(),
+            &[media::PEM_CHAIN, media::PKIX_CERT],
+        )
+        .ok_or_else(|| {
+            GenericError::not_acceptable(anyhow!(
+                "available as {} or {}",
+                media::PEM_CHAIN,
+                media::PKIX_CERT
+            ))
+        })?;
+
+        let chains = CertificateManager::chain_set(&cert).map_err(GenericError::internal)?;
+
+        let pem_chain = chains.get(index).cloned().ok_or_else(|| {
+            GenericError::not_found(anyhow!("no such chain"))
+                .with_kind(AcmeServerError::CertificateNotFound)
+        })?;
+
+        let body = match media_type == media::PKIX_CERT {
+            true => router::leaf_der(&pem_chain).map_err(GenericError::internal)?,
+            false => pem_chain.into_bytes(),
+        };
+
+        let mut headers = vec![("Vary".to_string(), "Accept".to_string())];
+        headers.extend((0..chains.len()).filter(|i| *i != index).map(|alternate| {
+            (
+                "Link".to_string(),
+                format!("<{}>;rel=\"alternate\"", router::chain_url(cert.serial, alternate)),
+            )
+        }));
+
+        Ok(HandleOutcome {
+            data: (media_type, body),
+            status_code: StatusCode::OK,
+            headers,
+        })
+    }
+
+    fn encode(data: &(&'static str, Vec<u8>)) -> Result<(&'static str, Vec<u8>)> {
+        Ok(data.clone())
+    }
+}
@@ -4,7 +4,88 @@ use ic_http_certification::{
     HttpUpdateResponse, StatusCode,
 };
 
-mod types;
+use crate::{
+    account::AccountManager,
+    clock,
+    config::Config,
+    debug_capture::{CaptureKind, DebugCapture},
+    load_shed::LoadShedder,
+    media,
+    nonce::NoncePool,
+    router,
+    source::{self, SourceLimiter},
+    tenant::{self, TenantRegistry},
+    trace,
+};
+use types::{AcmeServerError, GeneralRequest};
+
+/// Declares an ACME endpoint below a tenant's base path, e.g.
+/// `pub struct NewAccount(POST NEW_ACCOUNT, directory = "newAccount");` followed by its `handle`.
+/// `handle` gets the parsed JWS and verifies it itself, an optional `encode` replaces the JSON
+/// body of a successful response. Endpoints that queue work add `sheddable = true`, see
+/// [`Handler::SHEDDABLE`]. The endpoint still has to be listed in the router's
+/// `acme_routes!` table to be served.
+macro_rules! handler {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident(
+            $method:ident $path:expr
+            $(, directory = $directory:literal)?
+            $(, sheddable = $sheddable:literal)?
+        );
+
+        fn handle($req:ident: $payload:ty) -> R<HandleOutcome<$response:ty>> $body:block
+
+        $(fn encode($data:ident: &$encoded:ty) -> Result<(&'static str, Vec<u8>)> $encode:block)?
+    ) => {
+        $(#[$meta])*
+        $vis struct $name;
+
+        impl<'d> $crate::handler::Handler<'d> for $name {
+            const PATH: &'static str = $path;
+            const METHOD: $crate::handler::Method = $crate::handler::Method::$method;
+            const DIRECTORY: Option<&'static str> = handler!(@directory $($directory)?);
+            const SHEDDABLE: bool = handler!(@sheddable $($sheddable)?);
+
+            type RawRequest = $crate::handler::UpdateRequest<'d>;
+            type RequestPayload = $payload;
+            type ResponsePayload = $response;
+
+            fn handle(
+                $req: $payload,
+            ) -> $crate::handler::R<$crate::handler::HandleOutcome<$response>> $body
+
+            $(
+                fn encode($data: &$encoded) -> anyhow::Result<(&'static str, Vec<u8>)> $encode
+            )?
+        }
+    };
+
+    (@directory) => {
+        None
+    };
+
+    (@directory $directory:literal) => {
+        Some($directory)
+    };
+
+    (@sheddable) => {
+        false
+    };
+
+    (@sheddable $sheddable:literal) => {
+        $sheddable
+    };
+}
+
+pub mod account;
+pub mod fetch;
+pub mod order;
+pub mod parse;
+pub mod revocation;
+pub mod search;
+pub mod subscription;
+pub mod types;
 
 pub type R<T> = std::result::Result<T, GenericError>;
 pub type UpdateResponse<'a> = HttpUpdateResponse<'a>;
@@ -12,9 +93,19 @@ pub type RegularResponse<'a> = HttpResponse<'a>;
 pub type UpdateRequest<'a> = HttpUpdateRequest<'a>;
 pub type RegularRequest<'a> = HttpRequest<'a>;
 
-#[derive(Debug, Clone)]
+/// seconds a `badNonce` asks to wait while the pool has no fresh nonce to send along
+const NONCE_RETRY_AFTER_SECS: u64 = 1;
+
+/// sent when not even the problem document of an error can be encoded
+const SERVER_INTERNAL_PROBLEM: &[u8] =
+    br#"{"type":"urn:ietf:params:acme:error:serverInternal","status":500}"#;
+
+/// spelled as on the wire
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Method {
     GET,
+    HEAD,
     POST,
 }
 
@@ -22,6 +113,7 @@ impl Method {
     pub fn as_str(&self) -> &'static str {
         match self {
             Method::GET => "GET",
+            Method::HEAD => "HEAD",
             Method::POST => "POST",
         }
     }
@@ -29,6 +121,7 @@ impl Method {
     pub fn from_str(str_: &str) -> Result<Self> {
         match str_ {
             "GET" => Ok(Self::GET),
+            "HEAD" => Ok(Self::HEAD),
             "POST" => Ok(Self::POST),
             _ => Err(anyhow!("unsupported method")),
         }
@@ -43,6 +136,8 @@ pub trait RequestMarker<'a> {
     fn req_method(&self) -> Result<Method>;
 
     fn url(&self) -> &str;
+
+    fn headers(&self) -> &[HeaderField];
 }
 
 pub trait ResponseMarker<'a> {
@@ -67,6 +162,10 @@ impl<'a> RequestMarker<'a> for UpdateRequest<'a> {
     fn url(&self) -> &str {
         self.url()
     }
+
+    fn headers(&self) -> &[HeaderField] {
+        self.headers()
+    }
 }
 
 impl<'a> ResponseMarker<'a> for UpdateResponse<'a> {
@@ -101,6 +200,10 @@ impl<'a> RequestMarker<'a> for RegularRequest<'a> {
     fn url(&self) -> &str {
         self.url()
     }
+
+    fn headers(&self) -> &[HeaderField] {
+        self.headers()
+    }
 }
 impl<'a> ResponseMarker<'a> for RegularResponse<'a> {
     fn status_code(&self) -> StatusCode {
@@ -123,59 +226,428 @@ impl<'a> ResponseMarker<'a> for RegularResponse<'a> {
 pub struct GenericError {
     err: anyhow::Error,
     code: StatusCode,
+    /// overrides the problem type otherwise derived from `code`
+    kind: Option<AcmeServerError>,
+    /// seconds, sent back as `Retry-After` when present
+    retry_after: Option<u64>,
+    /// sent back as `Link`, e.g. the terms of service a `userActionRequired` asks to agree to
+    link: Option<String>,
+    /// name of the quota a `rateLimited` problem ran into and when it resets, IC time in
+    /// nanoseconds
+    limit: Option<(String, u64)>,
+    /// the request field a `malformed` problem is about
+    field: Option<String>,
 }
 
 impl GenericError {
-    fn forbidden(err: anyhow::Error) -> Self {
+    pub fn forbidden(err: anyhow::Error) -> Self {
         Self {
             err,
             code: StatusCode::FORBIDDEN,
+            kind: None,
+            retry_after: None,
+            link: None,
+            limit: None,
+            field: None,
         }
     }
 
-    fn bad_request(err: anyhow::Error) -> Self {
+    pub fn bad_request(err: anyhow::Error) -> Self {
         Self {
             err,
             code: StatusCode::BAD_REQUEST,
+            kind: None,
+            retry_after: None,
+            link: None,
+            limit: None,
+            field: None,
+        }
+    }
+
+    /// RFC 8555 §6.5, retried right away with the fresh nonce of the response
+    pub fn bad_nonce(err: anyhow::Error) -> Self {
+        Self {
+            err,
+            code: StatusCode::BAD_REQUEST,
+            kind: Some(AcmeServerError::BadNonce),
+            retry_after: Some(0),
+            link: None,
+            limit: None,
+            field: None,
+        }
+    }
+
+    pub fn service_unavailable(err: anyhow::Error, retry_after: u64) -> Self {
+        Self {
+            err,
+            code: StatusCode::SERVICE_UNAVAILABLE,
+            kind: None,
+            retry_after: Some(retry_after),
+            link: None,
+            limit: None,
+            field: None,
+        }
+    }
+
+    pub fn internal(err: anyhow::Error) -> Self {
+        Self {
+            err,
+            code: StatusCode::INTERNAL_SERVER_ERROR,
+            kind: None,
+            retry_after: None,
+            link: None,
+            limit: None,
+            field: None,
+        }
+    }
+
+    pub fn not_found(err: anyhow::Error) -> Self {
+        Self {
+            err,
+            code: StatusCode::NOT_FOUND,
+            kind: Some(AcmeServerError::MalformedRequest),
+            retry_after: None,
+            link: None,
+            limit: None,
+            field: None,
+        }
+    }
+
+    pub fn method_not_allowed(err: anyhow::Error) -> Self {
+        Self {
+            err,
+            code: StatusCode::METHOD_NOT_ALLOWED,
+            kind: Some(AcmeServerError::MalformedRequest),
+            retry_after: None,
+            link: None,
+            limit: None,
+            field: None,
+        }
+    }
+
+    pub fn unsupported_media_type(err: anyhow::Error) -> Self {
+        Self {
+            err,
+            code: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            kind: None,
+            retry_after: None,
+            link: None,
+            limit: None,
+            field: None,
+        }
+    }
+
+    pub fn payload_too_large(err: anyhow::Error) -> Self {
+        Self {
+            err,
+            code: StatusCode::PAYLOAD_TOO_LARGE,
+            kind: Some(AcmeServerError::MalformedRequest),
+            retry_after: None,
+            link: None,
+            limit: None,
+            field: None,
         }
     }
 
-    fn default_bad_request() -> Self {
-        Self::bad_request(anyhow!("failed to deserialize incoming request"))
+    pub fn not_acceptable(err: anyhow::Error) -> Self {
+        Self {
+            err,
+            code: StatusCode::NOT_ACCEPTABLE,
+            kind: None,
+            retry_after: None,
+            link: None,
+            limit: None,
+            field: None,
+        }
+    }
+
+    pub fn too_many_requests(err: anyhow::Error, retry_after: u64) -> Self {
+        Self {
+            err,
+            code: StatusCode::TOO_MANY_REQUESTS,
+            kind: None,
+            retry_after: Some(retry_after),
+            link: None,
+            limit: None,
+            field: None,
+        }
+    }
+
+    /// RFC 8555 §6.6, `Retry-After` points at `reset_at`
+    pub fn rate_limited(err: anyhow::Error, limit: &str, reset_at: u64) -> Self {
+        let retry_after = reset_at
+            .saturating_sub(clock::now_nanos())
+            .div_ceil(1_000_000_000);
+
+        Self {
+            err,
+            code: StatusCode::TOO_MANY_REQUESTS,
+            kind: Some(AcmeServerError::RateLimited),
+            retry_after: Some(retry_after),
+            link: None,
+            limit: Some((limit.to_string(), reset_at)),
+            field: None,
+        }
+    }
+
+    pub fn with_kind(mut self, kind: AcmeServerError) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    pub fn with_field(mut self, field: String) -> Self {
+        self.field = Some(field);
+        self
+    }
+
+    pub fn with_link(mut self, url: &str, rel: &str) -> Self {
+        self.link = Some(format!("<{url}>;rel=\"{rel}\""));
+        self
+    }
+
+    fn problem_type(&self) -> &'static str {
+        if let Some(kind) = &self.kind {
+            return kind.urn();
+        }
+
+        match self.code {
+            StatusCode::FORBIDDEN => "urn:ietf:params:acme:error:unauthorized",
+            StatusCode::BAD_REQUEST => "urn:ietf:params:acme:error:malformed",
+            _ => "urn:ietf:params:acme:error:serverInternal",
+        }
+    }
+
+    pub fn to_problem(&self) -> types::Error {
+        types::Error {
+            r#type: self.problem_type().to_string(),
+            title: self.code.canonical_reason().unwrap_or_default().to_string(),
+            detail: self.err.to_string(),
+            status: self.code.as_u16(),
+            instance: trace::instance(),
+            limit: self.limit.as_ref().map(|(name, _)| name.clone()),
+            reset: self.limit.as_ref().map(|(_, at)| clock::rfc3339(*at)),
+            field: self.field.clone(),
+        }
+    }
+
+    pub fn headers(&self) -> Vec<HeaderField> {
+        let mut headers = vec![("Content-Type".to_string(), media::PROBLEM_JSON.to_string())];
+
+        if let Some(secs) = self.retry_after {
+            headers.push(("Retry-After".to_string(), secs.to_string()));
+        }
+
+        if let Some(link) = &self.link {
+            headers.push(("Link".to_string(), link.clone()));
+        }
+
+        headers
     }
 }
 
 pub struct HandleOutcome<Data> {
     data: Data,
     status_code: StatusCode,
+    /// sent next to the content type, e.g. the `Location` of a created resource
+    headers: Vec<HeaderField>,
 }
 pub trait Handler<'d> {
     const PATH: &'static str;
     const METHOD: Method;
+    /// endpoints that queue validation or signing work are rejected while the canister sheds load
+    const SHEDDABLE: bool = false;
+    /// RFC 8555 §7.1.1, the directory field advertising the endpoint
+    const DIRECTORY: Option<&'static str> = None;
 
     type RawRequest: RequestMarker<'d>;
     type RequestPayload: serde::de::DeserializeOwned;
     type ResponsePayload: serde::Serialize;
 
-    fn build_error_resp(err: GenericError) -> <Self::RawRequest as RequestMarker<'d>>::Response {
-        todo!()
+    /// RFC 8555 §6.5, errors carry a fresh nonce too so the client can retry without another
+    /// newNonce round trip
+    fn build_error_resp(
+        mut err: GenericError,
+    ) -> <Self::RawRequest as RequestMarker<'d>>::Response {
+        let nonce = NoncePool::try_take();
+
+        if nonce.is_none() && matches!(err.kind, Some(AcmeServerError::BadNonce)) {
+            err.retry_after = Some(NONCE_RETRY_AFTER_SECS);
+        }
+
+        let body = serde_json::to_vec_pretty(&err.to_problem())
+            .unwrap_or_else(|_| SERVER_INTERNAL_PROBLEM.to_vec());
+        let mut headers = err.headers();
+        headers.extend(nonce.map(|nonce| ("Replay-Nonce".to_string(), nonce)));
+
+        let resp = HttpResponseBuilder::new()
+            .with_status_code(err.code)
+            .with_headers(headers)
+            .with_body(body)
+            .with_upgrade(false)
+            .build();
+
+        <Self::RawRequest as RequestMarker<'d>>::Response::from_base(resp)
     }
 
-    fn validate_raw_request(req: &Self::RawRequest) -> R<Self::RequestPayload> {
-        let raw = req.req_method().map_err(GenericError::bad_request)?;
+    /// Whether the endpoint serves `method` on `resource`, a path below a tenant's base path. A
+    /// `PATH` ending in `/` is a collection, its members follow it.
+    fn serves(method: &Method, resource: &str) -> bool {
+        if *method != Self::METHOD {
+            return false;
+        }
 
-        // TODO  verify jwk
+        match Self::PATH.ends_with('/') {
+            true => resource.len() > Self::PATH.len() && resource.starts_with(Self::PATH),
+            false => resource == Self::PATH,
+        }
+    }
 
-        serde_json::from_slice::<Self::RequestPayload>(req.raw_body())
-            .map_err(|_| anyhow!("unexpected payload encopuntered"))
-            .map_err(GenericError::bad_request)
+    fn admit() -> R<()> {
+        if Self::SHEDDABLE {
+            return LoadShedder::admit();
+        }
+
+        Ok(())
     }
 
+    /// RFC 8555 §6.4, a request is only processed at the `url` it was signed for, so it can't be
+    /// replayed against another resource. Only the path is compared, the canister is reachable
+    /// under more than one domain.
+    fn check_url(req: &Self::RawRequest) -> R<()> {
+        if !matches!(req.req_method(), Ok(Method::POST)) {
+            return Ok(());
+        }
+
+        // bodies that are not a JWS are rejected by the payload validation instead
+        let Ok(jws) = serde_json::from_slice::<GeneralRequest>(req.raw_body()) else {
+            return Ok(());
+        };
+        let header = jws.jwk_header()?;
+
+        let signed = header
+            .url
+            .split_once("://")
+            .and_then(|(_, rest)| rest.find('/').map(|i| &rest[i..]))
+            .unwrap_or_default();
+
+        if router::path(signed) != router::path(req.url()) {
+            return Err(GenericError::forbidden(anyhow!(
+                "the request was signed for {}, not the URL it was sent to",
+                header.url
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// RFC 8555 §6.5, the nonce of a signed POST must have been handed out here and not be used
+    /// yet. It is only used up once the signature verifies, see [`GeneralRequest::verify`], so a
+    /// forged request can't burn the nonce of a client.
+    fn check_nonce(req: &Self::RawRequest) -> R<()> {
+        if !matches!(req.req_method(), Ok(Method::POST)) {
+            return Ok(());
+        }
+
+        // bodies that are not a JWS are rejected by the payload validation instead
+        let Ok(jws) = serde_json::from_slice::<GeneralRequest>(req.raw_body()) else {
+            return Ok(());
+        };
+        let header = jws.jwk_header()?;
+
+        if !NoncePool::is_outstanding(&header.nonce) {
+            return Err(GenericError::bad_nonce(anyhow!(
+                "the nonce was not issued by this server or was already used"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// RFC 8555 §6.2, every POST carries a flattened JWS as `application/jose+json`
+    fn check_content_type(req: &Self::RawRequest) -> R<()> {
+        if !matches!(req.req_method(), Ok(Method::POST)) {
+            return Ok(());
+        }
+
+        let content_type = media::header(req.headers(), "Content-Type");
+
+        if !media::is(content_type, media::JOSE_JSON) {
+            return Err(GenericError::unsupported_media_type(anyhow!(
+                "requests must be sent as {}, not {}",
+                media::JOSE_JSON,
+                content_type.unwrap_or("without a Content-Type")
+            ))
+            .with_kind(AcmeServerError::MalformedRequest));
+        }
+
+        Ok(())
+    }
+
+    /// largest body the endpoint parses, the ingress limit unless it needs less
+    fn max_body_bytes() -> u64 {
+        Config::max_request_bytes()
+    }
+
+    fn validate_raw_request(req: &Self::RawRequest) -> R<Self::RequestPayload> {
+        req.req_method().map_err(GenericError::bad_request)?;
+
+        parse::check_size(req.raw_body(), Self::max_body_bytes())?;
+        parse::json::<Self::RequestPayload>("body", req.raw_body())
+    }
+
+    /// the client address forwarded by the boundary node scopes the request and is recorded on the
+    /// account that signed it, the key that signed it is throttled next to the other limits.
+    /// Accounts are looked up under the tenant the request was sent to, its `Accept` is kept for
+    /// the endpoints that negotiate their format.
     fn accept(req: Self::RawRequest) -> <Self::RawRequest as RequestMarker<'d>>::Response {
-        match Self::validate_raw_request(&req) {
+        let ip = source::client_ip(req.headers());
+        let tenant = TenantRegistry::resolve(router::path(req.url())).map(|t| t.id);
+        let accept = media::header(req.headers(), "Accept").map(str::to_string);
+
+        tenant::scoped(tenant, || {
+            media::scoped(accept, || {
+                source::scoped(ip.clone(), || Self::accept_from(req, ip.as_deref()))
+            })
+        })
+    }
+
+    fn accept_from(
+        req: Self::RawRequest,
+        ip: Option<&str>,
+    ) -> <Self::RawRequest as RequestMarker<'d>>::Response {
+        let captured = DebugCapture::capture_request(req.raw_body());
+
+        let admitted = SourceLimiter::admit(req.raw_body())
+            .and_then(|_| Self::admit())
+            .and_then(|_| Self::check_content_type(&req))
+            .and_then(|_| Self::check_url(&req))
+            .and_then(|_| Self::check_nonce(&req))
+            .and_then(|_| Self::validate_raw_request(&req));
+
+        let resp = match admitted {
             Ok(arg) => Self::collapse_resp(Self::handle(arg)),
             Err(e) => Self::build_error_resp(e),
+        };
+
+        // after handling, so a newAccount request is recorded on the account it created. Only a
+        // request whose signature verifies is recorded, see `AccountManager::from_jws`.
+        if let Some(ip) = ip {
+            AccountManager::seen(req.raw_body(), ip);
         }
+
+        if let Some(account_id) = captured {
+            DebugCapture::record(
+                &account_id,
+                CaptureKind::Response,
+                format!(
+                    "{} {}",
+                    resp.status_code(),
+                    String::from_utf8_lossy(resp.body())
+                ),
+            );
+        }
+
+        resp
     }
 
     fn collapse_resp(
@@ -187,14 +659,34 @@ pub trait Handler<'d> {
         }
     }
 
+    /// content type and body of a successful response, JSON unless the endpoint serves another
+    /// format
+    fn encode(data: &Self::ResponsePayload) -> Result<(&'static str, Vec<u8>)> {
+        Ok((media::JSON, serde_json::to_vec_pretty(data)?))
+    }
+
+    /// A response that can't be encoded becomes a `serverInternal` problem rather than a trap.
+    /// Whatever the handler changed stays changed, the handlers are safe to retry. Carries a fresh
+    /// nonce, RFC 8555 §6.5.
     fn build_success_resp(
         data: HandleOutcome<Self::ResponsePayload>,
     ) -> <Self::RawRequest as RequestMarker<'d>>::Response {
-        let body = serde_json::to_vec_pretty(&data.data).unwrap();
+        let (content_type, body) = match Self::encode(&data.data) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                return Self::build_error_resp(GenericError::internal(
+                    e.context("failed to encode the response"),
+                ))
+            }
+        };
+
+        let mut headers = vec![("Content-Type".to_string(), content_type.to_string())];
+        headers.extend(data.headers);
+        headers.extend(NoncePool::try_take().map(|nonce| ("Replay-Nonce".to_string(), nonce)));
 
-        // TODO: HEADERS
         let resp = HttpResponseBuilder::new()
             .with_status_code(data.status_code)
+            .with_headers(headers)
             .with_body(body)
             .with_upgrade(false)
             .build();
@@ -203,6 +695,4 @@ pub trait Handler<'d> {
     }
 
     fn handle(req: Self::RequestPayload) -> R<HandleOutcome<Self::ResponsePayload>>;
-
-    fn skip_jwk_verification() -> bool;
 }
@@ -0,0 +1,235 @@
+use anyhow::anyhow;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use ic_http_certification::StatusCode;
+
+use super::{
+    fetch,
+    types::{AcmeServerError, FinalizeRequest, GeneralRequest, NewOrderRequest, Order},
+    GenericError, HandleOutcome,
+};
+use crate::{
+    account::AccountManager,
+    api::ApiError,
+    authz::AuthorizationStore,
+    cert_manager::CertificateOwner,
+    clock,
+    idempotency::OrderRequestIndex,
+    issuance,
+    issuance_lock::{Claim, IssuanceLock},
+    jobs::{IssuanceJob, JobQueue},
+    maintenance::Maintenance,
+    order::{OrderManager, OrderStatus, FINALIZE_PATH, NEW_ORDER},
+    profile,
+    quota::AccountQuotas,
+};
+
+/// `e` as a problem document, an invalid request is reported as `kind`
+pub(super) fn rejected(e: ApiError, kind: AcmeServerError) -> GenericError {
+    match e {
+        ApiError::InvalidArgument(message) => {
+            GenericError::bad_request(anyhow!(message)).with_kind(kind)
+        }
+        ApiError::Unavailable {
+            message,
+            retry_after_secs,
+        } => GenericError::service_unavailable(anyhow!(message), retry_after_secs)
+            .with_kind(AcmeServerError::ServiceUnavailable),
+        ApiError::NotFound(message) => GenericError::not_found(anyhow!(message)),
+        ApiError::Unauthorized => GenericError::forbidden(anyhow!("unauthorized")),
+        ApiError::Internal(message) => GenericError::internal(anyhow!(message)),
+    }
+}
+
+handler! {
+    /// RFC 8555 §7.4, an order of the signing account for DNS names. Each name gets an
+    /// authorization whose challenges the account responds to, the order is issued under the
+    /// profile it asked for once finalized.
+    pub struct NewOrder(POST NEW_ORDER, directory = "newOrder", sheddable = true);
+
+    fn handle(req: GeneralRequest) -> R<HandleOutcome<Order>> {
+        let header = req.jwk_header()?;
+
+        if !header.url.ends_with(NEW_ORDER) {
+            return Err(GenericError::bad_request(anyhow!(
+                "`url` must be the newOrder URL"
+            )));
+        }
+
+        let Some(kid) = header.kid.as_deref() else {
+            return Err(GenericError::bad_request(anyhow!(
+                "orders must be signed with `kid`"
+            )));
+        };
+
+        let (account, key) = AccountManager::resolve_kid(kid).map_err(GenericError::forbidden)?;
+        req.verify(&header, &key)?;
+        AccountManager::check_terms(&account)?;
+
+        let payload = req.payload::<NewOrderRequest>()?;
+
+        if let Some(identifier) = payload.identifiers.iter().find(|i| i.r#type != "dns") {
+            return Err(GenericError::bad_request(anyhow!(
+                "identifiers of type `{}` are not supported",
+                identifier.r#type
+            ))
+            .with_kind(AcmeServerError::UnsupportedIdentifier));
+        }
+
+        let options = payload.issuance_options()?;
+        // the window is checked against the profile now, the order would fail once finalized
+        let issuance = profile::resolve(&options, clock::now_nanos())
+            .map_err(|e| GenericError::bad_request(e).with_field("notAfter".to_string()))?;
+
+        let domains = payload
+            .identifiers
+            .iter()
+            .map(|i| i.value.clone())
+            .collect::<Vec<_>>();
+        let domains = issuance::check_names(&domains, issuance.tenant.as_ref())
+            .map_err(|e| rejected(e, AcmeServerError::ValidationError))?;
+        AccountQuotas::check_order(&account, &domains)?;
+
+        Maintenance::admit()?;
+
+        let owner = CertificateOwner::Account(account.id.clone());
+        let request = issuance::order_request(&owner, &options, &issuance, &domains)
+            .map_err(|e| rejected(e, AcmeServerError::MalformedRequest))?;
+
+        // a retried request gets the order its first attempt opened
+        if let Some(order) = OrderRequestIndex::pending(&request) {
+            return Ok(HandleOutcome {
+                data: order.to_acme(),
+                status_code: StatusCode::OK,
+                headers: vec![("Location".to_string(), order.url())],
+            });
+        }
+
+        let replaces = options
+            .replaces
+            .as_deref()
+            .map(|cert_id| issuance::check_replaces(&owner, cert_id, &domains))
+            .transpose()
+            .map_err(|e| {
+                rejected(e, AcmeServerError::MalformedRequest).with_field("replaces".to_string())
+            })?;
+
+        let order = OrderManager::create(owner, domains.clone(), OrderStatus::Pending, None);
+        let order = OrderManager::update(order.id, |o| {
+            o.profile = Some(issuance.profile.name.clone());
+            o.options = Some(options);
+        })
+        .map_err(|e| rejected(e, AcmeServerError::MalformedRequest))?;
+        let order = match replaces {
+            Some(serial) => OrderManager::link_replacement(order.id, serial)
+                .map_err(|e| rejected(e, AcmeServerError::MalformedRequest))?,
+            None => order,
+        };
+
+        OrderRequestIndex::record(request, &order);
+        AuthorizationStore::open(order.id, &domains, order.expires_at)
+            .map_err(GenericError::internal)?;
+
+        Ok(HandleOutcome {
+            data: order.to_acme(),
+            status_code: StatusCode::CREATED,
+            headers: vec![("Location".to_string(), order.url())],
+        })
+    }
+}
+
+handler! {
+    /// RFC 8555 §7.4, a `ready` order of the signing account is finalized with a CSR for exactly
+    /// its names. The order turns `processing` and is signed by the job worker.
+    pub struct FinalizeOrder(POST FINALIZE_PATH, sheddable = true);
+
+    fn handle(req: GeneralRequest) -> R<HandleOutcome<Order>> {
+        let (account, resource) = fetch::signer(&req, FINALIZE_PATH)?;
+
+        let order = resource
+            .parse()
+            .ok()
+            .and_then(OrderManager::get)
+            .filter(|o| fetch::owned_by(&o.owner, &account))
+            .ok_or_else(|| {
+                GenericError::not_found(anyhow!("no such order"))
+                    .with_kind(AcmeServerError::OrderNotFound)
+            })?;
+
+        if order.status != OrderStatus::Ready {
+            return Err(GenericError::forbidden(anyhow!(
+                "the order is {}, only a ready order can be finalized",
+                order.status.as_str()
+            ))
+            .with_kind(AcmeServerError::OrderNotReady));
+        }
+
+        let payload = req.payload::<FinalizeRequest>()?;
+        let csr_der = BASE64_URL_SAFE_NO_PAD
+            .decode(&payload.csr)
+            .map_err(|_| GenericError::bad_request(anyhow!("`csr` is not base64url")))?;
+        let csr = issuance::check_csr(&csr_der, &order.domains)
+            .map_err(|e| rejected(e, AcmeServerError::BadCsr))?;
+        AccountQuotas::check_finalize(&account, &order.domains)?;
+
+        let options = order.options.clone().unwrap_or_default();
+        let key = issuance::order_lock(&order.owner, &csr, &options, &order.domains)
+            .map_err(|e| rejected(e, AcmeServerError::MalformedRequest))?;
+        let reserved_at = issuance::reserve_rate_limit(&order.domains, order.replaces)
+            .map_err(|e| {
+                let reset_at = e.reset_at;
+                GenericError::rate_limited(anyhow!(e.to_string()), "certificatesPerWeek", reset_at)
+            })?;
+
+        let processing = |id| {
+            OrderManager::update(id, |o| {
+                o.status = OrderStatus::Processing;
+                o.estimated_ready_at = Some(issuance::estimated_ready_at());
+            })
+        };
+
+        // a certificate for the same request that was just issued or is being signed is shared
+        let order = match IssuanceLock::claim(&key) {
+            Claim::Won => {
+                let order = processing(order.id).map_err(|e| {
+                    issuance::release_rate_limit(&order.domains, reserved_at);
+                    issuance::release(&key, &Err(e.clone()));
+                    rejected(e, AcmeServerError::MalformedRequest)
+                })?;
+
+                JobQueue::push(IssuanceJob::finalized(
+                    order.id,
+                    account.id,
+                    order.domains.clone(),
+                    csr_der,
+                    options,
+                    key,
+                    reserved_at,
+                ));
+
+                order
+            }
+            Claim::Issued(serial) => {
+                issuance::release_rate_limit(&order.domains, reserved_at);
+                issuance::complete(order.id, &issuance::duplicate(serial))
+                    .map_err(|e| rejected(e, AcmeServerError::MalformedRequest))?
+            }
+            Claim::InProgress => {
+                issuance::release_rate_limit(&order.domains, reserved_at);
+                IssuanceLock::follow(&key, order.id);
+                processing(order.id).map_err(|e| rejected(e, AcmeServerError::MalformedRequest))?
+            }
+        };
+
+        let mut headers = vec![("Location".to_string(), order.url())];
+
+        if let Some(secs) = order.retry_after_secs() {
+            headers.push(("Retry-After".to_string(), secs.to_string()));
+        }
+
+        Ok(HandleOutcome {
+            data: order.to_acme(),
+            status_code: StatusCode::OK,
+            headers,
+        })
+    }
+}
@@ -0,0 +1,63 @@
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+
+use super::{types::AcmeServerError, GenericError, R};
+use crate::config::Config;
+
+/// `path` of a field below `part`, as a client would spell it, e.g. `payload.identifiers[0].type`
+fn locate(part: &str, path: &str) -> String {
+    match path {
+        "" | "." | "?" => part.to_string(),
+        p if p.starts_with('[') => format!("{part}{p}"),
+        p => format!("{part}.{p}"),
+    }
+}
+
+fn malformed(err: anyhow::Error, field: String) -> GenericError {
+    GenericError::bad_request(err)
+        .with_kind(AcmeServerError::MalformedRequest)
+        .with_field(field)
+}
+
+/// bodies larger than `max` are refused before they are parsed
+pub fn check_size(body: &[u8], max: u64) -> R<()> {
+    if body.len() as u64 > max {
+        return Err(GenericError::payload_too_large(anyhow!(
+            "the body has {} bytes, at most {max} are accepted",
+            body.len()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Reads `bytes` as a `T`. A field that fails is named in the `malformed` problem, relative to
+/// `part` of the request, e.g. `protected` or `payload`. With `ServerConfig.strict_payloads`,
+/// fields `T` doesn't know are refused too instead of ignored.
+pub fn json<T: DeserializeOwned>(part: &str, bytes: &[u8]) -> R<T> {
+    let mut unknown = Vec::new();
+    let mut de = serde_json::Deserializer::from_slice(bytes);
+
+    let value = serde_path_to_error::deserialize::<_, T>(serde_ignored::Deserializer::new(
+        &mut de,
+        &mut |path: serde_ignored::Path| unknown.push(path.to_string()),
+    ))
+    .map_err(|e| {
+        let field = locate(part, &e.path().to_string());
+
+        malformed(anyhow!("`{field}`: {}", e.inner()), field)
+    })?;
+
+    de.end()
+        .map_err(|e| malformed(anyhow!("`{part}`: {e}"), part.to_string()))?;
+
+    if Config::strict_payloads() {
+        if let Some(path) = unknown.first() {
+            let field = locate(part, path);
+
+            return Err(malformed(anyhow!("`{field}` is not a known field"), field));
+        }
+    }
+
+    Ok(value)
+}
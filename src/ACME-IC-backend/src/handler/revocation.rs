@@ -0,0 +1,127 @@
+use anyhow::anyhow;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use ic_http_certification::StatusCode;
+use x509_cert::{der::Decode, Certificate};
+
+use super::{
+    types::{AcmeServerError, EmptyResponse, GeneralRequest, RevocationRequest},
+    GenericError, HandleOutcome,
+};
+use crate::{
+    account::AccountManager,
+    audit::AuditActor,
+    blocklist,
+    cert_manager::{CertificateManager, CertificateOwner},
+    crl, ocsp,
+    revocation::{Revocation, RevocationRegistry, KEY_COMPROMISE, REVOKE_CERT},
+};
+
+handler! {
+    /// RFC 8555 §7.6, signed with `kid` by the account that owns the certificate or with `jwk` set
+    /// to the certificate's own key. `keyCompromise` proven by the key itself revokes every
+    /// certificate for that key and blocklists it.
+    pub struct RevokeCert(POST REVOKE_CERT, directory = "revokeCert");
+
+    fn handle(req: GeneralRequest) -> R<HandleOutcome<EmptyResponse>> {
+        let header = req.jwk_header()?;
+
+        if !header.url.ends_with(REVOKE_CERT) {
+            return Err(GenericError::bad_request(anyhow!(
+                "`url` must be the revokeCert URL"
+            )));
+        }
+
+        let payload = req.payload::<RevocationRequest>()?;
+        let der = BASE64_URL_SAFE_NO_PAD
+            .decode(&payload.certificate)
+            .map_err(|_| GenericError::bad_request(anyhow!("`certificate` is not base64url")))?;
+        let leaf = Certificate::from_der(&der).map_err(|_| {
+            GenericError::bad_request(anyhow!("`certificate` is not a DER certificate"))
+        })?;
+
+        // the serial alone could name a certificate of another issuer, the whole leaf has to match
+        let cert = ocsp::serial_to_u64(&leaf.tbs_certificate.serial_number)
+            .and_then(CertificateManager::get)
+            .filter(|c| c.imported.is_none() && c.leaf_der().is_ok_and(|d| d == der))
+            .ok_or_else(|| {
+                GenericError::bad_request(anyhow!("the certificate was not issued here"))
+                    .with_kind(AcmeServerError::CertificateNotFound)
+            })?;
+
+        let reason = payload.reason.unwrap_or(0);
+
+        if !Revocation::valid_reason(reason) {
+            return Err(GenericError::bad_request(anyhow!(
+                "{reason} is not a valid revocation reason"
+            ))
+            .with_kind(AcmeServerError::BadRevocationReason));
+        }
+
+        if RevocationRegistry::get(cert.serial).is_some() {
+            return Err(
+                GenericError::bad_request(anyhow!("the certificate is already revoked"))
+                    .with_kind(AcmeServerError::AlreadyRevoked),
+            );
+        }
+
+        let revoked = match (&header.jwk, &header.kid) {
+            (Some(key), None) => {
+                req.verify(&header, key)?;
+
+                let spki = key.spki_der().map_err(GenericError::bad_request)?;
+
+                if cert.leaf_spki().ok().as_ref() != Some(&spki) {
+                    return Err(GenericError::forbidden(anyhow!(
+                        "the request must be signed with the key of the certificate"
+                    )));
+                }
+
+                let actor = AuditActor::CertificateKey {
+                    spki_hash: blocklist::spki_hash(&spki),
+                };
+
+                match reason {
+                    KEY_COMPROMISE => {
+                        RevocationRegistry::revoke_key(cert.serial, actor).map(|_| ())
+                    }
+                    _ => RevocationRegistry::revoke_as(cert.serial, reason, actor).map(|_| ()),
+                }
+            }
+            (None, Some(kid)) => {
+                let (account, key) =
+                    AccountManager::resolve_kid(kid).map_err(GenericError::forbidden)?;
+                req.verify(&header, &key)?;
+                AccountManager::check_terms(&account)?;
+
+                if cert.owner != CertificateOwner::Account(account.id.clone()) {
+                    return Err(GenericError::forbidden(anyhow!(
+                        "the account does not own the certificate"
+                    )));
+                }
+
+                let actor = AuditActor::Account {
+                    id: account.id,
+                    thumbprint: key.thumbprint(),
+                };
+
+                RevocationRegistry::revoke_as(cert.serial, reason, actor).map(|_| ())
+            }
+            _ => {
+                return Err(GenericError::bad_request(anyhow!(
+                    "exactly one of `jwk` or `kid` must be present"
+                )))
+            }
+        };
+
+        revoked.map_err(|e| GenericError::bad_request(anyhow!("{e:?}")))?;
+
+        // publish the revocation without waiting for the next scheduled re-signing
+        crl::refresh_in_background();
+
+        Ok(HandleOutcome {
+            data: EmptyResponse {},
+            status_code: StatusCode::OK,
+            headers: Vec::new(),
+        })
+    }
+}
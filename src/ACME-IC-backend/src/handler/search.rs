@@ -0,0 +1,78 @@
+use anyhow::anyhow;
+use ic_http_certification::StatusCode;
+
+use super::{
+    types::{CertificateSearch, CertificateSummary, GeneralRequest, Identifier},
+    GenericError, HandleOutcome,
+};
+use crate::{
+    account::AccountManager,
+    cert_manager::{CertificateManager, CertificateOwner, CERTIFICATE_SEARCH},
+    clock, router,
+};
+
+handler! {
+    /// POST-as-GET of `certificates?domain=<name>`, the certificates of the signing account that
+    /// name the domain, with `subdomains=true` also those of any name below it
+    pub struct SearchCertificates(POST CERTIFICATE_SEARCH);
+
+    fn handle(req: GeneralRequest) -> R<HandleOutcome<CertificateSearch>> {
+        let header = req.jwk_header()?;
+        let (path, query) = header.url.split_once('?').unwrap_or((&header.url, ""));
+
+        if !path.ends_with(CERTIFICATE_SEARCH) {
+            return Err(GenericError::bad_request(anyhow!(
+                "`url` must be the certificates URL"
+            )));
+        }
+
+        let Some(kid) = header.kid.as_deref() else {
+            return Err(GenericError::bad_request(anyhow!(
+                "searches must be signed with `kid`"
+            )));
+        };
+
+        let (account, key) = AccountManager::resolve_kid(kid).map_err(GenericError::forbidden)?;
+        req.verify(&header, &key)?;
+        AccountManager::check_terms(&account)?;
+
+        if !req.payload.is_empty() {
+            return Err(GenericError::bad_request(anyhow!(
+                "POST-as-GET requests carry an empty payload"
+            )));
+        }
+
+        let domain = router::query_param(query, "domain")
+            .filter(|d| !d.is_empty())
+            .ok_or_else(|| {
+                GenericError::bad_request(anyhow!("`domain` must be given")).with_field("domain".to_string())
+            })?;
+        let subdomains = router::query_param(query, "subdomains") == Some("true");
+
+        let owner = CertificateOwner::Account(account.id);
+        let now = clock::now_nanos();
+        let certificates = CertificateManager::search(domain, subdomains, Some(&owner))
+            .into_iter()
+            .map(|cert| CertificateSummary {
+                certificate: router::chain_url(cert.serial, 0),
+                identifiers: cert
+                    .domains
+                    .iter()
+                    .map(|domain| Identifier {
+                        r#type: "dns".to_string(),
+                        value: domain.clone(),
+                    })
+                    .collect(),
+                status: cert.status(now).as_str().to_string(),
+                not_before: clock::rfc3339(cert.not_before),
+                not_after: clock::rfc3339(cert.not_after),
+            })
+            .collect();
+
+        Ok(HandleOutcome {
+            data: CertificateSearch { certificates },
+            status_code: StatusCode::OK,
+            headers: Vec::new(),
+        })
+    }
+}
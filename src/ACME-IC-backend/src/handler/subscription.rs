@@ -0,0 +1,102 @@
+use anyhow::anyhow;
+use ic_http_certification::StatusCode;
+
+use super::{
+    order::rejected,
+    types::{
+        AcmeServerError, GeneralRequest, SubscriptionList, SubscriptionRequest, SubscriptionSummary,
+    },
+    GenericError, HandleOutcome, R,
+};
+use crate::{
+    account::AccountManager,
+    cert_manager::CertificateOwner,
+    clock,
+    events::{EventKind, Events, SUBSCRIPTIONS_PATH},
+};
+
+handler! {
+    /// The event subscriptions of the signing account. A POST-as-GET lists them, a POST of
+    /// `url`, `secret` and `events` adds one and a POST of `unsubscribe` drops one.
+    pub struct ManageSubscriptions(POST SUBSCRIPTIONS_PATH);
+
+    fn handle(req: GeneralRequest) -> R<HandleOutcome<SubscriptionList>> {
+        let header = req.jwk_header()?;
+
+        if !header.url.ends_with(SUBSCRIPTIONS_PATH) {
+            return Err(GenericError::bad_request(anyhow!(
+                "`url` must be the subscriptions URL"
+            )));
+        }
+
+        let Some(kid) = header.kid.as_deref() else {
+            return Err(GenericError::bad_request(anyhow!(
+                "subscriptions must be managed with `kid`"
+            )));
+        };
+
+        let (account, key) = AccountManager::resolve_kid(kid).map_err(GenericError::forbidden)?;
+        req.verify(&header, &key)?;
+        AccountManager::check_terms(&account)?;
+
+        let owner = CertificateOwner::Account(account.id);
+        let status_code = match req.payload.is_empty() {
+            true => StatusCode::OK,
+            false => match req.payload::<SubscriptionRequest>()? {
+                SubscriptionRequest {
+                    url: None,
+                    secret: None,
+                    events: None,
+                    unsubscribe: Some(id),
+                } => {
+                    Events::cancel(&owner, id)
+                        .map_err(|e| rejected(e, AcmeServerError::MalformedRequest))?;
+
+                    StatusCode::OK
+                }
+                SubscriptionRequest {
+                    url: Some(url),
+                    secret: Some(secret),
+                    events: Some(events),
+                    unsubscribe: None,
+                } => {
+                    let events = events
+                        .iter()
+                        .map(|kind| {
+                            EventKind::parse(kind).ok_or_else(|| {
+                                GenericError::bad_request(anyhow!("unknown event `{kind}`"))
+                                    .with_field("events".to_string())
+                            })
+                        })
+                        .collect::<R<Vec<_>>>()?;
+
+                    Events::register(owner.clone(), url, secret, events)
+                        .map_err(|e| rejected(e, AcmeServerError::MalformedRequest))?;
+
+                    StatusCode::CREATED
+                }
+                _ => {
+                    return Err(GenericError::bad_request(anyhow!(
+                        "give either `url`, `secret` and `events`, or `unsubscribe`"
+                    )))
+                }
+            },
+        };
+
+        let subscriptions = Events::of(&owner)
+            .into_iter()
+            .map(|s| SubscriptionSummary {
+                id: s.id,
+                url: s.url,
+                events: s.events.iter().map(|k| k.as_str().to_string()).collect(),
+                created_at: clock::rfc3339(s.created_at),
+            })
+            .collect();
+
+        Ok(HandleOutcome {
+            data: SubscriptionList { subscriptions },
+            status_code,
+            headers: Vec::new(),
+        })
+    }
+}
@@ -1,107 +1,554 @@
+use std::collections::BTreeMap;
+
 use anyhow::anyhow;
-use base64::Engine;
-use k256::{ecdsa::VerifyingKey, pkcs8::DecodePublicKey, PublicKey};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use candid::CandidType;
+use k256::{ecdsa::VerifyingKey, elliptic_curve::sec1::ToEncodedPoint, PublicKey};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
 use signature::Verifier;
+use x509_cert::{
+    der::{
+        asn1::{BitString, ObjectIdentifier},
+        Encode,
+    },
+    spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned},
+};
+
+use rsa::traits::PublicKeyParts;
 
-use super::{GenericError, R};
+use super::{parse, GenericError, R};
+use crate::{
+    billing::BillingConfig, clock, config::Config, metrics, nonce::NoncePool,
+    profile::IssuanceOptions, tenant, thumbprint,
+};
+
+/// RFC 8410 §3, `id-Ed25519`
+const ED25519: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
 
 // Basic types shared across multiple endpoints
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Identifier {
     pub r#type: String, // Using r# prefix for the 'type' keyword
     pub value: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Error {
     pub r#type: String,
     pub title: String,
     pub detail: String,
     pub status: u16,
     pub instance: Option<String>,
+    /// the quota a `rateLimited` problem ran into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<String>,
+    /// when that quota frees up again, RFC 3339
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reset: Option<String>,
+    /// the request field a `malformed` problem is about, e.g. `payload.identifiers[0].type`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
 }
 
 // Directory endpoint types
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct DirectoryMeta {
     pub terms_of_service: Option<String>,
     pub website: Option<String>,
     pub caa_identities: Option<Vec<String>>,
     pub external_account_required: Option<bool>,
+    /// non-standard, advertised by CAs that cap the identifiers of a single order
+    pub max_identifiers: Option<u32>,
+    /// profile name -> human readable description, draft-aaron-acme-profiles
+    pub profiles: Option<BTreeMap<String, String>>,
+    /// non-standard, what a certificate costs when billing is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pricing: Option<DirectoryPricing>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectoryPricing {
+    /// ICRC-2 ledger canister the fee is paid on
+    pub ledger: String,
+    /// per certificate, in the ledger's base units
+    pub fee: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Directory {
-    pub new_nonce: String,
-    pub new_account: String,
-    pub new_order: String,
-    pub revoke_cert: String,
-    pub key_change: String,
+    /// RFC 8555 §7.1.1 field -> URL of every endpoint served, e.g. `newAccount`
+    #[serde(flatten)]
+    pub resources: BTreeMap<String, String>,
     pub meta: Option<DirectoryMeta>,
 }
 
-// Account endpoint types
+/// RFC 9773 §4.2, when the client should renew a certificate
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RenewalInfo {
+    pub suggested_window: SuggestedWindow,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SuggestedWindow {
+    /// RFC 3339
+    pub start: String,
+    /// RFC 3339
+    pub end: String,
+}
+
+/// certificates of the signing account that name a domain, newest first
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CertificateSearch {
+    pub certificates: Vec<CertificateSummary>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CertificateSummary {
+    /// URL the chain is downloaded from, as the order named it
+    pub certificate: String,
+    pub identifiers: Vec<Identifier>,
+    /// `valid`, `expired` or `revoked`
+    pub status: String,
+    /// RFC 3339
+    pub not_before: String,
+    /// RFC 3339
+    pub not_after: String,
+}
+
+/// POSTed to the subscriptions URL, either a new subscription or the id of one to drop
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionRequest {
+    pub url: Option<String>,
+    pub secret: Option<String>,
+    /// `type`s of the events, e.g. `certificate.issued`
+    pub events: Option<Vec<String>>,
+    pub unsubscribe: Option<u64>,
+}
+
+/// event subscriptions of the signing account
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionList {
+    pub subscriptions: Vec<SubscriptionSummary>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionSummary {
+    pub id: u64,
+    pub url: String,
+    pub events: Vec<String>,
+    /// RFC 3339
+    pub created_at: String,
+}
+
+// Account endpoint types
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct JwkPublicKey {
     pub kty: String,
-    pub crv: String,
-    pub x: String,
-    pub y: Option<String>, // Only used for ES256K
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>, // EC and OKP keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>, // EC and OKP keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>, // Only used for EC keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>, // Only used for RS256
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>, // Only used for RS256
+}
+
+impl JwkPublicKey {
+    pub fn member<'a>(member: &'a Option<String>, name: &str) -> anyhow::Result<&'a str> {
+        member
+            .as_deref()
+            .ok_or_else(|| anyhow!("missing required JWK member `{name}`"))
+    }
+
+    /// the uncompressed SEC1 point of an EC JWK whose coordinates are `len` bytes each
+    fn ec_point(&self, len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut point = vec![0x04];
+
+        for (member, name) in [(&self.x, "x"), (&self.y, "y")] {
+            let raw = BASE64_URL_SAFE_NO_PAD
+                .decode(JwkPublicKey::member(member, name)?)
+                .map_err(|_| anyhow!("failed to decode JWK member `{name}`"))?;
+
+            if raw.len() != len {
+                return Err(anyhow!(
+                    "invalid {} coordinate length",
+                    self.crv.as_deref().unwrap_or("EC")
+                ));
+            }
+
+            point.extend(raw);
+        }
+
+        anyhow::Ok(point)
+    }
 }
 
 /// raw ECDSA(secp256k1) public key in der format
 #[derive(Debug, Clone)]
 pub struct Es256kPublicKey(pub PublicKey);
 
-impl<'de> Deserialize<'de> for Es256kPublicKey {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let raw_buf = Vec::<u8>::deserialize(deserializer)?;
+impl Es256kPublicKey {
+    pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
+        let Ok(signature) = k256::ecdsa::Signature::try_from(sig) else {
+            return false;
+        };
+
+        let verifying_key = VerifyingKey::from(&self.0);
+
+        verifying_key.verify(msg, &signature).is_ok()
+    }
+
+    pub fn spki_der(&self) -> anyhow::Result<Vec<u8>> {
+        let der = k256::pkcs8::EncodePublicKey::to_public_key_der(&self.0)
+            .map_err(|_| anyhow!("failed to encode public key"))?;
+
+        anyhow::Ok(der.into_vec())
+    }
+
+    pub fn to_jwk(&self) -> JwkPublicKey {
+        let point = self.0.to_encoded_point(false);
 
-        Self::from_public_key_der(raw_buf.as_slice())
-            .map_err(|e| serde::de::Error::custom(e.to_string()))
+        JwkPublicKey {
+            kty: "EC".to_string(),
+            crv: Some("secp256k1".to_string()),
+            // uncompressed points always carry both coordinates
+            x: point.x().map(|x| BASE64_URL_SAFE_NO_PAD.encode(x)),
+            y: point.y().map(|y| BASE64_URL_SAFE_NO_PAD.encode(y)),
+            n: None,
+            e: None,
+        }
     }
 }
-impl Es256kPublicKey {
-    pub fn from_public_key_der(slice: &[u8]) -> anyhow::Result<Self> {
-        let p = PublicKey::from_public_key_der(slice)
+
+/// ECDSA public key on one of the NIST curves, P-256 with ES256 and P-384 with ES384
+#[derive(Debug, Clone)]
+pub enum NistPublicKey {
+    P256(p256::PublicKey),
+    P384(p384::PublicKey),
+}
+
+impl NistPublicKey {
+    pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
+        match self {
+            Self::P256(key) => p256::ecdsa::Signature::try_from(sig).is_ok_and(|signature| {
+                p256::ecdsa::VerifyingKey::from(key)
+                    .verify(msg, &signature)
+                    .is_ok()
+            }),
+            Self::P384(key) => p384::ecdsa::Signature::try_from(sig).is_ok_and(|signature| {
+                p384::ecdsa::VerifyingKey::from(key)
+                    .verify(msg, &signature)
+                    .is_ok()
+            }),
+        }
+    }
+
+    pub fn spki_der(&self) -> anyhow::Result<Vec<u8>> {
+        let der = match self {
+            Self::P256(key) => p256::pkcs8::EncodePublicKey::to_public_key_der(key),
+            Self::P384(key) => p384::pkcs8::EncodePublicKey::to_public_key_der(key),
+        }
+        .map_err(|_| anyhow!("failed to encode public key"))?;
+
+        anyhow::Ok(der.into_vec())
+    }
+
+    pub fn to_jwk(&self) -> JwkPublicKey {
+        let (crv, point) = match self {
+            Self::P256(key) => ("P-256", key.to_encoded_point(false).as_bytes().to_vec()),
+            Self::P384(key) => ("P-384", key.to_encoded_point(false).as_bytes().to_vec()),
+        };
+        // uncompressed points are the tag followed by both coordinates
+        let (x, y) = point[1..].split_at(point.len() / 2);
+
+        JwkPublicKey {
+            kty: "EC".to_string(),
+            crv: Some(crv.to_string()),
+            x: Some(BASE64_URL_SAFE_NO_PAD.encode(x)),
+            y: Some(BASE64_URL_SAFE_NO_PAD.encode(y)),
+            n: None,
+            e: None,
+        }
+    }
+}
+
+/// raw Ed25519 public key, parsed from an OKP JWK as described in RFC 8037
+#[derive(Debug, Clone)]
+pub struct Ed25519PublicKey(pub ed25519_dalek::VerifyingKey);
+
+impl Ed25519PublicKey {
+    pub fn from_jwk(jwk: &JwkPublicKey) -> anyhow::Result<Self> {
+        if jwk.kty != "OKP" || jwk.crv.as_deref() != Some("Ed25519") {
+            return Err(anyhow!("unsupported OKP key type"));
+        }
+
+        let raw = BASE64_URL_SAFE_NO_PAD
+            .decode(JwkPublicKey::member(&jwk.x, "x")?)
+            .map_err(|_| anyhow!("failed to decode public key"))?;
+
+        let raw: [u8; ed25519_dalek::PUBLIC_KEY_LENGTH] = raw
+            .try_into()
+            .map_err(|_| anyhow!("invalid Ed25519 public key length"))?;
+
+        let key = ed25519_dalek::VerifyingKey::from_bytes(&raw)
             .map_err(|_| anyhow!("failed to deseralize public key"))?;
 
-        anyhow::Ok(Self(p))
+        anyhow::Ok(Self(key))
     }
+
     pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
-        let signature =
-            k256::ecdsa::Signature::try_from(sig).expect("failed to deserialize signature");
-        
-        let verifying_key = VerifyingKey::from(&self.0);
-        
-        match verifying_key.verify(msg, &signature) {
-            Ok(_) => true,
-            Err(_) => false,
+        let Ok(signature) = ed25519_dalek::Signature::from_slice(sig) else {
+            return false;
+        };
+
+        // strict verification rejects small order keys and malleable signatures
+        self.0.verify_strict(msg, &signature).is_ok()
+    }
+
+    pub fn spki_der(&self) -> anyhow::Result<Vec<u8>> {
+        let spki = SubjectPublicKeyInfoOwned {
+            algorithm: AlgorithmIdentifierOwned {
+                oid: ED25519,
+                parameters: None,
+            },
+            subject_public_key: BitString::from_bytes(self.0.as_bytes())
+                .map_err(|_| anyhow!("failed to encode public key"))?,
+        };
+
+        spki.to_der()
+            .map_err(|_| anyhow!("failed to encode public key"))
+    }
+
+    pub fn to_jwk(&self) -> JwkPublicKey {
+        JwkPublicKey {
+            kty: "OKP".to_string(),
+            crv: Some("Ed25519".to_string()),
+            x: Some(BASE64_URL_SAFE_NO_PAD.encode(self.0.as_bytes())),
+            y: None,
+            n: None,
+            e: None,
+        }
+    }
+}
+
+/// RSA public key built from the `n`/`e` members of an RSA JWK, used with RS256 (PKCS#1 v1.5 + SHA-256)
+#[derive(Debug, Clone)]
+pub struct Rs256PublicKey(pub rsa::RsaPublicKey);
+
+impl Rs256PublicKey {
+    fn decode_uint(member: &Option<String>, name: &str) -> anyhow::Result<rsa::BigUint> {
+        let raw = BASE64_URL_SAFE_NO_PAD
+            .decode(JwkPublicKey::member(member, name)?)
+            .map_err(|_| anyhow!("failed to decode JWK member `{name}`"))?;
+
+        anyhow::Ok(rsa::BigUint::from_bytes_be(&raw))
+    }
+
+    pub fn from_jwk(jwk: &JwkPublicKey) -> anyhow::Result<Self> {
+        if jwk.kty != "RSA" {
+            return Err(anyhow!("unsupported RSA key type"));
+        }
+
+        let n = Self::decode_uint(&jwk.n, "n")?;
+        let e = Self::decode_uint(&jwk.e, "e")?;
+
+        let min_bits = Config::with(|c| c.min_rsa_key_bits) as usize;
+
+        if n.bits() < min_bits {
+            return Err(anyhow!("RSA keys must be at least {min_bits} bits"));
+        }
+
+        let key =
+            rsa::RsaPublicKey::new(n, e).map_err(|_| anyhow!("failed to deseralize public key"))?;
+
+        anyhow::Ok(Self(key))
+    }
+
+    pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
+        let Ok(signature) = rsa::pkcs1v15::Signature::try_from(sig) else {
+            return false;
+        };
+
+        let verifying_key = rsa::pkcs1v15::VerifyingKey::<Sha256>::new(self.0.clone());
+
+        verifying_key.verify(msg, &signature).is_ok()
+    }
+
+    pub fn spki_der(&self) -> anyhow::Result<Vec<u8>> {
+        let der = rsa::pkcs8::EncodePublicKey::to_public_key_der(&self.0)
+            .map_err(|_| anyhow!("failed to encode public key"))?;
+
+        anyhow::Ok(der.into_vec())
+    }
+
+    pub fn to_jwk(&self) -> JwkPublicKey {
+        JwkPublicKey {
+            kty: "RSA".to_string(),
+            crv: None,
+            x: None,
+            y: None,
+            n: Some(BASE64_URL_SAFE_NO_PAD.encode(self.0.n().to_bytes_be())),
+            e: Some(BASE64_URL_SAFE_NO_PAD.encode(self.0.e().to_bytes_be())),
         }
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub enum RawJwkPublicKey {
     ES256K(Es256kPublicKey),
-    Ed25519,
+    /// ES256 and ES384, the algorithm follows the curve
+    Nist(NistPublicKey),
+    Ed25519(Ed25519PublicKey),
+    RS256(Rs256PublicKey),
+}
+
+impl<'de> Deserialize<'de> for RawJwkPublicKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let jwk = JwkPublicKey::deserialize(deserializer)?;
+
+        Self::from_jwk(&jwk).map_err(|e| serde::de::Error::custom(e.to_string()))
+    }
+}
+
+impl RawJwkPublicKey {
+    /// the JWS `alg` value that must accompany signatures made with this key
+    pub fn alg(&self) -> &'static str {
+        match self {
+            Self::ES256K(_) => "ES256K",
+            Self::Nist(NistPublicKey::P256(_)) => "ES256",
+            Self::Nist(NistPublicKey::P384(_)) => "ES384",
+            Self::Ed25519(_) => "EdDSA",
+            Self::RS256(_) => "RS256",
+        }
+    }
+
+    pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
+        match self {
+            Self::ES256K(key) => key.verify(msg, sig),
+            Self::Nist(key) => key.verify(msg, sig),
+            Self::Ed25519(key) => key.verify(msg, sig),
+            Self::RS256(key) => key.verify(msg, sig),
+        }
+    }
+
+    pub fn to_jwk(&self) -> JwkPublicKey {
+        match self {
+            Self::ES256K(key) => key.to_jwk(),
+            Self::Nist(key) => key.to_jwk(),
+            Self::Ed25519(key) => key.to_jwk(),
+            Self::RS256(key) => key.to_jwk(),
+        }
+    }
+
+    pub fn from_jwk(jwk: &JwkPublicKey) -> anyhow::Result<Self> {
+        match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+            ("EC", Some("secp256k1")) => {
+                let key = PublicKey::from_sec1_bytes(&jwk.ec_point(32)?)
+                    .map_err(|_| anyhow!("failed to deseralize public key"))?;
+
+                anyhow::Ok(Self::ES256K(Es256kPublicKey(key)))
+            }
+            ("EC", Some("P-256")) => {
+                let key = p256::PublicKey::from_sec1_bytes(&jwk.ec_point(32)?)
+                    .map_err(|_| anyhow!("failed to deseralize public key"))?;
+
+                anyhow::Ok(Self::Nist(NistPublicKey::P256(key)))
+            }
+            ("EC", Some("P-384")) => {
+                let key = p384::PublicKey::from_sec1_bytes(&jwk.ec_point(48)?)
+                    .map_err(|_| anyhow!("failed to deseralize public key"))?;
+
+                anyhow::Ok(Self::Nist(NistPublicKey::P384(key)))
+            }
+            ("OKP", _) => anyhow::Ok(Self::Ed25519(Ed25519PublicKey::from_jwk(jwk)?)),
+            ("RSA", _) => anyhow::Ok(Self::RS256(Rs256PublicKey::from_jwk(jwk)?)),
+            _ => Err(anyhow!("unsupported key type")),
+        }
+    }
+
+    /// DER `SubjectPublicKeyInfo`, encoded as a certificate for the same key carries it
+    pub fn spki_der(&self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Self::ES256K(key) => key.spki_der(),
+            Self::Nist(key) => key.spki_der(),
+            Self::Ed25519(key) => key.spki_der(),
+            Self::RS256(key) => key.spki_der(),
+        }
+    }
+
+    /// RFC 7638 thumbprint, base64url encoded
+    pub fn thumbprint(&self) -> String {
+        thumbprint::compute(&self.to_jwk())
+            .expect("JWKs built from parsed keys always carry their required members")
+    }
 }
 
 #[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct JwkHeader {
     pub alg: String,
     pub url: String,
     pub nonce: String,
     pub kid: Option<String>,
-    // slight deviation from the RFC, we will use the raw bytes here instead for simplicity sake now
     pub jwk: Option<RawJwkPublicKey>,
 }
 
+impl JwkHeader {
+    /// JWS algorithms accepted for account keys, anything else (`none`, MACs, ...) is refused
+    pub const ALLOWED_ALGS: &'static [&'static str] =
+        &["ES256K", "ES256", "ES384", "EdDSA", "RS256"];
+
+    /// header checks from RFC 8555 §6.2 that do not depend on the endpoint
+    pub fn validate(&self) -> R<()> {
+        if !Self::ALLOWED_ALGS.contains(&self.alg.as_str()) {
+            return Err(GenericError::bad_request(anyhow!(
+                "unsupported JWS algorithm `{}`, expected one of {}",
+                self.alg,
+                Self::ALLOWED_ALGS.join(", ")
+            ))
+            .with_kind(AcmeServerError::BadSignatureAlgorithm));
+        }
+
+        if self.jwk.is_some() == self.kid.is_some() {
+            return Err(GenericError::bad_request(anyhow!(
+                "exactly one of `jwk` or `kid` must be present in the protected header"
+            )));
+        }
+
+        if let Some(jwk) = &self.jwk {
+            if jwk.alg() != self.alg {
+                return Err(GenericError::bad_request(anyhow!(
+                    "`alg` does not match the type of the embedded `jwk`"
+                ))
+                .with_kind(AcmeServerError::BadSignatureAlgorithm));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct GeneralRequest {
     pub protected: String, // Base64url-encoded header
     pub payload: String,   // Base64url-encoded payload
@@ -109,37 +556,108 @@ pub struct GeneralRequest {
 }
 
 impl GeneralRequest {
-    fn deserialize_field<T: DeserializeOwned>(slice: &[u8]) -> R<T> {
-        let raw = Self::decode_base64(slice)?;
-        serde_json::from_slice(raw.as_ref()).map_err(|_| GenericError::default_bad_request())
+    fn deserialize_field<T: DeserializeOwned>(part: &str, slice: &[u8]) -> R<T> {
+        let raw = Self::decode_base64(part, slice)?;
+        parse::json(part, &raw)
     }
 
-    fn decode_base64(slice: &[u8]) -> R<Vec<u8>> {
-        base64::prelude::BASE64_STANDARD
-            .decode(slice)
-            .map_err(|_| GenericError::default_bad_request())
+    /// JWS members are base64url encoded without padding (RFC 7515 §2)
+    fn decode_base64(part: &str, slice: &[u8]) -> R<Vec<u8>> {
+        BASE64_URL_SAFE_NO_PAD.decode(slice).map_err(|_| {
+            GenericError::bad_request(anyhow!("`{part}` is not base64url without padding"))
+                .with_kind(AcmeServerError::MalformedRequest)
+                .with_field(part.to_string())
+        })
     }
     pub fn jwk_header(&self) -> R<JwkHeader> {
-        Self::deserialize_field::<JwkHeader>(self.protected.as_bytes())
+        let header = Self::deserialize_field::<JwkHeader>("protected", self.protected.as_bytes())?;
+        header.validate()?;
+
+        Ok(header)
     }
 
     pub fn payload<T: DeserializeOwned>(&self) -> R<T> {
-        Self::deserialize_field::<T>(self.payload.as_bytes())
+        Self::deserialize_field::<T>("payload", self.payload.as_bytes())
     }
 
     pub fn raw_signature(&self) -> R<Vec<u8>> {
-        Self::decode_base64(self.signature.as_bytes())
+        Self::decode_base64("signature", self.signature.as_bytes())
+    }
+
+    /// JWS signing input, `ASCII(BASE64URL(protected) || '.' || BASE64URL(payload))`
+    pub fn signing_input(&self) -> Vec<u8> {
+        format!("{}.{}", self.protected, self.payload).into_bytes()
+    }
+
+    /// Checks the signature of the request with `key` and then uses up its nonce, RFC 8555 §6.5.
+    /// A request whose signature doesn't verify leaves the nonce to its client.
+    pub fn verify(&self, header: &JwkHeader, key: &RawJwkPublicKey) -> R<()> {
+        let verified = self._verify(header, key);
+
+        if verified.is_err() {
+            metrics::jws_failure();
+        }
+
+        verified?;
+
+        if !NoncePool::redeem(&header.nonce) {
+            return Err(GenericError::bad_nonce(anyhow!(
+                "the nonce was not issued by this server or was already used"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// [`Self::verify`] for bookkeeping outside the handlers, a failure isn't counted again
+    pub fn is_signed_by(&self, header: &JwkHeader, key: &RawJwkPublicKey) -> bool {
+        self._verify(header, key).is_ok()
+    }
+
+    fn _verify(&self, header: &JwkHeader, key: &RawJwkPublicKey) -> R<()> {
+        if header.alg != key.alg() {
+            return Err(GenericError::bad_request(anyhow!(
+                "algorithm does not match the account key"
+            ))
+            .with_kind(AcmeServerError::BadSignatureAlgorithm));
+        }
+
+        let signature = self.raw_signature()?;
+
+        if !key.verify(&self.signing_input(), &signature) {
+            return Err(GenericError::forbidden(anyhow!("invalid JWS signature")));
+        }
+
+        Ok(())
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct NewAccountRequest {
+    #[serde(default)]
     pub terms_of_service_agreed: bool,
     pub contact: Option<Vec<String>>,
     pub external_account_binding: Option<serde_json::Value>,
+    /// only look the account of the key up, never create one
+    #[serde(default)]
+    pub only_return_existing: bool,
+}
+
+/// RFC 8555 §7.3.2, POSTed to the account URL. An absent `contact` leaves it unchanged, an empty
+/// one removes every contact.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountUpdateRequest {
+    pub contact: Option<Vec<String>>,
+    /// agrees to the current terms of service, RFC 8555 §7.3.3
+    pub terms_of_service_agreed: Option<bool>,
+    /// accepts the principal that offered to pay the account's certificate fees
+    pub billing_payer: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Account {
     pub status: String,
     pub contact: Option<Vec<String>>,
@@ -151,13 +669,49 @@ pub struct Account {
 
 // Order endpoint types
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct NewOrderRequest {
     pub identifiers: Vec<Identifier>,
     pub not_before: Option<String>, // ISO 8601 timestamp
     pub not_after: Option<String>,  // ISO 8601 timestamp
+    /// draft-aaron-acme-profiles, the server default is used when absent
+    pub profile: Option<String>,
+    /// RFC 9773 §5 id of the certificate the order renews
+    pub replaces: Option<String>,
+}
+
+impl NewOrderRequest {
+    /// the requested validity window, checked against the profile once the order is finalized
+    pub fn issuance_options(&self) -> R<IssuanceOptions> {
+        let parse = |field: &Option<String>, name: &str| {
+            field
+                .as_deref()
+                .map(clock::parse_rfc3339)
+                .transpose()
+                .map_err(|e| GenericError::bad_request(anyhow!("invalid `{name}`: {e}")))
+        };
+
+        if let Some(profile) = &self.profile {
+            if Config::profile(Some(profile)).is_none() {
+                return Err(GenericError::bad_request(anyhow!(
+                    "unknown certificate profile `{profile}`"
+                ))
+                .with_kind(AcmeServerError::InvalidProfile));
+            }
+        }
+
+        Ok(IssuanceOptions {
+            profile: self.profile.clone(),
+            not_before: parse(&self.not_before, "notBefore")?,
+            not_after: parse(&self.not_after, "notAfter")?,
+            replaces: self.replaces.clone(),
+            tenant: tenant::current(),
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Order {
     pub status: String,
     pub expires: Option<String>,
@@ -165,10 +719,14 @@ pub struct Order {
     pub authorizations: Vec<String>,
     pub finalize: String,
     pub certificate: Option<String>,
+    pub profile: Option<String>,
+    /// RFC 9773 §5 id of the certificate the order renews
+    pub replaces: Option<String>,
 }
 
 // Authorization endpoint types
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Challenge {
     pub r#type: String,
     pub url: String,
@@ -179,22 +737,27 @@ pub struct Challenge {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Authorization {
     pub status: String,
     pub expires: Option<String>,
     pub identifier: Identifier,
     pub challenges: Vec<Challenge>,
+    /// RFC 8555 §7.1.4, present and `true` for a wildcard name and absent otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub wildcard: Option<bool>,
 }
 
 // Finalize order endpoint types
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct FinalizeRequest {
     pub csr: String, // Base64url-encoded CSR
 }
 
 // Revoke certificate endpoint types
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct RevocationRequest {
     pub certificate: String, // Base64url-encoded DER certificate
     pub reason: Option<u8>,  // RFC 5280 revocation reason code
@@ -208,51 +771,8 @@ pub struct KeyAuthorizationComputed {
     pub key_authorization: String,
 }
 
-// HTTP challenge helpers
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct HttpChallengePath {
-    pub domain: String,
-    pub token: String,
-    pub key_authorization: String,
-    pub file_path: String,
-    pub validation_url: String,
-}
-
-// Client configuration
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct ClientConfig {
-    pub server_url: String,
-    pub email: Option<String>,
-    pub webroot_path: Option<String>,
-    pub domains: Vec<String>,
-    pub cert_path: String,
-    pub key_path: String,
-    pub account_key_path: String,
-    pub agree_tos: bool,
-    pub verbose: bool,
-}
-
-// ACME client state
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct ClientState {
-    pub directory: Directory,
-    pub account_url: Option<String>,
-    pub current_nonce: Option<String>,
-}
-
-// Certificate information
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Certificate {
-    pub domains: Vec<String>,
-    pub not_before: String,
-    pub not_after: String,
-    pub pem: String,
-    pub der: String,
-    pub issued_at: String,
-}
-
 // Server configuration
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct ServerConfig {
     pub port: u16,
     pub hostname: String,
@@ -263,9 +783,186 @@ pub struct ServerConfig {
     pub challenge_attempts: u8,
     pub cert_validity_days: u32,
     pub rate_limit: RateLimit,
+    pub min_rsa_key_bits: u32,
+    /// issuer domains that identify this CA in CAA `issue` records
+    pub caa_identities: Vec<String>,
+    /// canister consumers have to publish a dns-01 record before `request_certificate` issues
+    pub require_dns01_for_canisters: bool,
+    /// named profiles next to the implicit `classic` one, which follows `cert_validity_days`,
+    /// `None` keeps `shortlived` and `client-auth`
+    pub profiles: Option<Vec<CertificateProfile>>,
+    /// `None` keeps the default windows
+    pub revocation: Option<RevocationWindows>,
+    /// `None` leaves Certificate Transparency off
+    pub ct: Option<CtPolicy>,
+    /// ingress update calls with a larger argument are rejected before they execute, `None`
+    /// keeps the default
+    pub max_request_bytes: Option<u64>,
+    /// HTTPS endpoint that makes the http-01 requests and tls-alpn-01 handshakes outcalls can't,
+    /// `None` leaves dns-01 as the only challenge
+    pub challenge_prober: Option<String>,
+    /// `None` keeps the default policy
+    pub challenges: Option<ChallengePolicy>,
+    /// accounts must agree to these before they can be used, `None` asks for no agreement
+    pub terms_of_service: Option<TermsOfService>,
+    /// quotas of accounts without an override, `None` keeps the defaults
+    pub account_quota: Option<AccountQuota>,
+    /// refuse ACME payloads with fields this server doesn't know instead of ignoring them, `None`
+    /// ignores them
+    pub strict_payloads: Option<bool>,
+    /// a repeated order for the same names is answered with the pending one opened this long
+    /// before, `0` opens a new order every time and `None` keeps the default
+    pub order_reuse_window_secs: Option<u64>,
+    /// fee charged per certificate, `None` issues for free
+    pub billing: Option<BillingConfig>,
+    /// certificates this close to expiring are due for renewal, `None` keeps the default
+    pub renewal_threshold_days: Option<u32>,
+    /// distinguished name of the issuing root, `None` keeps `CN=ic.encrypt.icp`
+    pub issuer_name: Option<IssuerName>,
+    /// how leaf subjects are built, `None` keeps [`SubjectPolicy::CommonName`]
+    pub subject_policy: Option<SubjectPolicy>,
+    /// answer plain GETs of orders, authorizations, challenges and certificates at their
+    /// capability URLs for clients that don't POST-as-GET, `None` refuses them
+    pub legacy_get: Option<bool>,
+    /// notBefore of new certificates lies this far before their issuance, so clients whose clocks
+    /// run ahead accept them right away, `0` issues from the current time and `None` keeps an hour
+    pub clock_skew_secs: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Distinguished name the root certificate is issued to. A root that already exists keeps its
+/// name, a changed one is picked up by the next key rotation.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IssuerName {
+    pub common_name: String,
+    pub organization: Option<String>,
+    /// ISO 3166-1 alpha-2 code, e.g. `CH`
+    pub country: Option<String>,
+}
+
+/// What goes into the subject of a leaf. Either way it is built from the validated identifiers
+/// only, whatever DN the CSR asked for is ignored.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubjectPolicy {
+    /// the first identifier that fits a CN, an empty subject when none does
+    CommonName,
+    /// an empty subject, the identifiers are only named in the critical SAN extension
+    Empty,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChallengeType {
+    Http01,
+    Dns01,
+    TlsAlpn01,
+}
+
+impl ChallengeType {
+    /// RFC 8555 §8 name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Http01 => "http-01",
+            Self::Dns01 => "dns-01",
+            Self::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+/// Challenges offered per identifier, in order of preference.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ChallengePolicy {
+    pub names: Vec<ChallengeType>,
+    /// http-01 and tls-alpn-01 only prove control of a single name, RFC 8555 §8.3 and RFC 8737 §3
+    pub wildcards: Vec<ChallengeType>,
+}
+
+impl Default for ChallengePolicy {
+    fn default() -> Self {
+        Self {
+            names: vec![
+                ChallengeType::Dns01,
+                ChallengeType::Http01,
+                ChallengeType::TlsAlpn01,
+            ],
+            wildcards: vec![ChallengeType::Dns01],
+        }
+    }
+}
+
+/// RFC 8555 §7.3.3, changing `version` makes every account agree again
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TermsOfService {
+    pub url: String,
+    pub version: String,
+}
+
+/// Certificate Transparency, RFC 6962. With `enabled`, every leaf is first logged as a
+/// precertificate and issued with the SCTs the logs returned embedded.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CtPolicy {
+    pub enabled: bool,
+    /// the only logs precertificates are submitted to, each contributes at most one SCT
+    pub logs: Vec<CtLog>,
+    /// SCTs needed by certificate lifetime, the first entry covering the lifetime applies and
+    /// longer lifetimes use the last one
+    pub requirements: Vec<SctRequirement>,
+    pub on_failure: SctFailureMode,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CtLog {
+    pub name: String,
+    /// submission prefix ending in `/`, e.g. `https://ct.example.com/2025h1/`
+    pub url: String,
+    /// merge delay the log operator committed to, logs slower than a day are not accepted
+    pub max_merge_delay_secs: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SctRequirement {
+    pub max_lifetime_days: u32,
+    pub min_scts: u32,
+}
+
+/// What happens when fewer logs than required returned an SCT.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SctFailureMode {
+    FailIssuance,
+    /// issue the certificate without embedded SCTs, the precertificate may still have been logged
+    IssueWithoutScts,
+}
+
+/// How long signed revocation information stays valid, trading freshness against signing cost.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RevocationWindows {
+    /// nextUpdate - thisUpdate of every CRL
+    pub crl_validity_secs: u64,
+    /// how often the CRL is re-signed, has to leave time to fetch a new one before the old expires
+    pub crl_refresh_interval_secs: u64,
+    /// nextUpdate - thisUpdate of every OCSP response
+    pub ocsp_validity_secs: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyPurpose {
+    ServerAuth,
+    ClientAuth,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CertificateProfile {
+    pub name: String,
+    /// advertised in the directory next to the name
+    pub description: String,
+    /// validity when the order does not ask for one, and the longest window an order may request
+    pub validity_days: u32,
+    /// CRL distribution points and the OCSP responder in issued leaves, short-lived certificates
+    /// simply expire instead
+    pub revocation_pointers: bool,
+    /// extended key usages of issued leaves
+    pub key_purposes: Vec<KeyPurpose>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct RateLimit {
     pub requests_per_minute: u32,
     pub accounts_per_hour: u32,
@@ -273,8 +970,19 @@ pub struct RateLimit {
     pub certificates_per_week: u32,
 }
 
+/// Per-account limits, on top of the global [`RateLimit`] every account shares.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccountQuota {
+    /// orders that are not `valid` or `invalid` yet
+    pub max_pending_orders: u32,
+    /// certificates per registered domain in a rolling week
+    pub certificates_per_domain_per_week: u32,
+    /// certificates that are neither expired nor revoked
+    pub max_active_certificates: u32,
+}
+
 // Server-side account management
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct StoredAccount {
     pub id: String,
     pub public_key: JwkPublicKey,
@@ -284,18 +992,12 @@ pub struct StoredAccount {
     pub initial_ip: String,
     pub last_seen_ip: String,
     pub last_seen_at: String,
-}
-
-// CSR components
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct CsrInfo {
-    pub common_name: String,
-    pub organization: Option<String>,
-    pub organization_unit: Option<String>,
-    pub country: Option<String>,
-    pub state: Option<String>,
-    pub locality: Option<String>,
-    pub domains: Vec<String>,
+    /// version of the terms of service the account agreed to last
+    pub agreed_terms: Option<String>,
+    /// set by a controller for trusted integrators, replaces the configured quota
+    pub quota: Option<AccountQuota>,
+    /// tenant the account was registered under, `None` for accounts registered outside of any
+    pub tenant: Option<String>,
 }
 
 // Implementation types (optional, for actual implementation)
@@ -313,15 +1015,50 @@ pub enum AcmeServerError {
     OrderNotFound,
     RateLimited,
     InvalidContact,
+    UnsupportedContact,
+    UserActionRequired,
     MalformedRequest,
+    InvalidProfile,
+    BadPublicKey,
+    AlreadyRevoked,
+    BadRevocationReason,
+    OrderNotReady,
+    UnsupportedIdentifier,
+    /// not registered by RFC 8555, sent with 503 while the CA is in maintenance
+    ServiceUnavailable,
 }
 
-// Additional utility types for request/response tracking
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct NonceResponse {
-    pub nonce: String,
+impl AcmeServerError {
+    /// problem document type, RFC 8555 §6.7
+    pub fn urn(&self) -> &'static str {
+        match self {
+            Self::BadNonce => "urn:ietf:params:acme:error:badNonce",
+            Self::BadCsr => "urn:ietf:params:acme:error:badCSR",
+            Self::BadSignatureAlgorithm => "urn:ietf:params:acme:error:badSignatureAlgorithm",
+            Self::AccountDoesNotExist => "urn:ietf:params:acme:error:accountDoesNotExist",
+            Self::UnauthorizedForOrder => "urn:ietf:params:acme:error:unauthorized",
+            Self::InvalidChallenge => "urn:ietf:params:acme:error:incorrectResponse",
+            Self::DatabaseError => "urn:ietf:params:acme:error:serverInternal",
+            Self::ValidationError => "urn:ietf:params:acme:error:rejectedIdentifier",
+            Self::CertificateNotFound => "urn:ietf:params:acme:error:malformed",
+            Self::OrderNotFound => "urn:ietf:params:acme:error:malformed",
+            Self::RateLimited => "urn:ietf:params:acme:error:rateLimited",
+            Self::InvalidContact => "urn:ietf:params:acme:error:invalidContact",
+            Self::UnsupportedContact => "urn:ietf:params:acme:error:unsupportedContact",
+            Self::UserActionRequired => "urn:ietf:params:acme:error:userActionRequired",
+            Self::MalformedRequest => "urn:ietf:params:acme:error:malformed",
+            Self::InvalidProfile => "urn:ietf:params:acme:error:invalidProfile",
+            Self::BadPublicKey => "urn:ietf:params:acme:error:badPublicKey",
+            Self::AlreadyRevoked => "urn:ietf:params:acme:error:alreadyRevoked",
+            Self::BadRevocationReason => "urn:ietf:params:acme:error:badRevocationReason",
+            Self::OrderNotReady => "urn:ietf:params:acme:error:orderNotReady",
+            Self::UnsupportedIdentifier => "urn:ietf:params:acme:error:unsupportedIdentifier",
+            Self::ServiceUnavailable => "urn:ietf:params:acme:error:serviceUnavailable",
+        }
+    }
 }
 
+// Additional utility types for request/response tracking
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct EmptyRequest {}
 
@@ -0,0 +1,36 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api, config::Config, crl, handler::types::RevocationWindows, load_shed::LoadShedder,
+    maintenance::Maintenance,
+};
+
+pub const HEALTH_PATH: &str = "/health";
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct HealthStatus {
+    pub api_version: String,
+    /// effective revocation windows, as currently configured
+    pub revocation: RevocationWindows,
+    pub crl_number: Option<u64>,
+    pub crl_this_update: Option<u64>,
+    pub crl_next_update: Option<u64>,
+    pub shedding: bool,
+    /// new accounts and orders are refused, see `set_maintenance`
+    pub maintenance: bool,
+}
+
+pub fn status() -> HealthStatus {
+    let crl = crl::current_window();
+
+    HealthStatus {
+        api_version: api::API_VERSION.to_string(),
+        revocation: Config::revocation(),
+        crl_number: crl.map(|(number, _, _)| number),
+        crl_this_update: crl.map(|(_, this_update, _)| this_update),
+        crl_next_update: crl.map(|(_, _, next_update)| next_update),
+        shedding: LoadShedder::is_under_pressure(),
+        maintenance: Maintenance::is_enabled(),
+    }
+}
@@ -0,0 +1,215 @@
+use std::cell::RefCell;
+
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use candid::CandidType;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cert_manager::CertificateOwner,
+    clock,
+    config::Config,
+    mem::{candid_storable, Repository},
+    order::{OrderManager, OrderStatus, StoredOrder},
+    profile::{Issuance, IssuanceOptions},
+};
+
+thread_local! {
+    static REQUESTS: RefCell<OrderRequestIndex> = RefCell::new(OrderRequestIndex::init());
+}
+
+/// memory marker for the request each indexed order was opened for
+pub struct OrderRequests;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct IndexedOrder {
+    order: u64,
+    created_at: u64,
+}
+
+candid_storable!(IndexedOrder);
+
+/// SHA-256 over who asks for which names and key, under which profile and tenant, with the
+/// requested validity and the certificate it replaces, base64url encoded. The order of the names
+/// and repeated names don't change it, the window is the one requested as the resolved one moves
+/// with the time of the request.
+pub fn request_hash(
+    owner: &CertificateOwner,
+    spki: &[u8],
+    options: &IssuanceOptions,
+    issuance: &Issuance,
+    domains: &[String],
+) -> anyhow::Result<String> {
+    let mut domains = domains.to_vec();
+    domains.sort();
+    domains.dedup();
+
+    let encoded = candid::encode_args((
+        owner,
+        &issuance.profile.name,
+        issuance.tenant_id(),
+        spki,
+        options.not_before,
+        options.not_after,
+        &options.replaces,
+        domains,
+    ))?;
+
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(encoded)))
+}
+
+/// The latest order opened for each [`request_hash`], so a retried request gets the order its
+/// first attempt opened instead of a duplicate.
+pub struct OrderRequestIndex {
+    orders: Repository<String, IndexedOrder>,
+    /// the request of each indexed order, to drop its entry once the order settles
+    requests: Repository<u64, String>,
+}
+
+impl OrderRequestIndex {
+    fn init() -> Self {
+        Self {
+            orders: Repository::init::<Self>(),
+            requests: Repository::init::<OrderRequests>(),
+        }
+    }
+
+    /// The order opened for `request` within the configured reuse window, as long as it is not
+    /// done yet. Entries that can't be reused anymore are dropped.
+    pub fn pending(request: &str) -> Option<StoredOrder> {
+        let indexed = REQUESTS.with_borrow(|r| r.orders.get(&request.to_string()))?;
+        let now = clock::now_nanos();
+
+        let reusable = OrderManager::get(indexed.order).filter(|o| {
+            now < indexed.created_at + Config::order_reuse_window().as_nanos() as u64
+                && now < o.expires_at
+                && matches!(
+                    o.status,
+                    OrderStatus::Pending | OrderStatus::Ready | OrderStatus::Processing
+                )
+        });
+
+        if reusable.is_none() {
+            REQUESTS.with_borrow_mut(|r| {
+                r.orders.remove(&request.to_string());
+                r.requests.remove(&indexed.order);
+            });
+        }
+
+        reusable
+    }
+
+    pub fn record(request: String, order: &StoredOrder) {
+        let indexed = IndexedOrder {
+            order: order.id,
+            created_at: order.created_at,
+        };
+
+        REQUESTS.with_borrow_mut(|r| {
+            r.requests.insert(order.id, request.clone());
+            r.orders.insert(request, indexed);
+        });
+    }
+
+    /// drops the entry of order `id` once it is valid or invalid, it can't be reused anymore
+    pub fn settle(id: u64) {
+        REQUESTS.with_borrow_mut(|r| {
+            let Some(request) = r.requests.remove(&id) else {
+                return;
+            };
+
+            // a later order for the same request may have taken the entry over
+            if r.orders.get(&request).is_some_and(|o| o.order == id) {
+                r.orders.remove(&request);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::*;
+    use crate::{handler::types::CertificateProfile, issuance_lock::LockKey};
+
+    fn issuance(profile: &str) -> Issuance {
+        Issuance {
+            profile: CertificateProfile {
+                name: profile.to_string(),
+                description: String::new(),
+                validity_days: 90,
+                revocation_pointers: true,
+                key_purposes: vec![],
+            },
+            not_before: 0,
+            not_after: 0,
+            tenant: None,
+        }
+    }
+
+    fn hash(owner: &CertificateOwner, options: &IssuanceOptions, domains: &[&str]) -> String {
+        let domains = domains.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+
+        request_hash(owner, b"spki", options, &issuance("classic"), &domains).unwrap()
+    }
+
+    #[test]
+    fn names_are_compared_as_a_set() {
+        let owner = CertificateOwner::Account("1".to_string());
+        let options = IssuanceOptions::default();
+
+        assert_eq!(
+            hash(&owner, &options, &["a.example.org", "b.example.org"]),
+            hash(
+                &owner,
+                &options,
+                &["b.example.org", "a.example.org", "b.example.org"]
+            )
+        );
+    }
+
+    #[test]
+    fn every_part_of_the_request_counts() {
+        let account = CertificateOwner::Account("1".to_string());
+        let options = IssuanceOptions::default();
+        let domains = ["example.org".to_string()];
+        let base = hash(&account, &options, &["example.org"]);
+
+        let canister = CertificateOwner::Canister(Principal::anonymous());
+        assert_ne!(base, hash(&canister, &options, &["example.org"]));
+
+        let windowed = IssuanceOptions {
+            not_after: Some(1),
+            ..Default::default()
+        };
+        assert_ne!(base, hash(&account, &windowed, &["example.org"]));
+
+        let other_key =
+            request_hash(&account, b"other", &options, &issuance("classic"), &domains).unwrap();
+        assert_ne!(base, other_key);
+
+        let other_profile = request_hash(
+            &account,
+            b"spki",
+            &options,
+            &issuance("shortlived"),
+            &domains,
+        )
+        .unwrap();
+        assert_ne!(base, other_profile);
+
+        assert_ne!(base, hash(&account, &options, &["www.example.org"]));
+    }
+
+    #[test]
+    fn lock_keys_of_the_same_request_match() {
+        let owner = CertificateOwner::Account("1".to_string());
+        let options = IssuanceOptions::default();
+
+        assert_eq!(
+            LockKey::new(&hash(&owner, &options, &["a.example.org", "b.example.org"])),
+            LockKey::new(&hash(&owner, &options, &["b.example.org", "a.example.org"]))
+        );
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::anyhow;
+use ic_cdk::api::call::{arg_data_raw, arg_data_raw_size};
+use ic_http_certification::HttpUpdateRequest;
+
+use crate::{
+    config::Config,
+    handler::{types::GeneralRequest, Method},
+    router::{self, UpdateBody},
+};
+
+/// controller methods that take a whole exported page, only the ingress message limit bounds them
+const BULK_METHODS: &[&str] = &["import_accounts"];
+
+/// Decides whether an ingress update is worth executing. Runs in `canister_inspect_message`, so a
+/// rejected call is never charged the execution of the method itself.
+///
+/// Calls from other canisters skip inspection altogether, they pay for their own messages.
+pub fn check(method: &str) -> anyhow::Result<()> {
+    // a page of 1000 accounts doesn't fit the limit meant for ACME requests
+    if BULK_METHODS.contains(&method) && ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return anyhow::Ok(());
+    }
+
+    let max = Config::max_request_bytes();
+    let size = arg_data_raw_size();
+
+    // checked before the argument is even copied
+    if size as u64 > max {
+        return Err(anyhow!(
+            "request of {size} bytes exceeds the limit of {max}"
+        ));
+    }
+
+    if method != "http_request_update" {
+        return anyhow::Ok(());
+    }
+
+    let req = candid::decode_one::<HttpUpdateRequest>(&arg_data_raw())
+        .map_err(|_| anyhow!("malformed HTTP request"))?;
+    let http_method = Method::from_str(req.method().as_str())?;
+
+    let body = router::update_route(&http_method, router::path(req.url()))
+        .ok_or_else(|| anyhow!("no update route for {} {}", http_method.as_str(), req.url()))?;
+
+    match body {
+        UpdateBody::Der if req.body().is_empty() => Err(anyhow!("empty request body")),
+        UpdateBody::Der => anyhow::Ok(()),
+        UpdateBody::Empty if !req.body().is_empty() => Err(anyhow!("unexpected request body")),
+        UpdateBody::Empty => anyhow::Ok(()),
+        UpdateBody::Jws => {
+            serde_json::from_slice::<GeneralRequest>(req.body())
+                .map_err(|_| anyhow!("request body is not a flattened JWS"))?;
+
+            anyhow::Ok(())
+        }
+    }
+}
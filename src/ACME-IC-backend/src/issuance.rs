@@ -0,0 +1,523 @@
+use std::time::Duration;
+
+use candid::Principal;
+use x509_cert::der::Encode;
+
+use crate::{
+    api::{ApiError, ApiResult},
+    audit::{AuditAction, AuditActor, AuditLog, AuditOutcome},
+    authz::AuthorizationStore,
+    billing::{Billing, Payer},
+    blocklist::KeyBlocklist,
+    caa,
+    cert_manager::{CertificateManager, CertificateOwner, IssuedCertificate, LeafRequest},
+    challenge::{self, CANISTER_TOKEN},
+    clock,
+    config::Config,
+    csr::Csr,
+    expiry::{self, ExpiryMonitor, RenewalRequest},
+    handler::types::{ChallengeType, Identifier},
+    idempotency::{self, OrderRequestIndex},
+    issuance_lock::{Claim, IssuanceLock, LockKey},
+    jobs::{IssuanceJob, JobQueue},
+    load_shed::LoadShedder,
+    maintenance::Maintenance,
+    order::{OrderManager, OrderStatus, StoredOrder},
+    pickup, policy,
+    profile::{self, Issuance, IssuanceOptions},
+    rate_limit::{LimitReached, RegisteredDomainLimiter},
+    tenant::Tenant,
+    token::TokenStore,
+    trace,
+};
+
+/// most names a single canister-requested certificate may cover
+pub const MAX_SANS: usize = 100;
+/// rough time one queued issuance takes, CAA and dns-01 outcalls plus the threshold signature
+const ESTIMATED_ISSUANCE_TIME: Duration = Duration::from_secs(30);
+
+/// everything queued ahead has to be validated and signed first
+pub fn estimated_ready_at() -> u64 {
+    let ahead = JobQueue::len() + 1;
+
+    clock::now_nanos() + ahead * ESTIMATED_ISSUANCE_TIME.as_nanos() as u64
+}
+
+/// the checks that need no outcalls, so a bad request fails before anything is queued
+fn prepare(
+    caller: Principal,
+    domains: Vec<String>,
+    csr_der: Vec<u8>,
+    options: &IssuanceOptions,
+) -> ApiResult<(Vec<String>, Csr, Issuance)> {
+    if caller == Principal::anonymous() {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let issuance = profile::resolve(options, clock::now_nanos())
+        .map_err(|e| ApiError::InvalidArgument(e.to_string()))?;
+    let domains = check_names(&domains, issuance.tenant.as_ref())?;
+    let csr = check_csr(&csr_der, &domains)?;
+
+    Ok((domains, csr, issuance))
+}
+
+/// `domains` normalized, as long as the policy and that of `tenant` allow every one of them
+pub fn check_names(domains: &[String], tenant: Option<&Tenant>) -> ApiResult<Vec<String>> {
+    let domains = policy::normalize_domains(domains);
+
+    if domains.is_empty() || domains.len() > MAX_SANS {
+        return Err(ApiError::InvalidArgument(format!(
+            "between 1 and {MAX_SANS} domains must be requested"
+        )));
+    }
+
+    for domain in &domains {
+        policy::check_domain(domain).map_err(|e| ApiError::InvalidArgument(e.to_string()))?;
+    }
+
+    if let Some(tenant) = tenant {
+        for domain in &domains {
+            let identifier = Identifier {
+                r#type: "dns".to_string(),
+                value: domain.clone(),
+            };
+
+            tenant
+                .permits(&identifier)
+                .map_err(|e| ApiError::InvalidArgument(e.to_string()))?;
+        }
+    }
+
+    Ok(domains)
+}
+
+/// a CSR for exactly the normalized `domains`, over a key that can still be certified
+pub fn check_csr(csr_der: &[u8], domains: &[String]) -> ApiResult<Csr> {
+    let csr = Csr::from_der(csr_der).map_err(|e| ApiError::InvalidArgument(e.to_string()))?;
+
+    if csr.domains != domains {
+        return Err(ApiError::InvalidArgument(
+            "CSR names do not match the requested domains".to_string(),
+        ));
+    }
+
+    // Let's Encrypt refuses to certify a key again once its compromise was reported
+    KeyBlocklist::check(&spki_der(&csr)?).map_err(|e| ApiError::InvalidArgument(e.to_string()))?;
+
+    Ok(csr)
+}
+
+fn spki_der(csr: &Csr) -> ApiResult<Vec<u8>> {
+    csr.public_key
+        .to_der()
+        .map_err(|e| ApiError::InvalidArgument(e.to_string()))
+}
+
+/// the [`idempotency::request_hash`] of a prepared request
+fn request_key(
+    owner: &CertificateOwner,
+    csr: &Csr,
+    options: &IssuanceOptions,
+    issuance: &Issuance,
+    domains: &[String],
+) -> ApiResult<String> {
+    idempotency::request_hash(owner, &spki_der(csr)?, options, issuance, domains)
+        .map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+/// The [`idempotency::request_hash`] of a new ACME order. Its key is only known once the order is
+/// finalized, so it is left out.
+pub fn order_request(
+    owner: &CertificateOwner,
+    options: &IssuanceOptions,
+    issuance: &Issuance,
+    domains: &[String],
+) -> ApiResult<String> {
+    idempotency::request_hash(owner, &[], options, issuance, domains)
+        .map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+/// the lock an ACME order of `owner` finalized with `csr` is signed under
+pub fn order_lock(
+    owner: &CertificateOwner,
+    csr: &Csr,
+    options: &IssuanceOptions,
+    domains: &[String],
+) -> ApiResult<LockKey> {
+    let issuance = profile::resolve(options, clock::now_nanos())
+        .map_err(|e| ApiError::InvalidArgument(e.to_string()))?;
+
+    request_key(owner, csr, options, &issuance, domains).map(|request| LockKey::new(&request))
+}
+
+/// Takes the certificate's slot of the weekly limit before any outcall, so orders over the limit
+/// fail right away and concurrent ones can't overrun it. Like Let's Encrypt does for ARI
+/// renewals, an order that `replaces` a certificate is exempt and gets no slot, it still counts
+/// against the limit once issued. Returns the time the slot is held under, see
+/// [`release_rate_limit`].
+pub fn reserve_rate_limit(
+    domains: &[String],
+    replaces: Option<u64>,
+) -> Result<Option<u64>, LimitReached> {
+    if replaces.is_some() {
+        return Ok(None);
+    }
+
+    let per_week = Config::with(|c| c.rate_limit.certificates_per_week);
+
+    RegisteredDomainLimiter::reserve(domains, per_week).map(Some)
+}
+
+/// gives back the slot [`reserve_rate_limit`] took for an issuance that failed
+pub fn release_rate_limit(domains: &[String], reserved_at: Option<u64>) {
+    if let Some(at) = reserved_at {
+        RegisteredDomainLimiter::release(domains, at);
+    }
+}
+
+pub async fn check_caa(domain: &str) -> ApiResult<()> {
+    let identities = Config::with(|c| c.caa_identities.clone());
+
+    caa::check(domain, &identities)
+        .await
+        .map_err(|e| ApiError::InvalidArgument(e.to_string()))
+}
+
+/// proof of control when the config asks for it, with the challenge that provided it. `token` is
+/// the http-01 token of the authorization.
+pub async fn prove_control(
+    caller: Principal,
+    domain: &str,
+    token: &str,
+) -> ApiResult<Option<ChallengeType>> {
+    if !Config::with(|c| c.require_dns01_for_canisters) {
+        return Ok(None);
+    }
+
+    challenge::verify_canister(&caller, domain, token)
+        .await
+        .map(Some)
+        .map_err(|e| ApiError::InvalidArgument(e.to_string()))
+}
+
+/// CAA and, when the config asks for it, proof of control for a single name
+pub async fn validate(caller: Principal, domain: &str) -> ApiResult<()> {
+    check_caa(domain).await?;
+    prove_control(caller, domain, CANISTER_TOKEN).await?;
+
+    Ok(())
+}
+
+/// signs once every name validated, the rate limit slot was reserved up front
+pub async fn sign(
+    caller: Principal,
+    domains: Vec<String>,
+    csr: Csr,
+    issuance: &Issuance,
+) -> ApiResult<IssuedCertificate> {
+    CertificateManager::issue(
+        domains,
+        csr.public_key,
+        CertificateOwner::Canister(caller),
+        issuance,
+    )
+    .await
+    .map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+/// [`sign`] for several validated requests of possibly different owners, signed together. Each
+/// request gets its own outcome, a failed leaf doesn't fail the others.
+pub async fn sign_batch(
+    requests: Vec<(CertificateOwner, Vec<String>, Csr, Issuance)>,
+) -> ApiResult<Vec<ApiResult<IssuedCertificate>>> {
+    let requests = requests
+        .into_iter()
+        .map(|(owner, domains, csr, issuance)| LeafRequest {
+            domains,
+            public_key: csr.public_key,
+            owner,
+            issuance,
+        })
+        .collect();
+
+    let batch = CertificateManager::issue_batch(requests)
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+
+    Ok(batch
+        .results
+        .into_iter()
+        .map(|r| r.map_err(|e| ApiError::Internal(e.to_string())))
+        .collect())
+}
+
+/// `request` is the [`idempotency::request_hash`] the fee is charged under, a retry isn't
+/// charged again
+async fn issue(
+    caller: Principal,
+    domains: Vec<String>,
+    csr: Csr,
+    issuance: Issuance,
+    request: &str,
+) -> ApiResult<IssuedCertificate> {
+    let reserved_at = reserve_rate_limit(&domains, None)?;
+    let payment = format!("request:{request}");
+
+    let issued = async {
+        for domain in &domains {
+            validate(caller, domain).await?;
+        }
+
+        Billing::charge(&Payer::Principal(caller), &payment).await?;
+
+        let issued = sign(caller, domains.clone(), csr, &issuance).await?;
+        Billing::settle(&payment, issued.serial);
+
+        Ok(issued)
+    }
+    .await;
+
+    if let Err(e) = &issued {
+        release_rate_limit(&domains, reserved_at);
+
+        // a retry of the request isn't charged again, one that can't succeed gets the fee back
+        if !matches!(e, ApiError::Internal(_)) {
+            Billing::refund(&payment);
+        }
+    }
+
+    issued
+}
+
+/// audits a finished issuance, certificates handed to orders that waited on it are not recorded
+/// again
+pub fn audit(actor: AuditActor, domains: &[String], outcome: &ApiResult<IssuedCertificate>) {
+    AuditLog::record(
+        actor,
+        AuditAction::CertificateIssued,
+        domains.to_vec(),
+        AuditOutcome::of(outcome),
+        outcome.as_ref().ok().map(|c| c.serial),
+    );
+}
+
+/// moves a `processing` order to its outcome and queues the webhook with the signed pickup URL
+pub fn complete(id: u64, outcome: &ApiResult<IssuedCertificate>) -> ApiResult<StoredOrder> {
+    let order = OrderManager::update(id, |o| {
+        o.estimated_ready_at = None;
+
+        match outcome {
+            Ok(cert) => {
+                o.status = OrderStatus::Valid;
+                o.certificate_serial = Some(cert.serial);
+            }
+            Err(e) => {
+                o.status = OrderStatus::Invalid;
+                o.error = Some(format!("{e:?}"));
+            }
+        }
+    })?;
+
+    OrderRequestIndex::settle(order.id);
+
+    if order.notify_url.is_some() {
+        JobQueue::push_webhook(order.id);
+    }
+
+    Ok(order)
+}
+
+/// The serial of the certificate `cert_id` names, if `owner` may open an order to replace it. The
+/// certificate has to share a name with the order and must not be replaced by another order
+/// already, one that failed or expired doesn't count.
+pub fn check_replaces(
+    owner: &CertificateOwner,
+    cert_id: &str,
+    domains: &[String],
+) -> ApiResult<u64> {
+    let cert = expiry::certificate(cert_id)
+        .filter(|c| &c.owner == owner)
+        .ok_or_else(|| {
+            ApiError::InvalidArgument(format!(
+                "`replaces` names no certificate issued to the caller: {cert_id}"
+            ))
+        })?;
+
+    if !cert.domains.iter().any(|d| domains.contains(d)) {
+        return Err(ApiError::InvalidArgument(format!(
+            "certificate {} shares no name with the order",
+            cert.serial
+        )));
+    }
+
+    if let Some(order) = OrderManager::replacement(cert.serial) {
+        return Err(ApiError::InvalidArgument(format!(
+            "certificate {} is already replaced by order {}",
+            cert.serial, order.id
+        )));
+    }
+
+    Ok(cert.serial)
+}
+
+/// the certificate a concurrent issuance for the same key just produced
+pub fn duplicate(serial: u64) -> ApiResult<IssuedCertificate> {
+    CertificateManager::get(serial)
+        .ok_or_else(|| ApiError::Internal(format!("certificate {serial} is missing")))
+}
+
+/// drops the lock of `key` and shares the outcome with every order that waited on it
+pub fn release(key: &LockKey, outcome: &ApiResult<IssuedCertificate>) {
+    let followers = IssuanceLock::release(key, outcome.as_ref().ok().map(|c| c.serial));
+
+    for id in followers {
+        if let Err(e) = complete(id, outcome) {
+            trace::log(&format!("{e:?}"));
+        }
+    }
+}
+
+/// runs [`issue`] under the lock of `key`
+async fn issue_locked(
+    key: LockKey,
+    caller: Principal,
+    domains: Vec<String>,
+    csr: Csr,
+    issuance: Issuance,
+    request: &str,
+) -> ApiResult<IssuedCertificate> {
+    let outcome = issue(caller, domains.clone(), csr, issuance, request).await;
+
+    audit(AuditActor::Principal(caller), &domains, &outcome);
+    release(&key, &outcome);
+
+    outcome
+}
+
+/// Issuance for canister consumers that do not speak ACME, runs the same checks an order goes
+/// through before finalization.
+pub async fn request_certificate(
+    caller: Principal,
+    domains: Vec<String>,
+    csr_der: Vec<u8>,
+    options: IssuanceOptions,
+) -> ApiResult<IssuedCertificate> {
+    // nothing would stop the same certificate from being replaced over and over
+    if options.replaces.is_some() {
+        return Err(ApiError::InvalidArgument(
+            "only orders can replace a certificate, see submit_order".to_string(),
+        ));
+    }
+
+    Maintenance::admit_call()?;
+    LoadShedder::admit_call()?;
+
+    let (domains, csr, issuance) = prepare(caller, domains, csr_der.clone(), &options)?;
+    let owner = CertificateOwner::Canister(caller);
+    let request = request_key(&owner, &csr, &options, &issuance, &domains)?;
+    let key = LockKey::new(&request);
+
+    match IssuanceLock::claim(&key) {
+        Claim::Won => {
+            let profile = issuance.profile.name.clone();
+            let tenant = issuance.tenant_id().map(str::to_string);
+            let issued = issue_locked(key, caller, domains, csr, issuance, &request).await;
+
+            if let Ok(cert) = &issued {
+                ExpiryMonitor::remember(
+                    cert,
+                    RenewalRequest {
+                        csr_der,
+                        profile: Some(profile),
+                        notify_url: None,
+                        tenant,
+                    },
+                );
+            }
+
+            issued
+        }
+        Claim::Issued(serial) => duplicate(serial),
+        Claim::InProgress => Err(ApiError::InvalidArgument(
+            "a certificate for the same names is being issued, retry shortly to receive it"
+                .to_string(),
+        )),
+    }
+}
+
+/// Like [`request_certificate`] but returns right away with the order in `processing`. The
+/// validation and signing steps are queued as a job [`JobQueue`] advances over several
+/// rounds, the outcome is delivered to `notify_url` as a signed pickup URL or polled for.
+///
+/// An order for names that are already being signed for the caller waits for that issuance and
+/// gets the same certificate. A retry of an order that is still pending gets that order back. An
+/// order that `replaces` a certificate is linked to it, see [`check_replaces`].
+pub fn submit_order(
+    caller: Principal,
+    domains: Vec<String>,
+    csr_der: Vec<u8>,
+    notify_url: Option<String>,
+    options: IssuanceOptions,
+) -> ApiResult<StoredOrder> {
+    if let Some(url) = &notify_url {
+        pickup::check_notify_url(url).map_err(|e| ApiError::InvalidArgument(e.to_string()))?;
+    }
+
+    // the seed is drawn right after install
+    if !TokenStore::ready() {
+        return Err(ApiError::Internal(
+            "challenge tokens are not ready yet, retry shortly".to_string(),
+        ));
+    }
+
+    let (domains, csr, issuance) = prepare(caller, domains, csr_der.clone(), &options)?;
+    let owner = CertificateOwner::Canister(caller);
+    let request = request_key(&owner, &csr, &options, &issuance, &domains)?;
+
+    if let Some(order) = OrderRequestIndex::pending(&request) {
+        return Ok(order);
+    }
+
+    // an order already in flight is returned above, only new ones wait for the maintenance or
+    // are shed
+    Maintenance::admit_call()?;
+    LoadShedder::admit_call()?;
+
+    let replaces = options
+        .replaces
+        .as_deref()
+        .map(|cert_id| check_replaces(&owner, cert_id, &domains))
+        .transpose()?;
+
+    let key = LockKey::new(&request);
+
+    let estimated_ready_at = estimated_ready_at();
+    let order = OrderManager::create(owner, domains.clone(), OrderStatus::Processing, notify_url);
+    let order = OrderManager::update(order.id, |o| {
+        o.estimated_ready_at = Some(estimated_ready_at);
+        o.profile = Some(issuance.profile.name.clone());
+    })?;
+    let order = match replaces {
+        Some(serial) => OrderManager::link_replacement(order.id, serial)?,
+        None => order,
+    };
+    OrderRequestIndex::record(request, &order);
+
+    match IssuanceLock::claim(&key) {
+        Claim::Won => {}
+        Claim::Issued(serial) => return complete(order.id, &duplicate(serial)),
+        Claim::InProgress => {
+            IssuanceLock::follow(&key, order.id);
+            return Ok(order);
+        }
+    }
+
+    AuthorizationStore::open(order.id, &domains, order.expires_at)
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    JobQueue::push(IssuanceJob::new(
+        order.id, caller, domains, csr_der, &issuance, key,
+    ));
+
+    Ok(order)
+}
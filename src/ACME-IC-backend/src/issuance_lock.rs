@@ -0,0 +1,190 @@
+use std::{
+    cell::{Cell, RefCell},
+    time::Duration,
+};
+
+use candid::CandidType;
+use ic_cdk_timers::TimerId;
+use serde::Deserialize;
+
+use crate::{
+    clock,
+    mem::{candid_storable, Repository},
+};
+
+/// a lock whose holder never released it, e.g. because its callback trapped, is taken over after
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// how long a finished issuance is handed out again instead of signing a duplicate
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+thread_local! {
+    static LOCKS: RefCell<IssuanceLock> = RefCell::new(IssuanceLock::init());
+    static PRUNE_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+}
+
+/// Who asked for which names and key, with which validity from which CA, two issuances with the
+/// same key would produce interchangeable certificates. Kept as the request's
+/// [`request_hash`](crate::idempotency::request_hash) so it can be stored.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LockKey(String);
+
+impl LockKey {
+    pub fn new(request: &str) -> Self {
+        Self(request.to_string())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum LockState {
+    Held {
+        since: u64,
+        /// deferred orders waiting for the holder's outcome
+        followers: Vec<u64>,
+    },
+    Issued {
+        serial: u64,
+        at: u64,
+    },
+}
+
+candid_storable!(LockState);
+
+pub enum Claim {
+    /// the caller holds the lock and must [`IssuanceLock::release`] it once signing is done
+    Won,
+    /// someone else is signing the same names right now
+    InProgress,
+    /// the same names were just issued, serial of that certificate
+    Issued(u64),
+}
+
+/// Short-lived per-request lock taken before signing, so concurrent orders for the same names
+/// and key from the same owner reach the signer once and the rest get that certificate.
+///
+/// Kept in stable memory, so orders waiting on a lock still get its outcome after an upgrade.
+pub struct IssuanceLock {
+    locks: Repository<String, LockState>,
+}
+
+impl IssuanceLock {
+    fn init() -> Self {
+        Self {
+            locks: Repository::init::<Self>(),
+        }
+    }
+
+    /// whether the lock can be dropped, a held one with followers is kept for whoever takes it over
+    fn expired(state: &LockState, now: u64) -> bool {
+        match state {
+            LockState::Held { since, followers } => {
+                followers.is_empty() && *since + LOCK_TIMEOUT.as_nanos() as u64 <= now
+            }
+            LockState::Issued { at, .. } => *at + DUPLICATE_WINDOW.as_nanos() as u64 <= now,
+        }
+    }
+
+    /// drops expired locks, on a timer so claims don't have to walk every lock
+    fn prune() {
+        let now = clock::now_nanos();
+
+        LOCKS.with_borrow_mut(|l| {
+            let expired = l
+                .locks
+                .iter()
+                .filter(|(_, state)| Self::expired(state, now))
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>();
+
+            for key in expired {
+                l.locks.remove(&key);
+            }
+        })
+    }
+
+    pub fn claim(key: &LockKey) -> Claim {
+        let now = clock::now_nanos();
+
+        LOCKS.with_borrow_mut(|l| {
+            // a lock the timer hasn't pruned yet still counts as expired
+            match l.locks.get(&key.0) {
+                Some(LockState::Issued { serial, at })
+                    if at + DUPLICATE_WINDOW.as_nanos() as u64 > now =>
+                {
+                    Claim::Issued(serial)
+                }
+                Some(LockState::Held { since, .. })
+                    if since + LOCK_TIMEOUT.as_nanos() as u64 > now =>
+                {
+                    Claim::InProgress
+                }
+                Some(LockState::Held { followers, .. }) => {
+                    l.locks.insert(
+                        key.0.clone(),
+                        LockState::Held {
+                            since: now,
+                            followers,
+                        },
+                    );
+                    Claim::Won
+                }
+                _ => {
+                    l.locks.insert(
+                        key.0.clone(),
+                        LockState::Held {
+                            since: now,
+                            followers: vec![],
+                        },
+                    );
+                    Claim::Won
+                }
+            }
+        })
+    }
+
+    /// lets deferred order `id` wait for the current holder, right after [`Claim::InProgress`]
+    pub fn follow(key: &LockKey, id: u64) {
+        LOCKS.with_borrow_mut(|l| {
+            if let Some(LockState::Held {
+                since,
+                mut followers,
+            }) = l.locks.get(&key.0)
+            {
+                followers.push(id);
+                l.locks
+                    .insert(key.0.clone(), LockState::Held { since, followers });
+            }
+        })
+    }
+
+    /// drops the lock, remembering `serial` for duplicate suppression if signing succeeded, and
+    /// returns the orders that were waiting on it
+    pub fn release(key: &LockKey, serial: Option<u64>) -> Vec<u64> {
+        let now = clock::now_nanos();
+
+        LOCKS.with_borrow_mut(|l| {
+            let followers = match l.locks.remove(&key.0) {
+                Some(LockState::Held { followers, .. }) => followers,
+                _ => vec![],
+            };
+
+            if let Some(serial) = serial {
+                l.locks
+                    .insert(key.0.clone(), LockState::Issued { serial, at: now });
+            }
+
+            followers
+        })
+    }
+}
+
+/// has to be called again after every upgrade
+pub fn start_prune_timer() {
+    if let Some(previous) = PRUNE_TIMER.take() {
+        ic_cdk_timers::clear_timer(previous);
+    }
+
+    PRUNE_TIMER.set(Some(ic_cdk_timers::set_timer_interval(
+        LOCK_TIMEOUT,
+        IssuanceLock::prune,
+    )));
+}
@@ -0,0 +1,803 @@
+use std::{
+    cell::{Cell, RefCell},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+use crate::{
+    account::AccountManager,
+    api::{ApiError, ApiResult},
+    audit::AuditActor,
+    authz::{AuthorizationState, AuthorizationStore},
+    billing::{self, Billing, Payer},
+    cert_manager::{CertificateOwner, IssuedCertificate},
+    challenge::{self, CANISTER_TOKEN},
+    clock,
+    config::Config,
+    csr::Csr,
+    debug_capture::DebugCapture,
+    events,
+    expiry::{ExpiryMonitor, RenewalRequest},
+    handler::types::ChallengeType,
+    issuance,
+    issuance_lock::LockKey,
+    load_shed::{LoadShedder, Queue},
+    mem::{candid_storable, Repository},
+    order::{OrderManager, OrderStatus},
+    pickup,
+    profile::{self, Issuance, IssuanceOptions},
+    rate_limit::RegisteredDomainLimiter,
+    thumbprint,
+    token::TokenStore,
+    trace,
+};
+
+/// how often the worker advances the queued jobs, also the `Retry-After` of a polled order
+pub const WORKER_INTERVAL: Duration = Duration::from_secs(2);
+/// jobs advanced per round, each step is at most two outcalls or one signature
+const JOBS_PER_ROUND: usize = 16;
+/// attempts of a single step before the job is parked until a controller requeues it
+pub const MAX_ATTEMPTS: u32 = 8;
+
+thread_local! {
+    static JOBS: RefCell<JobQueue> = RefCell::new(JobQueue::init());
+    /// a round is still awaiting its outcalls when the next timer fires
+    static ROUND_RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// What a job does the next time the worker gets to it.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobStep {
+    /// CAA, and proof of control when required, for the name at this index
+    Validate(u32),
+    Sign,
+}
+
+impl JobStep {
+    fn queue(&self) -> Queue {
+        match self {
+            Self::Validate(_) => Queue::Validation,
+            Self::Sign => Queue::Signing,
+        }
+    }
+}
+
+/// Progress of the step a job is at, shared by every kind of job.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JobState {
+    /// IC time in nanoseconds
+    pub queued_at: u64,
+    /// attempts of the current step, the job is parked once it reaches [`MAX_ATTEMPTS`]
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// the step is not attempted again before
+    pub retry_at: u64,
+}
+
+impl JobState {
+    pub fn new(now: u64) -> Self {
+        Self {
+            queued_at: now,
+            attempts: 0,
+            last_error: None,
+            retry_at: 0,
+        }
+    }
+
+    pub fn parked(&self) -> bool {
+        self.attempts >= MAX_ATTEMPTS
+    }
+
+    pub fn due(&self, now: u64) -> bool {
+        !self.parked() && self.retry_at <= now
+    }
+
+    /// counted before the step runs, so a step that keeps trapping is parked as well
+    pub fn attempt(&mut self) {
+        self.attempts += 1;
+    }
+
+    /// retries with exponential backoff, starting at the worker interval
+    pub fn failed(&mut self, error: String, now: u64) {
+        let backoff = (WORKER_INTERVAL.as_nanos() as u64) << self.attempts.min(16);
+
+        self.last_error = Some(error);
+        self.retry_at = now + backoff;
+    }
+
+    fn advanced(&mut self) {
+        self.attempts = 0;
+        self.last_error = None;
+        self.retry_at = 0;
+    }
+
+    /// the last error is kept so the operator still sees why the job got stuck
+    pub fn requeue(&mut self) {
+        self.attempts = 0;
+        self.retry_at = 0;
+    }
+}
+
+/// An order in `processing`, with everything needed to resume it in a later round.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IssuanceJob {
+    pub order: u64,
+    pub caller: Principal,
+    pub domains: Vec<String>,
+    pub csr_der: Vec<u8>,
+    /// the profile and window accepted on submission, resolved again before signing
+    pub options: IssuanceOptions,
+    pub step: JobStep,
+    pub state: JobState,
+    /// the lock the job holds until it finishes, `None` for jobs queued before locks were kept
+    /// in stable memory
+    pub lock: Option<LockKey>,
+    /// the ACME account whose order the job signs, `caller` is anonymous then. `None` for the
+    /// orders of canisters.
+    pub account: Option<String>,
+    /// when the job took its slot of the weekly rate limit, `None` until it did, for orders that
+    /// replace a certificate and for jobs queued before slots were reserved
+    pub reserved_at: Option<u64>,
+}
+
+/// [`IssuanceJob`] as stored at version 1 of the [`JobQueue`], before jobs could be retried
+#[derive(CandidType, Deserialize)]
+struct IssuanceJobV1 {
+    order: u64,
+    caller: Principal,
+    domains: Vec<String>,
+    csr_der: Vec<u8>,
+    options: IssuanceOptions,
+    step: JobStep,
+}
+
+candid_storable!(IssuanceJobV1);
+
+/// Delivery of an order's `notify_url` webhook, retried until the receiver answers with a 2xx.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WebhookJob {
+    pub order: u64,
+    pub state: JobState,
+}
+
+candid_storable!(WebhookJob);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum JobKind {
+    Issuance(JobStep),
+    Webhook,
+}
+
+/// A queued job as shown to controllers.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JobInfo {
+    pub order: u64,
+    pub kind: JobKind,
+    pub state: JobState,
+    pub age_secs: u64,
+    pub parked: bool,
+}
+
+impl JobInfo {
+    fn new(order: u64, kind: JobKind, state: JobState, now: u64) -> Self {
+        Self {
+            order,
+            kind,
+            age_secs: Duration::from_nanos(now.saturating_sub(state.queued_at)).as_secs(),
+            parked: state.parked(),
+            state,
+        }
+    }
+}
+
+impl IssuanceJob {
+    pub fn new(
+        order: u64,
+        caller: Principal,
+        domains: Vec<String>,
+        csr_der: Vec<u8>,
+        issuance: &Issuance,
+        lock: LockKey,
+    ) -> Self {
+        Self {
+            order,
+            caller,
+            domains,
+            csr_der,
+            options: IssuanceOptions {
+                profile: Some(issuance.profile.name.clone()),
+                not_before: Some(issuance.not_before),
+                not_after: Some(issuance.not_after),
+                // the order keeps the certificate it replaces
+                replaces: None,
+                tenant: issuance.tenant_id().map(str::to_string),
+            },
+            step: JobStep::Validate(0),
+            state: JobState::new(clock::now_nanos()),
+            lock: Some(lock),
+            account: None,
+            reserved_at: None,
+        }
+    }
+
+    /// The order of `account`, finalized with `csr_der`. Its names were validated through the
+    /// challenges the account responded to, so the job goes straight to its signature.
+    /// `lock` and `reserved_at` are the lock and rate limit slot taken on finalization.
+    pub fn finalized(
+        order: u64,
+        account: String,
+        domains: Vec<String>,
+        csr_der: Vec<u8>,
+        options: IssuanceOptions,
+        lock: LockKey,
+        reserved_at: Option<u64>,
+    ) -> Self {
+        Self {
+            order,
+            caller: Principal::anonymous(),
+            domains,
+            csr_der,
+            options,
+            step: JobStep::Sign,
+            state: JobState::new(clock::now_nanos()),
+            lock: Some(lock),
+            account: Some(account),
+            reserved_at,
+        }
+    }
+
+    fn owner(&self) -> CertificateOwner {
+        match &self.account {
+            Some(id) => CertificateOwner::Account(id.clone()),
+            None => CertificateOwner::Canister(self.caller),
+        }
+    }
+
+    fn actor(&self) -> AuditActor {
+        match &self.account {
+            Some(id) => AuditActor::Account {
+                id: id.clone(),
+                thumbprint: AccountManager::get(id)
+                    .and_then(|a| thumbprint::compute(&a.public_key).ok())
+                    .unwrap_or_default(),
+            },
+            None => AuditActor::Principal(self.caller),
+        }
+    }
+
+    /// who pays the fee of the job, the account itself for the orders of accounts
+    fn payer(&self) -> Payer {
+        match &self.account {
+            Some(id) => Payer::Account(id.clone()),
+            None => Payer::Principal(self.caller),
+        }
+    }
+
+    async fn charge(&self) -> ApiResult<()> {
+        Billing::charge(&self.payer(), &billing::order_key(self.order)).await
+    }
+}
+
+candid_storable!(IssuanceJob);
+
+/// memory marker for the pending webhook deliveries
+pub struct WebhookQueue;
+
+/// Issuance and webhook work that does not fit in one message, keyed by order id so it is picked
+/// up oldest first and survives upgrades.
+pub struct JobQueue {
+    jobs: Repository<u64, IssuanceJob>,
+    webhooks: Repository<u64, WebhookJob>,
+}
+
+impl JobQueue {
+    fn init() -> Self {
+        Self {
+            jobs: Repository::init::<Self>(),
+            webhooks: Repository::init::<WebhookQueue>(),
+        }
+    }
+
+    /// gives jobs queued before retries existed a fresh retry state
+    pub fn migrate_retry_state() -> anyhow::Result<()> {
+        let now = clock::now_nanos();
+
+        Repository::<u64, IssuanceJob>::migrate::<Self, IssuanceJobV1>(|old| IssuanceJob {
+            order: old.order,
+            caller: old.caller,
+            domains: old.domains,
+            csr_der: old.csr_der,
+            options: old.options,
+            step: old.step,
+            state: JobState::new(now),
+            lock: None,
+            account: None,
+            reserved_at: None,
+        });
+
+        anyhow::Ok(())
+    }
+
+    pub fn push(job: IssuanceJob) {
+        LoadShedder::enqueued(job.step.queue());
+        JOBS.with_borrow_mut(|q| q.jobs.insert(job.order, job));
+    }
+
+    pub fn push_webhook(order: u64) {
+        JOBS.with_borrow_mut(|q| {
+            q.webhooks.insert(
+                order,
+                WebhookJob {
+                    order,
+                    state: JobState::new(clock::now_nanos()),
+                },
+            )
+        });
+    }
+
+    pub fn len() -> u64 {
+        JOBS.with_borrow(|q| q.jobs.len())
+    }
+
+    /// whether the issuance job of `order` is still queued
+    pub fn contains(order: u64) -> bool {
+        JOBS.with_borrow(|q| q.jobs.contains(&order))
+    }
+
+    fn advance_to(order: u64, step: JobStep) {
+        JOBS.with_borrow_mut(|q| {
+            let advanced = q.jobs.update(&order, |job| {
+                job.step = step;
+                job.state.advanced();
+            });
+
+            if let Some(job) = advanced {
+                LoadShedder::enqueued(job.step.queue());
+            }
+        })
+    }
+
+    fn update(order: u64, f: impl FnOnce(&mut JobState)) {
+        JOBS.with_borrow_mut(|q| q.jobs.update(&order, |job| f(&mut job.state)));
+    }
+
+    fn update_webhook(order: u64, f: impl FnOnce(&mut JobState)) {
+        JOBS.with_borrow_mut(|q| q.webhooks.update(&order, |job| f(&mut job.state)));
+    }
+
+    fn reserved(order: u64, at: Option<u64>) {
+        JOBS.with_borrow_mut(|q| q.jobs.update(&order, |job| job.reserved_at = at));
+    }
+
+    fn remove(order: u64) -> Option<IssuanceJob> {
+        JOBS.with_borrow_mut(|q| q.jobs.remove(&order))
+    }
+
+    fn remove_webhook(order: u64) -> Option<WebhookJob> {
+        JOBS.with_borrow_mut(|q| q.webhooks.remove(&order))
+    }
+
+    /// every queued job, issuance first, with how long it has been waiting
+    pub fn list() -> Vec<JobInfo> {
+        let now = clock::now_nanos();
+
+        JOBS.with_borrow(|q| {
+            q.jobs
+                .values()
+                .map(|j| JobInfo::new(j.order, JobKind::Issuance(j.step), j.state, now))
+                .chain(
+                    q.webhooks
+                        .values()
+                        .map(|w| JobInfo::new(w.order, JobKind::Webhook, w.state, now)),
+                )
+                .collect()
+        })
+    }
+
+    /// retries the current step of the order's job right away, parked or not
+    pub fn requeue(order: u64) -> ApiResult<()> {
+        let requeued = JOBS.with_borrow_mut(|q| {
+            q.jobs.update(&order, |j| j.state.requeue()).is_some()
+                || q.webhooks.update(&order, |w| w.state.requeue()).is_some()
+        });
+
+        if !requeued {
+            return Err(ApiError::NotFound(format!("job for order {order}")));
+        }
+
+        Ok(())
+    }
+
+    /// Drops the order's job. A cancelled issuance turns the order `invalid` and releases the
+    /// orders waiting on it, a cancelled webhook is simply not delivered.
+    pub fn cancel(order: u64) -> ApiResult<()> {
+        if let Some(job) = JOBS.with_borrow(|q| q.jobs.get(&order)) {
+            finish(
+                &job,
+                Err(ApiError::InvalidArgument(
+                    "issuance was cancelled by a controller".to_string(),
+                )),
+            );
+
+            return Ok(());
+        }
+
+        Self::remove_webhook(order)
+            .map(|_| ())
+            .ok_or_else(|| ApiError::NotFound(format!("job for order {order}")))
+    }
+}
+
+/// resets the round flag even when a step traps, the futures of a trapped callback are dropped
+struct RoundGuard;
+
+impl Drop for RoundGuard {
+    fn drop(&mut self) {
+        ROUND_RUNNING.set(false);
+    }
+}
+
+/// removes the job and hands the outcome to its order and to the orders that waited on it
+fn finish(job: &IssuanceJob, outcome: ApiResult<IssuedCertificate>) {
+    if JobQueue::remove(job.order).is_none() {
+        return;
+    }
+
+    LoadShedder::drained(job.step.queue());
+    issuance::audit(job.actor(), &job.domains, &outcome);
+    if let Some(key) = &job.lock {
+        issuance::release(key, &outcome);
+    }
+
+    match &outcome {
+        Ok(_) if job.reserved_at.is_none() => RegisteredDomainLimiter::record(&job.domains),
+        Ok(_) => {}
+        Err(_) => {
+            issuance::release_rate_limit(&job.domains, job.reserved_at);
+            Billing::refund(&billing::order_key(job.order));
+        }
+    }
+
+    if let Ok(cert) = &outcome {
+        ExpiryMonitor::remember(
+            cert,
+            RenewalRequest {
+                csr_der: job.csr_der.clone(),
+                profile: job.options.profile.clone(),
+                notify_url: OrderManager::get(job.order).and_then(|o| o.notify_url),
+                tenant: job.options.tenant.clone(),
+            },
+        );
+    }
+
+    if let Err(e) = issuance::complete(job.order, &outcome) {
+        trace::log(&format!("{e:?}"));
+    }
+}
+
+/// Signs every job at [`JobStep::Sign`] in one batch, the signatures are awaited together. The
+/// fees are taken first. A job whose request no longer resolves or whose fee can't be paid fails
+/// alone, and only the jobs whose leaf couldn't be signed are tried again.
+async fn sign(jobs: Vec<IssuanceJob>) {
+    let mut prepared = Vec::new();
+
+    for job in jobs {
+        JobQueue::update(job.order, JobState::attempt);
+
+        let request = Csr::from_der(&job.csr_der).and_then(|csr| {
+            let issuance = profile::resolve(&job.options, clock::now_nanos())?;
+
+            anyhow::Ok((job.owner(), job.domains.clone(), csr, issuance))
+        });
+
+        match request {
+            Ok(request) => prepared.push((job, request)),
+            Err(e) => finish(&job, Err(ApiError::InvalidArgument(e.to_string()))),
+        }
+    }
+
+    let charges = futures::future::join_all(prepared.iter().map(|(job, _)| job.charge())).await;
+
+    let mut batch = Vec::new();
+    let mut requests = Vec::new();
+
+    for ((job, request), charged) in prepared.into_iter().zip(charges) {
+        match charged {
+            Ok(()) => {
+                batch.push(job);
+                requests.push(request);
+            }
+            Err(ApiError::Internal(e)) => {
+                JobQueue::update(job.order, |s| s.failed(e, clock::now_nanos()))
+            }
+            Err(e) => finish(&job, Err(e)),
+        }
+    }
+
+    if batch.is_empty() {
+        return;
+    }
+
+    let results = match issuance::sign_batch(requests).await {
+        Ok(results) => results,
+        Err(e) => batch.iter().map(|_| Err(e.clone())).collect(),
+    };
+
+    for (job, result) in batch.iter().zip(results) {
+        match result {
+            Ok(cert) => {
+                Billing::settle(&billing::order_key(job.order), cert.serial);
+                finish(job, Ok(cert));
+            }
+            // the signature or an outcall failed, not the request, so only it is tried again
+            Err(ApiError::Internal(e)) => {
+                JobQueue::update(job.order, |s| s.failed(e, clock::now_nanos()))
+            }
+            Err(e) => finish(job, Err(e)),
+        }
+    }
+}
+
+/// runs the next step of `job`, one name is validated per round
+async fn advance(mut job: IssuanceJob) {
+    match job.step {
+        JobStep::Validate(index) => {
+            JobQueue::update(job.order, JobState::attempt);
+
+            // the slot is taken before the first outcall, a retried step keeps it
+            if index == 0 && job.reserved_at.is_none() {
+                let replaces = OrderManager::get(job.order).and_then(|o| o.replaces);
+
+                match issuance::reserve_rate_limit(&job.domains, replaces) {
+                    Ok(at) => {
+                        job.reserved_at = at;
+                        JobQueue::reserved(job.order, at);
+                    }
+                    Err(e) => {
+                        finish(&job, Err(e.into()));
+                        return;
+                    }
+                }
+            }
+
+            let domain = &job.domains[index as usize];
+
+            if let Err(e) = issuance::check_caa(domain).await {
+                finish(&job, Err(e));
+                return;
+            }
+
+            // a used up or expired token can't become usable again, no retry helps
+            let token = match TokenStore::usable(job.order, index) {
+                Ok(token) => token,
+                Err(e) => {
+                    AuthorizationStore::failed(job.order, index, e.to_string(), None);
+                    finish(&job, Err(ApiError::InvalidArgument(e.to_string())));
+                    return;
+                }
+            };
+
+            let attempts = AuthorizationStore::attempt(job.order, index);
+            let token = token.as_deref().unwrap_or(CANISTER_TOKEN);
+
+            match issuance::prove_control(job.caller, domain, token).await {
+                Ok(by) => {
+                    AuthorizationStore::validated(job.order, index, by);
+                    TokenStore::consume(job.order, index);
+                }
+                Err(e) => {
+                    let detail = match &e {
+                        ApiError::InvalidArgument(detail) => detail.clone(),
+                        e => format!("{e:?}"),
+                    };
+
+                    // the name may not be set up yet, it is tried again until the attempts ran out
+                    if attempts < Config::with(|c| c.challenge_attempts) as u32 {
+                        let now = clock::now_nanos();
+                        JobQueue::update(job.order, |s| s.failed(detail.clone(), now));
+
+                        let retry_at = JOBS
+                            .with_borrow(|q| q.jobs.get(&job.order))
+                            .map(|j| j.state.retry_at);
+                        AuthorizationStore::failed(job.order, index, detail, retry_at);
+                    } else {
+                        AuthorizationStore::failed(job.order, index, detail, None);
+                        finish(&job, Err(e));
+                    }
+
+                    return;
+                }
+            }
+
+            let next = if index as usize + 1 < job.domains.len() {
+                JobStep::Validate(index + 1)
+            } else {
+                JobStep::Sign
+            };
+
+            LoadShedder::drained(job.step.queue());
+            JobQueue::advance_to(job.order, next);
+        }
+        JobStep::Sign => sign(vec![job]).await,
+    }
+}
+
+/// the challenge an account responded to with its key authorization, RFC 8555 §8.1
+fn response(state: &AuthorizationState) -> anyhow::Result<(ChallengeType, String, String)> {
+    let kind = state
+        .challenge
+        .ok_or_else(|| anyhow!("no challenge was responded to"))?;
+    let account = OrderManager::get(state.order)
+        .and_then(|o| match o.owner {
+            CertificateOwner::Account(id) => AccountManager::get(&id),
+            CertificateOwner::Canister(_) => None,
+        })
+        .ok_or_else(|| anyhow!("the account of the order is gone"))?;
+    let token = TokenStore::usable(state.order, state.index)?
+        .ok_or_else(|| anyhow!("the authorization has no token"))?;
+    let key_authorization = AccountManager::key_authorization(&account, &token)?.key_authorization;
+
+    anyhow::Ok((kind, token, key_authorization))
+}
+
+/// RFC 8555 §7.1.6, an order with an invalid authorization is invalid
+fn invalidate(order: u64, index: u32, detail: String) {
+    AuthorizationStore::failed(order, index, detail.clone(), None);
+
+    // another of its names may have failed the order already
+    if OrderManager::get(order).is_none_or(|o| o.status != OrderStatus::Pending) {
+        return;
+    }
+
+    if let Err(e) = issuance::complete(order, &Err(ApiError::InvalidArgument(detail))) {
+        trace::log(&format!("{e:?}"));
+    }
+}
+
+/// a step of the validation of `order` for the debug capture of its account, if it has one
+fn capture(order: u64, trace: impl FnOnce() -> String) {
+    if let Some(CertificateOwner::Account(id)) = OrderManager::get(order).map(|o| o.owner) {
+        DebugCapture::trace(&id, trace);
+    }
+}
+
+/// One attempt of the challenge an account responded to, after CAA. A failed attempt is retried
+/// with backoff until `challenge_attempts` ran out, the order turns `ready` once its last name is
+/// valid and `invalid` once one of them is.
+async fn validate_response(state: AuthorizationState) {
+    let (order, index) = (state.order, state.index);
+    let identifier = &state.identifier;
+
+    // a used up or expired token can't become usable again, no retry helps
+    let (kind, token, key_authorization) = match response(&state) {
+        Ok(response) => response,
+        Err(e) => {
+            capture(order, || format!("{identifier}: {e}"));
+            return invalidate(order, index, e.to_string());
+        }
+    };
+
+    // like for canister orders, a CAA record that forbids issuance isn't retried
+    if let Err(e) = issuance::check_caa(identifier).await {
+        capture(order, || format!("CAA of {identifier}: {e:?}"));
+        return invalidate(order, index, format!("{e:?}"));
+    }
+
+    let attempts = AuthorizationStore::attempt(order, index);
+
+    match challenge::validate(kind, identifier, &token, &key_authorization).await {
+        Ok(()) => {
+            capture(order, || {
+                format!(
+                    "{} of {identifier} valid on attempt {attempts}",
+                    kind.as_str()
+                )
+            });
+
+            AuthorizationStore::validated(order, index, Some(kind));
+            TokenStore::consume(order, index);
+            OrderManager::authorized(order);
+        }
+        Err(e) => {
+            let detail = e.to_string();
+            capture(order, || {
+                format!(
+                    "{} of {identifier} failed on attempt {attempts}: {detail}, expected `{}`",
+                    kind.as_str(),
+                    key_authorization
+                )
+            });
+
+            // the name may not be set up yet, it is tried again until the attempts ran out
+            if attempts < Config::with(|c| c.challenge_attempts) as u32 {
+                let backoff = (WORKER_INTERVAL.as_nanos() as u64) << attempts.min(16);
+                let retry_at = clock::now_nanos() + backoff;
+
+                AuthorizationStore::failed(order, index, detail, Some(retry_at));
+            } else {
+                invalidate(order, index, detail);
+            }
+        }
+    }
+}
+
+async fn deliver(webhook: WebhookJob) {
+    JobQueue::update_webhook(webhook.order, JobState::attempt);
+
+    let delivered = match OrderManager::get(webhook.order) {
+        Some(order) => pickup::notify(&order).await,
+        // nothing left to tell
+        None => anyhow::Ok(()),
+    };
+
+    match delivered {
+        Ok(()) => {
+            JobQueue::remove_webhook(webhook.order);
+        }
+        Err(e) => JobQueue::update_webhook(webhook.order, |s| {
+            s.failed(e.to_string(), clock::now_nanos())
+        }),
+    }
+}
+
+async fn round() {
+    if ROUND_RUNNING.replace(true) {
+        return;
+    }
+    let _guard = RoundGuard;
+
+    let now = clock::now_nanos();
+    let (jobs, webhooks) = JOBS.with_borrow(|q| {
+        (
+            q.jobs
+                .values()
+                .filter(|j| j.state.due(now))
+                .take(JOBS_PER_ROUND)
+                .collect::<Vec<_>>(),
+            q.webhooks
+                .values()
+                .filter(|w| w.state.due(now))
+                .take(JOBS_PER_ROUND)
+                .collect::<Vec<_>>(),
+        )
+    });
+
+    let (signing, validating): (Vec<_>, Vec<_>) =
+        jobs.into_iter().partition(|j| j.step == JobStep::Sign);
+
+    for job in validating {
+        advance(job).await;
+    }
+
+    for state in AuthorizationStore::due(now, JOBS_PER_ROUND) {
+        validate_response(state).await;
+    }
+
+    // every order that reached its signature this round shares one batch
+    sign(signing).await;
+
+    for webhook in webhooks {
+        deliver(webhook).await;
+    }
+
+    events::deliver_due().await;
+    Billing::refund_pending().await;
+}
+
+/// has to be called again after every upgrade, the queue depths are rebuilt from the stored jobs
+pub fn start_worker() {
+    JOBS.with_borrow(|q| {
+        for job in q.jobs.values() {
+            LoadShedder::enqueued(job.step.queue());
+        }
+    });
+
+    for _ in 0..AuthorizationStore::pending_len() {
+        LoadShedder::enqueued(Queue::Validation);
+    }
+
+    ic_cdk_timers::set_timer_interval(WORKER_INTERVAL, || ic_cdk::spawn(round()));
+}
@@ -1,34 +1,68 @@
-use std::{cell::RefCell, rc::Rc, str::FromStr, time::Duration};
+use std::{cell::RefCell, str::FromStr, time::Duration};
 
 use ic_cdk::api::management_canister::ecdsa::{
-    self, ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyResponse,
-    SignWithEcdsaArgument, SignWithEcdsaResponse,
+    self, ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, SignWithEcdsaArgument,
 };
 
-use ic_stable_structures::Storable;
-use k256::{
-    ecdsa::DerSignature, elliptic_curve::PublicKey, pkcs8::SubjectPublicKeyInfo, Secp256k1,
-};
-use signature::Keypair;
+use anyhow::anyhow;
+use candid::CandidType;
+use ic_stable_structures::StableCell;
+use k256::{ecdsa::DerSignature, elliptic_curve::PublicKey, Secp256k1};
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
 use tiny_keccak::{Hasher, Keccak};
 use x509_cert::{
-    builder::{Builder, CertificateBuilder, Profile},
+    attr::AttributeTypeAndValue,
+    certificate::{TbsCertificate, Version},
     der::{
-        asn1::{BitString, GeneralizedTime},
+        asn1::{BitString, GeneralizedTime, Ia5String, OctetString},
+        oid::{
+            db::{
+                rfc4519::{COMMON_NAME, COUNTRY_NAME, ORGANIZATION_NAME},
+                rfc5912::{ECDSA_WITH_SHA_256, ID_KP_CLIENT_AUTH, ID_KP_SERVER_AUTH},
+            },
+            ObjectIdentifier,
+        },
         pem::LineEnding,
-        Encode, EncodePem,
+        Any, Encode, EncodePem, Tag,
+    },
+    ext::{
+        pkix::{
+            name::GeneralName, AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage,
+            KeyUsage, KeyUsages, SubjectAltName, SubjectKeyIdentifier,
+        },
+        AsExtension, Extension,
     },
-    name::Name,
+    name::{Name, RelativeDistinguishedName},
     serial_number::SerialNumber,
-    spki::{self, DynSignatureAlgorithmIdentifier, SignatureBitStringEncoding},
+    spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned},
     time::{Time, Validity},
 };
 
-// TODO proper CNAME
-#[cfg(feature = "local")]
+use crate::{
+    cert_manager::CertificateManager,
+    config::{Config, MAX_COMMON_NAME_LEN},
+    ct::{Embedding, PrecertificatePoison},
+    handler::types::{CertificateProfile, IssuerName, KeyPurpose, SubjectPolicy},
+    mem::{candid_storable, Mem, Memory, Repository},
+    ocsp,
+    rotation::KeyRotations,
+};
+
+thread_local! {
+    static PUBLIC_KEYS: RefCell<PublicKeyCache> = RefCell::new(PublicKeyCache::init());
+    static SCHEME: RefCell<StableCell<KeyScheme, Memory>> = RefCell::new(
+        Mem::cell::<KeyScheme, _>(KeyScheme::default()),
+    );
+}
+
+/// name the root key is derived under, not the one its certificate carries, so configuring the
+/// issuer DN leaves the key as it is
 const ROOT_NAME: &str = "CN=ic.encrypt.icp";
-const ROOT_SERIAL_NUMBER: u64 = 0;
-/// 1 year in nanoseconds. This does not take into account the extra 1 day in a leap year
+pub const ROOT_SERIAL_NUMBER: u64 = 0;
+/// validity of the root, leaves take theirs from the selected profile. 1 year in nanoseconds, this
+/// does not take into account the extra 1 day in a leap year
 const ONE_YEAR_VALIDITY_NANOS: u64 = 31536000000000000;
 
 #[cfg(feature = "local")]
@@ -61,230 +95,598 @@ impl EcdsaKeyIds {
     }
 }
 
+/// What a derived key signs, the first component of its structured derivation path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyRole {
+    Root,
+    #[allow(unused)]
+    Intermediate,
+    #[allow(unused)]
+    OcspSigner,
+    #[allow(unused)]
+    Leaf,
+}
+
+impl KeyRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Root => "root",
+            Self::Intermediate => "intermediate",
+            Self::OcspSigner => "ocsp-signer",
+            Self::Leaf => "leaf",
+        }
+    }
+}
+
+/// How the first version of every key is derived. Keys of canisters installed before structured
+/// paths existed stay where they are, anything else would silently swap the key behind the root,
+/// the tenants and the client mode accounts. Rotated versions are always structured.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyScheme {
+    /// `[namespace, Keccak-512(DER subject ‖ serial)]`, what every key was derived under before
+    #[default]
+    Legacy,
+    /// `[namespace, role, version, SHA-256(DER subject), serial]`
+    Structured,
+}
+
+candid_storable!(KeyScheme);
+
+impl KeyScheme {
+    /// the scheme of the first version of every key, [`Self::Legacy`] unless set at install
+    pub fn initial() -> Self {
+        SCHEME.with_borrow(|cell| *cell.get())
+    }
+
+    /// a fresh install has no key yet, all of them can be structured
+    pub fn install() {
+        SCHEME.with_borrow_mut(|cell| {
+            if let Err(e) = cell.set(Self::Structured) {
+                Mem::write_failed("the key scheme", e);
+            }
+        })
+    }
+}
+
+/// version every key starts at, rotating a key derives it again under the next one
+pub const INITIAL_KEY_VERSION: u32 = 1;
+
+/// A tECDSA key derived under `[namespace, role, version, SHA-256(DER subject), serial]`, the
+/// namespace only when set, or under the path of [`KeyScheme::Legacy`]. Keys of different roles,
+/// subjects or versions never collide.
 #[derive(Clone, Debug)]
 pub struct AcmeKey {
+    role: KeyRole,
+    version: u32,
     domain: Name,
+    /// the name certificates carry for this key, `domain` unless renamed
+    name: Name,
     serial_number: u64,
+    /// extra derivation path component isolating keys of different tenants, empty for the default CA
+    namespace: Vec<u8>,
+    /// the tenant whose CA the key belongs to, see [`Self::with_tenant`]
+    tenant: Option<String>,
 }
 
 impl AcmeKey {
-    pub fn new_root() -> Self {
-        Self {
-            domain: Name::from_str(ROOT_NAME).unwrap(),
-            serial_number: ROOT_SERIAL_NUMBER,
-        }
-    }
-    pub fn new(domain: Name, serial_number: u64) -> Self {
+    pub fn new(role: KeyRole, domain: Name, serial_number: u64) -> Self {
         Self {
+            role,
+            version: INITIAL_KEY_VERSION,
+            name: domain.clone(),
             domain,
             serial_number,
+            namespace: Vec::new(),
+            tenant: None,
         }
     }
 
-    pub fn id(&self) -> Vec<u8> {
-        let mut buff = Vec::new();
+    pub fn new_root() -> Self {
+        Self::new(
+            KeyRole::Root,
+            Name::from_str(ROOT_NAME).unwrap(),
+            ROOT_SERIAL_NUMBER,
+        )
+        .with_name(Certificate::root_name())
+    }
 
-        let mut hasher = Keccak::v512();
+    /// the same key under another name, the derivation path stays as it is
+    pub fn with_name(mut self, name: Name) -> Self {
+        self.name = name;
+        self
+    }
 
-        self.domain.encode_to_slice(&mut buff).unwrap();
+    /// the key of tenant `id`, derived under its own namespace so it never collides with the
+    /// default CA or another tenant
+    pub fn with_tenant(mut self, id: &str) -> Self {
+        self.namespace = format!("tenant:{id}").into_bytes();
+        self.tenant = Some(id.to_string());
+        self
+    }
 
-        hasher.update(&buff);
-        hasher.update(&self.serial_number.to_bytes_checked());
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
 
-        buff.clear();
+    /// the root key certificates, CRLs and OCSP responses are signed with, see [`KeyRotations`]
+    pub fn issuer() -> Self {
+        Self::new_root().with_version(KeyRotations::active_version())
+    }
 
-        hasher.finalize(&mut buff);
+    /// the same key derived anew, e.g. `version + 1` to rotate it
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
 
-        buff
+    pub fn version(&self) -> u32 {
+        self.version
     }
 
-    pub fn is_root(&self) -> bool {
-        self.domain.is_empty()
+    pub fn scheme(&self) -> KeyScheme {
+        match self.version > INITIAL_KEY_VERSION {
+            true => KeyScheme::Structured,
+            false => KeyScheme::initial(),
+        }
     }
 
-    pub fn hash_mesage(msg: &[u8], buff: &mut [u8]) {
-        let mut hasher = Keccak::v512();
+    pub fn derivation_path(&self) -> Vec<Vec<u8>> {
+        let mut path = Vec::new();
 
-        hasher.update(msg);
+        if !self.namespace.is_empty() {
+            path.push(self.namespace.clone());
+        }
 
-        hasher.finalize(buff);
-    }
-}
+        let subject = self.domain.to_der().unwrap_or_default();
 
-#[derive(Clone, Debug)]
-pub struct AcmeVerifyingKey(PublicKey<Secp256k1>);
+        if self.scheme() == KeyScheme::Legacy {
+            let mut hasher = Keccak::v512();
+            hasher.update(&subject);
+            hasher.update(&self.serial_number.to_be_bytes());
+
+            let mut id = vec![0; 64];
+            hasher.finalize(&mut id);
+            path.push(id);
+
+            return path;
+        }
+
+        path.extend([
+            self.role.as_str().as_bytes().to_vec(),
+            self.version.to_be_bytes().to_vec(),
+            Sha256::digest(subject).to_vec(),
+            self.serial_number.to_be_bytes().to_vec(),
+        ]);
 
-impl spki::EncodePublicKey for AcmeVerifyingKey {
-    fn to_public_key_der(&self) -> spki::Result<spki::Document> {
-        self.0.to_public_key_der()
+        path
     }
-}
 
-impl signature::Keypair for AcmeKey {
-    type VerifyingKey = AcmeVerifyingKey;
+    /// the tECDSA public key behind this key's derivation path, only asked from the management
+    /// canister the first time
+    pub async fn public_key(&self) -> anyhow::Result<PublicKey<Secp256k1>> {
+        let derivation_path = self.derivation_path();
+
+        if let Some(key) = PublicKeyCache::get(&derivation_path) {
+            return anyhow::Ok(key);
+        }
 
-    fn verifying_key(&self) -> Self::VerifyingKey {
-        let pub_key_req = ecdsa::EcdsaPublicKeyArgument {
-            canister_id: Some(ic_cdk::id()),
-            derivation_path: vec![self.id()],
-            key_id: EcdsaKeyIds::TestKeyLocalDevelopment.to_key_id(),
+        let arg = ecdsa::EcdsaPublicKeyArgument {
+            canister_id: None,
+            derivation_path: derivation_path.clone(),
+            key_id: ECDSA_KEY_ID.to_key_id(),
         };
 
-        let pub_key = Rc::new(RefCell::new(EcdsaPublicKeyResponse::default()));
-        let pub_key_transport = pub_key.clone();
+        let (response,) = ecdsa_public_key(arg)
+            .await
+            .map_err(|(code, msg)| anyhow!("ecdsa_public_key failed: {code:?} {msg}"))?;
 
-        let fut = async move {
-            let (response,) = ecdsa_public_key(pub_key_req).await.unwrap();
+        let key = k256::PublicKey::from_sec1_bytes(&response.public_key)?;
+        PublicKeyCache::insert(&derivation_path, response.public_key);
 
-            *pub_key_transport.borrow_mut() = response;
-        };
+        anyhow::Ok(key)
+    }
 
-        ic_cdk::spawn(fut);
+    /// ecdsa-with-SHA256 signature over `msg`, awaited from the management canister
+    pub async fn sign(&self, msg: &[u8]) -> anyhow::Result<DerSignature> {
+        let arg = SignWithEcdsaArgument {
+            message_hash: Sha256::digest(msg).to_vec(),
+            derivation_path: self.derivation_path(),
+            key_id: ECDSA_KEY_ID.to_key_id(),
+        };
 
-        let pub_key = Rc::into_inner(pub_key).unwrap().into_inner();
+        let (response,) = sign_with_ecdsa(arg)
+            .await
+            .map_err(|(code, msg)| anyhow!("sign_with_ecdsa failed: {code:?} {msg}"))?;
 
-        let pub_key = k256::PublicKey::from_sec1_bytes(&pub_key.public_key).unwrap();
+        anyhow::Ok(k256::ecdsa::Signature::try_from(response.signature.as_slice())?.to_der())
+    }
 
-        AcmeVerifyingKey(pub_key)
+    /// algorithm identifier matching signatures produced by [`AcmeKey::sign`]
+    pub fn signature_algorithm() -> AlgorithmIdentifierOwned {
+        AlgorithmIdentifierOwned {
+            oid: ECDSA_WITH_SHA_256,
+            parameters: None,
+        }
     }
 }
 
-impl DynSignatureAlgorithmIdentifier for AcmeKey {
-    fn signature_algorithm_identifier(&self) -> spki::Result<spki::AlgorithmIdentifierOwned> {
-        let verifying_key = self.verifying_key();
-        let subject_public_key_info = SubjectPublicKeyInfo::from_key(verifying_key).unwrap();
+/// SEC1 encoded tECDSA public keys by derivation path, they never change for a given key id so
+/// the management canister is asked once per key.
+pub struct PublicKeyCache {
+    keys: Repository<[u8; 32], Vec<u8>>,
+}
 
-        Ok(subject_public_key_info.algorithm)
+impl PublicKeyCache {
+    fn init() -> Self {
+        Self {
+            keys: Repository::init::<Self>(),
+        }
     }
-}
 
-impl signature::Signer<Asn1EncodedSignature> for AcmeKey {
-    fn try_sign(&self, msg: &[u8]) -> Result<Asn1EncodedSignature, signature::Error> {
-        let id = self.id();
-        let mut message_hash = Vec::with_capacity(32);
+    /// the key id is part of the entry, so a cache never outlives a switch to another master key
+    fn entry(derivation_path: &[Vec<u8>]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(ECDSA_KEY_ID.to_key_id().name.as_bytes());
 
-        Self::hash_mesage(msg, &mut message_hash);
+        for component in derivation_path {
+            hasher.update((component.len() as u32).to_be_bytes());
+            hasher.update(component);
+        }
 
-        let arg = SignWithEcdsaArgument {
-            message_hash,
-            derivation_path: vec![id],
-            key_id: ECDSA_KEY_ID.to_key_id(),
-        };
+        hasher.finalize().into()
+    }
+
+    pub fn get(derivation_path: &[Vec<u8>]) -> Option<PublicKey<Secp256k1>> {
+        let sec1 = PUBLIC_KEYS.with_borrow(|c| c.keys.get(&Self::entry(derivation_path)))?;
+
+        k256::PublicKey::from_sec1_bytes(&sec1).ok()
+    }
 
-        let sig = Rc::new(RefCell::new(SignWithEcdsaResponse::default()));
-        let sig_transport = sig.clone();
+    fn insert(derivation_path: &[Vec<u8>], sec1: Vec<u8>) {
+        PUBLIC_KEYS.with_borrow_mut(|c| c.keys.insert(Self::entry(derivation_path), sec1));
+    }
+}
+
+/// Certificates assembled around a tECDSA signature. x509-cert's `CertificateBuilder` wants a
+/// synchronous `Signer`, which `sign_with_ecdsa` can never be, so the TBSCertificate is encoded
+/// here, signed with [`AcmeKey::sign`] and the certificate put together by hand.
+pub struct Certificate;
+
+impl Certificate {
+    /// name of the issuing root, the subject of the root certificate once there is one so a
+    /// changed issuer DN doesn't break the chains of the leaves it signs
+    pub fn root_name() -> Name {
+        CertificateManager::root_name().unwrap_or_else(Self::configured_name)
+    }
 
-        let fut = async move {
-            let (response,) = sign_with_ecdsa(arg).await.unwrap();
+    /// the issuer DN of the server config, what the next root is issued to
+    pub fn configured_name() -> Name {
+        Self::distinguished_name(&Config::issuer_name())
+            .expect("validated issuer names always encode")
+    }
 
-            *sig_transport.borrow_mut() = response;
+    fn rdn(
+        oid: ObjectIdentifier,
+        tag: Tag,
+        value: &str,
+    ) -> anyhow::Result<RelativeDistinguishedName> {
+        let atv = AttributeTypeAndValue {
+            oid,
+            value: Any::new(tag, value.as_bytes())?,
         };
 
-        ic_cdk::spawn(fut);
+        anyhow::Ok(RelativeDistinguishedName::try_from(vec![atv])?)
+    }
+
+    /// `C`, `O` and `CN` from the most general to the most specific, tagged as `Name::from_str`
+    /// would tag them
+    fn distinguished_name(name: &IssuerName) -> anyhow::Result<Name> {
+        let mut rdns = Vec::new();
 
-        let sig = Rc::into_inner(sig).unwrap().into_inner().signature;
+        if let Some(country) = &name.country {
+            rdns.push(Self::rdn(COUNTRY_NAME, Tag::PrintableString, country)?);
+        }
+        if let Some(organization) = &name.organization {
+            rdns.push(Self::rdn(ORGANIZATION_NAME, Tag::Utf8String, organization)?);
+        }
+        rdns.push(Self::rdn(COMMON_NAME, Tag::Utf8String, &name.common_name)?);
 
-        Ok(k256::ecdsa::Signature::try_from(sig.as_slice())
-            .unwrap()
-            .to_der()
-            .into())
+        anyhow::Ok(Name::from(rdns))
     }
-}
 
-#[derive(Clone)]
-pub struct Asn1EncodedSignature(DerSignature);
+    /// Subject of a leaf for the validated `domains`, see [`SubjectPolicy`]. Nothing of the DN the
+    /// CSR asked for makes it into the certificate.
+    pub fn leaf_subject(domains: &[String]) -> anyhow::Result<Name> {
+        let common_name = match Config::subject_policy() {
+            SubjectPolicy::CommonName => domains.iter().find(|d| d.len() <= MAX_COMMON_NAME_LEN),
+            SubjectPolicy::Empty => None,
+        };
 
-impl Asn1EncodedSignature {
-    pub fn new(s: DerSignature) -> Self {
-        Self(s)
+        match common_name {
+            Some(cn) => anyhow::Ok(Name::from(vec![Self::rdn(
+                COMMON_NAME,
+                Tag::Utf8String,
+                cn,
+            )?])),
+            None => anyhow::Ok(Name::default()),
+        }
     }
-}
 
-impl From<DerSignature> for Asn1EncodedSignature {
-    fn from(value: DerSignature) -> Self {
-        Self::new(value)
+    /// dNSName entry of the SAN. A wildcard keeps its `*.` as the whole leftmost label, the only
+    /// form RFC 6125 §6.4.3 has clients match.
+    fn dns_name(domain: &str) -> anyhow::Result<GeneralName> {
+        let base = domain.strip_prefix("*.").unwrap_or(domain);
+
+        if base.contains('*') {
+            return Err(anyhow!(
+                "{domain} has a wildcard outside its leftmost label"
+            ));
+        }
+
+        anyhow::Ok(GeneralName::DnsName(Ia5String::new(domain)?))
     }
-}
 
-impl SignatureBitStringEncoding for Asn1EncodedSignature {
-    fn to_bitstring(&self) -> spki::der::Result<BitString> {
-        Ok(BitString::from_bytes(self.0.as_bytes()).unwrap())
+    /// RFC 5280 §4.2.1.2 method 1, the same identifier the CRL refers to its issuer with
+    fn key_identifier(spki: &SubjectPublicKeyInfoOwned) -> anyhow::Result<OctetString> {
+        anyhow::Ok(OctetString::new(
+            Sha1::digest(spki.subject_public_key.raw_bytes()).to_vec(),
+        )?)
     }
-}
 
-pub struct Certificate {
-    key: AcmeKey,
-}
+    fn push_extension(
+        extensions: &mut Vec<Extension>,
+        subject: &Name,
+        extension: &impl AsExtension,
+    ) -> anyhow::Result<()> {
+        let extension = extension.to_extension(subject, extensions)?;
+        extensions.push(extension);
 
-impl Certificate {
-    pub fn new(key: AcmeKey) -> Self {
-        Self { key }
+        anyhow::Ok(())
     }
 
-    pub fn root() -> Self {
-        let key = AcmeKey::new_root();
+    fn tbs(
+        serial_number: u64,
+        issuer: Name,
+        subject: Name,
+        subject_public_key_info: SubjectPublicKeyInfoOwned,
+        validity: Validity,
+        extensions: Vec<Extension>,
+    ) -> TbsCertificate {
+        TbsCertificate {
+            version: Version::V3,
+            serial_number: SerialNumber::from(serial_number),
+            signature: AcmeKey::signature_algorithm(),
+            issuer,
+            validity,
+            subject,
+            subject_public_key_info,
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: Some(extensions),
+        }
+    }
+
+    /// signs the DER encoded `tbs_certificate` with `issuer` and returns the PEM certificate
+    async fn sign(issuer: &AcmeKey, tbs_certificate: TbsCertificate) -> anyhow::Result<String> {
+        let signature = issuer.sign(&tbs_certificate.to_der()?).await?;
+
+        let cert = x509_cert::Certificate {
+            tbs_certificate,
+            signature_algorithm: AcmeKey::signature_algorithm(),
+            signature: BitString::from_bytes(signature.as_bytes())?,
+        };
 
-        Self { key }
+        // since we're in a fokin blockchain, just default to unix LF for now
+        anyhow::Ok(cert.to_pem(LineEnding::LF)?)
     }
 
-    pub fn root_name() -> Name {
-        Name::from_str(ROOT_NAME).unwrap()
+    /// self-signed CA certificate over the issuing root key
+    pub async fn build_root() -> anyhow::Result<String> {
+        let key = AcmeKey::issuer();
+
+        Self::build_ca(&key, &key, ROOT_SERIAL_NUMBER).await
     }
 
-    pub fn profile(&self) -> Profile {
-        if self.key.is_root() {
-            return Profile::Root;
+    /// CA certificate over the key of `subject` signed by `issuer`, self-signed when both are the
+    /// same key and a cross-sign otherwise
+    pub async fn build_ca(
+        issuer: &AcmeKey,
+        subject: &AcmeKey,
+        serial_number: u64,
+    ) -> anyhow::Result<String> {
+        let self_signed = issuer.derivation_path() == subject.derivation_path();
+        let spki = SubjectPublicKeyInfoOwned::from_key(subject.public_key().await?)?;
+        let subject = subject.name.clone();
+
+        let mut extensions = Vec::new();
+        Self::push_extension(
+            &mut extensions,
+            &subject,
+            &SubjectKeyIdentifier(Self::key_identifier(&spki)?),
+        )?;
+
+        // tells the key of a cross-signing issuer apart from the subject's own
+        if !self_signed {
+            let issuer_spki = SubjectPublicKeyInfoOwned::from_key(issuer.public_key().await?)?;
+
+            Self::push_extension(
+                &mut extensions,
+                &subject,
+                &AuthorityKeyIdentifier {
+                    key_identifier: Some(Self::key_identifier(&issuer_spki)?),
+                    authority_cert_issuer: None,
+                    authority_cert_serial_number: None,
+                },
+            )?;
         }
+        Self::push_extension(
+            &mut extensions,
+            &subject,
+            &BasicConstraints {
+                ca: true,
+                path_len_constraint: None,
+            },
+        )?;
+        Self::push_extension(
+            &mut extensions,
+            &subject,
+            &KeyUsage(KeyUsages::KeyCertSign | KeyUsages::CRLSign),
+        )?;
+
+        let tbs = Self::tbs(
+            serial_number,
+            issuer.name.clone(),
+            subject,
+            spki,
+            Self::generate_validity_info()?,
+            extensions,
+        );
 
-        // TODO we dont support subCA certificate for now
-        Profile::Leaf {
-            issuer: Self::root_name(),
-            enable_key_agreement: true,
-            enable_key_encipherment: true,
-        }
+        Self::sign(issuer, tbs).await
     }
 
-    pub fn build(self) -> String {
-        let verifying_key = self.key.verifying_key();
-
-        let profile = self.profile();
-        let key = self.key;
+    /// issues an end-entity certificate for `subject_public_key_info`, signed by `issuer`
+    #[allow(clippy::too_many_arguments)]
+    pub async fn build_leaf(
+        issuer: &AcmeKey,
+        serial_number: u64,
+        subject: Name,
+        subject_public_key_info: SubjectPublicKeyInfoOwned,
+        validity: Validity,
+        domains: &[String],
+        profile: &CertificateProfile,
+        ct: &Embedding,
+    ) -> anyhow::Result<String> {
+        let issuer_spki = SubjectPublicKeyInfoOwned::from_key(issuer.public_key().await?)?;
+
+        let san = domains
+            .iter()
+            .map(|d| Self::dns_name(d))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let purposes = profile
+            .key_purposes
+            .iter()
+            .map(|p| match p {
+                KeyPurpose::ServerAuth => ID_KP_SERVER_AUTH,
+                KeyPurpose::ClientAuth => ID_KP_CLIENT_AUTH,
+            })
+            .collect();
+
+        let mut extensions = Vec::new();
+        let ext = &mut extensions;
+
+        Self::push_extension(
+            ext,
+            &subject,
+            &SubjectKeyIdentifier(Self::key_identifier(&subject_public_key_info)?),
+        )?;
+        Self::push_extension(
+            ext,
+            &subject,
+            &AuthorityKeyIdentifier {
+                key_identifier: Some(Self::key_identifier(&issuer_spki)?),
+                authority_cert_issuer: None,
+                authority_cert_serial_number: None,
+            },
+        )?;
+        Self::push_extension(
+            ext,
+            &subject,
+            &BasicConstraints {
+                ca: false,
+                path_len_constraint: None,
+            },
+        )?;
+        Self::push_extension(
+            ext,
+            &subject,
+            &KeyUsage(
+                KeyUsages::DigitalSignature
+                    | KeyUsages::NonRepudiation
+                    | KeyUsages::KeyEncipherment
+                    | KeyUsages::KeyAgreement,
+            ),
+        )?;
+        // critical when the subject is empty, RFC 5280 §4.2.1.6
+        Self::push_extension(ext, &subject, &SubjectAltName(san))?;
+        Self::push_extension(ext, &subject, &ExtendedKeyUsage(purposes))?;
+
+        if profile.revocation_pointers {
+            Self::push_extension(ext, &subject, &ocsp::authority_info_access()?)?;
+            Self::push_extension(ext, &subject, &crate::crl::distribution_points(issuer)?)?;
+        }
 
-        let serial_number = SerialNumber::from(key.serial_number);
-        let validity = Self::generate_validity_info();
-        let subject = key.domain.to_owned();
-        let subject_public_key_info = SubjectPublicKeyInfo::from_key(verifying_key).unwrap();
+        match ct {
+            Embedding::None => {}
+            Embedding::Poison => Self::push_extension(ext, &subject, &PrecertificatePoison)?,
+            Embedding::Scts(scts) => Self::push_extension(ext, &subject, scts)?,
+        }
 
-        let cert = CertificateBuilder::new(
-            profile,
+        let tbs = Self::tbs(
             serial_number,
-            validity,
+            issuer.name.clone(),
             subject,
             subject_public_key_info,
-            &key,
-        )
-        .unwrap();
+            validity,
+            extensions,
+        );
 
-        let cert = cert.build().unwrap();
+        Self::sign(issuer, tbs).await
+    }
 
-        // since we're in a fokin blockchain, just default to unix LF for now
-        cert.to_pem(LineEnding::LF).unwrap()
+    /// validity window between two IC timestamps, GeneralizedTime ends with the year 9999
+    pub fn validity(not_before_nanos: u64, not_after_nanos: u64) -> anyhow::Result<Validity> {
+        let time = |nanos| {
+            GeneralizedTime::from_unix_duration(Duration::from_nanos(nanos))
+                .map(Time::GeneralTime)
+                .map_err(|e| anyhow!("{nanos} is no certificate time: {e}"))
+        };
+
+        anyhow::Ok(Validity {
+            not_before: time(not_before_nanos)?,
+            not_after: time(not_after_nanos)?,
+        })
     }
 
-    fn generate_validity_info() -> Validity {
-        let now = Duration::from_nanos(ic_cdk::api::time());
-        let expiry = now + Duration::from_nanos(ONE_YEAR_VALIDITY_NANOS);
+    /// backdated like leaves, see [`Config::clock_skew`]
+    fn generate_validity_info() -> anyhow::Result<Validity> {
+        let now = ic_cdk::api::time();
+        let skew = Config::clock_skew().as_nanos() as u64;
+
+        Self::validity(now.saturating_sub(skew), now + ONE_YEAR_VALIDITY_NANOS)
+    }
+}
 
-        let not_before = Time::GeneralTime(GeneralizedTime::from_unix_duration(now).unwrap());
-        let not_after = Time::GeneralTime(GeneralizedTime::from_unix_duration(expiry).unwrap());
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Validity {
-            not_before,
-            not_after,
+    fn key(role: KeyRole) -> AcmeKey {
+        AcmeKey::new(role, Name::from_str(ROOT_NAME).unwrap(), ROOT_SERIAL_NUMBER).with_version(2)
+    }
+
+    #[test]
+    fn roles_and_versions_derive_distinct_paths() {
+        let roles = [
+            KeyRole::Root,
+            KeyRole::Intermediate,
+            KeyRole::OcspSigner,
+            KeyRole::Leaf,
+        ];
+        let mut paths: Vec<_> = roles.iter().map(|&r| key(r).derivation_path()).collect();
+        paths.push(key(KeyRole::Root).with_version(3).derivation_path());
+
+        for (i, a) in paths.iter().enumerate() {
+            assert!(paths[i + 1..].iter().all(|b| a != b));
         }
     }
 
-    pub fn build_root() -> String {
-        Self::root().build()
+    #[test]
+    fn namespace_prefixes_the_path() {
+        let path = key(KeyRole::Root).with_tenant("a").derivation_path();
+
+        assert_eq!(path[0], b"tenant:a");
+        assert_eq!(path[1], b"root");
     }
 }
@@ -1,12 +1,104 @@
+// the crate keeps the name of the dfx canister it builds
+#![allow(non_snake_case)]
+
+mod account;
+mod acme_client;
+mod api;
+mod audit;
+mod authz;
+mod billing;
+mod blocklist;
+mod caa;
+mod capability;
+mod ceremony;
 mod cert_manager;
+mod certification;
+mod challenge;
+mod client;
+mod clock;
+mod compression;
+mod config;
+mod crl;
+mod csr;
+mod ct;
+mod debug_capture;
+mod dns;
+mod events;
+mod expiry;
 mod handler;
+mod health;
+mod idempotency;
+mod inspect;
+mod issuance;
+mod issuance_lock;
+mod jobs;
 mod key;
+mod load_shed;
+mod maintenance;
+mod media;
 mod mem;
+mod metrics;
+mod nonce;
+mod ocsp;
+mod order;
+mod outcall;
+mod pickup;
+mod policy;
+mod prober;
+mod profile;
+mod psl;
+mod quota;
+mod rate_limit;
+mod revocation;
+mod rotation;
+mod router;
+mod source;
+mod streaming;
+mod tenant;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod thumbprint;
+mod token;
+mod trace;
+mod upgrade;
 
-#[ic_cdk::query]
-fn greet(name: String) -> String {
-    format!("Hello, {}!", name)
-}
+use account::AccountManager;
+use acme_client::DnsChallenge;
+use api::{ApiError, ApiResult};
+use audit::{AuditActor, AuditLog, AuditPage, AuditRetention};
+use authz::{AuthorizationState, AuthorizationStore};
+use billing::{Billing, BillingProfile, Payer};
+use blocklist::{BlockedKey, KeyBlocklist};
+use candid::Principal;
+use ceremony::{CeremonyEntry, CeremonyTranscript, SignedTranscript};
+use cert_manager::{
+    CertificateManager, CertificateMatch, CertificateOwner, IssuedCertificate, IssuerChain,
+};
+use client::{
+    environment::{ClientEnvironments, ClientProfile},
+    OrderPlan, ServerLimits,
+};
+use config::Config;
+use ct::{CtReceipt, CtReceiptStore};
+use debug_capture::{CaptureEntry, DebugCapture};
+use events::{EventDelivery, EventKind, Events, Subscription};
+use expiry::{ExpiringCertificate, ExpiryMonitor};
+use handler::types::{AccountQuota, CertificateProfile, RateLimit, ServerConfig};
+use health::HealthStatus;
+use jobs::{JobInfo, JobQueue};
+use load_shed::{LoadShedConfig, LoadShedStatus, LoadShedder};
+use maintenance::Maintenance;
+use metrics::Metrics;
+use nonce::{NoncePool, NoncePoolConfig, NoncePoolStatus};
+use order::{OrderManager, StoredOrder};
+use profile::IssuanceOptions;
+use psl::PublicSuffixListStatus;
+use quota::{AccountQuotas, QuotaStatus};
+use revocation::{Revocation, RevocationRegistry};
+use rotation::{KeyRotation, KeyRotations};
+use streaming::{StreamingCallbackHttpResponse, StreamingHttpResponse, StreamingToken};
+use tenant::{Tenant, TenantPolicy, TenantRegistry};
+use token::{ChallengeToken, TokenStore};
 
 // In the following, we register a custom getrandom implementation because
 // otherwise getrandom (which is a dependency of k256) fails to compile.
@@ -17,9 +109,653 @@ pub fn always_fail(_buf: &mut [u8]) -> Result<(), getrandom::Error> {
     Err(getrandom::Error::UNSUPPORTED)
 }
 
+#[ic_cdk::init]
+fn init() {
+    audit::install_panic_hook();
+    upgrade::stamp_versions();
+    key::KeyScheme::install();
+    certification::init();
+    crl::start_refresh_timer();
+    psl::start_refresh_timer();
+    issuance_lock::start_prune_timer();
+    expiry::start_timer();
+    jobs::start_worker();
+    nonce::start();
+    audit::start_rotation();
+    capability::start();
+    token::start();
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    if let Err(e) = upgrade::save() {
+        ic_cdk::trap(&e.to_string());
+    }
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    audit::install_panic_hook();
+
+    if let Err(e) = upgrade::migrate() {
+        ic_cdk::trap(&e.to_string());
+    }
+
+    if let Err(e) = upgrade::restore() {
+        ic_cdk::trap(&e.to_string());
+    }
+
+    certification::init();
+    router::certify_resources();
+    crl::start_refresh_timer();
+    psl::start_refresh_timer();
+    issuance_lock::start_prune_timer();
+    expiry::start_timer();
+    jobs::start_worker();
+    nonce::start();
+    audit::start_rotation();
+    capability::start();
+    token::start();
+}
+
+/// rejects oversized and malformed ingress calls before they are executed
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    match inspect::check(&ic_cdk::api::call::method_name()) {
+        Ok(()) => ic_cdk::api::call::accept_message(),
+        Err(e) => ic_cdk::trap(&e.to_string()),
+    }
+}
+
+#[ic_cdk::query]
+pub fn http_request(req: ic_http_certification::HttpRequest) -> StreamingHttpResponse {
+    streaming::chunked(&req, router::dispatch_query(&req))
+}
+
+/// the chunks after the first of a response too large for one message
+#[ic_cdk::query]
+fn http_request_streaming_callback(token: StreamingToken) -> StreamingCallbackHttpResponse {
+    streaming::callback(token).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()))
+}
+
+#[ic_cdk::update]
+pub async fn http_request_update(
+    req: ic_http_certification::HttpUpdateRequest<'_>,
+) -> ic_http_certification::HttpResponse<'static> {
+    router::dispatch_update(&req).await
+}
+
+fn caller_is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Ok(());
+    }
+
+    Err("caller is not a controller".to_string())
+}
+
+#[ic_cdk::query]
+fn api_version() -> String {
+    api::API_VERSION.to_string()
+}
+
+/// also served as JSON at `/health`
+#[ic_cdk::query]
+fn health() -> HealthStatus {
+    health::status()
+}
+
+/// also served in the Prometheus text format at `/metrics`
+#[ic_cdk::query]
+fn metrics() -> Metrics {
+    metrics::current()
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn server_config() -> ServerConfig {
+    Config::get()
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_server_config(config: ServerConfig) -> ApiResult<()> {
+    Config::set(config)?;
+
+    // picks up changed revocation windows right away
+    crl::start_refresh_timer();
+
+    Ok(())
+}
+
+/// profiles orders and `IssuanceOptions` can select, as advertised in the directory
+#[ic_cdk::query]
+fn certificate_profiles() -> Vec<CertificateProfile> {
+    Config::profiles()
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_load_shed_config(config: LoadShedConfig) -> ApiResult<()> {
+    LoadShedder::configure(config)
+}
+
+#[ic_cdk::query]
+fn load_shed_status() -> LoadShedStatus {
+    LoadShedder::status()
+}
+
+/// Enters or leaves maintenance. While in it, new ACME accounts and new orders are refused with
+/// 503 and `Retry-After`. Orders in flight, downloads, OCSP and the directory keep working.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_maintenance(
+    enabled: bool,
+    retry_after_secs: Option<u64>,
+    message: Option<String>,
+) -> ApiResult<Maintenance> {
+    Maintenance::set(enabled, retry_after_secs, message)
+}
+
+#[ic_cdk::query]
+fn maintenance() -> Maintenance {
+    Maintenance::get()
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_nonce_pool_config(config: NoncePoolConfig) -> ApiResult<()> {
+    NoncePool::configure(config)
+}
+
+#[ic_cdk::query]
+fn nonce_pool_status() -> NoncePoolStatus {
+    NoncePool::status()
+}
+
+/// queued issuance and webhook jobs, parked ones ran out of attempts and wait for a requeue
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn list_jobs() -> Vec<JobInfo> {
+    JobQueue::list()
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn requeue_job(order: u64) -> ApiResult<()> {
+    JobQueue::requeue(order)
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn cancel_job(order: u64) -> ApiResult<()> {
+    JobQueue::cancel(order)
+}
+
+/// an empty `base_path` serves the tenant under `/t/<id>`. There is no default tenant, ACME is
+/// only served below the base path of one created here
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn create_tenant(tenant: Tenant) -> ApiResult<Tenant> {
+    TenantRegistry::create(tenant)
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn delete_tenant(id: String) -> ApiResult<Tenant> {
+    TenantRegistry::remove(&id)
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_tenant_admins(id: String, admins: Vec<Principal>) -> ApiResult<()> {
+    let mut tenant = TenantRegistry::ensure_admin(&id, &ic_cdk::caller())?;
+    tenant.admins = admins;
+
+    TenantRegistry::update(tenant)
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn list_tenants() -> Vec<Tenant> {
+    TenantRegistry::list()
+}
+
+#[ic_cdk::query]
+fn get_tenant(id: String) -> ApiResult<Tenant> {
+    TenantRegistry::ensure_admin(&id, &ic_cdk::caller())
+}
+
+#[ic_cdk::update]
+fn set_tenant_policy(id: String, policy: TenantPolicy) -> ApiResult<()> {
+    let mut tenant = TenantRegistry::ensure_admin(&id, &ic_cdk::caller())?;
+    tenant.policy = policy;
+
+    TenantRegistry::update(tenant)
+}
+
+#[ic_cdk::update]
+fn set_tenant_rate_limit(id: String, rate_limit: RateLimit) -> ApiResult<()> {
+    let mut tenant = TenantRegistry::ensure_admin(&id, &ic_cdk::caller())?;
+    tenant.rate_limit = rate_limit;
+
+    TenantRegistry::update(tenant)
+}
+
+/// derives the next version of the tenant's key and issues its leaves with it from now on,
+/// returns the new version
+#[ic_cdk::update]
+async fn rotate_tenant_key(id: String) -> ApiResult<u32> {
+    let version = TenantRegistry::rotate_key(&id, &ic_cdk::caller()).await?;
+    crl::refresh_in_background();
+
+    Ok(version)
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn enable_debug_capture(account_id: String, duration_secs: u64) -> ApiResult<u64> {
+    DebugCapture::enable(account_id, duration_secs)
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn disable_debug_capture(account_id: String) {
+    DebugCapture::disable(&account_id)
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn debug_capture_entries(account_id: Option<String>, start: u64, limit: u64) -> Vec<CaptureEntry> {
+    DebugCapture::entries(account_id, start, limit)
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn clear_debug_capture() {
+    DebugCapture::clear()
+}
+
+/// issuance, revocation and account events from sequence `start` on, oldest first
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn audit_log(start: u64, limit: u32) -> ApiResult<AuditPage> {
+    AuditLog::page(start, limit)
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn audit_retention() -> AuditRetention {
+    AuditLog::retention()
+}
+
+/// entries older than the retention, or past its size, are rotated out oldest first
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_audit_retention(retention: AuditRetention) -> ApiResult<()> {
+    AuditLog::set_retention(retention)
+}
+
+/// one page of accounts in the documented JSON export schema, for migrating to another CA
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn export_accounts(after: Option<String>, limit: u32) -> ApiResult<String> {
+    serde_json::to_string_pretty(&AccountManager::export(after, limit))
+        .map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+/// registers the accounts of an exported page, returns how many were new
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn import_accounts(export: String) -> ApiResult<u64> {
+    let export =
+        serde_json::from_str(&export).map_err(|e| ApiError::InvalidArgument(e.to_string()))?;
+
+    AccountManager::import(export).map_err(|e| ApiError::InvalidArgument(e.to_string()))
+}
+
+/// replaces the configured quota of one account, e.g. for a trusted integrator, `None` goes back
+/// to the configured one
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_account_quota(account_id: String, quota: Option<AccountQuota>) -> ApiResult<()> {
+    AccountManager::set_quota(&account_id, quota).map(|_| ())
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn account_quota(account_id: String) -> ApiResult<QuotaStatus> {
+    AccountQuotas::status(&account_id)
+}
+
+/// the caller offers to pay the certificate fees of `consumer` from its `subaccount`, drawing on
+/// the ICRC-2 approval the caller gave this canister, once `consumer` accepts. An ACME account
+/// accepts with `billingPayer` in an account update.
 #[ic_cdk::update]
-pub fn http_request_update(
-    req: ic_http_certification::HttpUpdateRequest,
-) -> ic_http_certification::HttpResponse {
-    todo!()
+fn link_billing_account(consumer: Payer, subaccount: Option<Vec<u8>>) -> ApiResult<BillingProfile> {
+    Billing::link(consumer, ic_cdk::caller(), subaccount)
 }
+
+/// the caller accepts `payer`, which proposed to pay its fees with `link_billing_account`
+#[ic_cdk::update]
+fn accept_billing_payer(payer: Principal) -> ApiResult<BillingProfile> {
+    Billing::accept(Payer::Principal(ic_cdk::caller()), payer)
+}
+
+#[ic_cdk::update]
+fn unlink_billing_account(consumer: Payer) -> ApiResult<BillingProfile> {
+    Billing::unlink(consumer, ic_cdk::caller())
+}
+
+#[ic_cdk::query]
+fn billing_profile(consumer: Payer) -> BillingProfile {
+    Billing::profile(&consumer)
+}
+
+/// issues the certificates of `consumer` without a fee
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_billing_exempt(consumer: Payer, exempt: bool) -> BillingProfile {
+    Billing::set_exempt(consumer, exempt)
+}
+
+/// POSTs signed `events` of `owner`'s certificates to `url`, the caller's own by default; only
+/// controllers subscribe for other owners
+#[ic_cdk::update]
+fn create_subscription(
+    url: String,
+    secret: String,
+    events: Vec<EventKind>,
+    owner: Option<CertificateOwner>,
+) -> ApiResult<Subscription> {
+    Events::subscribe(ic_cdk::caller(), owner, url, secret, events)
+}
+
+#[ic_cdk::update]
+fn delete_subscription(id: u64) -> ApiResult<()> {
+    Events::unsubscribe(ic_cdk::caller(), id)
+}
+
+#[ic_cdk::query]
+fn subscriptions() -> Vec<Subscription> {
+    Events::list(ic_cdk::caller())
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn dead_letters() -> Vec<EventDelivery> {
+    Events::dead_letters()
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn redeliver_event(id: u64) -> ApiResult<()> {
+    Events::redeliver(id)
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn drop_dead_letter(id: u64) -> ApiResult<()> {
+    Events::drop_dead_letter(id)
+}
+
+#[ic_cdk::update]
+async fn request_certificate(
+    domains: Vec<String>,
+    csr_der: Vec<u8>,
+    options: Option<IssuanceOptions>,
+) -> ApiResult<IssuedCertificate> {
+    issuance::request_certificate(
+        ic_cdk::caller(),
+        domains,
+        csr_der,
+        options.unwrap_or_default(),
+    )
+    .await
+}
+
+/// queues issuance and returns the order in `processing`, `notify_url` receives a signed pickup
+/// URL once the certificate is ready
+#[ic_cdk::update]
+fn submit_order(
+    domains: Vec<String>,
+    csr_der: Vec<u8>,
+    notify_url: Option<String>,
+    options: Option<IssuanceOptions>,
+) -> ApiResult<StoredOrder> {
+    issuance::submit_order(
+        ic_cdk::caller(),
+        domains,
+        csr_der,
+        notify_url,
+        options.unwrap_or_default(),
+    )
+}
+
+#[ic_cdk::query]
+fn get_order(id: u64) -> ApiResult<StoredOrder> {
+    let caller = ic_cdk::caller();
+
+    OrderManager::get(id)
+        .filter(|o| {
+            o.owner == CertificateOwner::Canister(caller) || ic_cdk::api::is_controller(&caller)
+        })
+        .ok_or_else(|| ApiError::NotFound(format!("order {id}")))
+}
+
+/// validation state of every name of the order, with the error and next attempt of a retried one
+#[ic_cdk::query]
+fn order_authorizations(id: u64) -> ApiResult<Vec<AuthorizationState>> {
+    get_order(id).map(|o| AuthorizationStore::for_order(o.id))
+}
+
+/// the challenge token of every name of the order, the http-01 resource is served at it
+#[ic_cdk::query]
+fn order_challenge_tokens(id: u64) -> ApiResult<Vec<ChallengeToken>> {
+    get_order(id).map(|o| TokenStore::for_order(o.id))
+}
+
+#[ic_cdk::query]
+fn get_certificate(serial: u64) -> ApiResult<IssuedCertificate> {
+    CertificateManager::get(serial)
+        .ok_or_else(|| ApiError::NotFound(format!("certificate {serial}")))
+}
+
+/// every submission of the certificate's precertificate to a CT log, in submission order
+#[ic_cdk::query]
+fn ct_receipts(serial: u64) -> Vec<CtReceipt> {
+    CtReceiptStore::for_certificate(serial)
+}
+
+/// archives a certificate issued elsewhere, `notify_url` is warned 30 days before it expires
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn import_certificate(
+    pem_chain: String,
+    notify_url: Option<String>,
+) -> ApiResult<IssuedCertificate> {
+    if let Some(url) = &notify_url {
+        pickup::check_notify_url(url).map_err(|e| ApiError::InvalidArgument(e.to_string()))?;
+    }
+
+    CertificateManager::import(
+        &pem_chain,
+        CertificateOwner::Canister(ic_cdk::caller()),
+        notify_url,
+    )
+    .map_err(|e| ApiError::InvalidArgument(e.to_string()))
+}
+
+/// adds a chain certificates are also offered with, `pem` starts with the issuer of the leaves
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn add_issuer_chain(pem: String) -> ApiResult<IssuerChain> {
+    CertificateManager::add_chain(&pem).map_err(|e| ApiError::InvalidArgument(e.to_string()))
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn remove_issuer_chain(id: u64) -> ApiResult<()> {
+    CertificateManager::remove_chain(id)
+        .map(|_| ())
+        .ok_or_else(|| ApiError::NotFound(format!("issuer chain {id}")))
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn issuer_chains() -> Vec<IssuerChain> {
+    CertificateManager::chains()
+}
+
+/// issued and imported certificates naming `domain`
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn certificates_for_domain(domain: String) -> Vec<IssuedCertificate> {
+    CertificateManager::for_domain(&domain)
+}
+
+/// Certificates naming `domain`, or with `subdomains` any name below it too, newest first.
+/// Controllers see every certificate, other callers those requested by themselves.
+#[ic_cdk::query]
+fn search_certificates(domain: String, subdomains: bool) -> Vec<CertificateMatch> {
+    let caller = ic_cdk::caller();
+    let owner =
+        (!ic_cdk::api::is_controller(&caller)).then_some(CertificateOwner::Canister(caller));
+    let now = clock::now_nanos();
+
+    CertificateManager::search(&domain, subdomains, owner.as_ref())
+        .iter()
+        .map(|cert| cert.to_match(now))
+        .collect()
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn expiring_certificates(days: u32) -> Vec<IssuedCertificate> {
+    expiry::expiring_within(days)
+}
+
+/// certificates the daily check found inside their renewal window, with their renewal orders
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn renewal_due() -> Vec<ExpiringCertificate> {
+    ExpiryMonitor::expiring()
+}
+
+/// certificates issued to the caller from now on are ordered again once they are due for renewal
+#[ic_cdk::update]
+fn set_auto_renew(enabled: bool) -> ApiResult<()> {
+    ExpiryMonitor::set_auto_renew(ic_cdk::caller(), enabled)
+}
+
+#[ic_cdk::query]
+fn auto_renew() -> bool {
+    ExpiryMonitor::auto_renew(&ic_cdk::caller())
+}
+
+/// TXT value the caller has to publish at `_acme-challenge.<domain>` before requesting a certificate
+#[ic_cdk::query]
+fn dns01_proof_value() -> String {
+    challenge::dns01_txt_value(&challenge::canister_key_authorization(&ic_cdk::caller()))
+}
+
+/// `acmeIdentifier` the caller's tls-alpn-01 certificate has to carry, for when dns-01 can't be used
+#[ic_cdk::query]
+fn tls_alpn01_digest() -> Vec<u8> {
+    challenge::tls_alpn01_digest(&challenge::canister_key_authorization(&ic_cdk::caller()))
+}
+
+/// checks a planned certificate against policy and the target server's limits before ordering it
+#[ic_cdk::query]
+fn plan_order(
+    domains: Vec<String>,
+    limits: Option<ServerLimits>,
+    csr_der: Option<Vec<u8>>,
+) -> OrderPlan {
+    client::plan_order(&domains, &limits.unwrap_or_default(), csr_der.as_deref())
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_client_profile(profile: ClientProfile) -> ApiResult<()> {
+    ClientEnvironments::configure(profile)
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn client_environments() -> ClientEnvironments {
+    ClientEnvironments::get()
+}
+
+/// switches client mode from the staging to the production directory once staging succeeded
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn promote_client_to_production() -> ApiResult<()> {
+    ClientEnvironments::promote()
+}
+
+/// orders the client profile's domains from the active environment, returns the TXT records to
+/// publish before `complete_client_order`
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn start_client_order() -> ApiResult<Vec<DnsChallenge>> {
+    acme_client::start_order().await
+}
+
+/// finalizes the pending client order with `csr_der` once its records resolve, the certificate is
+/// archived like an imported one
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn complete_client_order(csr_der: Vec<u8>) -> ApiResult<IssuedCertificate> {
+    acme_client::complete_order(csr_der).await
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn revoke_certificate(serial: u64, reason: u8) -> ApiResult<Revocation> {
+    let revocation = RevocationRegistry::revoke(serial, reason)?;
+
+    // publish the revocation without waiting for the next scheduled re-signing
+    crl::refresh_in_background();
+
+    Ok(revocation)
+}
+
+/// the key of `serial` is compromised, every certificate for it is revoked and the key can't be
+/// used for an account or a certificate again
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn revoke_compromised_key(serial: u64) -> ApiResult<Vec<Revocation>> {
+    let revocations =
+        RevocationRegistry::revoke_key(serial, AuditActor::Principal(ic_cdk::caller()))?;
+
+    crl::refresh_in_background();
+
+    Ok(revocations)
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn blocked_keys() -> Vec<BlockedKey> {
+    KeyBlocklist::list()
+}
+
+#[ic_cdk::query]
+fn list_revocations() -> Vec<Revocation> {
+    RevocationRegistry::list()
+}
+
+/// fetches the public suffix list now instead of waiting for the weekly refresh
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn refresh_public_suffix_list() -> ApiResult<u64> {
+    psl::refresh()
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+#[ic_cdk::query]
+fn public_suffix_list_status() -> PublicSuffixListStatus {
+    psl::status()
+}
+
+/// root and intermediate creations with the document signed over them, also served at
+/// `/ceremony.json`
+#[ic_cdk::query]
+fn ceremony_transcript() -> Option<SignedTranscript> {
+    ceremony::signed()
+}
+
+#[ic_cdk::query]
+fn ceremony_entries() -> Vec<CeremonyEntry> {
+    CeremonyTranscript::entries()
+}
+
+/// re-signs the transcript, e.g. when the background signing after a ceremony failed
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn sign_ceremony_transcript() -> ApiResult<SignedTranscript> {
+    ceremony::sign()
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+/// derives the next root key and serves it cross-signed with the current one for `overlap_secs`
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn start_key_rotation(overlap_secs: u64) -> ApiResult<KeyRotation> {
+    KeyRotations::start(overlap_secs).await
+}
+
+/// switches issuance to the new root key once the overlap of the rotation is over
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn complete_key_rotation() -> ApiResult<KeyRotation> {
+    KeyRotations::complete()
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn cancel_key_rotation() -> ApiResult<KeyRotation> {
+    KeyRotations::cancel()
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn key_rotations() -> Vec<KeyRotation> {
+    KeyRotations::list()
+}
+
+// must stay at the bottom of the crate root so every method above is picked up
+ic_cdk::export_candid!();
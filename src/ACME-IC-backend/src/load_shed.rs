@@ -0,0 +1,144 @@
+use std::cell::RefCell;
+
+use anyhow::anyhow;
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::{
+    api::{ApiError, ApiResult},
+    handler::{GenericError, R},
+};
+
+const DEFAULT_MAX_VALIDATION_DEPTH: u64 = 256;
+const DEFAULT_MAX_SIGNING_DEPTH: u64 = 64;
+const DEFAULT_RETRY_AFTER_SECS: u64 = 30;
+
+thread_local! {
+    static SHEDDER: RefCell<LoadShedder> = RefCell::new(LoadShedder::default());
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LoadShedConfig {
+    pub max_validation_depth: u64,
+    pub max_signing_depth: u64,
+    /// value of the `Retry-After` header sent along with shed requests
+    pub retry_after_secs: u64,
+}
+
+impl Default for LoadShedConfig {
+    fn default() -> Self {
+        Self {
+            max_validation_depth: DEFAULT_MAX_VALIDATION_DEPTH,
+            max_signing_depth: DEFAULT_MAX_SIGNING_DEPTH,
+            retry_after_secs: DEFAULT_RETRY_AFTER_SECS,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LoadShedStatus {
+    pub config: LoadShedConfig,
+    pub validation_depth: u64,
+    pub signing_depth: u64,
+    pub shedding: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Queue {
+    Validation,
+    Signing,
+}
+
+/// Tracks the depth of the validation and signing queues and decides whether new work is admitted.
+///
+/// Only admission is affected, work that is already queued keeps being drained by the workers
+/// regardless of the current pressure.
+#[derive(Default)]
+pub struct LoadShedder {
+    config: LoadShedConfig,
+    validation_depth: u64,
+    signing_depth: u64,
+}
+
+impl LoadShedder {
+    fn depth_mut(&mut self, queue: Queue) -> &mut u64 {
+        match queue {
+            Queue::Validation => &mut self.validation_depth,
+            Queue::Signing => &mut self.signing_depth,
+        }
+    }
+
+    fn _is_under_pressure(&self) -> bool {
+        self.validation_depth >= self.config.max_validation_depth
+            || self.signing_depth >= self.config.max_signing_depth
+    }
+
+    /// record that a job has been pushed to `queue`
+    pub fn enqueued(queue: Queue) {
+        SHEDDER.with_borrow_mut(|s| {
+            let depth = s.depth_mut(queue);
+            *depth = depth.saturating_add(1);
+        })
+    }
+
+    /// record that a job has left `queue`, whether it succeeded or not
+    pub fn drained(queue: Queue) {
+        SHEDDER.with_borrow_mut(|s| {
+            let depth = s.depth_mut(queue);
+            *depth = depth.saturating_sub(1);
+        })
+    }
+
+    pub fn is_under_pressure() -> bool {
+        SHEDDER.with_borrow(|s| s._is_under_pressure())
+    }
+
+    /// rejects with 503 + `Retry-After` when any queue is over its configured depth
+    pub fn admit() -> R<()> {
+        SHEDDER.with_borrow(|s| {
+            if !s._is_under_pressure() {
+                return Ok(());
+            }
+
+            Err(GenericError::service_unavailable(
+                anyhow!("the server is under heavy load, please retry later"),
+                s.config.retry_after_secs,
+            ))
+        })
+    }
+
+    /// [`Self::admit`] for calls that queue new work
+    pub fn admit_call() -> ApiResult<()> {
+        SHEDDER.with_borrow(|s| {
+            if !s._is_under_pressure() {
+                return Ok(());
+            }
+
+            Err(ApiError::Unavailable {
+                message: "the server is under heavy load, please retry later".to_string(),
+                retry_after_secs: s.config.retry_after_secs,
+            })
+        })
+    }
+
+    pub fn configure(config: LoadShedConfig) -> ApiResult<()> {
+        if config.max_validation_depth == 0 || config.max_signing_depth == 0 {
+            return Err(ApiError::InvalidArgument(
+                "queue depths must be greater than zero".to_string(),
+            ));
+        }
+
+        SHEDDER.with_borrow_mut(|s| s.config = config);
+
+        Ok(())
+    }
+
+    pub fn status() -> LoadShedStatus {
+        SHEDDER.with_borrow(|s| LoadShedStatus {
+            config: s.config.clone(),
+            validation_depth: s.validation_depth,
+            signing_depth: s.signing_depth,
+            shedding: s._is_under_pressure(),
+        })
+    }
+}
@@ -0,0 +1,136 @@
+use std::cell::RefCell;
+
+use anyhow::anyhow;
+use candid::CandidType;
+use ic_stable_structures::StableCell;
+use serde::Deserialize;
+
+use crate::{
+    api::{ApiError, ApiResult},
+    clock,
+    handler::{types::AcmeServerError, GenericError, R},
+    mem::{candid_storable, Mem, Memory},
+};
+
+const DEFAULT_RETRY_AFTER_SECS: u64 = 300;
+/// longest message shown to refused clients
+const MAX_MESSAGE_LEN: usize = 256;
+
+thread_local! {
+    static MAINTENANCE: RefCell<StableCell<Maintenance, Memory>> = RefCell::new(
+        Mem::cell::<Maintenance, _>(Maintenance::default()),
+    );
+}
+
+/// Whether the CA is in maintenance. Kept in stable memory, so an upgrade made during the
+/// maintenance doesn't end it.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Maintenance {
+    pub enabled: bool,
+    /// when the maintenance started, `None` outside of one
+    pub since: Option<u64>,
+    /// value of the `Retry-After` header sent along with refused requests
+    pub retry_after_secs: u64,
+    /// told to refused clients, e.g. when the CA is expected back
+    pub message: Option<String>,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            since: None,
+            retry_after_secs: DEFAULT_RETRY_AFTER_SECS,
+            message: None,
+        }
+    }
+}
+
+candid_storable!(Maintenance);
+
+impl Maintenance {
+    pub fn get() -> Self {
+        MAINTENANCE.with_borrow(|cell| cell.get().clone())
+    }
+
+    pub fn is_enabled() -> bool {
+        MAINTENANCE.with_borrow(|cell| cell.get().enabled)
+    }
+
+    /// Enters or leaves maintenance. Entering it again only updates the retry delay and the
+    /// message, `since` keeps when it started.
+    pub fn set(
+        enabled: bool,
+        retry_after_secs: Option<u64>,
+        message: Option<String>,
+    ) -> ApiResult<Self> {
+        let retry_after_secs = retry_after_secs.unwrap_or(DEFAULT_RETRY_AFTER_SECS);
+
+        if retry_after_secs == 0 {
+            return Err(ApiError::InvalidArgument(
+                "retry_after_secs must be greater than zero".to_string(),
+            ));
+        }
+
+        if message.as_ref().is_some_and(|m| m.len() > MAX_MESSAGE_LEN) {
+            return Err(ApiError::InvalidArgument(format!(
+                "message must be at most {MAX_MESSAGE_LEN} bytes"
+            )));
+        }
+
+        MAINTENANCE.with_borrow_mut(|cell| {
+            let since = match enabled {
+                true => cell.get().since.or(Some(clock::now_nanos())),
+                false => None,
+            };
+            let maintenance = Self {
+                enabled,
+                since,
+                retry_after_secs,
+                message: message.filter(|_| enabled),
+            };
+
+            cell.set(maintenance.clone())
+                .map_err(|e| ApiError::Internal(format!("failed to store maintenance: {e:?}")))?;
+
+            Ok(maintenance)
+        })
+    }
+
+    fn refusal(&self) -> String {
+        match &self.message {
+            Some(message) => format!("the CA is in maintenance: {message}"),
+            None => "the CA is in maintenance, please retry later".to_string(),
+        }
+    }
+
+    /// rejects with 503 + `Retry-After` while in maintenance, for ACME requests that open new
+    /// accounts or orders
+    pub fn admit() -> R<()> {
+        let maintenance = Self::get();
+
+        if !maintenance.enabled {
+            return Ok(());
+        }
+
+        Err(GenericError::service_unavailable(
+            anyhow!(maintenance.refusal()),
+            maintenance.retry_after_secs,
+        )
+        .with_kind(AcmeServerError::ServiceUnavailable))
+    }
+
+    /// [`Self::admit`] for Candid calls that open new orders
+    pub fn admit_call() -> ApiResult<()> {
+        let maintenance = Self::get();
+
+        if !maintenance.enabled {
+            return Ok(());
+        }
+
+        Err(ApiError::Unavailable {
+            message: maintenance.refusal(),
+            retry_after_secs: maintenance.retry_after_secs,
+        })
+    }
+}
@@ -0,0 +1,104 @@
+use std::cell::RefCell;
+
+use ic_http_certification::HeaderField;
+
+/// RFC 8555 §6.2, the only body an ACME POST may carry
+pub const JOSE_JSON: &str = "application/jose+json";
+pub const JSON: &str = "application/json";
+/// RFC 7807, every ACME error
+pub const PROBLEM_JSON: &str = "application/problem+json";
+/// RFC 8555 §9.1, the default certificate download
+pub const PEM_CHAIN: &str = "application/pem-certificate-chain";
+/// RFC 2585, the DER leaf alone
+pub const PKIX_CERT: &str = "application/pkix-cert";
+
+thread_local! {
+    /// `Accept` of the request being handled, see [`scoped`]
+    static ACCEPT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub fn header<'h>(headers: &'h [HeaderField], name: &str) -> Option<&'h str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// the media type without parameters, lowercased
+fn essence(media_type: &str) -> String {
+    media_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// whether a `Content-Type` names `expected`, parameters such as `charset` are ignored
+pub fn is(content_type: Option<&str>, expected: &str) -> bool {
+    content_type.is_some_and(|c| essence(c) == expected)
+}
+
+/// runs `f` with `accept` as the header [`accept`] reports
+pub fn scoped<T>(accept: Option<String>, f: impl FnOnce() -> T) -> T {
+    ACCEPT.set(accept);
+    let result = f();
+    ACCEPT.set(None);
+
+    result
+}
+
+/// `Accept` of the ACME request being handled, `None` outside of one or when it wasn't sent
+pub fn accept() -> Option<String> {
+    ACCEPT.with_borrow(|a| a.clone())
+}
+
+/// The `offered` type an `Accept` header prefers, RFC 9110 §12.5.1. The first offer wins ties and
+/// stands in for a missing header, `None` means nothing offered is acceptable.
+pub fn negotiate(accept: Option<&str>, offered: &[&'static str]) -> Option<&'static str> {
+    let Some(accept) = accept.filter(|a| !a.trim().is_empty()) else {
+        return offered.first().copied();
+    };
+
+    let ranges = accept
+        .split(',')
+        .map(|range| {
+            let quality = range
+                .split(';')
+                .skip(1)
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            (essence(range), quality)
+        })
+        .collect::<Vec<_>>();
+
+    // the most specific range matching an offer decides its quality
+    let quality = |offer: &str| {
+        let (kind, _) = offer.split_once('/').unwrap_or((offer, ""));
+
+        [offer.to_string(), format!("{kind}/*"), "*/*".to_string()]
+            .iter()
+            .find_map(|candidate| {
+                ranges
+                    .iter()
+                    .find(|(range, _)| range == candidate)
+                    .map(|(_, q)| *q)
+            })
+            .unwrap_or(0.0)
+    };
+
+    offered
+        .iter()
+        .map(|offer| (*offer, quality(offer)))
+        .filter(|(_, q)| *q > 0.0)
+        .fold(
+            None,
+            |best: Option<(&'static str, f32)>, (offer, q)| match best {
+                Some((_, best_q)) if best_q >= q => best,
+                _ => Some((offer, q)),
+            },
+        )
+        .map(|(offer, _)| offer)
+}
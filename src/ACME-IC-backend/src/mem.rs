@@ -1,75 +1,325 @@
-use crate::cert_manager::CertificateManager;
+use std::{cell::RefCell, fmt::Debug, ops::RangeBounds};
+
+use crate::{
+    account::{AccountManager, AccountThumbprintIndex},
+    audit::AuditLog,
+    authz::{AuthorizationStore, PendingValidations},
+    billing::{BillingAttempts, BillingPayments, BillingProfiles},
+    blocklist::KeyBlocklist,
+    capability::CapabilitySecret,
+    ceremony::{CeremonyTranscript, SignedTranscript},
+    cert_manager::{
+        CertificateDomainIndex, CertificateExpiryIndex, CertificateKeyIndex, CertificateManager,
+        CertificateOwnerSuffixIndex, CertificateStore, CertificateSuffixIndex,
+        ImportedCertificateIndex, IssuerChainStore, RootCertificateCell,
+    },
+    client::environment::{ClientAccountKeys, ClientEnvironments},
+    crl::{IssuerCrls, SignedCrl, TenantCrls},
+    ct::CtReceiptStore,
+    debug_capture::{DebugCapture, DebugCaptureData, DebugCaptureIndex},
+    events::{EventDeliveryQueue, EventSequence, ExpiryAnnouncements, SubscriptionStore},
+    expiry::{AutoRenewConsumers, ExpiringIndex, RenewalRequests},
+    idempotency::{OrderRequestIndex, OrderRequests},
+    issuance_lock::IssuanceLock,
+    jobs::{JobQueue, WebhookQueue},
+    key::{KeyScheme, PublicKeyCache},
+    maintenance::Maintenance,
+    metrics::MetricCounters,
+    order::{OrderManager, ReplacedCertificates},
+    pickup::PickupSecret,
+    psl::PublicSuffixList,
+    quota::AccountQuotas,
+    rate_limit::RegisteredDomainLimiter,
+    revocation::RevocationRegistry,
+    rotation::KeyRotations,
+    tenant::{TenantKeyVersions, TenantRegistry, TenantRoots},
+    token::{TokenSeed, TokenStore},
+    upgrade::{SchemaVersions, UpgradeSnapshot},
+};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
-    DefaultMemoryImpl, StableMinHeap,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
 };
 
+/// Assigns every listed type its own memory id, in order. Entries must only ever be appended, the
+/// id of a type is its position in the list and is what its data is found under after an upgrade.
+/// The name next to a type is what the [`MemoryRegistry`] and the schema versions record, it must
+/// never change even when the type is renamed.
 macro_rules! mem_id {
-    (
-        $($rest:ty;)*) => {
-        mem_id!(@internal 0_u8; $($rest;)*);
+    ($($rest:ty = $names:literal;)*) => {
+        mem_id!(@internal 0_u8; $($rest = $names;)*);
     };
 
-
-
-    (@internal $counter:expr; $ident:ty; $($rest:ty;)*) => {
+    (@internal $counter:expr; $ident:ty = $name:literal; $($rest:ty = $names:literal;)*) => {
         impl StorageItem for $ident {
-            const ID: u8= $counter ;
+            const ID: u8 = $counter;
+            const NAME: &'static str = $name;
         }
 
-        mem_id!(@internal $counter + 1; $($rest;)*);
-
-
+        mem_id!(@internal $counter + 1; $($rest = $names;)*);
     };
 
     (@internal $counter:expr;) => {
         pub const TOTAL_MEMORY_ID_USED: u8 = $counter;
-     };
-    }
+    };
+}
 
-mem_id!(Mem; CertificateManager;);
+/// Implements [`Storable`] for types kept in stable memory as their candid encoding, with no
+/// bound on the encoded size.
+macro_rules! candid_storable {
+    ($($ty:ty),+ $(,)?) => {
+        $(
+            impl ic_stable_structures::Storable for $ty {
+                fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+                    std::borrow::Cow::Owned(candid::encode_one(self).unwrap())
+                }
+
+                fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+                    candid::decode_one(&bytes).unwrap()
+                }
+
+                const BOUND: ic_stable_structures::storable::Bound =
+                    ic_stable_structures::storable::Bound::Unbounded;
+            }
+        )+
+    };
+}
+
+pub(crate) use candid_storable;
+
+mem_id!(
+    Mem = "Mem";
+    CertificateManager = "CertificateManager";
+    TenantRegistry = "TenantRegistry";
+    AccountManager = "AccountManager";
+    AccountThumbprintIndex = "AccountThumbprintIndex";
+    DebugCapture = "DebugCapture";
+    DebugCaptureIndex = "DebugCaptureIndex";
+    DebugCaptureData = "DebugCaptureData";
+    CertificateStore = "CertificateStore";
+    RootCertificateCell = "RootCertificateCell";
+    ClientEnvironments = "ClientEnvironments";
+    RevocationRegistry = "RevocationRegistry";
+    SignedCrl = "SignedCrl";
+    PublicSuffixList = "PublicSuffixList";
+    RegisteredDomainLimiter = "RegisteredDomainLimiter";
+    OrderManager = "OrderManager";
+    PickupSecret = "PickupSecret";
+    CeremonyTranscript = "CeremonyTranscript";
+    SignedTranscript = "SignedTranscript";
+    MemoryRegistry = "MemoryRegistry";
+    IssuanceLock = "IssuanceLock";
+    SchemaVersions = "SchemaVersions";
+    UpgradeSnapshot = "UpgradeSnapshot";
+    PublicKeyCache = "PublicKeyCache";
+    CertificateDomainIndex = "CertificateDomainIndex";
+    CertificateExpiryIndex = "CertificateExpiryIndex";
+    ImportedCertificateIndex = "ImportedCertificateIndex";
+    JobQueue = "JobQueue";
+    AuditLog = "AuditLog";
+    MetricCounters = "MetricCounters";
+    WebhookQueue = "WebhookQueue";
+    IssuerChainStore = "IssuerChainStore";
+    AuthorizationStore = "AuthorizationStore";
+    ClientAccountKeys = "ClientAccountKeys";
+    CtReceiptStore = "CtReceiptStore";
+    CertificateKeyIndex = "CertificateKeyIndex";
+    KeyBlocklist = "KeyBlocklist";
+    AccountQuotas = "AccountQuotas";
+    OrderRequestIndex = "OrderRequestIndex";
+    OrderRequests = "OrderRequests";
+    KeyScheme = "KeyScheme";
+    KeyRotations = "KeyRotations";
+    IssuerCrls = "IssuerCrls";
+    BillingProfiles = "BillingProfiles";
+    BillingPayments = "BillingPayments";
+    BillingAttempts = "BillingAttempts";
+    SubscriptionStore = "SubscriptionStore";
+    EventDeliveryQueue = "EventDeliveryQueue";
+    EventSequence = "EventSequence";
+    ExpiryAnnouncements = "ExpiryAnnouncements";
+    ExpiringIndex = "ExpiringIndex";
+    AutoRenewConsumers = "AutoRenewConsumers";
+    RenewalRequests = "RenewalRequests";
+    TenantRoots = "TenantRoots";
+    TenantKeyVersions = "TenantKeyVersions";
+    TenantCrls = "TenantCrls";
+    ReplacedCertificates = "ReplacedCertificates";
+    CapabilitySecret = "CapabilitySecret";
+    PendingValidations = "PendingValidations";
+    TokenStore = "TokenStore";
+    TokenSeed = "TokenSeed";
+    CertificateSuffixIndex = "CertificateSuffixIndex";
+    CertificateOwnerSuffixIndex = "CertificateOwnerSuffixIndex";
+    Maintenance = "Maintenance";
+);
+
+// the memory manager hands out ids 0..=254, 255 marks an unallocated bucket
+const _: () = assert!(TOTAL_MEMORY_ID_USED < u8::MAX, "out of memory ids");
 
 pub trait StorageItem {
     const ID: u8;
+    /// recorded next to the id on first use, see [`Mem::memory_for`]
+    const NAME: &'static str;
 
     fn memory_id() -> MemoryId {
         MemoryId::new(Self::ID)
     }
 }
 
-pub trait StorageRegistry {
-    fn get(&self, id: MemoryId) -> Memory;
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+thread_local! {
+    static MEM: Mem = Mem::init();
 }
 
-pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+/// Owner of which memory id belongs to which type, persisted so a reordered `mem_id!` list is
+/// caught on upgrade instead of silently reading another subsystem's data.
+pub struct MemoryRegistry;
 
+/// Id 0 is kept reserved, it used to hold a registry heap that never got wired up.
 pub struct Mem {
     mgr: MemoryManager<DefaultMemoryImpl>,
-    registry: StableMinHeap<u8, Memory>,
+    registry: RefCell<StableBTreeMap<u8, String, Memory>>,
 }
 
-impl StorageRegistry for Mem {
-    fn get(&self, id: MemoryId) -> Memory {
-        self._get(id)
+impl Mem {
+    /// memory reserved for `T` by `mem_id!`
+    ///
+    /// traps if the id was claimed by another type before, or `T` was registered under another id
+    pub fn memory_for<T: StorageItem>() -> Memory {
+        MEM.with(|m| {
+            m.claim(T::ID, T::NAME);
+            m.mgr.get(T::memory_id())
+        })
     }
-}
 
-impl Mem {
-    fn _get(&self, id: MemoryId) -> Memory {
-        self.mgr.get(id)
+    /// A [`StableCell`] in the memory reserved for `S`, holding `default` until the first write.
+    pub fn cell<S: StorageItem, T: Storable>(default: T) -> StableCell<T, Memory> {
+        StableCell::init(Self::memory_for::<S>(), default).unwrap_or_else(|err| {
+            ic_cdk::trap(&format!("{} initialization must succeed: {err:?}", S::NAME))
+        })
+    }
+
+    /// A write to stable memory failed, e.g. because it can't grow. The call traps so everything
+    /// it changed is rolled back, e.g. no serial is ever handed out twice.
+    pub fn write_failed(what: &str, err: impl Debug) -> ! {
+        ic_cdk::trap(&format!("failed to write {what}: {err:?}"))
     }
 
-    // fn _is_unique(&self, id: MemoryId) ->bool{
+    fn claim(&self, id: u8, name: &str) {
+        let mut registry = self.registry.borrow_mut();
+
+        if let Some(owner) = registry.get(&id) {
+            if owner != name {
+                ic_cdk::trap(&format!(
+                    "memory {id} belongs to {owner}, not {name}; mem_id! entries must only be appended"
+                ));
+            }
 
-    // }
+            return;
+        }
+
+        if let Some((other, _)) = registry.iter().find(|(_, owner)| owner == name) {
+            ic_cdk::trap(&format!(
+                "{name} moved from memory {other} to {id}; mem_id! entries must only be appended"
+            ));
+        }
 
-    // fn _register()
+        registry.insert(id, name.to_string());
+    }
 
     pub fn init() -> Self {
         let mgr = MemoryManager::init(DefaultMemoryImpl::default());
-        let registry = StableMinHeap::init(mgr.get(Self::memory_id()))
-            .expect("registry initialization must successfull");
+        let registry = StableBTreeMap::init(mgr.get(MemoryRegistry::memory_id()));
+
+        Self {
+            mgr,
+            registry: RefCell::new(registry),
+        }
+    }
+}
+
+/// A typed map over the memory `mem_id!` reserved for a subsystem, every `StableBTreeMap` of the
+/// canister goes through this so it can't end up on a memory it doesn't own.
+pub struct Repository<K: Storable + Ord + Clone, V: Storable> {
+    map: StableBTreeMap<K, V, Memory>,
+}
+
+impl<K: Storable + Ord + Clone, V: Storable> Repository<K, V> {
+    /// the repository stored in the memory of `S`
+    pub fn init<S: StorageItem>() -> Self {
+        Self {
+            map: StableBTreeMap::init(Mem::memory_for::<S>()),
+        }
+    }
+
+    /// rewrites every value of the repository of `S` from its `Old` layout, for upgrade migrations
+    ///
+    /// everything is rewritten within one message, so this only suits collections that fit in the
+    /// instruction limit of `post_upgrade`
+    pub fn migrate<S: StorageItem, Old: Storable>(f: impl Fn(Old) -> V) {
+        let old: StableBTreeMap<K, Old, Memory> = StableBTreeMap::init(Mem::memory_for::<S>());
+        let entries = old.iter().collect::<Vec<_>>();
+
+        let mut new: StableBTreeMap<K, V, Memory> = StableBTreeMap::init(old.into_memory());
+
+        for (key, value) in entries {
+            new.insert(key, f(value));
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.map.get(key)
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// returns the value previously stored under `key`
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.map.insert(key, value)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.map.remove(key)
+    }
+
+    /// applies `f` to the value under `key` and stores it back, `None` if there is none
+    pub fn update(&mut self, key: &K, f: impl FnOnce(&mut V)) -> Option<V>
+    where
+        V: Clone,
+    {
+        let mut value = self.map.get(key)?;
+
+        f(&mut value);
+        self.map.insert(key.clone(), value.clone());
+
+        Some(value)
+    }
+
+    pub fn last(&self) -> Option<(K, V)> {
+        self.map.last_key_value()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.map.iter()
+    }
+
+    pub fn range(&self, range: impl RangeBounds<K>) -> impl Iterator<Item = (K, V)> + '_ {
+        self.map.range(range)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = V> + '_ {
+        self.map.values()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.map.len()
+    }
 
-        Self { mgr, registry }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
     }
 }
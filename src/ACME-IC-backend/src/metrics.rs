@@ -0,0 +1,238 @@
+use std::{cell::RefCell, fmt::Write};
+
+use candid::CandidType;
+use ic_stable_structures::StableCell;
+use serde::Deserialize;
+
+use crate::{
+    mem::{candid_storable, Mem, Memory},
+    nonce::NoncePool,
+    order::OrderStatus,
+    trace,
+};
+
+pub const METRICS_PATH: &str = "/metrics";
+
+const WASM_PAGE_BYTES: u64 = 64 * 1024;
+
+thread_local! {
+    static COUNTERS: RefCell<StableCell<MetricCounters, Memory>> = RefCell::new(
+        Mem::cell::<MetricCounters, _>(MetricCounters::default()),
+    );
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ChallengeCount {
+    /// RFC 8555 §8 challenge type, e.g. `dns-01`
+    pub kind: String,
+    pub valid: u64,
+    pub invalid: u64,
+}
+
+/// Counters bumped by the handlers as things happen, kept in stable memory so they survive
+/// upgrades and only ever grow.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct MetricCounters {
+    pub certificates_issued: u64,
+    pub certificates_revoked: u64,
+    /// orders that reached each status
+    pub orders: Vec<(OrderStatus, u64)>,
+    pub challenges: Vec<ChallengeCount>,
+    pub jws_failures: u64,
+    /// leaves signed together by the job worker, optional as it was added after the others
+    pub signing_batches: Option<u64>,
+    /// cycles those batches consumed, see [`crate::cert_manager::SignedBatch`]
+    pub batch_signing_cycles: Option<u128>,
+}
+
+candid_storable!(MetricCounters);
+
+/// The counters with the gauges read at the time of the call, also served at `/metrics`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Metrics {
+    pub counters: MetricCounters,
+    pub nonce_pool_available: u64,
+    pub stable_memory_bytes: u64,
+    pub cycles_balance: u128,
+}
+
+/// a bump made from a query is dropped with the rest of its state changes
+fn bump(f: impl FnOnce(&mut MetricCounters)) {
+    COUNTERS.with_borrow_mut(|cell| {
+        let mut counters = cell.get().clone();
+        f(&mut counters);
+
+        if let Err(e) = cell.set(counters) {
+            trace::log(&format!("failed to store the metric counters: {e:?}"));
+        }
+    })
+}
+
+pub fn certificate_issued() {
+    bump(|c| c.certificates_issued += 1)
+}
+
+pub fn certificate_revoked() {
+    bump(|c| c.certificates_revoked += 1)
+}
+
+pub fn order_reached(status: OrderStatus) {
+    bump(|c| match c.orders.iter_mut().find(|(s, _)| *s == status) {
+        Some((_, count)) => *count += 1,
+        None => c.orders.push((status, 1)),
+    })
+}
+
+pub fn challenge_validated(kind: &str, valid: bool) {
+    bump(|c| {
+        let index = match c.challenges.iter().position(|ch| ch.kind == kind) {
+            Some(index) => index,
+            None => {
+                c.challenges.push(ChallengeCount {
+                    kind: kind.to_string(),
+                    ..Default::default()
+                });
+                c.challenges.len() - 1
+            }
+        };
+
+        if valid {
+            c.challenges[index].valid += 1;
+        } else {
+            c.challenges[index].invalid += 1;
+        }
+    })
+}
+
+pub fn batch_signed(certificates: u64, cycles: u128) {
+    bump(|c| {
+        if certificates > 0 {
+            *c.signing_batches.get_or_insert(0) += 1;
+            *c.batch_signing_cycles.get_or_insert(0) += cycles;
+        }
+    })
+}
+
+pub fn jws_failure() {
+    bump(|c| c.jws_failures += 1)
+}
+
+pub fn current() -> Metrics {
+    Metrics {
+        counters: COUNTERS.with_borrow(|cell| cell.get().clone()),
+        nonce_pool_available: NoncePool::status().available,
+        stable_memory_bytes: ic_cdk::api::stable::stable_size() * WASM_PAGE_BYTES,
+        cycles_balance: ic_cdk::api::canister_balance128(),
+    }
+}
+
+/// one metric family, each sample is its label set, empty for none, and value
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: Vec<(String, String)>) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{name}{labels} {value}");
+    }
+}
+
+fn single(value: impl ToString) -> Vec<(String, String)> {
+    vec![(String::new(), value.to_string())]
+}
+
+/// Prometheus text exposition format 0.0.4
+pub fn render(metrics: &Metrics) -> String {
+    let c = &metrics.counters;
+    let mut out = String::new();
+
+    family(
+        &mut out,
+        "acme_certificates_issued_total",
+        "counter",
+        "Certificates signed by this CA.",
+        single(c.certificates_issued),
+    );
+    family(
+        &mut out,
+        "acme_certificates_revoked_total",
+        "counter",
+        "Certificates revoked.",
+        single(c.certificates_revoked),
+    );
+    family(
+        &mut out,
+        "acme_orders_total",
+        "counter",
+        "Orders that reached each status.",
+        c.orders
+            .iter()
+            .map(|(status, count)| {
+                (
+                    format!("{{status=\"{}\"}}", status.as_str()),
+                    count.to_string(),
+                )
+            })
+            .collect(),
+    );
+    family(
+        &mut out,
+        "acme_challenges_total",
+        "counter",
+        "Challenge validations by type and result.",
+        c.challenges
+            .iter()
+            .flat_map(|ch| {
+                [("valid", ch.valid), ("invalid", ch.invalid)].map(|(result, count)| {
+                    (
+                        format!("{{type=\"{}\",result=\"{result}\"}}", ch.kind),
+                        count.to_string(),
+                    )
+                })
+            })
+            .collect(),
+    );
+    family(
+        &mut out,
+        "acme_jws_verification_failures_total",
+        "counter",
+        "JWS requests rejected for their signature.",
+        single(c.jws_failures),
+    );
+    family(
+        &mut out,
+        "acme_signing_batches_total",
+        "counter",
+        "Batches of leaves signed concurrently.",
+        single(c.signing_batches.unwrap_or_default()),
+    );
+    family(
+        &mut out,
+        "acme_batch_signing_cycles_total",
+        "counter",
+        "Cycles consumed while signing batches.",
+        single(c.batch_signing_cycles.unwrap_or_default()),
+    );
+    family(
+        &mut out,
+        "acme_nonce_pool_available",
+        "gauge",
+        "Nonces ready to be handed out.",
+        single(metrics.nonce_pool_available),
+    );
+    family(
+        &mut out,
+        "acme_stable_memory_bytes",
+        "gauge",
+        "Stable memory allocated by the canister.",
+        single(metrics.stable_memory_bytes),
+    );
+    family(
+        &mut out,
+        "acme_cycles_balance",
+        "gauge",
+        "Cycles held by the canister.",
+        single(metrics.cycles_balance),
+    );
+
+    out
+}
@@ -0,0 +1,393 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashSet, VecDeque},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use candid::CandidType;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk_timers::TimerId;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::{ApiError, ApiResult},
+    clock, trace,
+};
+
+/// RFC 8555 §7.2 resource name below a tenant's base path
+pub const NEW_NONCE: &str = "/new-nonce";
+/// weight of the latest interval in the smoothed consumption rate
+const RATE_SMOOTHING: f64 = 0.3;
+/// bytes of entropy per nonce, 128 bits as RFC 8555 §6.5 suggests
+const NONCE_BYTES: usize = 16;
+/// nonces handed out and not redeemed yet, the oldest are forgotten beyond this and their
+/// requests get a `badNonce`
+const MAX_OUTSTANDING: usize = 100_000;
+
+thread_local! {
+    static POOL: RefCell<NoncePool> = RefCell::new(NoncePool::default());
+    static REFILL_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+}
+
+/// Bounds the pool tunes itself within.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NoncePoolConfig {
+    /// nonces derived from one `raw_rand` call
+    pub min_batch: u32,
+    pub max_batch: u32,
+    /// time between scheduled refills
+    pub min_refill_interval_secs: u64,
+    pub max_refill_interval_secs: u64,
+}
+
+impl Default for NoncePoolConfig {
+    fn default() -> Self {
+        Self {
+            min_batch: 16,
+            max_batch: 1024,
+            min_refill_interval_secs: 5,
+            max_refill_interval_secs: 5 * 60,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NoncePoolStatus {
+    pub config: NoncePoolConfig,
+    pub available: u64,
+    /// nonces the next refill derives
+    pub batch: u32,
+    pub refill_interval_secs: u64,
+    /// smoothed consumption
+    pub nonces_per_minute: u64,
+    pub refills: u64,
+}
+
+/// Pre-generated `Replay-Nonce` values.
+///
+/// Every refill draws one `raw_rand` seed and derives a batch of nonces from it. The batch follows
+/// the smoothed consumption rate so the pool covers two refill intervals of demand, and the
+/// interval halves whenever the pool ran dry and doubles while nothing was taken. A nonce that was
+/// handed out is outstanding until a request redeems it. Kept on the heap, nonces handed out
+/// before an upgrade are simply not honoured afterwards.
+pub struct NoncePool {
+    config: NoncePoolConfig,
+    nonces: VecDeque<String>,
+    outstanding: HashSet<String>,
+    /// outstanding nonces oldest first, redeemed ones are only dropped once they reach the front
+    handed_out: VecDeque<String>,
+    batch: u32,
+    interval_secs: u64,
+    /// nonces per minute, `None` before the first tick
+    rate: Option<f64>,
+    taken: u64,
+    exhausted: bool,
+    last_tick: u64,
+    refills: u64,
+}
+
+impl Default for NoncePool {
+    fn default() -> Self {
+        let config = NoncePoolConfig::default();
+
+        Self {
+            batch: config.min_batch,
+            interval_secs: config.max_refill_interval_secs,
+            config,
+            nonces: VecDeque::new(),
+            outstanding: HashSet::new(),
+            handed_out: VecDeque::new(),
+            rate: None,
+            taken: 0,
+            exhausted: false,
+            last_tick: 0,
+            refills: 0,
+        }
+    }
+}
+
+impl NoncePool {
+    /// recomputes batch and interval from what was taken since the last tick
+    fn tune(&mut self, now: u64) {
+        let elapsed = Duration::from_nanos(now.saturating_sub(self.last_tick)).as_secs_f64();
+        let observed = self.taken as f64 * 60.0 / elapsed.max(1.0);
+
+        let rate = match self.rate {
+            Some(rate) => RATE_SMOOTHING * observed + (1.0 - RATE_SMOOTHING) * rate,
+            None => observed,
+        };
+
+        // enough for two intervals, a burst within one interval is absorbed by the pool
+        let wanted = (rate / 60.0 * self.interval_secs as f64 * 2.0).ceil() as u32;
+        self.batch = wanted.clamp(self.config.min_batch, self.config.max_batch);
+
+        self.interval_secs = if self.exhausted {
+            (self.interval_secs / 2).max(self.config.min_refill_interval_secs)
+        } else if self.taken == 0 {
+            (self.interval_secs * 2).min(self.config.max_refill_interval_secs)
+        } else {
+            self.interval_secs
+        };
+
+        self.rate = Some(rate);
+        self.taken = 0;
+        self.exhausted = false;
+        self.last_tick = now;
+    }
+
+    fn needs_refill(&self) -> bool {
+        (self.nonces.len() as u64) < self.batch as u64
+    }
+
+    fn pop(&mut self) -> Option<String> {
+        let Some(nonce) = self.nonces.pop_front() else {
+            self.exhausted = true;
+            return None;
+        };
+
+        self.taken += 1;
+
+        if self.handed_out.len() >= MAX_OUTSTANDING {
+            if let Some(oldest) = self.handed_out.pop_front() {
+                self.outstanding.remove(&oldest);
+            }
+        }
+
+        self.outstanding.insert(nonce.clone());
+        self.handed_out.push_back(nonce.clone());
+
+        Some(nonce)
+    }
+
+    pub fn status() -> NoncePoolStatus {
+        POOL.with_borrow(|p| NoncePoolStatus {
+            config: p.config.clone(),
+            available: p.nonces.len() as u64,
+            batch: p.batch,
+            refill_interval_secs: p.interval_secs,
+            nonces_per_minute: p.rate.unwrap_or_default().round() as u64,
+            refills: p.refills,
+        })
+    }
+
+    pub fn configure(config: NoncePoolConfig) -> ApiResult<()> {
+        if config.min_batch == 0
+            || config.min_batch > config.max_batch
+            || config.min_refill_interval_secs == 0
+            || config.min_refill_interval_secs > config.max_refill_interval_secs
+        {
+            return Err(ApiError::InvalidArgument(
+                "nonce pool bounds must be non-zero and min must not exceed max".to_string(),
+            ));
+        }
+
+        POOL.with_borrow_mut(|p| {
+            p.batch = p.batch.clamp(config.min_batch, config.max_batch);
+            p.interval_secs = p.interval_secs.clamp(
+                config.min_refill_interval_secs,
+                config.max_refill_interval_secs,
+            );
+            p.config = config;
+        });
+
+        schedule(Duration::ZERO);
+
+        Ok(())
+    }
+
+    /// whether `nonce` was handed out and not redeemed yet, without using it up
+    pub fn is_outstanding(nonce: &str) -> bool {
+        POOL.with_borrow(|p| p.outstanding.contains(nonce))
+    }
+
+    /// RFC 8555 §6.5, whether `nonce` was handed out and not redeemed before, it is used up
+    /// either way
+    pub fn redeem(nonce: &str) -> bool {
+        POOL.with_borrow_mut(|p| p.outstanding.remove(nonce))
+    }
+
+    /// `nonce` handed out as if a client had fetched it, for tests of signed requests
+    #[cfg(test)]
+    pub fn hand_out(nonce: &str) {
+        POOL.with_borrow_mut(|p| {
+            p.nonces.push_front(nonce.to_string());
+            p.pop();
+        })
+    }
+
+    /// a fresh nonce without waiting for a refill, `None` while the pool is dry
+    pub fn try_take() -> Option<String> {
+        POOL.with_borrow_mut(|p| p.pop())
+    }
+
+    /// a fresh nonce, refilling right away when the pool ran dry
+    pub async fn take() -> anyhow::Result<String> {
+        if let Some(nonce) = POOL.with_borrow_mut(|p| p.pop()) {
+            return anyhow::Ok(nonce);
+        }
+
+        refill().await?;
+
+        POOL.with_borrow_mut(|p| p.pop())
+            .ok_or_else(|| anyhow!("the nonce pool could not be refilled"))
+    }
+}
+
+/// derives one batch from a single `raw_rand` seed
+async fn refill() -> anyhow::Result<()> {
+    let (seed,) = raw_rand()
+        .await
+        .map_err(|(code, msg)| anyhow!("failed to draw a nonce seed: {code:?} {msg}"))?;
+
+    POOL.with_borrow_mut(|p| {
+        for i in 0..p.batch {
+            let mut hasher = Sha256::new();
+            hasher.update(&seed);
+            hasher.update(i.to_be_bytes());
+
+            p.nonces
+                .push_back(BASE64_URL_SAFE_NO_PAD.encode(&hasher.finalize()[..NONCE_BYTES]));
+        }
+
+        p.refills += 1;
+    });
+
+    anyhow::Ok(())
+}
+
+async fn tick() {
+    let needs_refill = POOL.with_borrow_mut(|p| {
+        p.tune(clock::now_nanos());
+        p.needs_refill()
+    });
+
+    if needs_refill {
+        if let Err(e) = refill().await {
+            trace::log(&e.to_string());
+        }
+    }
+
+    schedule(Duration::from_secs(POOL.with_borrow(|p| p.interval_secs)));
+}
+
+fn schedule(delay: Duration) {
+    if let Some(previous) = REFILL_TIMER.take() {
+        ic_cdk_timers::clear_timer(previous);
+    }
+
+    REFILL_TIMER.set(Some(ic_cdk_timers::set_timer(delay, || {
+        ic_cdk::spawn(tick())
+    })));
+}
+
+/// has to be called again after every upgrade
+pub fn start() {
+    POOL.with_borrow_mut(|p| p.last_tick = clock::now_nanos());
+    schedule(Duration::ZERO);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60_000_000_000;
+
+    #[test]
+    fn batch_covers_two_intervals_of_demand() {
+        let mut pool = NoncePool {
+            taken: 30,
+            ..Default::default()
+        };
+
+        pool.tune(MINUTE);
+
+        // 30 per minute over two 5 minute intervals
+        assert_eq!(pool.batch, 300);
+        assert_eq!(pool.rate, Some(30.0));
+    }
+
+    #[test]
+    fn batch_stays_within_the_configured_bounds() {
+        let mut pool = NoncePool {
+            taken: 10_000,
+            ..Default::default()
+        };
+        pool.tune(MINUTE);
+
+        assert_eq!(pool.batch, pool.config.max_batch);
+
+        let mut pool = NoncePool {
+            taken: 1,
+            ..Default::default()
+        };
+        pool.tune(MINUTE);
+
+        assert_eq!(pool.batch, pool.config.min_batch);
+    }
+
+    #[test]
+    fn interval_halves_after_running_dry() {
+        let mut pool = NoncePool::default();
+
+        assert_eq!(pool.pop(), None);
+        assert!(pool.exhausted);
+
+        pool.tune(MINUTE);
+
+        assert_eq!(pool.interval_secs, pool.config.max_refill_interval_secs / 2);
+        assert!(!pool.exhausted);
+    }
+
+    #[test]
+    fn interval_doubles_while_idle_up_to_the_maximum() {
+        let mut pool = NoncePool {
+            interval_secs: 60,
+            ..Default::default()
+        };
+
+        pool.tune(MINUTE);
+        assert_eq!(pool.interval_secs, 120);
+
+        pool.interval_secs = pool.config.max_refill_interval_secs;
+        pool.tune(2 * MINUTE);
+        assert_eq!(pool.interval_secs, pool.config.max_refill_interval_secs);
+    }
+
+    #[test]
+    fn later_rates_are_smoothed() {
+        let mut pool = NoncePool {
+            taken: 100,
+            ..Default::default()
+        };
+        pool.tune(MINUTE);
+        pool.tune(2 * MINUTE);
+
+        assert_eq!(pool.rate, Some(70.0));
+    }
+
+    #[test]
+    fn pop_counts_what_was_taken() {
+        let mut pool = NoncePool::default();
+        pool.nonces.extend(["a".to_string(), "b".to_string()]);
+
+        assert_eq!(pool.pop().as_deref(), Some("a"));
+        assert_eq!(pool.taken, 1);
+        assert!(!pool.exhausted);
+        assert!(pool.needs_refill());
+    }
+
+    #[test]
+    fn nonces_are_redeemed_once() {
+        NoncePool::hand_out("a");
+
+        assert!(NoncePool::is_outstanding("a"));
+        assert!(NoncePool::is_outstanding("a"));
+        assert!(NoncePool::redeem("a"));
+        assert!(!NoncePool::is_outstanding("a"));
+        assert!(!NoncePool::redeem("a"));
+        assert!(!NoncePool::redeem("never handed out"));
+    }
+}
@@ -0,0 +1,508 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use der::{
+    asn1::{BitString, GeneralizedTime, Ia5String, Null, ObjectIdentifier, OctetString},
+    Any, Choice, Decode, Encode, Enumerated, Sequence,
+};
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use x509_cert::{
+    der::oid::db::{
+        rfc5280::ID_AD_OCSP,
+        rfc5912::{ID_SHA_1, ID_SHA_256},
+        rfc6960::{ID_PKIX_OCSP_BASIC, ID_PKIX_OCSP_NONCE},
+    },
+    ext::{
+        pkix::{crl::CrlReason, name::GeneralName, AccessDescription, AuthorityInfoAccessSyntax},
+        Extensions,
+    },
+    name::Name,
+    serial_number::SerialNumber,
+    spki::AlgorithmIdentifierOwned,
+    Certificate, Version,
+};
+
+use crate::{
+    cert_manager::CertificateManager,
+    clock,
+    config::Config,
+    key::{self, AcmeKey},
+    revocation::RevocationRegistry,
+    rotation::KeyRotations,
+    router::OCSP_PATH,
+    tenant::TenantRegistry,
+    trace,
+};
+
+/// bounds the work a single request can cause
+const MAX_REQUESTS_PER_CALL: usize = 16;
+
+/// bounds the heap held by cached responses, a few hundred bytes each
+const MAX_CACHED_RESPONSES: usize = 10_000;
+
+thread_local! {
+    /// the name and key of every root and tenant key version, by derivation path, neither changes
+    static ISSUERS: RefCell<HashMap<Vec<Vec<u8>>, Issuer>> = RefCell::new(HashMap::new());
+    /// signed responses to single certificate requests, by the DER of their `CertID`
+    static RESPONSES: RefCell<BTreeMap<Vec<u8>, Cached>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// A signed response that is served again until it is halfway to its `nextUpdate`, so that no
+/// client is handed a response about to expire.
+struct Cached {
+    serial: u64,
+    refresh_at: u64,
+    der: Vec<u8>,
+}
+
+/// RFC 6960 §4.1.1
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+pub struct CertId {
+    pub hash_algorithm: AlgorithmIdentifierOwned,
+    pub issuer_name_hash: OctetString,
+    pub issuer_key_hash: OctetString,
+    pub serial_number: SerialNumber,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+pub struct Request {
+    pub req_cert: CertId,
+    #[asn1(context_specific = "0", optional = "true")]
+    pub single_request_extensions: Option<Extensions>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+pub struct TbsRequest {
+    #[asn1(context_specific = "0", default = "Default::default")]
+    pub version: Version,
+    #[asn1(context_specific = "1", optional = "true")]
+    pub requestor_name: Option<GeneralName>,
+    pub request_list: Vec<Request>,
+    #[asn1(context_specific = "2", optional = "true")]
+    pub request_extensions: Option<Extensions>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+pub struct OcspRequest {
+    pub tbs_request: TbsRequest,
+    #[asn1(context_specific = "0", optional = "true")]
+    pub optional_signature: Option<Any>,
+}
+
+/// RFC 6960 §4.2.1
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Enumerated)]
+#[repr(u32)]
+pub enum OcspResponseStatus {
+    Successful = 0,
+    MalformedRequest = 1,
+    InternalError = 2,
+    TryLater = 3,
+    SigRequired = 5,
+    Unauthorized = 6,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+pub struct ResponseBytes {
+    pub response_type: ObjectIdentifier,
+    pub response: OctetString,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+pub struct OcspResponse {
+    pub response_status: OcspResponseStatus,
+    #[asn1(context_specific = "0", optional = "true")]
+    pub response_bytes: Option<ResponseBytes>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+pub struct BasicOcspResponse {
+    pub tbs_response_data: ResponseData,
+    pub signature_algorithm: AlgorithmIdentifierOwned,
+    pub signature: BitString,
+    #[asn1(context_specific = "0", optional = "true")]
+    pub certs: Option<Vec<Certificate>>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+pub struct ResponseData {
+    #[asn1(context_specific = "0", default = "Default::default")]
+    pub version: Version,
+    pub responder_id: ResponderId,
+    pub produced_at: GeneralizedTime,
+    pub responses: Vec<SingleResponse>,
+    #[asn1(context_specific = "1", optional = "true")]
+    pub response_extensions: Option<Extensions>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Choice)]
+pub enum ResponderId {
+    #[asn1(context_specific = "1", tag_mode = "EXPLICIT", constructed = "true")]
+    ByName(Name),
+    #[asn1(context_specific = "2", tag_mode = "EXPLICIT", constructed = "true")]
+    ByKey(OctetString),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+pub struct SingleResponse {
+    pub cert_id: CertId,
+    pub cert_status: CertStatus,
+    pub this_update: GeneralizedTime,
+    #[asn1(context_specific = "0", optional = "true")]
+    pub next_update: Option<GeneralizedTime>,
+    #[asn1(context_specific = "1", optional = "true")]
+    pub single_extensions: Option<Extensions>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Choice)]
+pub enum CertStatus {
+    #[asn1(context_specific = "0", tag_mode = "IMPLICIT")]
+    Good(Null),
+    #[asn1(context_specific = "1", tag_mode = "IMPLICIT", constructed = "true")]
+    Revoked(RevokedInfo),
+    #[asn1(context_specific = "2", tag_mode = "IMPLICIT")]
+    Unknown(Null),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+pub struct RevokedInfo {
+    pub revocation_time: GeneralizedTime,
+    #[asn1(context_specific = "0", optional = "true")]
+    pub revocation_reason: Option<CrlReason>,
+}
+
+/// The identity of the issuing CA as it appears in a `CertID`.
+#[derive(Clone)]
+struct Issuer {
+    name_der: Vec<u8>,
+    /// the issuer public key as carried in its SPKI bit string
+    key: Vec<u8>,
+}
+
+impl Issuer {
+    fn hash(alg: &ObjectIdentifier, data: &[u8]) -> Option<Vec<u8>> {
+        if *alg == ID_SHA_1 {
+            return Some(Sha1::digest(data).to_vec());
+        }
+
+        if *alg == ID_SHA_256 {
+            return Some(Sha256::digest(data).to_vec());
+        }
+
+        None
+    }
+
+    fn issued(&self, id: &CertId) -> bool {
+        let alg = &id.hash_algorithm.oid;
+
+        Self::hash(alg, &self.name_der).as_deref() == Some(id.issuer_name_hash.as_bytes())
+            && Self::hash(alg, &self.key).as_deref() == Some(id.issuer_key_hash.as_bytes())
+    }
+}
+
+/// where leaves point relying parties for their revocation status
+pub fn responder_url() -> String {
+    format!("{}{OCSP_PATH}", Config::base_url())
+}
+
+/// AuthorityInfoAccess extension advertising [`responder_url`] as id-ad-ocsp
+pub fn authority_info_access() -> anyhow::Result<AuthorityInfoAccessSyntax> {
+    let uri = GeneralName::UniformResourceIdentifier(Ia5String::new(&responder_url())?);
+
+    anyhow::Ok(AuthorityInfoAccessSyntax(vec![AccessDescription {
+        access_method: ID_AD_OCSP,
+        access_location: uri,
+    }]))
+}
+
+fn generalized_time(nanos: u64) -> anyhow::Result<GeneralizedTime> {
+    anyhow::Ok(GeneralizedTime::from_unix_duration(Duration::from_nanos(
+        nanos,
+    ))?)
+}
+
+/// serials are issued from a u64 counter, anything wider was never issued here
+pub fn serial_to_u64(serial: &SerialNumber) -> Option<u64> {
+    let bytes = serial.as_bytes();
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    let bytes = &bytes[start..];
+
+    if bytes.len() > 8 {
+        return None;
+    }
+
+    let mut buf = [0u8; 8];
+    buf[8 - bytes.len()..].copy_from_slice(bytes);
+
+    Some(u64::from_be_bytes(buf))
+}
+
+fn status_of(issuer: &Issuer, id: &CertId) -> anyhow::Result<CertStatus> {
+    let serial = match serial_to_u64(&id.serial_number) {
+        Some(serial) if issuer.issued(id) => serial,
+        _ => return anyhow::Ok(CertStatus::Unknown(Null)),
+    };
+
+    if let Some(revocation) = RevocationRegistry::get(serial) {
+        return anyhow::Ok(CertStatus::Revoked(RevokedInfo {
+            revocation_time: generalized_time(revocation.revoked_at)?,
+            revocation_reason: Some(revocation.crl_reason()),
+        }));
+    }
+
+    // archive ids of imported certificates are not serials this CA issued
+    match CertificateManager::get(serial) {
+        Some(cert) if cert.imported.is_none() => anyhow::Ok(CertStatus::Good(Null)),
+        _ => anyhow::Ok(CertStatus::Unknown(Null)),
+    }
+}
+
+/// The name and key of `key`, see [`ISSUERS`]. `name` is `None` until the key has a root, the
+/// name follows the config until then and is not kept.
+async fn issuer(key: &AcmeKey, name: Option<Name>) -> anyhow::Result<Issuer> {
+    let path = key.derivation_path();
+
+    if let Some(issuer) = ISSUERS.with_borrow(|i| i.get(&path).cloned()) {
+        return anyhow::Ok(issuer);
+    }
+
+    let issuer = Issuer {
+        name_der: name
+            .clone()
+            .unwrap_or_else(key::Certificate::root_name)
+            .to_der()?,
+        key: key
+            .public_key()
+            .await?
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec(),
+    };
+
+    if name.is_some() {
+        ISSUERS.with_borrow_mut(|i| i.insert(path, issuer.clone()));
+    }
+
+    anyhow::Ok(issuer)
+}
+
+/// The root or tenant key the request asks about, each answers under its own name and key.
+/// Requests for a key this CA never had are answered by the active root key.
+async fn issuer_for(request: &OcspRequest) -> anyhow::Result<(AcmeKey, Issuer)> {
+    let asked = |issuer: &Issuer| {
+        request
+            .tbs_request
+            .request_list
+            .first()
+            .is_some_and(|req| issuer.issued(&req.req_cert))
+    };
+    let mut active = None;
+
+    for version in KeyRotations::issuer_versions() {
+        let key = AcmeKey::new_root().with_version(version);
+        let name = CertificateManager::root_name().map(|_| KeyRotations::issuer_name(version));
+        let issuer = issuer(&key, name).await?;
+
+        if asked(&issuer) {
+            return anyhow::Ok((key, issuer));
+        }
+
+        active.get_or_insert((key, issuer));
+    }
+
+    for tenant in TenantRegistry::list() {
+        for version in tenant.key_versions() {
+            let key = tenant.key(version);
+            let issuer = issuer(&key, tenant.root_name(version)).await?;
+
+            if asked(&issuer) {
+                return anyhow::Ok((key, issuer));
+            }
+        }
+    }
+
+    active.ok_or_else(|| anyhow!("no issuing key"))
+}
+
+/// `echo_nonce` is false for responses that are cached, they are served to other requests
+async fn basic_response(
+    request: OcspRequest,
+    echo_nonce: bool,
+) -> anyhow::Result<BasicOcspResponse> {
+    let (issuer_key, issuer) = issuer_for(&request).await?;
+
+    let now = clock::now_nanos();
+    let this_update = generalized_time(now)?;
+    let validity = Duration::from_secs(Config::revocation().ocsp_validity_secs);
+    let next_update = generalized_time(now + validity.as_nanos() as u64)?;
+
+    let responses = request
+        .tbs_request
+        .request_list
+        .into_iter()
+        .map(|req| {
+            anyhow::Ok(SingleResponse {
+                cert_status: status_of(&issuer, &req.req_cert)?,
+                cert_id: req.req_cert,
+                this_update,
+                next_update: Some(next_update),
+                single_extensions: None,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // echo the nonce so clients can bind the response to their request, RFC 8954
+    let nonce = request
+        .tbs_request
+        .request_extensions
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|ext| echo_nonce && ext.extn_id == ID_PKIX_OCSP_NONCE)
+        .collect::<Vec<_>>();
+
+    let tbs_response_data = ResponseData {
+        version: Version::V1,
+        responder_id: ResponderId::ByKey(OctetString::new(Sha1::digest(&issuer.key).to_vec())?),
+        produced_at: this_update,
+        responses,
+        response_extensions: (!nonce.is_empty()).then_some(nonce),
+    };
+
+    let signature = issuer_key.sign(&tbs_response_data.to_der()?).await?;
+
+    anyhow::Ok(BasicOcspResponse {
+        tbs_response_data,
+        signature_algorithm: AcmeKey::signature_algorithm(),
+        signature: BitString::from_bytes(signature.as_bytes())?,
+        certs: None,
+    })
+}
+
+fn error_response(status: OcspResponseStatus) -> Vec<u8> {
+    OcspResponse {
+        response_status: status,
+        response_bytes: None,
+    }
+    .to_der()
+    .unwrap_or_default()
+}
+
+fn encode(basic: &BasicOcspResponse) -> anyhow::Result<Vec<u8>> {
+    let response = OcspResponse {
+        response_status: OcspResponseStatus::Successful,
+        response_bytes: Some(ResponseBytes {
+            response_type: ID_PKIX_OCSP_BASIC,
+            response: OctetString::new(basic.to_der()?)?,
+        }),
+    };
+
+    response
+        .to_der()
+        .map_err(|e| anyhow!("failed to encode OCSP response: {e}"))
+}
+
+/// drops the cached responses for `serial`, its status changed
+pub fn forget(serial: u64) {
+    RESPONSES.with_borrow_mut(|r| r.retain(|_, cached| cached.serial != serial));
+}
+
+fn cached(key: &[u8], now: u64) -> Option<Vec<u8>> {
+    RESPONSES.with_borrow(|r| {
+        r.get(key)
+            .filter(|cached| cached.refresh_at > now)
+            .map(|cached| cached.der.clone())
+    })
+}
+
+/// Caches the response to a single certificate request. Unknown serials are not cached, the
+/// certificate may be issued before the response would expire.
+fn cache(key: Vec<u8>, basic: &BasicOcspResponse, der: &[u8], now: u64) {
+    let Some(single) = basic.tbs_response_data.responses.first() else {
+        return;
+    };
+
+    let serial = match (
+        &single.cert_status,
+        serial_to_u64(&single.cert_id.serial_number),
+    ) {
+        (CertStatus::Unknown(_), _) | (_, None) => return,
+        (_, Some(serial)) => serial,
+    };
+
+    let next_update = single
+        .next_update
+        .map(|t| t.to_unix_duration().as_nanos() as u64)
+        .unwrap_or(now);
+
+    RESPONSES.with_borrow_mut(|r| {
+        if r.len() >= MAX_CACHED_RESPONSES {
+            r.retain(|_, cached| cached.refresh_at > now);
+        }
+
+        if r.len() >= MAX_CACHED_RESPONSES {
+            r.pop_first();
+        }
+
+        r.insert(
+            key,
+            Cached {
+                serial,
+                refresh_at: now + next_update.saturating_sub(now) / 2,
+                der: der.to_vec(),
+            },
+        );
+    });
+}
+
+/// DER encoded `OCSPResponse` for a DER encoded `OCSPRequest`, failures are reported through the
+/// OCSP response status as RFC 6960 requires
+///
+/// A request for a single certificate is answered from the cache and without its nonce, signing
+/// every request would let any client drive the issuing key.
+pub async fn respond(body: &[u8]) -> Vec<u8> {
+    let request = match OcspRequest::from_der(body) {
+        Ok(req)
+            if !req.tbs_request.request_list.is_empty()
+                && req.tbs_request.request_list.len() <= MAX_REQUESTS_PER_CALL =>
+        {
+            req
+        }
+        _ => return error_response(OcspResponseStatus::MalformedRequest),
+    };
+
+    let now = clock::now_nanos();
+    let key = match request.tbs_request.request_list.as_slice() {
+        [single] => single.req_cert.to_der().ok(),
+        _ => None,
+    };
+
+    if let Some(der) = key.as_deref().and_then(|key| cached(key, now)) {
+        return der;
+    }
+
+    let encoded = basic_response(request, key.is_none())
+        .await
+        .and_then(|basic| {
+            let der = encode(&basic)?;
+
+            if let Some(key) = key {
+                cache(key, &basic, &der, now);
+            }
+
+            anyhow::Ok(der)
+        });
+
+    match encoded {
+        Ok(der) => der,
+        Err(e) => {
+            trace::log(&format!("OCSP response failed: {e}"));
+
+            error_response(OcspResponseStatus::InternalError)
+        }
+    }
+}
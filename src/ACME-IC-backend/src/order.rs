@@ -0,0 +1,272 @@
+use std::{cell::RefCell, time::Duration};
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::{
+    api::{ApiError, ApiResult},
+    authz::{AuthorizationState, AuthorizationStore, ValidationStatus},
+    capability,
+    cert_manager::{CertificateManager, CertificateOwner},
+    clock,
+    config::Config,
+    expiry,
+    handler::types::{Identifier, Order},
+    jobs::WORKER_INTERVAL,
+    mem::{candid_storable, Repository},
+    metrics,
+    profile::IssuanceOptions,
+    quota::AccountQuotas,
+    router, trace,
+};
+
+/// RFC 8555 §7.4, below a tenant's base path
+pub const NEW_ORDER: &str = "/new-order";
+pub const ORDER_PATH: &str = "/order/";
+/// where an account finalizes its order with a CSR, RFC 8555 §7.4
+pub const FINALIZE_PATH: &str = "/finalize/";
+pub const CERTIFICATE_PATH: &str = "/certificate/";
+
+/// how long an order can be finalized or picked up after it was created, RFC 8555 §7.1.3 `expires`
+pub const ORDER_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+thread_local! {
+    static ORDERS: RefCell<OrderManager> = RefCell::new(OrderManager::init());
+}
+
+/// RFC 8555 §7.1.6
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderStatus {
+    Pending,
+    Ready,
+    Processing,
+    Valid,
+    Invalid,
+}
+
+impl OrderStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Ready => "ready",
+            Self::Processing => "processing",
+            Self::Valid => "valid",
+            Self::Invalid => "invalid",
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StoredOrder {
+    pub id: u64,
+    pub owner: CertificateOwner,
+    pub status: OrderStatus,
+    pub domains: Vec<String>,
+    pub created_at: u64,
+    pub expires_at: u64,
+    /// when a `processing` order is expected to turn `valid`, clients should not poll before
+    pub estimated_ready_at: Option<u64>,
+    pub certificate_serial: Option<u64>,
+    /// why the order turned `invalid`
+    pub error: Option<String>,
+    /// HTTPS endpoint that is sent a signed pickup URL once the order is done processing
+    pub notify_url: Option<String>,
+    /// certificate profile the order is issued under
+    pub profile: Option<String>,
+    /// serial of the certificate the order renews, RFC 9773 §5 `replaces`
+    pub replaces: Option<u64>,
+    /// what newOrder asked for, the order is issued with it once finalized. Canister orders are
+    /// finalized when submitted and have none.
+    pub options: Option<IssuanceOptions>,
+}
+
+impl StoredOrder {
+    pub fn url(&self) -> String {
+        let resource = format!("{ORDER_PATH}{}", self.id);
+
+        format!("{}{}", Config::base_url(), capability::path(&resource))
+    }
+
+    /// Where the order is finalized, orders of accounts take their CSR at their own URL while
+    /// those of canisters were finalized when submitted and point back at the order.
+    pub fn finalize_url(&self) -> String {
+        if !matches!(self.owner, CertificateOwner::Account(_)) {
+            return self.url();
+        }
+
+        let resource = format!("{FINALIZE_PATH}{}", self.id);
+
+        format!("{}{}", Config::base_url(), capability::path(&resource))
+    }
+
+    /// whether the order still stands for the certificate it replaces, a failed or expired one
+    /// leaves it to be replaced by another order
+    fn replacing(&self, now: u64) -> bool {
+        match self.status {
+            OrderStatus::Valid => true,
+            OrderStatus::Invalid => false,
+            _ => self.expires_at > now,
+        }
+    }
+
+    /// seconds a client polling a `processing` order should wait, at least one worker round
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        if self.status != OrderStatus::Processing {
+            return None;
+        }
+
+        let remaining = self
+            .estimated_ready_at
+            .unwrap_or_default()
+            .saturating_sub(clock::now_nanos());
+
+        Some(
+            Duration::from_nanos(remaining)
+                .max(WORKER_INTERVAL)
+                .as_secs(),
+        )
+    }
+
+    /// RFC 8555 §7.1.3 representation, see [`Self::finalize_url`]. Orders that waited on another
+    /// issuance have no authorizations of their own.
+    pub fn to_acme(&self) -> Order {
+        Order {
+            status: self.status.as_str().to_string(),
+            expires: Some(clock::rfc3339(self.expires_at)),
+            identifiers: self
+                .domains
+                .iter()
+                .map(|domain| Identifier {
+                    r#type: "dns".to_string(),
+                    value: domain.clone(),
+                })
+                .collect(),
+            authorizations: AuthorizationStore::for_order(self.id)
+                .iter()
+                .map(AuthorizationState::url)
+                .collect(),
+            finalize: self.finalize_url(),
+            certificate: self
+                .certificate_serial
+                .map(|serial| router::chain_url(serial, 0)),
+            profile: self.profile.clone(),
+            replaces: self
+                .replaces
+                .and_then(CertificateManager::get)
+                .and_then(|cert| expiry::cert_id(&cert)),
+        }
+    }
+}
+
+candid_storable!(StoredOrder);
+
+/// memory marker for the order that replaces each renewed certificate
+pub struct ReplacedCertificates;
+
+pub struct OrderManager {
+    orders: Repository<u64, StoredOrder>,
+    /// certificate serial to the last order opened to replace it
+    replaced: Repository<u64, u64>,
+}
+
+impl OrderManager {
+    fn init() -> Self {
+        Self {
+            orders: Repository::init::<Self>(),
+            replaced: Repository::init::<ReplacedCertificates>(),
+        }
+    }
+
+    pub fn create(
+        owner: CertificateOwner,
+        domains: Vec<String>,
+        status: OrderStatus,
+        notify_url: Option<String>,
+    ) -> StoredOrder {
+        let now = clock::now_nanos();
+
+        let order = ORDERS.with_borrow_mut(|m| {
+            let id = m.orders.last().map(|(id, _)| id + 1).unwrap_or(1);
+
+            let order = StoredOrder {
+                id,
+                owner,
+                status,
+                domains,
+                created_at: now,
+                expires_at: now + ORDER_LIFETIME.as_nanos() as u64,
+                estimated_ready_at: None,
+                certificate_serial: None,
+                error: None,
+                notify_url,
+                profile: None,
+                replaces: None,
+                options: None,
+            };
+
+            m.orders.insert(id, order.clone());
+            metrics::order_reached(status);
+
+            order
+        });
+
+        AccountQuotas::order_opened(&order.owner, order.id);
+
+        order
+    }
+
+    pub fn get(id: u64) -> Option<StoredOrder> {
+        ORDERS.with_borrow(|m| m.orders.get(&id))
+    }
+
+    /// `order` turns `ready` once every one of its authorizations is valid, RFC 8555 §7.1.6
+    pub fn authorized(order: u64) {
+        let authorizations = AuthorizationStore::for_order(order);
+
+        if authorizations.is_empty()
+            || authorizations
+                .iter()
+                .any(|a| a.status != ValidationStatus::Valid)
+        {
+            return;
+        }
+
+        let ready = Self::update(order, |o| {
+            if o.status == OrderStatus::Pending {
+                o.status = OrderStatus::Ready;
+            }
+        });
+
+        if let Err(e) = ready {
+            trace::log(&format!("{e:?}"));
+        }
+    }
+
+    pub fn update(id: u64, f: impl FnOnce(&mut StoredOrder)) -> ApiResult<StoredOrder> {
+        let previous = Self::get(id).map(|o| o.status);
+        let order = ORDERS
+            .with_borrow_mut(|m| m.orders.update(&id, f))
+            .ok_or_else(|| ApiError::NotFound(format!("order {id}")))?;
+
+        if previous != Some(order.status) {
+            metrics::order_reached(order.status);
+        }
+
+        Ok(order)
+    }
+
+    /// the order that stands for `serial` being replaced, see [`StoredOrder::replacing`]
+    pub fn replacement(serial: u64) -> Option<StoredOrder> {
+        let id = ORDERS.with_borrow(|m| m.replaced.get(&serial))?;
+
+        Self::get(id).filter(|o| o.replacing(clock::now_nanos()))
+    }
+
+    /// records `order` as the renewal of the certificate `serial`
+    pub fn link_replacement(order: u64, serial: u64) -> ApiResult<StoredOrder> {
+        let order = Self::update(order, |o| o.replaces = Some(serial))?;
+        ORDERS.with_borrow_mut(|m| m.replaced.insert(serial, order.id));
+
+        Ok(order)
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::anyhow;
+use candid::CandidType;
+use ic_cdk::api::{
+    call::RejectionCode,
+    management_canister::http_request::{
+        http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse,
+        TransformArgs, TransformContext,
+    },
+};
+use serde::Deserialize;
+
+use crate::dns;
+
+/// attempts per outcall, see [`send`]
+const MAX_ATTEMPTS: u32 = 3;
+/// cycles attached to an attempt at most, however often it fell short
+const MAX_CYCLES: u128 = 100_000_000_000;
+
+/// What of a response takes part in consensus. Every replica makes the outcall itself and their
+/// responses only agree once dates, request ids, TTLs and the order of records are gone.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Transform {
+    /// status and the DNS JSON answers, see [`dns::normalize`]
+    Doh,
+    /// status and body, plus the headers named here, lowercased and sorted
+    Body { headers: Vec<String> },
+    /// status alone, e.g. of a webhook receiver
+    Status,
+}
+
+impl Transform {
+    /// status and body
+    pub fn body() -> Self {
+        Self::Body {
+            headers: Vec::new(),
+        }
+    }
+
+    fn apply(&self, response: HttpResponse, max_body_bytes: usize) -> HttpResponse {
+        let HttpResponse {
+            status,
+            headers,
+            mut body,
+        } = response;
+        // the outcall already refused anything larger, this only keeps a transform from passing
+        // more on when the limit was raised
+        body.truncate(max_body_bytes);
+
+        match self {
+            Self::Doh => HttpResponse {
+                status,
+                headers: Vec::new(),
+                body: dns::normalize(&body),
+            },
+            Self::Body { headers: kept } => {
+                let mut headers = headers
+                    .into_iter()
+                    .map(|h| HttpHeader {
+                        name: h.name.to_ascii_lowercase(),
+                        value: h.value,
+                    })
+                    .filter(|h| kept.contains(&h.name))
+                    .collect::<Vec<_>>();
+                headers.sort();
+
+                HttpResponse {
+                    status,
+                    headers,
+                    body,
+                }
+            }
+            Self::Status => HttpResponse {
+                status,
+                headers: Vec::new(),
+                body: Vec::new(),
+            },
+        }
+    }
+}
+
+/// The transform of every outcall, what to keep comes with the context. A context that doesn't
+/// decode keeps nothing but the status.
+#[ic_cdk::query(hidden = true)]
+fn transform_outcall(args: TransformArgs) -> HttpResponse {
+    match candid::decode_args::<(Transform, u64)>(&args.context) {
+        Ok((transform, max_body_bytes)) => transform.apply(args.response, max_body_bytes as usize),
+        Err(_) => Transform::Status.apply(args.response, 0),
+    }
+}
+
+/// An HTTPS outcall, made through [`send`].
+pub struct Outcall {
+    pub url: String,
+    pub method: HttpMethod,
+    pub headers: Vec<HttpHeader>,
+    pub body: Option<Vec<u8>>,
+    /// larger responses fail the outcall, the cycles it costs grow with it
+    pub max_response_bytes: u64,
+    /// attached to the first attempt, unused cycles are refunded by the management canister
+    pub cycles: u128,
+    pub transform: Transform,
+}
+
+impl Outcall {
+    pub fn get(url: String, max_response_bytes: u64, cycles: u128, transform: Transform) -> Self {
+        Self {
+            url,
+            method: HttpMethod::GET,
+            headers: Vec::new(),
+            body: None,
+            max_response_bytes,
+            cycles,
+            transform,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push(HttpHeader {
+            name: name.to_string(),
+            value: value.into(),
+        });
+        self
+    }
+
+    fn argument(&self) -> anyhow::Result<CanisterHttpRequestArgument> {
+        let context = candid::encode_args((self.transform.clone(), self.max_response_bytes))?;
+
+        anyhow::Ok(CanisterHttpRequestArgument {
+            url: self.url.clone(),
+            max_response_bytes: Some(self.max_response_bytes),
+            method: self.method,
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+            transform: Some(TransformContext::from_name(
+                "transform_outcall".to_string(),
+                context,
+            )),
+        })
+    }
+}
+
+/// a management canister rejection over the cycles attached rather than the outcall itself
+fn short_of_cycles(msg: &str) -> bool {
+    msg.contains("cycles")
+}
+
+/// Makes `outcall`, retrying up to [`MAX_ATTEMPTS`] times. An attempt that fell short of cycles
+/// never left the canister and is made again with twice as many. Replicas that saw different
+/// responses (`SysTransient`) are simply asked again, unless the request is a POST that may
+/// already have had its effect. Any other rejection is returned right away.
+pub async fn send(outcall: Outcall) -> anyhow::Result<HttpResponse> {
+    let arg = outcall.argument()?;
+    let idempotent = matches!(outcall.method, HttpMethod::GET | HttpMethod::HEAD);
+    let mut cycles = outcall.cycles;
+    let mut attempt = 1;
+
+    loop {
+        let (code, msg) = match http_request(arg.clone(), cycles).await {
+            Ok((resp,)) => return anyhow::Ok(resp),
+            Err(rejection) => rejection,
+        };
+
+        let retry = match code {
+            _ if short_of_cycles(&msg) && cycles < MAX_CYCLES => {
+                cycles = cycles.saturating_mul(2).min(MAX_CYCLES);
+                true
+            }
+            RejectionCode::SysTransient => idempotent,
+            _ => false,
+        };
+
+        if !retry || attempt >= MAX_ATTEMPTS {
+            return Err(anyhow!("{code:?} {msg}"));
+        }
+
+        attempt += 1;
+    }
+}
+
+/// the status code of a response, `0` for one that doesn't fit
+pub fn status(resp: &HttpResponse) -> u16 {
+    u16::try_from(&resp.status.0).unwrap_or_default()
+}
@@ -0,0 +1,218 @@
+use std::{cell::RefCell, time::Duration};
+
+use anyhow::anyhow;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use candid::CandidType;
+use hmac::{Hmac, Mac};
+use ic_cdk::api::management_canister::{
+    http_request::{HttpHeader, HttpMethod},
+    main::raw_rand,
+};
+use ic_stable_structures::StableCell;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    cert_manager::CertificateManager,
+    clock,
+    config::Config,
+    mem::{candid_storable, Mem, Memory},
+    order::{OrderManager, OrderStatus, StoredOrder},
+    outcall::{self, Outcall, Transform},
+    router,
+};
+
+pub const PICKUP_PATH: &str = "/pickup/";
+/// how long a pickup URL sent in a webhook stays usable
+const PICKUP_URL_VALIDITY: Duration = Duration::from_secs(24 * 60 * 60);
+const WEBHOOK_MAX_RESPONSE_BYTES: u64 = 1024;
+/// unused cycles are refunded by the management canister
+const WEBHOOK_OUTCALL_CYCLES: u128 = 2_000_000_000;
+
+thread_local! {
+    static SECRET: RefCell<StableCell<PickupSecret, Memory>> = RefCell::new(
+        Mem::cell::<PickupSecret, _>(PickupSecret::default()),
+    );
+}
+
+/// HMAC key authenticating pickup URLs, drawn from `raw_rand` the first time one is needed
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct PickupSecret(Vec<u8>);
+
+candid_storable!(PickupSecret);
+
+/// body POSTed to an order's `notify_url`
+#[derive(Serialize, Debug)]
+struct PickupNotification {
+    order: u64,
+    status: &'static str,
+    /// absent when the order turned invalid
+    pickup_url: Option<String>,
+    /// RFC 3339, after which `pickup_url` is refused
+    pickup_expires: Option<String>,
+    error: Option<String>,
+}
+
+async fn secret() -> anyhow::Result<Vec<u8>> {
+    let stored = SECRET.with_borrow(|cell| cell.get().0.clone());
+
+    if !stored.is_empty() {
+        return anyhow::Ok(stored);
+    }
+
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|(code, msg)| anyhow!("failed to draw the pickup secret: {code:?} {msg}"))?;
+
+    SECRET.with_borrow_mut(|cell| {
+        // a concurrent call may have won the race, its secret already signed URLs
+        if cell.get().0.is_empty() {
+            cell.set(PickupSecret(bytes))
+                .map_err(|e| anyhow!("failed to store the pickup secret: {e:?}"))?;
+        }
+
+        anyhow::Ok(cell.get().0.clone())
+    })
+}
+
+fn mac(secret: &[u8], order: u64, expires: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(format!("{order}.{expires}").as_bytes());
+
+    mac
+}
+
+/// time-limited URL the certificate of `order` can be downloaded from without authentication
+pub async fn signed_url(order: u64) -> anyhow::Result<(String, u64)> {
+    let secret = secret().await?;
+    let expires = clock::now_nanos() + PICKUP_URL_VALIDITY.as_nanos() as u64;
+    let signature =
+        BASE64_URL_SAFE_NO_PAD.encode(mac(&secret, order, expires).finalize().into_bytes());
+
+    let url = format!(
+        "{}{PICKUP_PATH}{order}?expires={expires}&sig={signature}",
+        Config::base_url()
+    );
+
+    anyhow::Ok((url, expires))
+}
+
+/// PEM chain for a pickup URL, `None` for anything expired, tampered with or not yet issued
+pub fn redeem(url: &str) -> Option<String> {
+    let (path, query) = url.split_once('?')?;
+    let order = path.strip_prefix(PICKUP_PATH)?.parse::<u64>().ok()?;
+    let expires = router::query_param(query, "expires")?.parse::<u64>().ok()?;
+    let signature = BASE64_URL_SAFE_NO_PAD
+        .decode(router::query_param(query, "sig")?)
+        .ok()?;
+
+    if expires < clock::now_nanos() {
+        return None;
+    }
+
+    let secret = SECRET.with_borrow(|cell| cell.get().0.clone());
+
+    if secret.is_empty() {
+        return None;
+    }
+
+    // constant time, the signature is attacker controlled
+    mac(&secret, order, expires).verify_slice(&signature).ok()?;
+
+    let serial = OrderManager::get(order)?.certificate_serial?;
+
+    CertificateManager::get(serial).map(|cert| cert.pem_chain)
+}
+
+/// Tells the order's `notify_url` that it finished processing.
+///
+/// Outcalls are made by every replica of the subnet, so receivers can see the same notification
+/// more than once and should deduplicate on the `Idempotency-Key` header.
+pub async fn notify(order: &StoredOrder) -> anyhow::Result<()> {
+    let Some(notify_url) = &order.notify_url else {
+        return anyhow::Ok(());
+    };
+
+    let (pickup_url, pickup_expires) = match order.status {
+        OrderStatus::Valid => {
+            let (url, expires) = signed_url(order.id).await?;
+            (Some(url), Some(clock::rfc3339(expires)))
+        }
+        _ => (None, None),
+    };
+
+    let body = serde_json::to_vec(&PickupNotification {
+        order: order.id,
+        status: order.status.as_str(),
+        pickup_url,
+        pickup_expires,
+        error: order.error.clone(),
+    })?;
+
+    post_webhook(
+        notify_url,
+        format!("order-{}-{}", order.id, order.status.as_str()),
+        Vec::new(),
+        body,
+    )
+    .await
+    .map_err(|e| anyhow!("webhook for order {}: {e}", order.id))
+}
+
+/// POSTs a JSON `body` to `url` with `headers` on top of the usual ones, anything but a 2xx
+/// answer is an error
+pub async fn post_webhook(
+    url: &str,
+    idempotency_key: String,
+    mut headers: Vec<HttpHeader>,
+    body: Vec<u8>,
+) -> anyhow::Result<()> {
+    headers.extend([
+        HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        },
+        HttpHeader {
+            name: "Idempotency-Key".to_string(),
+            value: idempotency_key,
+        },
+    ]);
+
+    // receivers answer differently on every replica (dates, request ids), only the status code
+    // takes part in consensus
+    let outcall = Outcall {
+        url: url.to_string(),
+        method: HttpMethod::POST,
+        headers,
+        body: Some(body),
+        max_response_bytes: WEBHOOK_MAX_RESPONSE_BYTES,
+        cycles: WEBHOOK_OUTCALL_CYCLES,
+        transform: Transform::Status,
+    };
+
+    let resp = outcall::send(outcall)
+        .await
+        .map_err(|e| anyhow!("outcall failed: {e}"))?;
+
+    if !(200..300).contains(&outcall::status(&resp)) {
+        return Err(anyhow!("answered with HTTP {}", resp.status));
+    }
+
+    anyhow::Ok(())
+}
+
+/// webhook targets have to be reachable by an outcall and must not leak pickup URLs in clear text
+pub fn check_notify_url(url: &str) -> anyhow::Result<()> {
+    let host = url
+        .strip_prefix("https://")
+        .ok_or_else(|| anyhow!("notify URL must use https"))?
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default();
+
+    if host.is_empty() || host.contains('@') {
+        return Err(anyhow!("notify URL has no valid host"));
+    }
+
+    anyhow::Ok(())
+}
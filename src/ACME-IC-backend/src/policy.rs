@@ -0,0 +1,69 @@
+use anyhow::anyhow;
+
+use crate::psl;
+
+const MAX_DOMAIN_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+/// Checks every identifier has to pass before any validation work is spent on it, public suffixes
+/// and wildcards directly below one are never issued for.
+pub fn check_domain(domain: &str) -> anyhow::Result<()> {
+    let name = domain.strip_prefix("*.").unwrap_or(domain);
+
+    if name.is_empty() || name.len() > MAX_DOMAIN_LEN {
+        return Err(anyhow!("{domain} is not a valid domain name"));
+    }
+
+    let labels = name.split('.').collect::<Vec<_>>();
+
+    if labels.len() < 2 {
+        return Err(anyhow!("{domain} must contain at least two labels"));
+    }
+
+    for label in labels {
+        let valid = !label.is_empty()
+            && label.len() <= MAX_LABEL_LEN
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+
+        if !valid {
+            return Err(anyhow!("{domain} contains an invalid label"));
+        }
+    }
+
+    // an all numeric TLD means this is an IP address in disguise
+    if name
+        .rsplit('.')
+        .next()
+        .is_some_and(|tld| tld.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return Err(anyhow!("IP addresses are not supported"));
+    }
+
+    if psl::is_public_suffix(name) {
+        return match domain.starts_with("*.") {
+            true => Err(anyhow!(
+                "{domain} would cover every domain below the public suffix {name}"
+            )),
+            false => Err(anyhow!("{domain} is a public suffix")),
+        };
+    }
+
+    anyhow::Ok(())
+}
+
+/// lowercases, deduplicates and sorts the requested names
+pub fn normalize_domains(domains: &[String]) -> Vec<String> {
+    let mut domains = domains
+        .iter()
+        .map(|d| d.trim().trim_end_matches('.').to_ascii_lowercase())
+        .collect::<Vec<_>>();
+
+    domains.sort();
+    domains.dedup();
+
+    domains
+}
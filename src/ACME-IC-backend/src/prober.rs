@@ -0,0 +1,69 @@
+use anyhow::anyhow;
+use x509_cert::{der::Decode, Certificate};
+
+use crate::{
+    config::Config,
+    media,
+    outcall::{self, Outcall, Transform},
+};
+
+/// RFC 8737 §6.1, the ALPN protocol a validation handshake negotiates
+const ACME_TLS_ALPN: &str = "acme-tls/1";
+/// a self-signed validation certificate or a key authorization is well below this
+const PROBE_MAX_RESPONSE_BYTES: u64 = 16 * 1024;
+/// unused cycles are refunded by the management canister
+const PROBE_OUTCALL_CYCLES: u128 = 1_000_000_000;
+
+/// Outcalls only speak HTTPS and can't negotiate ALPN, connections validation needs beyond that
+/// are made by the configured prober. It is asked `GET <prober>?host=<domain>&port=<port>` plus
+/// `query` and answers with what the server returned.
+async fn probe(domain: &str, port: u16, query: &str, accept: &str) -> anyhow::Result<Vec<u8>> {
+    let prober = Config::with(|c| c.challenge_prober.clone())
+        .ok_or_else(|| anyhow!("no challenge prober is configured"))?;
+    let separator = if prober.contains('?') { '&' } else { '?' };
+
+    // the body is all consensus needs, the prober's headers differ between replicas
+    let outcall = Outcall::get(
+        format!("{prober}{separator}host={domain}&port={port}&{query}"),
+        PROBE_MAX_RESPONSE_BYTES,
+        PROBE_OUTCALL_CYCLES,
+        Transform::body(),
+    )
+    .with_header("Accept", accept);
+
+    let resp = outcall::send(outcall)
+        .await
+        .map_err(|e| anyhow!("probe of {domain}:{port} failed: {e}"))?;
+
+    if outcall::status(&resp) != 200 {
+        return Err(anyhow!(
+            "probe of {domain}:{port} failed with HTTP {}",
+            resp.status
+        ));
+    }
+
+    anyhow::Ok(resp.body)
+}
+
+pub fn configured() -> bool {
+    Config::with(|c| c.challenge_prober.is_some())
+}
+
+/// the certificate `{domain}:443` presents when `acme-tls/1` is negotiated, as DER
+pub async fn tls_alpn_certificate(domain: &str) -> anyhow::Result<Certificate> {
+    let der = probe(
+        domain,
+        443,
+        &format!("alpn={ACME_TLS_ALPN}"),
+        media::PKIX_CERT,
+    )
+    .await?;
+
+    Certificate::from_der(&der)
+        .map_err(|_| anyhow!("{domain} presented no valid certificate for {ACME_TLS_ALPN}"))
+}
+
+/// body of `http://{domain}{path}`, RFC 8555 §8.3 asks for port 80 which outcalls can't reach
+pub async fn http_resource(domain: &str, path: &str) -> anyhow::Result<Vec<u8>> {
+    probe(domain, 80, &format!("path={path}"), "*/*").await
+}
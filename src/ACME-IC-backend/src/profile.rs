@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::{
+    config::Config,
+    handler::types::CertificateProfile,
+    tenant::{Tenant, TenantRegistry},
+};
+
+/// profile used when an order does not name one
+pub const CLASSIC: &str = "classic";
+/// a requested notBefore may lie at most this far in the future
+const MAX_NOT_BEFORE_DELAY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// What the requester asked for, everything is optional and checked by [`resolve`].
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct IssuanceOptions {
+    pub profile: Option<String>,
+    pub not_before: Option<u64>,
+    pub not_after: Option<u64>,
+    /// RFC 9773 §5 id of the certificate the order renews, as [`crate::expiry::cert_id`] gives
+    /// it, only orders can name one
+    pub replaces: Option<String>,
+    /// id of the tenant whose CA signs the certificate, the default CA when absent
+    pub tenant: Option<String>,
+}
+
+/// The profile and validity window a certificate is actually issued with.
+#[derive(Clone, Debug)]
+pub struct Issuance {
+    pub profile: CertificateProfile,
+    pub not_before: u64,
+    pub not_after: u64,
+    /// the tenant that signs the certificate under its own policy and key
+    pub tenant: Option<Tenant>,
+}
+
+impl Issuance {
+    pub fn tenant_id(&self) -> Option<&str> {
+        self.tenant.as_ref().map(|t| t.id.as_str())
+    }
+}
+
+/// Picks the profile and clamps the requested window to it, RFC 8555 §7.4 lets the server refuse
+/// windows it does not support. notBefore is backdated by [`Config::clock_skew`], a requested
+/// start before that is moved up to it. A tenant's `max_validity_days` caps the window further.
+pub fn resolve(options: &IssuanceOptions, now: u64) -> anyhow::Result<Issuance> {
+    let profile = Config::profile(options.profile.as_deref()).ok_or_else(|| {
+        anyhow!(
+            "unknown certificate profile {}",
+            options.profile.as_deref().unwrap_or_default()
+        )
+    })?;
+    let tenant = options
+        .tenant
+        .as_deref()
+        .map(|id| TenantRegistry::get(id).ok_or_else(|| anyhow!("unknown tenant {id}")))
+        .transpose()?;
+    let validity_days = match &tenant {
+        Some(tenant) => profile.validity_days.min(tenant.policy.max_validity_days),
+        None => profile.validity_days,
+    };
+    let max_validity = validity_days as u64 * NANOS_PER_DAY;
+
+    // a start further in the past is moved up, the certificate is simply valid for a bit less
+    let earliest = now.saturating_sub(Config::clock_skew().as_nanos() as u64);
+    let mut not_before = options.not_before.unwrap_or(earliest).max(earliest);
+
+    if not_before > now + MAX_NOT_BEFORE_DELAY.as_nanos() as u64 {
+        return Err(anyhow!("notBefore is too far in the future"));
+    }
+
+    let not_after = options.not_after.unwrap_or(not_before + max_validity);
+
+    if not_after <= not_before.max(now) {
+        return Err(anyhow!("notAfter must be later than notBefore and now"));
+    }
+
+    if not_after - not_before > max_validity {
+        // the backdating doesn't cost the requester any of the validity asked for, the start
+        // moves up instead
+        if not_after - not_before.max(now) > max_validity {
+            return Err(anyhow!(
+                "at most {validity_days} days of validity are allowed under the {} profile",
+                profile.name
+            ));
+        }
+
+        not_before = not_after - max_validity;
+    }
+
+    anyhow::Ok(Issuance {
+        profile,
+        not_before,
+        not_after,
+        tenant,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 100 * NANOS_PER_DAY;
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    fn shortlived(not_before: Option<u64>, not_after: Option<u64>) -> IssuanceOptions {
+        IssuanceOptions {
+            profile: Some("shortlived".to_string()),
+            not_before,
+            not_after,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn orders_without_options_get_the_full_classic_window() {
+        let issuance = resolve(&IssuanceOptions::default(), NOW).unwrap();
+
+        assert_eq!(issuance.profile.name, CLASSIC);
+        assert_eq!(issuance.not_before, NOW - HOUR);
+        assert_eq!(issuance.not_after, NOW - HOUR + 365 * NANOS_PER_DAY);
+    }
+
+    #[test]
+    fn the_start_is_backdated_by_the_clock_skew() {
+        let issuance = resolve(&shortlived(None, None), NOW).unwrap();
+
+        assert_eq!(issuance.profile.name, "shortlived");
+        assert_eq!(issuance.not_before, NOW - HOUR);
+        assert_eq!(issuance.not_after, NOW - HOUR + 7 * NANOS_PER_DAY);
+
+        // an earlier start is moved up to the backdated one
+        let issuance = resolve(&shortlived(Some(NOW - 2 * NANOS_PER_DAY), None), NOW).unwrap();
+
+        assert_eq!(issuance.not_before, NOW - HOUR);
+    }
+
+    #[test]
+    fn the_backdating_does_not_shorten_the_requested_window() {
+        let not_after = NOW + 7 * NANOS_PER_DAY;
+        let issuance = resolve(&shortlived(None, Some(not_after)), NOW).unwrap();
+
+        assert_eq!(issuance.not_before, NOW);
+        assert_eq!(issuance.not_after, not_after);
+    }
+
+    #[test]
+    fn windows_the_profile_does_not_allow_are_refused() {
+        let refused = [
+            shortlived(None, Some(NOW + 8 * NANOS_PER_DAY)),
+            shortlived(None, Some(NOW)),
+            shortlived(Some(NOW + 8 * NANOS_PER_DAY), None),
+            IssuanceOptions {
+                profile: Some("unknown".to_string()),
+                ..Default::default()
+            },
+        ];
+
+        for options in refused {
+            assert!(resolve(&options, NOW).is_err(), "{options:?} was resolved");
+        }
+    }
+}
@@ -0,0 +1,281 @@
+use std::{cell::RefCell, collections::HashSet, time::Duration};
+
+use anyhow::anyhow;
+use candid::CandidType;
+use ic_stable_structures::StableCell;
+use serde::Deserialize;
+
+use crate::{
+    clock,
+    mem::{candid_storable, Mem, Memory},
+    outcall::{self, Outcall, Transform},
+    trace,
+};
+
+pub const PSL_URL: &str = "https://publicsuffix.org/list/public_suffix_list.dat";
+/// the list is a little over 200KiB, leave room for it to grow
+const PSL_MAX_RESPONSE_BYTES: u64 = 1024 * 1024;
+/// unused cycles are refunded by the management canister
+const PSL_OUTCALL_CYCLES: u128 = 30_000_000_000;
+/// anything smaller is an error page or a truncated download, not the real list
+const MIN_RULES: usize = 1_000;
+const PSL_REFRESH_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Used until the first successful refresh, covers the suffixes certificates are most commonly
+/// requested under. Single label TLDs need no entry, the implicit `*` rule already treats them
+/// as public suffixes.
+const SEED: &str = "
+ac.uk
+co.uk
+gov.uk
+ltd.uk
+me.uk
+net.uk
+org.uk
+plc.uk
+com.au
+net.au
+org.au
+co.jp
+ne.jp
+or.jp
+co.nz
+net.nz
+org.nz
+com.br
+net.br
+com.cn
+net.cn
+org.cn
+co.in
+net.in
+org.in
+co.za
+com.mx
+com.tr
+co.kr
+*.ck
+!www.ck
+github.io
+gitlab.io
+herokuapp.com
+netlify.app
+pages.dev
+vercel.app
+ic0.app
+raw.ic0.app
+icp0.io
+raw.icp0.io
+";
+
+thread_local! {
+    static LIST: RefCell<StableCell<PublicSuffixList, Memory>> = RefCell::new(
+        Mem::cell::<PublicSuffixList, _>(PublicSuffixList::default()),
+    );
+    /// lookup tables built from `LIST`, dropped whenever the stored list changes
+    static RULES: RefCell<Option<Rules>> = const { RefCell::new(None) };
+}
+
+/// The last public suffix list fetched from [`PSL_URL`], empty until the first refresh.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct PublicSuffixList {
+    pub rules: Vec<String>,
+    pub fetched_at: Option<u64>,
+}
+
+candid_storable!(PublicSuffixList);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PublicSuffixListStatus {
+    /// rules in effect, the built-in seed is used while nothing was fetched yet
+    pub rules: u64,
+    pub fetched_at: Option<u64>,
+    pub source: String,
+}
+
+/// Rules split by kind, see https://github.com/publicsuffix/list/wiki/Format
+#[derive(Default)]
+struct Rules {
+    exact: HashSet<String>,
+    /// `*.ck` is stored as `ck`
+    wildcard: HashSet<String>,
+    /// `!www.ck` is stored as `www.ck`
+    exception: HashSet<String>,
+}
+
+impl Rules {
+    fn from_lines<'a>(lines: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut rules = Self::default();
+
+        for rule in lines.into_iter().filter_map(parse_line) {
+            if let Some(base) = rule.strip_prefix("*.") {
+                rules.wildcard.insert(base.to_string());
+            } else if let Some(exception) = rule.strip_prefix('!') {
+                rules.exception.insert(exception.to_string());
+            } else {
+                rules.exact.insert(rule);
+            }
+        }
+
+        rules
+    }
+
+    /// labels of the public suffix of `labels`, the longest matching rule wins and exceptions
+    /// beat wildcards
+    fn suffix_len(&self, labels: &[&str]) -> usize {
+        let n = labels.len();
+
+        for i in 0..n {
+            let candidate = labels[i..].join(".");
+
+            if self.exception.contains(&candidate) {
+                return n - i - 1;
+            }
+
+            if self.exact.contains(&candidate)
+                || (i + 1 < n && self.wildcard.contains(&labels[i + 1..].join(".")))
+            {
+                return n - i;
+            }
+        }
+
+        // the implicit `*` rule
+        1
+    }
+}
+
+/// the rule on a list line, `None` for comments, blanks and internationalized rules
+fn parse_line(line: &str) -> Option<String> {
+    let rule = line.split_whitespace().next()?;
+
+    // identifiers reaching the policy are ASCII only, unicode rules could never match them
+    if rule.starts_with("//") || !rule.is_ascii() {
+        return None;
+    }
+
+    Some(rule.to_ascii_lowercase())
+}
+
+fn with_rules<T>(f: impl FnOnce(&Rules) -> T) -> T {
+    RULES.with_borrow_mut(|cached| {
+        let rules = cached.get_or_insert_with(|| {
+            LIST.with_borrow(|cell| {
+                let list = cell.get();
+
+                match list.rules.is_empty() {
+                    true => Rules::from_lines(SEED.lines()),
+                    false => Rules::from_lines(list.rules.iter().map(String::as_str)),
+                }
+            })
+        });
+
+        f(rules)
+    })
+}
+
+/// public suffix of `domain`, e.g. `co.uk` for `www.example.co.uk`
+pub fn public_suffix(domain: &str) -> String {
+    let labels = domain.split('.').collect::<Vec<_>>();
+    let len = with_rules(|r| r.suffix_len(&labels)).min(labels.len());
+
+    labels[labels.len() - len..].join(".")
+}
+
+pub fn is_public_suffix(domain: &str) -> bool {
+    public_suffix(domain) == domain
+}
+
+/// public suffix plus one label, e.g. `example.co.uk` for `www.example.co.uk`, `None` when
+/// `domain` is a public suffix itself
+pub fn registered_domain(domain: &str) -> Option<String> {
+    let labels = domain.split('.').collect::<Vec<_>>();
+    let len = with_rules(|r| r.suffix_len(&labels));
+
+    (len < labels.len()).then(|| labels[labels.len() - len - 1..].join("."))
+}
+
+pub fn status() -> PublicSuffixListStatus {
+    LIST.with_borrow(|cell| {
+        let list = cell.get();
+
+        match list.fetched_at {
+            Some(_) => PublicSuffixListStatus {
+                rules: list.rules.len() as u64,
+                fetched_at: list.fetched_at,
+                source: PSL_URL.to_string(),
+            },
+            None => PublicSuffixListStatus {
+                rules: SEED.lines().filter_map(parse_line).count() as u64,
+                fetched_at: None,
+                source: "built-in seed".to_string(),
+            },
+        }
+    })
+}
+
+/// downloads the current list and replaces the stored one, returns the number of rules
+pub async fn refresh() -> anyhow::Result<u64> {
+    // replicas may see different caching headers, only the body and status take part in
+    // consensus
+    let outcall = Outcall::get(
+        PSL_URL.to_string(),
+        PSL_MAX_RESPONSE_BYTES,
+        PSL_OUTCALL_CYCLES,
+        Transform::body(),
+    );
+
+    let resp = outcall::send(outcall)
+        .await
+        .map_err(|e| anyhow!("public suffix list download failed: {e}"))?;
+
+    if outcall::status(&resp) != 200 {
+        return Err(anyhow!(
+            "public suffix list download failed with HTTP {}",
+            resp.status
+        ));
+    }
+
+    let body = String::from_utf8(resp.body)
+        .map_err(|_| anyhow!("public suffix list is not valid UTF-8"))?;
+    let rules = body.lines().filter_map(parse_line).collect::<Vec<_>>();
+
+    if rules.len() < MIN_RULES || !rules.iter().any(|r| r == "co.uk") {
+        return Err(anyhow!(
+            "downloaded public suffix list looks incomplete ({} rules), keeping the current one",
+            rules.len()
+        ));
+    }
+
+    let count = rules.len() as u64;
+
+    LIST.with_borrow_mut(|cell| {
+        cell.set(PublicSuffixList {
+            rules,
+            fetched_at: Some(clock::now_nanos()),
+        })
+        .map_err(|e| anyhow!("failed to store public suffix list: {e:?}"))?;
+
+        anyhow::Ok(())
+    })?;
+
+    RULES.with_borrow_mut(|cached| *cached = None);
+
+    anyhow::Ok(count)
+}
+
+fn refresh_in_background() {
+    ic_cdk::spawn(async {
+        if let Err(e) = refresh().await {
+            trace::log(&format!("public suffix list refresh failed: {e}"));
+        }
+    });
+}
+
+/// the stored list survives upgrades, only the schedule has to be set up again
+pub fn start_refresh_timer() {
+    if LIST.with_borrow(|cell| cell.get().fetched_at.is_none()) {
+        ic_cdk_timers::set_timer(Duration::ZERO, refresh_in_background);
+    }
+
+    ic_cdk_timers::set_timer_interval(PSL_REFRESH_INTERVAL, refresh_in_background);
+}
@@ -0,0 +1,328 @@
+use std::cell::RefCell;
+
+use anyhow::anyhow;
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::{
+    account::AccountManager,
+    api::{ApiError, ApiResult},
+    cert_manager::{CertificateOwner, IssuedCertificate},
+    clock,
+    config::Config,
+    handler::{
+        types::{AccountQuota, StoredAccount},
+        GenericError, R,
+    },
+    mem::{candid_storable, Repository},
+    order::{OrderManager, OrderStatus},
+    rate_limit::{RegisteredDomainLimiter, WEEK},
+    revocation::RevocationRegistry,
+};
+
+thread_local! {
+    static QUOTAS: RefCell<AccountQuotas> = RefCell::new(AccountQuotas::init());
+}
+
+/// What counts against the quota of one account. Entries that stopped counting are dropped
+/// whenever the usage is read.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct QuotaUsage {
+    pending_orders: Vec<u64>,
+    /// registered domain and issuance time of every certificate of the last week
+    issued: Vec<(String, u64)>,
+    /// serial and `not_after` of every certificate that is neither expired nor revoked
+    active: Vec<(u64, u64)>,
+}
+
+candid_storable!(QuotaUsage);
+
+impl QuotaUsage {
+    fn current(mut self, now: u64) -> Self {
+        let cutoff = now.saturating_sub(WEEK.as_nanos() as u64);
+
+        self.pending_orders.retain(|id| {
+            OrderManager::get(*id).is_some_and(|o| {
+                o.expires_at > now
+                    && matches!(
+                        o.status,
+                        OrderStatus::Pending | OrderStatus::Ready | OrderStatus::Processing
+                    )
+            })
+        });
+        self.issued.retain(|(_, at)| *at > cutoff);
+        self.active.retain(|(serial, not_after)| {
+            *not_after > now && RevocationRegistry::get(*serial).is_none()
+        });
+
+        self
+    }
+
+    /// the pending order cap
+    fn check_pending(&self, quota: &AccountQuota) -> Result<(), Exceeded> {
+        if self.pending_orders.len() >= quota.max_pending_orders as usize {
+            // the oldest pending order expires first
+            let reset_at = self
+                .pending_orders
+                .iter()
+                .filter_map(|id| OrderManager::get(*id))
+                .map(|o| o.expires_at)
+                .min()
+                .unwrap_or_default();
+
+            return Err(Exceeded {
+                err: anyhow!(
+                    "the account already has {} pending orders",
+                    self.pending_orders.len()
+                ),
+                limit: "pendingOrders",
+                reset_at,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// the caps one more certificate for `domains` would exceed
+    fn check_issuance(&self, quota: &AccountQuota, domains: &[String]) -> Result<(), Exceeded> {
+        if self.active.len() >= quota.max_active_certificates as usize {
+            let reset_at = self
+                .active
+                .iter()
+                .map(|(_, not_after)| *not_after)
+                .min()
+                .unwrap_or_default();
+
+            return Err(Exceeded {
+                err: anyhow!(
+                    "the account already has {} active certificates",
+                    self.active.len()
+                ),
+                limit: "activeCertificates",
+                reset_at,
+            });
+        }
+
+        for registered in RegisteredDomainLimiter::registered_domains(domains) {
+            let issued = self
+                .issued
+                .iter()
+                .filter(|(domain, _)| *domain == registered)
+                .map(|(_, at)| *at)
+                .collect::<Vec<_>>();
+
+            if issued.len() >= quota.certificates_per_domain_per_week as usize {
+                let reset_at =
+                    issued.iter().min().copied().unwrap_or_default() + WEEK.as_nanos() as u64;
+
+                return Err(Exceeded {
+                    err: anyhow!(
+                        "the account was issued {} certificates for {registered} in the last week",
+                        issued.len()
+                    ),
+                    limit: "certificatesPerDomain",
+                    reset_at,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The first quota a request would exceed, `limit` names it in the problem.
+struct Exceeded {
+    err: anyhow::Error,
+    limit: &'static str,
+    /// when the quota frees up again
+    reset_at: u64,
+}
+
+impl From<Exceeded> for GenericError {
+    fn from(e: Exceeded) -> Self {
+        GenericError::rate_limited(e.err, e.limit, e.reset_at)
+    }
+}
+
+/// An account's quota next to what it used of it.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct QuotaStatus {
+    pub quota: AccountQuota,
+    /// whether `quota` is an override rather than the configured one
+    pub overridden: bool,
+    pub pending_orders: u32,
+    pub active_certificates: u32,
+    /// certificates of the last week per registered domain
+    pub issued_this_week: Vec<(String, u32)>,
+}
+
+/// Per-account usage of the [`AccountQuota`], keyed by account id. The quota itself is the
+/// configured one unless the account carries an override.
+pub struct AccountQuotas {
+    usage: Repository<String, QuotaUsage>,
+}
+
+impl AccountQuotas {
+    fn init() -> Self {
+        Self {
+            usage: Repository::init::<Self>(),
+        }
+    }
+
+    fn usage(account: &str) -> QuotaUsage {
+        QUOTAS
+            .with_borrow(|q| q.usage.get(&account.to_string()))
+            .unwrap_or_default()
+            .current(clock::now_nanos())
+    }
+
+    fn record(account: &str, f: impl FnOnce(&mut QuotaUsage)) {
+        let mut usage = Self::usage(account);
+        f(&mut usage);
+
+        QUOTAS.with_borrow_mut(|q| q.usage.insert(account.to_string(), usage));
+    }
+
+    pub fn quota(account: &StoredAccount) -> AccountQuota {
+        account.quota.unwrap_or_else(Config::account_quota)
+    }
+
+    /// Consulted before `account` opens an order for `domains`, fails with a `rateLimited`
+    /// problem naming the first quota it would exceed and when that quota frees up.
+    pub fn check_order(account: &StoredAccount, domains: &[String]) -> R<()> {
+        let quota = Self::quota(account);
+        let usage = Self::usage(&account.id);
+
+        usage
+            .check_pending(&quota)
+            .and_then(|_| usage.check_issuance(&quota, domains))
+            .map_err(GenericError::from)
+    }
+
+    /// Consulted again when `account` finalizes an order for `domains`, certificates issued since
+    /// the order was opened may have used up its issuance quotas.
+    pub fn check_finalize(account: &StoredAccount, domains: &[String]) -> R<()> {
+        let quota = Self::quota(account);
+
+        Self::usage(&account.id)
+            .check_issuance(&quota, domains)
+            .map_err(GenericError::from)
+    }
+
+    /// orders of an account count as pending until they are `valid`, `invalid` or expired
+    pub fn order_opened(owner: &CertificateOwner, order: u64) {
+        if let CertificateOwner::Account(id) = owner {
+            Self::record(id, |u| u.pending_orders.push(order));
+        }
+    }
+
+    pub fn certificate_issued(cert: &IssuedCertificate) {
+        if let CertificateOwner::Account(id) = &cert.owner {
+            Self::record(id, |u| {
+                for registered in RegisteredDomainLimiter::registered_domains(&cert.domains) {
+                    u.issued.push((registered, cert.issued_at));
+                }
+
+                u.active.push((cert.serial, cert.not_after));
+            });
+        }
+    }
+
+    pub fn status(account_id: &str) -> ApiResult<QuotaStatus> {
+        let account = AccountManager::get(account_id)
+            .ok_or_else(|| ApiError::NotFound(format!("account {account_id}")))?;
+        let usage = Self::usage(account_id);
+
+        let mut issued_this_week = Vec::<(String, u32)>::new();
+
+        for (domain, _) in &usage.issued {
+            match issued_this_week.iter_mut().find(|(d, _)| d == domain) {
+                Some((_, count)) => *count += 1,
+                None => issued_this_week.push((domain.clone(), 1)),
+            }
+        }
+
+        Ok(QuotaStatus {
+            quota: Self::quota(&account),
+            overridden: account.quota.is_some(),
+            pending_orders: usage.pending_orders.len() as u32,
+            active_certificates: usage.active.len() as u32,
+            issued_this_week,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUOTA: AccountQuota = AccountQuota {
+        max_pending_orders: 2,
+        certificates_per_domain_per_week: 2,
+        max_active_certificates: 3,
+    };
+
+    fn names(domains: &[&str]) -> Vec<String> {
+        domains.iter().map(|d| d.to_string()).collect()
+    }
+
+    fn exceeded(checked: Result<(), Exceeded>) -> Option<(&'static str, u64)> {
+        checked.err().map(|e| (e.limit, e.reset_at))
+    }
+
+    #[test]
+    fn pending_orders_are_capped() {
+        let mut usage = QuotaUsage {
+            pending_orders: vec![1],
+            ..Default::default()
+        };
+        assert_eq!(exceeded(usage.check_pending(&QUOTA)), None);
+
+        usage.pending_orders.push(2);
+        assert!(matches!(
+            exceeded(usage.check_pending(&QUOTA)),
+            Some(("pendingOrders", _))
+        ));
+    }
+
+    #[test]
+    fn active_certificates_are_capped_until_the_first_expires() {
+        let mut usage = QuotaUsage {
+            active: vec![(1, 30), (2, 10)],
+            ..Default::default()
+        };
+        let domains = names(&["example.org"]);
+        assert_eq!(exceeded(usage.check_issuance(&QUOTA, &domains)), None);
+
+        usage.active.push((3, 20));
+        assert_eq!(
+            exceeded(usage.check_issuance(&QUOTA, &domains)),
+            Some(("activeCertificates", 10))
+        );
+    }
+
+    #[test]
+    fn weekly_certificates_count_per_registered_domain() {
+        let usage = QuotaUsage {
+            issued: vec![
+                ("example.org".to_string(), 2),
+                ("example.org".to_string(), 1),
+            ],
+            ..Default::default()
+        };
+        let reset_at = 1 + WEEK.as_nanos() as u64;
+
+        assert_eq!(
+            exceeded(usage.check_issuance(&QUOTA, &names(&["www.example.org"]))),
+            Some(("certificatesPerDomain", reset_at))
+        );
+        assert_eq!(
+            exceeded(usage.check_issuance(&QUOTA, &names(&["*.example.org"]))),
+            Some(("certificatesPerDomain", reset_at))
+        );
+        assert_eq!(
+            exceeded(usage.check_issuance(&QUOTA, &names(&["example.com"]))),
+            None
+        );
+    }
+}
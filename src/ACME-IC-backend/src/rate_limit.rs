@@ -0,0 +1,156 @@
+use std::{cell::RefCell, fmt, time::Duration};
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::{
+    api::ApiError,
+    clock,
+    mem::{candid_storable, Repository},
+    psl,
+};
+
+pub const WEEK: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+thread_local! {
+    static ISSUANCES: RefCell<RegisteredDomainLimiter> =
+        RefCell::new(RegisteredDomainLimiter::init());
+}
+
+/// issuance times within the last week, oldest first
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct IssuanceHistory(Vec<u64>);
+
+candid_storable!(IssuanceHistory);
+
+impl IssuanceHistory {
+    fn recent(mut self, now: u64) -> Self {
+        let cutoff = now.saturating_sub(WEEK.as_nanos() as u64);
+        self.0.retain(|t| *t > cutoff);
+
+        self
+    }
+}
+
+/// Enforces `RateLimit::certificates_per_week` per registered domain, so every name below
+/// `example.co.uk` shares one budget while unrelated customers of a shared suffix do not.
+pub struct RegisteredDomainLimiter {
+    issued: Repository<String, IssuanceHistory>,
+}
+
+impl RegisteredDomainLimiter {
+    fn init() -> Self {
+        Self {
+            issued: Repository::init::<Self>(),
+        }
+    }
+
+    /// the distinct registered domains a certificate for `domains` counts against
+    pub fn registered_domains(domains: &[String]) -> Vec<String> {
+        let mut registered = domains
+            .iter()
+            .filter_map(|d| psl::registered_domain(d.strip_prefix("*.").unwrap_or(d)))
+            .collect::<Vec<_>>();
+
+        registered.sort();
+        registered.dedup();
+
+        registered
+    }
+
+    /// Takes a slot of the weekly budget of every registered domain of `domains` at once, before
+    /// any outcall, so concurrent issuances can't all pass the check before one of them is signed.
+    /// Returns the time the slot is held under, for [`Self::release`].
+    pub fn reserve(domains: &[String], per_week: u32) -> Result<u64, LimitReached> {
+        let now = clock::now_nanos();
+        let registered = Self::registered_domains(domains);
+
+        ISSUANCES.with_borrow_mut(|l| {
+            let histories = registered
+                .into_iter()
+                .map(|r| {
+                    let history = l.issued.get(&r).unwrap_or_default().recent(now);
+                    (r, history)
+                })
+                .collect::<Vec<_>>();
+
+            for (registered, history) in &histories {
+                if history.0.len() >= per_week as usize {
+                    return Err(LimitReached {
+                        registered: registered.clone(),
+                        issued: history.0.len(),
+                        reset_at: history.0.iter().min().copied().unwrap_or(now)
+                            + WEEK.as_nanos() as u64,
+                    });
+                }
+            }
+
+            for (registered, mut history) in histories {
+                history.0.push(now);
+                l.issued.insert(registered, history);
+            }
+
+            Ok(now)
+        })
+    }
+
+    /// gives back the slot [`Self::reserve`] took at `at` for an issuance that failed
+    pub fn release(domains: &[String], at: u64) {
+        ISSUANCES.with_borrow_mut(|l| {
+            for registered in Self::registered_domains(domains) {
+                let Some(mut history) = l.issued.get(&registered) else {
+                    continue;
+                };
+
+                if let Some(i) = history.0.iter().position(|t| *t == at) {
+                    history.0.remove(i);
+                    l.issued.insert(registered, history);
+                }
+            }
+        })
+    }
+
+    /// counts a certificate that was issued without a reserved slot
+    pub fn record(domains: &[String]) {
+        let now = clock::now_nanos();
+
+        ISSUANCES.with_borrow_mut(|l| {
+            for registered in Self::registered_domains(domains) {
+                let mut history = l.issued.get(&registered).unwrap_or_default().recent(now);
+                history.0.push(now);
+
+                l.issued.insert(registered, history);
+            }
+        })
+    }
+}
+
+/// a registered domain that used up its weekly budget, frees up a slot at `reset_at`
+#[derive(Debug)]
+pub struct LimitReached {
+    pub registered: String,
+    pub issued: usize,
+    pub reset_at: u64,
+}
+
+impl fmt::Display for LimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "too many certificates ({}) already issued for {} in the last week",
+            self.issued, self.registered
+        )
+    }
+}
+
+impl From<LimitReached> for ApiError {
+    fn from(e: LimitReached) -> Self {
+        ApiError::Unavailable {
+            message: e.to_string(),
+            retry_after_secs: e
+                .reset_at
+                .saturating_sub(clock::now_nanos())
+                .div_ceil(1_000_000_000),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::cell::RefCell;
+
+use candid::CandidType;
+use serde::Deserialize;
+use x509_cert::ext::pkix::crl::CrlReason;
+
+use crate::{
+    api::{ApiError, ApiResult},
+    audit::{AuditAction, AuditActor, AuditLog, AuditOutcome},
+    blocklist::KeyBlocklist,
+    cert_manager::CertificateManager,
+    clock,
+    events::Events,
+    mem::{candid_storable, Repository},
+    metrics, ocsp,
+};
+
+pub const REVOKE_CERT: &str = "/revoke-cert";
+/// RFC 5280 §5.3.1 `keyCompromise`
+pub const KEY_COMPROMISE: u8 = 1;
+
+thread_local! {
+    static REVOCATIONS: RefCell<RevocationRegistry> = RefCell::new(RevocationRegistry::init());
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Revocation {
+    pub serial: u64,
+    pub revoked_at: u64,
+    /// RFC 5280 §5.3.1 reason code
+    pub reason: u8,
+}
+
+candid_storable!(Revocation);
+
+impl Revocation {
+    pub fn valid_reason(reason: u8) -> bool {
+        // 7 is unassigned
+        reason != 7 && reason <= 10
+    }
+
+    pub fn crl_reason(&self) -> CrlReason {
+        match self.reason {
+            1 => CrlReason::KeyCompromise,
+            2 => CrlReason::CaCompromise,
+            3 => CrlReason::AffiliationChanged,
+            4 => CrlReason::Superseded,
+            5 => CrlReason::CessationOfOperation,
+            6 => CrlReason::CertificateHold,
+            8 => CrlReason::RemoveFromCRL,
+            9 => CrlReason::PrivilegeWithdrawn,
+            10 => CrlReason::AaCompromise,
+            _ => CrlReason::Unspecified,
+        }
+    }
+}
+
+/// Revoked serials of every certificate issued by this canister, the source for OCSP and CRLs.
+pub struct RevocationRegistry {
+    revoked: Repository<u64, Revocation>,
+}
+
+impl RevocationRegistry {
+    fn init() -> Self {
+        Self {
+            revoked: Repository::init::<Self>(),
+        }
+    }
+
+    /// revokes on behalf of the caller, the attempt is audited whether it succeeds or not
+    pub fn revoke(serial: u64, reason: u8) -> ApiResult<Revocation> {
+        Self::revoke_as(serial, reason, AuditActor::Principal(ic_cdk::caller()))
+    }
+
+    pub fn revoke_as(serial: u64, reason: u8, actor: AuditActor) -> ApiResult<Revocation> {
+        let revocation = Self::_revoke(serial, reason);
+        let cert = CertificateManager::get(serial);
+
+        if let (Ok(revoked), Some(cert)) = (&revocation, &cert) {
+            metrics::certificate_revoked();
+            Events::revoked(cert, revoked);
+        }
+
+        AuditLog::record(
+            actor,
+            AuditAction::CertificateRevoked,
+            cert.map(|c| c.domains).unwrap_or_default(),
+            AuditOutcome::of(&revocation),
+            Some(serial),
+        );
+
+        revocation
+    }
+
+    /// The key of `serial` is compromised: it is blocklisted and every certificate issued for it
+    /// is revoked with `keyCompromise`, as Let's Encrypt does. Returns the revocations it made,
+    /// certificates that were already revoked keep their reason.
+    pub fn revoke_key(serial: u64, actor: AuditActor) -> ApiResult<Vec<Revocation>> {
+        let cert = CertificateManager::get(serial)
+            .ok_or_else(|| ApiError::NotFound(format!("certificate {serial}")))?;
+
+        if cert.imported.is_some() {
+            return Err(ApiError::InvalidArgument(format!(
+                "certificate {serial} was imported, revoke it with its issuer"
+            )));
+        }
+
+        let hash = cert
+            .key_hash()
+            .map_err(|e| ApiError::Internal(e.to_string()))?;
+
+        KeyBlocklist::block(&hash, Some(serial));
+        AuditLog::record(
+            actor.clone(),
+            AuditAction::KeyBlocked,
+            vec![hash.clone()],
+            AuditOutcome::Success,
+            Some(serial),
+        );
+
+        let revocations = CertificateManager::with_key(&hash)
+            .into_iter()
+            .filter(|c| c.imported.is_none() && Self::get(c.serial).is_none())
+            .filter_map(|c| Self::revoke_as(c.serial, KEY_COMPROMISE, actor.clone()).ok())
+            .collect();
+
+        Ok(revocations)
+    }
+
+    fn _revoke(serial: u64, reason: u8) -> ApiResult<Revocation> {
+        if !Revocation::valid_reason(reason) {
+            return Err(ApiError::InvalidArgument(format!(
+                "{reason} is not a valid revocation reason"
+            )));
+        }
+
+        match CertificateManager::get(serial) {
+            None => return Err(ApiError::NotFound(format!("certificate {serial}"))),
+            Some(cert) if cert.imported.is_some() => {
+                return Err(ApiError::InvalidArgument(format!(
+                    "certificate {serial} was imported, revoke it with its issuer"
+                )));
+            }
+            Some(_) => {}
+        }
+
+        REVOCATIONS.with_borrow_mut(|r| {
+            if r.revoked.contains(&serial) {
+                return Err(ApiError::InvalidArgument(format!(
+                    "certificate {serial} is already revoked"
+                )));
+            }
+
+            let revocation = Revocation {
+                serial,
+                revoked_at: clock::now_nanos(),
+                reason,
+            };
+
+            r.revoked.insert(serial, revocation.clone());
+            ocsp::forget(serial);
+
+            Ok(revocation)
+        })
+    }
+
+    pub fn get(serial: u64) -> Option<Revocation> {
+        REVOCATIONS.with_borrow(|r| r.revoked.get(&serial))
+    }
+
+    pub fn list() -> Vec<Revocation> {
+        REVOCATIONS.with_borrow(|r| r.revoked.values().collect())
+    }
+}
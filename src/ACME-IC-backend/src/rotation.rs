@@ -0,0 +1,309 @@
+use std::{cell::RefCell, time::Duration};
+
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use x509_cert::name::Name;
+
+use crate::{
+    api::{ApiError, ApiResult},
+    audit::{AuditAction, AuditActor, AuditLog, AuditOutcome},
+    ceremony::{CeremonyKind, CeremonyTranscript},
+    cert_manager::CertificateManager,
+    clock, crl,
+    key::{AcmeKey, Certificate, INITIAL_KEY_VERSION},
+    mem::{candid_storable, Repository},
+    trace,
+};
+
+/// relying parties need time to pick up the new root, but the old one expires within a year
+const MAX_OVERLAP: Duration = Duration::from_secs(180 * 24 * 60 * 60);
+
+thread_local! {
+    static ROTATIONS: RefCell<KeyRotations> = RefCell::new(KeyRotations::init());
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RotationStatus {
+    /// both roots are served, leaves are still issued with the old key
+    Overlapping,
+    /// leaves are issued with the new key
+    Completed,
+    Cancelled,
+}
+
+/// One rotation of the root key from `from_version` to `to_version`, kept as its audit trail.
+/// Leaves are issued straight from the root, there is no intermediate key to rotate with it.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct KeyRotation {
+    pub id: u64,
+    pub from_version: u32,
+    pub to_version: u32,
+    pub status: RotationStatus,
+    /// self-signed root over the new key
+    pub root_pem: String,
+    /// the new key certified by the old root, leaves of the new key chain up to the old root with it
+    pub cross_signed_pem: String,
+    /// the old key certified by the new root, leaves of the old key chain up to the new root with it
+    pub reverse_cross_signed_pem: String,
+    /// alternate chains added for the two cross-signs, see [`CertificateManager::add_chain`]
+    pub chains: Vec<u64>,
+    pub started_at: u64,
+    /// the rotation can be completed from then on
+    pub overlap_until: u64,
+    pub finished_at: Option<u64>,
+    pub initiator: Principal,
+}
+
+candid_storable!(KeyRotation);
+
+/// Every rotation of the root key, the version of the issuing key follows the last completed one.
+pub struct KeyRotations {
+    rotations: Repository<u64, KeyRotation>,
+}
+
+impl KeyRotations {
+    fn init() -> Self {
+        Self {
+            rotations: Repository::init::<Self>(),
+        }
+    }
+
+    pub fn list() -> Vec<KeyRotation> {
+        ROTATIONS.with_borrow(|r| r.rotations.values().collect())
+    }
+
+    fn completed() -> Vec<KeyRotation> {
+        Self::list()
+            .into_iter()
+            .filter(|r| r.status == RotationStatus::Completed)
+            .collect()
+    }
+
+    /// version of the root key leaves, CRLs and OCSP responses are signed with
+    pub fn active_version() -> u32 {
+        Self::completed()
+            .last()
+            .map(|r| r.to_version)
+            .unwrap_or(INITIAL_KEY_VERSION)
+    }
+
+    /// every root key version that issued leaves, the active one first
+    pub fn issuer_versions() -> Vec<u32> {
+        let mut versions = vec![Self::active_version()];
+        versions.extend(Self::completed().iter().rev().map(|r| r.from_version));
+
+        versions
+    }
+
+    /// The subject of the root of key `version`, the issuer its leaves name. A rotation may change
+    /// the DN, the cross-sign of a rotation away from `version` is issued under the old one.
+    pub fn issuer_name(version: u32) -> Name {
+        Self::completed()
+            .into_iter()
+            .find(|r| r.from_version == version)
+            .and_then(|r| {
+                x509_cert::Certificate::load_pem_chain(r.cross_signed_pem.as_bytes()).ok()
+            })
+            .and_then(|chain| chain.into_iter().next())
+            .map(|cross_signed| cross_signed.tbs_certificate.issuer)
+            .unwrap_or_else(Certificate::root_name)
+    }
+
+    fn in_progress() -> Option<KeyRotation> {
+        Self::list()
+            .into_iter()
+            .find(|r| r.status == RotationStatus::Overlapping)
+    }
+
+    fn audit(action: AuditAction, rotation: &ApiResult<KeyRotation>) {
+        let identifiers = match rotation {
+            Ok(r) => vec![
+                format!("rotation:{}", r.id),
+                format!("v{}", r.from_version),
+                format!("v{}", r.to_version),
+            ],
+            Err(_) => Vec::new(),
+        };
+
+        AuditLog::record(
+            AuditActor::Principal(ic_cdk::caller()),
+            action,
+            identifiers,
+            AuditOutcome::of(rotation),
+            None,
+        );
+    }
+
+    /// Derives the next version of the root key, certifies it and cross-signs the two roots in
+    /// both directions. Both roots are served as alternate chains for `overlap_secs`, issuance
+    /// stays with the old key until [`KeyRotations::complete`].
+    pub async fn start(overlap_secs: u64) -> ApiResult<KeyRotation> {
+        let started = Self::_start(overlap_secs).await;
+        Self::audit(AuditAction::KeyRotationStarted, &started);
+
+        started
+    }
+
+    async fn _start(overlap_secs: u64) -> ApiResult<KeyRotation> {
+        if overlap_secs > MAX_OVERLAP.as_secs() {
+            return Err(ApiError::InvalidArgument(format!(
+                "the overlap can be at most {} seconds",
+                MAX_OVERLAP.as_secs()
+            )));
+        }
+
+        if let Some(rotation) = Self::in_progress() {
+            return Err(ApiError::InvalidArgument(format!(
+                "rotation {} is still in progress",
+                rotation.id
+            )));
+        }
+
+        let internal = |e: anyhow::Error| ApiError::Internal(e.to_string());
+
+        let from_version = Self::active_version();
+        let to_version = from_version + 1;
+        let old = AcmeKey::new_root().with_version(from_version);
+        // a changed issuer DN takes effect with the new root
+        let new = AcmeKey::new_root()
+            .with_version(to_version)
+            .with_name(Certificate::configured_name());
+        let old_root_pem = CertificateManager::root_pem().await.map_err(internal)?;
+
+        let root_serial = CertificateManager::next_serial();
+        let cross_serial = CertificateManager::next_serial();
+        let reverse_serial = CertificateManager::next_serial();
+
+        let root_pem = Certificate::build_ca(&new, &new, root_serial)
+            .await
+            .map_err(internal)?;
+        let cross_signed_pem = Certificate::build_ca(&old, &new, cross_serial)
+            .await
+            .map_err(internal)?;
+        let reverse_cross_signed_pem = Certificate::build_ca(&new, &old, reverse_serial)
+            .await
+            .map_err(internal)?;
+
+        // another rotation may have started while this one was waiting for its signatures
+        if Self::in_progress().is_some() || Self::active_version() != from_version {
+            return Err(ApiError::InvalidArgument(
+                "another rotation started in the meantime".to_string(),
+            ));
+        }
+
+        let chains = [
+            format!("{cross_signed_pem}{old_root_pem}"),
+            format!("{reverse_cross_signed_pem}{root_pem}"),
+        ]
+        .iter()
+        .map(|pem| CertificateManager::add_chain(pem).map(|c| c.id))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(internal)?;
+
+        let now = clock::now_nanos();
+
+        let rotation = ROTATIONS.with_borrow_mut(|r| {
+            let rotation = KeyRotation {
+                id: r.rotations.last().map(|(id, _)| id + 1).unwrap_or(1),
+                from_version,
+                to_version,
+                status: RotationStatus::Overlapping,
+                root_pem: root_pem.clone(),
+                cross_signed_pem: cross_signed_pem.clone(),
+                reverse_cross_signed_pem: reverse_cross_signed_pem.clone(),
+                chains,
+                started_at: now,
+                overlap_until: now + Duration::from_secs(overlap_secs).as_nanos() as u64,
+                finished_at: None,
+                initiator: ic_cdk::caller(),
+            };
+
+            r.rotations.insert(rotation.id, rotation.clone());
+
+            rotation
+        });
+
+        let ceremonies = [
+            (CeremonyKind::RootCreated, &new, root_serial, &root_pem),
+            (
+                CeremonyKind::CrossSigned,
+                &new,
+                cross_serial,
+                &cross_signed_pem,
+            ),
+            (
+                CeremonyKind::CrossSigned,
+                &old,
+                reverse_serial,
+                &reverse_cross_signed_pem,
+            ),
+        ];
+
+        for (kind, key, serial, pem) in ceremonies {
+            if let Err(e) = CeremonyTranscript::record(kind, key, serial, pem) {
+                trace::log(&format!("failed to record the rotation ceremony: {e}"));
+            }
+        }
+
+        Ok(rotation)
+    }
+
+    fn finish(
+        status: RotationStatus,
+        f: impl FnOnce(&KeyRotation) -> ApiResult<()>,
+    ) -> ApiResult<KeyRotation> {
+        let rotation = Self::in_progress()
+            .ok_or_else(|| ApiError::NotFound("rotation in progress".to_string()))?;
+
+        f(&rotation)?;
+
+        ROTATIONS
+            .with_borrow_mut(|r| {
+                r.rotations.update(&rotation.id, |r| {
+                    r.status = status;
+                    r.finished_at = Some(clock::now_nanos());
+                })
+            })
+            .ok_or_else(|| ApiError::NotFound(format!("rotation {}", rotation.id)))
+    }
+
+    /// Switches issuance to the new key once the overlap is over. The cross-signed chains stay
+    /// served for the leaves of either key.
+    pub fn complete() -> ApiResult<KeyRotation> {
+        let completed = Self::finish(RotationStatus::Completed, |rotation| {
+            if clock::now_nanos() < rotation.overlap_until {
+                return Err(ApiError::InvalidArgument(format!(
+                    "the overlap of rotation {} is not over yet",
+                    rotation.id
+                )));
+            }
+
+            CertificateManager::replace_root(&rotation.root_pem)
+                .map_err(|e| ApiError::Internal(e.to_string()))
+        });
+
+        if completed.is_ok() {
+            // the CRL is signed with the issuing key
+            crl::refresh_in_background();
+        }
+
+        Self::audit(AuditAction::KeyRotationCompleted, &completed);
+
+        completed
+    }
+
+    /// abandons the rotation in progress and stops serving its chains, the old key keeps issuing
+    pub fn cancel() -> ApiResult<KeyRotation> {
+        let cancelled = Self::finish(RotationStatus::Cancelled, |rotation| {
+            for id in &rotation.chains {
+                CertificateManager::remove_chain(*id);
+            }
+
+            Ok(())
+        });
+
+        Self::audit(AuditAction::KeyRotationCancelled, &cancelled);
+
+        cancelled
+    }
+}
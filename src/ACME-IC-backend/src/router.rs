@@ -0,0 +1,572 @@
+use anyhow::anyhow;
+use ic_http_certification::{HeaderField, HttpResponse, HttpResponseBuilder, StatusCode};
+use x509_cert::der::Encode;
+
+use crate::{
+    authz::{self, AuthorizationState, AuthorizationStore, AUTHZ_PATH, CHALLENGE_PATH},
+    capability,
+    ceremony::{self, CEREMONY_PATH},
+    cert_manager::{CertificateManager, IssuedCertificate},
+    certification, compression,
+    config::Config,
+    crl::{self, CRL_PATH, VERSIONED_CRL_PATH},
+    expiry::{self, RENEWAL_INFO_PATH, RENEWAL_INFO_RETRY_AFTER},
+    handler::{
+        account::{NewAccount, UpdateAccount},
+        fetch::{FetchAuthorization, FetchCertificate, FetchOrder, RespondChallenge},
+        order::{FinalizeOrder, NewOrder},
+        revocation::RevokeCert,
+        search::SearchCertificates,
+        subscription::ManageSubscriptions,
+        types::{ChallengeType, RenewalInfo},
+        GenericError, Handler, Method, RegularRequest, RequestMarker, ResponseMarker,
+        UpdateRequest,
+    },
+    health::{self, HEALTH_PATH},
+    media,
+    metrics::{self, METRICS_PATH},
+    nonce::{NoncePool, NEW_NONCE},
+    ocsp,
+    order::{OrderManager, StoredOrder, CERTIFICATE_PATH, FINALIZE_PATH, ORDER_PATH},
+    pickup::{self, PICKUP_PATH},
+    tenant::{TenantRegistry, DIRECTORY},
+    trace,
+};
+
+pub const OCSP_PATH: &str = "/ocsp";
+
+pub fn path(url: &str) -> &str {
+    url.split('?').next().unwrap_or_default()
+}
+
+/// value of `name` in the query string `query`, as it was sent
+pub fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn respond(status: StatusCode, content_type: &str, body: Vec<u8>) -> HttpResponse<'static> {
+    HttpResponseBuilder::new()
+        .with_status_code(status)
+        .with_headers(vec![("Content-Type".to_string(), content_type.to_string())])
+        .with_body(body)
+        .with_upgrade(false)
+        .build()
+}
+
+/// polled until the order leaves `processing`, `Retry-After` paces the client
+fn order(order: StoredOrder) -> HttpResponse<'static> {
+    let mut headers = vec![
+        ("Content-Type".to_string(), media::JSON.to_string()),
+        ("Location".to_string(), order.url()),
+    ];
+
+    if let Some(secs) = order.retry_after_secs() {
+        headers.push(("Retry-After".to_string(), secs.to_string()));
+    }
+
+    HttpResponseBuilder::new()
+        .with_status_code(StatusCode::OK)
+        .with_headers(headers)
+        .with_body(serde_json::to_vec_pretty(&order.to_acme()).unwrap_or_default())
+        .with_upgrade(false)
+        .build()
+}
+
+/// RFC 8555 §7.5, `Retry-After` paces a client polling a validation that is being retried
+fn authorization(state: AuthorizationState) -> HttpResponse<'static> {
+    let mut headers = vec![("Content-Type".to_string(), media::JSON.to_string())];
+
+    if let Some(secs) = state.retry_after_secs() {
+        headers.push(("Retry-After".to_string(), secs.to_string()));
+    }
+
+    HttpResponseBuilder::new()
+        .with_status_code(StatusCode::OK)
+        .with_headers(headers)
+        .with_body(serde_json::to_vec_pretty(&state.to_acme()).unwrap_or_default())
+        .with_upgrade(false)
+        .build()
+}
+
+/// RFC 8555 §7.5.1, linked `up` to its authorization
+fn challenge(state: AuthorizationState, kind: ChallengeType) -> HttpResponse<'static> {
+    let mut headers = vec![
+        ("Content-Type".to_string(), media::JSON.to_string()),
+        ("Link".to_string(), format!("<{}>;rel=\"up\"", state.url())),
+    ];
+
+    if let Some(secs) = state.retry_after_secs() {
+        headers.push(("Retry-After".to_string(), secs.to_string()));
+    }
+
+    HttpResponseBuilder::new()
+        .with_status_code(StatusCode::OK)
+        .with_headers(headers)
+        .with_body(serde_json::to_vec_pretty(&state.to_challenge(kind)).unwrap_or_default())
+        .with_upgrade(false)
+        .build()
+}
+
+/// the leaf of `pem_chain` as DER, served as [`media::PKIX_CERT`]
+pub fn leaf_der(pem_chain: &str) -> anyhow::Result<Vec<u8>> {
+    let chain = x509_cert::Certificate::load_pem_chain(pem_chain.as_bytes())?;
+    let leaf = chain
+        .first()
+        .ok_or_else(|| anyhow!("the chain holds no certificate"))?;
+
+    anyhow::Ok(leaf.to_der()?)
+}
+
+/// `<serial>` for the chain a certificate was issued with, `<serial>/<n>` for its n-th alternate
+pub fn chain_ref(resource: &str) -> Option<(u64, usize)> {
+    let (serial, index) = match resource.split_once('/') {
+        Some((serial, index)) => (serial, index.parse().ok().filter(|i| *i > 0)?),
+        None => (resource, 0),
+    };
+
+    Some((serial.parse().ok()?, index))
+}
+
+/// the capability URL of a chain of a certificate
+pub fn chain_url(serial: u64, index: usize) -> String {
+    let resource = match index {
+        0 => format!("{CERTIFICATE_PATH}{serial}"),
+        n => format!("{CERTIFICATE_PATH}{serial}/{n}"),
+    };
+
+    format!("{}{}", Config::base_url(), capability::path(&resource))
+}
+
+/// RFC 8555 §7.4.2, the PEM chain unless the client asks for the DER leaf alone, every other chain
+/// of the set is linked as `alternate`
+fn certificate(
+    cert: IssuedCertificate,
+    index: usize,
+    accept: Option<&str>,
+) -> HttpResponse<'static> {
+    let Some(media_type) = media::negotiate(accept, &[media::PEM_CHAIN, media::PKIX_CERT]) else {
+        return respond(
+            StatusCode::NOT_ACCEPTABLE,
+            "text/plain",
+            format!("available as {} or {}", media::PEM_CHAIN, media::PKIX_CERT).into_bytes(),
+        );
+    };
+
+    let internal_error = |e: anyhow::Error| {
+        respond(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "text/plain",
+            e.to_string().into_bytes(),
+        )
+    };
+
+    let chains = match CertificateManager::chain_set(&cert) {
+        Ok(chains) => chains,
+        Err(e) => return internal_error(e),
+    };
+
+    let Some(pem_chain) = chains.get(index) else {
+        return not_found();
+    };
+
+    let body = if media_type == media::PKIX_CERT {
+        match leaf_der(pem_chain) {
+            Ok(der) => der,
+            Err(e) => return internal_error(e),
+        }
+    } else {
+        pem_chain.clone().into_bytes()
+    };
+
+    let mut headers = vec![
+        ("Content-Type".to_string(), media_type.to_string()),
+        ("Vary".to_string(), "Accept".to_string()),
+    ];
+
+    for alternate in (0..chains.len()).filter(|i| *i != index) {
+        headers.push((
+            "Link".to_string(),
+            format!("<{}>;rel=\"alternate\"", chain_url(cert.serial, alternate)),
+        ));
+    }
+
+    HttpResponseBuilder::new()
+        .with_status_code(StatusCode::OK)
+        .with_headers(headers)
+        .with_body(body)
+        .with_upgrade(false)
+        .build()
+}
+
+/// RFC 8555 §7.1.1, the directory of the tenant `path` belongs to
+fn directory(path: &str) -> HttpResponse<'static> {
+    match TenantRegistry::resolve(path) {
+        Some(tenant) => respond(
+            StatusCode::OK,
+            media::JSON,
+            serde_json::to_vec_pretty(&tenant.directory(&Config::base_url())).unwrap_or_default(),
+        ),
+        None => not_found(),
+    }
+}
+
+/// RFC 9773 §4.2, `Retry-After` tells the client when to ask again
+fn renewal_info(info: RenewalInfo) -> HttpResponse<'static> {
+    HttpResponseBuilder::new()
+        .with_status_code(StatusCode::OK)
+        .with_headers(vec![
+            ("Content-Type".to_string(), media::JSON.to_string()),
+            (
+                "Retry-After".to_string(),
+                RENEWAL_INFO_RETRY_AFTER.as_secs().to_string(),
+            ),
+        ])
+        .with_body(serde_json::to_vec_pretty(&info).unwrap_or_default())
+        .with_upgrade(false)
+        .build()
+}
+
+/// the response of an ACME [`Handler`], owned so it outlives the request it answers
+fn handled<'a>(resp: impl ResponseMarker<'a>) -> HttpResponse<'static> {
+    HttpResponseBuilder::new()
+        .with_status_code(resp.status_code())
+        .with_headers(resp.headers().to_vec())
+        .with_body(resp.body().to_vec())
+        .with_upgrade(false)
+        .build()
+}
+
+fn not_found() -> HttpResponse<'static> {
+    respond(StatusCode::NOT_FOUND, "text/plain", b"not found".to_vec())
+}
+
+/// RFC 8555 §6.3, orders, authorizations, challenges and certificates are fetched with a
+/// POST-as-GET unless legacy GETs are enabled
+fn post_as_get_required() -> HttpResponse<'static> {
+    problem(
+        GenericError::method_not_allowed(anyhow!("fetch this resource with a POST-as-GET request")),
+        vec![("Allow".to_string(), "POST".to_string())],
+    )
+}
+
+/// `err` as a problem document, for responses built outside of a `Handler`
+fn problem(err: GenericError, extra_headers: Vec<HeaderField>) -> HttpResponse<'static> {
+    let problem = err.to_problem();
+    let mut headers = err.headers();
+    headers.extend(extra_headers);
+
+    HttpResponseBuilder::new()
+        .with_status_code(
+            StatusCode::from_u16(problem.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        )
+        .with_headers(headers)
+        .with_body(serde_json::to_vec_pretty(&problem).unwrap_or_default())
+        .with_upgrade(false)
+        .build()
+}
+
+fn is_capability_resource(path: &str) -> bool {
+    [
+        ORDER_PATH,
+        FINALIZE_PATH,
+        AUTHZ_PATH,
+        CHALLENGE_PATH,
+        CERTIFICATE_PATH,
+    ]
+    .iter()
+    .any(|prefix| path.starts_with(prefix))
+}
+
+/// Unauthenticated GET of a resource at its capability URL, for clients that predate
+/// POST-as-GET. A URL without its token is as unknown as a missing resource.
+fn legacy_get(p: &str, accept: Option<&str>) -> HttpResponse<'static> {
+    let Some(resource) = capability::verify(p) else {
+        return not_found();
+    };
+
+    let resp = if let Some(id) = resource.strip_prefix(ORDER_PATH) {
+        id.parse().ok().and_then(OrderManager::get).map(order)
+    } else if let Some(id) = resource.strip_prefix(AUTHZ_PATH) {
+        authz::authz_ref(id)
+            .and_then(|(order, index)| AuthorizationStore::get(order, index))
+            .map(authorization)
+    } else if let Some(id) = resource.strip_prefix(CHALLENGE_PATH) {
+        authz::challenge_ref(id)
+            .and_then(|(order, index, kind)| Some((AuthorizationStore::get(order, index)?, kind)))
+            .filter(|(state, kind)| state.check_offered(*kind).is_ok())
+            .map(|(state, kind)| challenge(state, kind))
+    } else if let Some(id) = resource.strip_prefix(CERTIFICATE_PATH) {
+        chain_ref(id)
+            .and_then(|(serial, index)| Some((CertificateManager::get(serial)?, index)))
+            .map(|(cert, index)| certificate(cert, index, accept))
+    } else {
+        None
+    };
+
+    resp.unwrap_or_else(not_found)
+}
+
+fn upgrade() -> HttpResponse<'static> {
+    HttpResponseBuilder::new().with_upgrade(true).build()
+}
+
+fn crl_response(der: Option<Vec<u8>>) -> Option<HttpResponse<'static>> {
+    der.map(|der| respond(StatusCode::OK, "application/pkix-crl", der))
+}
+
+fn ceremony_response() -> Option<HttpResponse<'static>> {
+    ceremony::signed().map(|signed| {
+        respond(
+            StatusCode::OK,
+            media::JSON,
+            serde_json::to_vec_pretty(&signed).unwrap_or_default(),
+        )
+    })
+}
+
+/// certifies the resources served from stable memory, after each change and after an upgrade
+pub fn certify_resources() {
+    let crls = crl::versions()
+        .into_iter()
+        .map(|version| (crl::path(version), crl_response(crl::current(version))))
+        .chain(crl::tenant_versions().into_iter().map(|(id, version)| {
+            let der = crl::tenant_current(&id, version);
+
+            (crl::tenant_path(&id, version), crl_response(der))
+        }));
+
+    for (path, response) in crls.chain([(CEREMONY_PATH.to_string(), ceremony_response())]) {
+        let Some(response) = response else {
+            continue;
+        };
+
+        if let Err(e) = certification::certify(&path, response) {
+            trace::log(&format!("failed to certify {path}: {e}"));
+        }
+    }
+}
+
+/// Query entry point of the HTTP gateway, anything that needs consensus or signing is upgraded.
+pub fn dispatch_query(req: &RegularRequest) -> HttpResponse<'static> {
+    let url = RequestMarker::url(req);
+    let method = req.req_method();
+
+    let accept_encoding = media::header(req.headers(), "Accept-Encoding");
+
+    if matches!(method, Ok(Method::GET)) {
+        if let Some(resp) = certification::certified(path(url), accept_encoding) {
+            return resp;
+        }
+    }
+
+    let id = trace::request_id(req.headers());
+    let Some(resp) = trace::scoped(Some(id.clone()), || route_query(req)) else {
+        return upgrade();
+    };
+
+    // dynamic or per-request responses can't be certified ahead of time
+    certification::skipped(
+        url,
+        compression::negotiated(accept_encoding, trace::tagged(&id, resp)),
+    )
+}
+
+/// the responses a query answers itself, `None` for those that need an update call
+fn route_query(req: &RegularRequest) -> Option<HttpResponse<'static>> {
+    let url = RequestMarker::url(req);
+
+    let resp = match (req.req_method(), path(url)) {
+        // not signed yet, certified once they are
+        (Ok(Method::GET), CRL_PATH) | (Ok(Method::GET), CEREMONY_PATH) => not_found(),
+        (Ok(Method::GET), p) if p.starts_with(VERSIONED_CRL_PATH) => not_found(),
+        (Ok(Method::GET), HEALTH_PATH) => respond(
+            StatusCode::OK,
+            media::JSON,
+            serde_json::to_vec_pretty(&health::status()).unwrap_or_default(),
+        ),
+        (Ok(Method::GET), METRICS_PATH) => respond(
+            StatusCode::OK,
+            "text/plain; version=0.0.4",
+            metrics::render(&metrics::current()).into_bytes(),
+        ),
+        (Ok(Method::GET), p) if p.starts_with(PICKUP_PATH) => match pickup::redeem(url) {
+            Some(pem) => respond(StatusCode::OK, media::PEM_CHAIN, pem.into_bytes()),
+            None => not_found(),
+        },
+        (Ok(Method::GET), p) if is_capability_resource(p) => match Config::legacy_get() {
+            true => legacy_get(p, media::header(req.headers(), "Accept")),
+            false => post_as_get_required(),
+        },
+        (Ok(Method::GET), p) if tenant_resource(p) == Some(DIRECTORY) => directory(p),
+        (Ok(Method::GET), p) if p.starts_with(RENEWAL_INFO_PATH) => {
+            match expiry::renewal_info(&p[RENEWAL_INFO_PATH.len()..]) {
+                Some(info) => renewal_info(info),
+                None => not_found(),
+            }
+        }
+        _ => return None,
+    };
+
+    Some(resp)
+}
+
+fn is_new_nonce(path: &str) -> bool {
+    tenant_resource(path) == Some(NEW_NONCE)
+}
+
+/// `path` relative to the base path of the tenant it belongs to
+fn tenant_resource(path: &str) -> Option<&str> {
+    TenantRegistry::resolve(path).map(|t| &path[t.base_path.len()..])
+}
+
+/// The dispatch table of the ACME endpoints declared with `handler!`, matched in order below a
+/// tenant's base path.
+macro_rules! acme_routes {
+    ($($handler:ty),* $(,)?) => {
+        fn is_acme_route(method: &Method, path: &str) -> bool {
+            tenant_resource(path).is_some_and(|resource| {
+                false $(|| <$handler as Handler>::serves(method, resource))*
+            })
+        }
+
+        fn dispatch_acme(
+            method: &Method,
+            path: &str,
+            req: &UpdateRequest<'_>,
+        ) -> Option<HttpResponse<'static>> {
+            let resource = tenant_resource(path)?;
+
+            $(
+                if <$handler as Handler>::serves(method, resource) {
+                    return Some(handled(<$handler as Handler>::accept(req.clone())));
+                }
+            )*
+
+            None
+        }
+
+        /// RFC 8555 §7.1.1 field and path below the tenant's base path of every endpoint the
+        /// directory advertises
+        pub fn directory_resources() -> Vec<(&'static str, &'static str)> {
+            let mut resources = Vec::new();
+
+            $(
+                if let Some(field) = <$handler as Handler>::DIRECTORY {
+                    resources.push((field, <$handler as Handler>::PATH));
+                }
+            )*
+
+            resources
+        }
+    };
+}
+
+acme_routes!(
+    NewAccount,
+    UpdateAccount,
+    NewOrder,
+    RevokeCert,
+    SearchCertificates,
+    ManageSubscriptions
+);
+
+/// POST-as-GET of the resources served at capability URLs, which live outside any tenant's base
+/// path, and the POSTs that ask for a challenge to be validated.
+fn dispatch_fetch(path: &str, req: &UpdateRequest<'_>) -> Option<HttpResponse<'static>> {
+    let resp = if path.starts_with(ORDER_PATH) {
+        handled(<FetchOrder as Handler>::accept(req.clone()))
+    } else if path.starts_with(FINALIZE_PATH) {
+        handled(<FinalizeOrder as Handler>::accept(req.clone()))
+    } else if path.starts_with(AUTHZ_PATH) {
+        handled(<FetchAuthorization as Handler>::accept(req.clone()))
+    } else if path.starts_with(CHALLENGE_PATH) {
+        handled(<RespondChallenge as Handler>::accept(req.clone()))
+    } else if path.starts_with(CERTIFICATE_PATH) {
+        handled(<FetchCertificate as Handler>::accept(req.clone()))
+    } else {
+        return None;
+    };
+
+    Some(resp)
+}
+
+/// RFC 8555 §7.2, the nonce is sent in `Replay-Nonce`. A GET is answered with 204, a HEAD, which
+/// clients send to refetch a nonce after `badNonce`, with 200.
+async fn new_nonce(method: &Method) -> HttpResponse<'static> {
+    let status = match method {
+        Method::HEAD => StatusCode::OK,
+        _ => StatusCode::NO_CONTENT,
+    };
+
+    match NoncePool::take().await {
+        Ok(nonce) => HttpResponseBuilder::new()
+            .with_status_code(status)
+            .with_headers(vec![
+                ("Replay-Nonce".to_string(), nonce),
+                ("Cache-Control".to_string(), "no-store".to_string()),
+            ])
+            .with_upgrade(false)
+            .build(),
+        Err(e) => respond(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "text/plain",
+            e.to_string().into_bytes(),
+        ),
+    }
+}
+
+/// What an update route expects as its body.
+pub enum UpdateBody {
+    /// DER, e.g. an OCSP request
+    Der,
+    /// a flattened JWS, as every ACME POST below a tenant's base path
+    Jws,
+    /// nothing, e.g. a new nonce
+    Empty,
+}
+
+/// the body `method` on `path` expects, `None` for anything [`dispatch_update`] does not serve
+pub fn update_route(method: &Method, path: &str) -> Option<UpdateBody> {
+    match (method, path) {
+        (Method::POST, OCSP_PATH) => Some(UpdateBody::Der),
+        (Method::POST, p) if is_capability_resource(p) => Some(UpdateBody::Jws),
+        (Method::GET | Method::HEAD, p) if is_new_nonce(p) => Some(UpdateBody::Empty),
+        (m, p) if is_acme_route(m, p) => Some(UpdateBody::Jws),
+        _ => None,
+    }
+}
+
+/// Update entry point of the HTTP gateway, large responses are compressed as the client accepts.
+pub async fn dispatch_update(req: &UpdateRequest<'_>) -> HttpResponse<'static> {
+    let id = trace::request_id(RequestMarker::headers(req));
+    let resp = trace::traced(id.clone(), route_update(req)).await;
+
+    compression::negotiated(
+        media::header(RequestMarker::headers(req), "Accept-Encoding"),
+        trace::tagged(&id, resp),
+    )
+}
+
+async fn route_update(req: &UpdateRequest<'_>) -> HttpResponse<'static> {
+    let method = match req.req_method() {
+        Ok(method) => method,
+        Err(_) => return respond(StatusCode::METHOD_NOT_ALLOWED, "text/plain", Vec::new()),
+    };
+
+    match (method, path(RequestMarker::url(req))) {
+        (Method::POST, OCSP_PATH) => respond(
+            StatusCode::OK,
+            "application/ocsp-response",
+            ocsp::respond(req.raw_body()).await,
+        ),
+        (Method::POST, p) if is_capability_resource(p) => {
+            dispatch_fetch(p, req).unwrap_or_else(not_found)
+        }
+        (method @ (Method::GET | Method::HEAD), p) if is_new_nonce(p) => new_nonce(&method).await,
+        (method, p) => dispatch_acme(&method, p, req).unwrap_or_else(not_found),
+    }
+}
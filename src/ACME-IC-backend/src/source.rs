@@ -0,0 +1,136 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use ic_http_certification::HeaderField;
+
+use crate::{
+    account::AccountManager,
+    clock,
+    config::Config,
+    handler::{types::AcmeServerError, GenericError, R},
+    media,
+    tenant::{self, TenantRegistry},
+};
+
+/// set by the boundary nodes to the address a request came from. They are not signed and any
+/// caller can send them, so the address is only good for bookkeeping, never for throttling or
+/// authorization
+const REAL_IP: &str = "x-real-ip";
+const FORWARDED_FOR: &str = "x-forwarded-for";
+/// `RateLimit::requests_per_minute` is counted per signer over fixed windows of this length
+const WINDOW: Duration = Duration::from_secs(60);
+/// signers tracked per window, the least active one makes room for a new one
+const MAX_TRACKED: usize = 50_000;
+
+thread_local! {
+    /// source of the request being handled, see [`scoped`]
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+    static LIMITER: RefCell<SourceLimiter> = RefCell::new(SourceLimiter::default());
+}
+
+/// the client address the boundary node forwarded, the first hop of `X-Forwarded-For` otherwise
+pub fn client_ip(headers: &[HeaderField]) -> Option<String> {
+    media::header(headers, REAL_IP)
+        .or_else(|| media::header(headers, FORWARDED_FOR).and_then(|v| v.split(',').next()))
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .map(str::to_string)
+}
+
+/// runs `f` with `ip` as the source [`current`] reports
+pub fn scoped<T>(ip: Option<String>, f: impl FnOnce() -> T) -> T {
+    CURRENT.set(ip);
+    let result = f();
+    CURRENT.set(None);
+
+    result
+}
+
+/// source of the ACME request being handled, `None` outside of one
+pub fn current() -> Option<String> {
+    CURRENT.with_borrow(|c| c.clone())
+}
+
+/// Requests per signer in the current window, a second dimension next to the per-account and
+/// per-domain limits. A request counts for the key that verifiably signed it rather than for the
+/// address in its headers, which anyone could set to skip the limit or to use up another
+/// client's. Kept on the heap, an upgrade simply starts a new window.
+#[derive(Default)]
+pub struct SourceLimiter {
+    window_start: u64,
+    requests: HashMap<String, u32>,
+    /// `requests` ordered by count, the first entry is evicted when the table is full
+    by_count: BTreeSet<(u32, String)>,
+}
+
+impl SourceLimiter {
+    /// Counts a request for the key that signed `raw_body`, `rateLimited` once it exceeds
+    /// `requests_per_minute`. Requests to a tenant are counted apart from the others, against the
+    /// tenant's own limit. A body its key did not sign isn't counted, its handler refuses it.
+    pub fn admit(raw_body: &[u8]) -> R<()> {
+        let Some(signer) = AccountManager::signer(raw_body) else {
+            return Ok(());
+        };
+
+        let now = clock::now_nanos();
+        let window = WINDOW.as_nanos() as u64;
+        let (key, per_minute) = match tenant::current().and_then(|id| TenantRegistry::get(&id)) {
+            Some(tenant) => (
+                format!("{} {signer}", tenant.id),
+                tenant.rate_limit.requests_per_minute,
+            ),
+            None => (signer, Config::with(|c| c.rate_limit.requests_per_minute)),
+        };
+
+        LIMITER.with_borrow_mut(|l| {
+            if now >= l.window_start + window {
+                l.window_start = now;
+                l.requests.clear();
+                l.by_count.clear();
+            }
+
+            let count = l.count(key);
+
+            if count > per_minute {
+                let retry_after = Duration::from_nanos(l.window_start + window - now).as_secs();
+
+                return Err(GenericError::too_many_requests(
+                    anyhow!(
+                        "too many requests from this account key, at most {per_minute} per minute"
+                    ),
+                    retry_after.max(1),
+                )
+                .with_kind(AcmeServerError::RateLimited));
+            }
+
+            Ok(())
+        })
+    }
+
+    /// one more request of `key`, returns how many it sent this window
+    fn count(&mut self, key: String) -> u32 {
+        let previous = match self.requests.get(&key) {
+            Some(&count) => {
+                self.by_count.remove(&(count, key.clone()));
+                count
+            }
+            None => {
+                if self.requests.len() >= MAX_TRACKED {
+                    if let Some((_, evicted)) = self.by_count.pop_first() {
+                        self.requests.remove(&evicted);
+                    }
+                }
+                0
+            }
+        };
+
+        self.requests.insert(key.clone(), previous + 1);
+        self.by_count.insert((previous + 1, key));
+
+        previous + 1
+    }
+}
@@ -0,0 +1,200 @@
+use candid::{define_function, CandidType};
+use ic_http_certification::{HeaderField, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{certification, media, router};
+
+/// bodies are cut into chunks of this size, well below the 2 MB the HTTP gateway relays at once
+pub const CHUNK_BYTES: usize = 1 << 20;
+
+/// Where the next chunk starts. The resource is the path it was requested at, its chunks are cut
+/// from the same coding of the certified body, or rendered again for a resource without one.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingToken {
+    pub resource: String,
+    pub accept: Option<String>,
+    pub accept_encoding: Option<String>,
+    pub offset: u64,
+    /// SHA-256 of the whole body, so a resource that changed midway is not spliced together
+    pub body_sha256: Vec<u8>,
+}
+
+define_function!(pub StreamingCallback : (StreamingToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingToken,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: Vec<u8>,
+    /// `None` once the last chunk is served
+    pub token: Option<StreamingToken>,
+}
+
+/// The query response of the HTTP gateway, an [`HttpResponse`] that may leave the rest of its body
+/// to [`callback`].
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingHttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+    pub upgrade: Option<bool>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+/// the token of the chunk after `offset` of a body of `len` bytes, `None` if it ends before it
+fn next(
+    resource: &str,
+    headers: &[HeaderField],
+    len: usize,
+    offset: usize,
+    body_sha256: impl FnOnce() -> Vec<u8>,
+) -> Option<StreamingToken> {
+    if offset >= len {
+        return None;
+    }
+
+    Some(StreamingToken {
+        resource: resource.to_string(),
+        accept: media::header(headers, "Accept").map(str::to_string),
+        accept_encoding: media::header(headers, "Accept-Encoding").map(str::to_string),
+        offset: offset as u64,
+        body_sha256: body_sha256(),
+    })
+}
+
+/// Serves the first chunk of `response` to `req`, the HTTP gateway fetches the rest through
+/// [`callback`]. Certification covers the whole body, which the gateway verifies once it has
+/// every chunk.
+pub fn chunked(req: &HttpRequest, response: HttpResponse<'static>) -> StreamingHttpResponse {
+    let resource = router::path(req.url());
+    let body = response.body();
+    let end = body.len().min(CHUNK_BYTES);
+
+    let streaming_strategy = next(resource, req.headers(), body.len(), end, || {
+        Sha256::digest(body).to_vec()
+    })
+    .map(|token| StreamingStrategy::Callback {
+        callback: StreamingCallback::new(
+            ic_cdk::id(),
+            "http_request_streaming_callback".to_string(),
+        ),
+        token,
+    });
+
+    StreamingHttpResponse {
+        status_code: response.status_code().as_u16(),
+        headers: response.headers().to_vec(),
+        body: body[..end].to_vec(),
+        upgrade: response.upgrade(),
+        streaming_strategy,
+    }
+}
+
+/// The chunk of the resource `token` points at. A certified body is sliced from the stored
+/// response, anything else is rendered again as the first chunk was.
+pub fn callback(token: StreamingToken) -> anyhow::Result<StreamingCallbackHttpResponse> {
+    let headers = [
+        ("Accept", &token.accept),
+        ("Accept-Encoding", &token.accept_encoding),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((name.to_string(), value.clone()?)))
+    .collect::<Vec<_>>();
+    let offset = token.offset as usize;
+
+    let chunk = certification::chunk(
+        &token.resource,
+        token.accept_encoding.as_deref(),
+        offset,
+        CHUNK_BYTES,
+    );
+    let chunk = match chunk {
+        Some(chunk) => chunk,
+        None => {
+            let req = HttpRequest::get(token.resource.clone())
+                .with_headers(headers.clone())
+                .build();
+            let response = router::dispatch_query(&req);
+            let body = response.body();
+            let start = offset.min(body.len());
+            let end = body.len().min(start + CHUNK_BYTES);
+
+            certification::Chunk {
+                bytes: body[start..end].to_vec(),
+                len: body.len(),
+                body_sha256: Sha256::digest(body).to_vec(),
+            }
+        }
+    };
+
+    if chunk.body_sha256 != token.body_sha256 {
+        anyhow::bail!("{} changed while it was streamed", token.resource);
+    }
+
+    let end = offset.min(chunk.len) + chunk.bytes.len();
+
+    Ok(StreamingCallbackHttpResponse {
+        token: next(&token.resource, &headers, chunk.len, end, || {
+            chunk.body_sha256.clone()
+        }),
+        body: chunk.bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_http_certification::{HttpResponseBuilder, StatusCode};
+
+    use super::*;
+
+    fn headers() -> Vec<HeaderField> {
+        vec![
+            ("accept".to_string(), "application/pkix-cert".to_string()),
+            ("Accept-Encoding".to_string(), "gzip".to_string()),
+            ("User-Agent".to_string(), "test".to_string()),
+        ]
+    }
+
+    #[test]
+    fn a_body_within_one_chunk_is_not_streamed() {
+        let req = HttpRequest::get("/health?verbose").build();
+        let response = HttpResponseBuilder::new()
+            .with_status_code(StatusCode::OK)
+            .with_body(vec![7; CHUNK_BYTES])
+            .build();
+
+        let streamed = chunked(&req, response);
+
+        assert_eq!(streamed.body.len(), CHUNK_BYTES);
+        assert!(streamed.streaming_strategy.is_none());
+    }
+
+    #[test]
+    fn the_token_carries_what_the_chunk_is_negotiated_on() {
+        let token = next("/crl.der", &headers(), 3 * CHUNK_BYTES, CHUNK_BYTES, || {
+            vec![1, 2, 3]
+        })
+        .unwrap();
+
+        assert_eq!(token.resource, "/crl.der");
+        assert_eq!(token.accept.as_deref(), Some("application/pkix-cert"));
+        assert_eq!(token.accept_encoding.as_deref(), Some("gzip"));
+        assert_eq!(token.offset, CHUNK_BYTES as u64);
+        assert_eq!(token.body_sha256, [1, 2, 3]);
+    }
+
+    #[test]
+    fn there_is_no_token_past_the_last_chunk() {
+        let hashed = || panic!("the body is not hashed for a token that isn't handed out");
+
+        assert!(next("/crl.der", &headers(), CHUNK_BYTES, CHUNK_BYTES, hashed).is_none());
+        assert!(next("/crl.der", &headers(), 0, 0, hashed).is_none());
+    }
+}
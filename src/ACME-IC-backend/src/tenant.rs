@@ -0,0 +1,385 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use anyhow::anyhow;
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use x509_cert::name::Name;
+
+use crate::{
+    api::{ApiError, ApiResult},
+    audit::{AuditAction, AuditActor, AuditLog, AuditOutcome},
+    config::Config,
+    expiry::RENEWAL_INFO_PATH,
+    handler::types::{Directory, Identifier, RateLimit},
+    key::{AcmeKey, Certificate, INITIAL_KEY_VERSION, ROOT_SERIAL_NUMBER},
+    mem::{candid_storable, Repository},
+    nonce::NEW_NONCE,
+    router,
+};
+
+/// namespace of the tenants created without a base path, `/t/<id>`
+pub const TENANT_PREFIX: &str = "/t/";
+/// RFC 8555 §7.1.1 resource below a tenant's base path
+pub const DIRECTORY: &str = "/directory";
+
+thread_local! {
+    static TENANTS: RefCell<TenantRegistry> = RefCell::new(TenantRegistry::init());
+    /// tenant of the ACME request being handled, see [`scoped`]
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// runs `f` with tenant `id` as the one [`current`] reports
+pub fn scoped<T>(id: Option<String>, f: impl FnOnce() -> T) -> T {
+    CURRENT.set(id);
+    let result = f();
+    CURRENT.set(None);
+
+    result
+}
+
+/// id of the tenant the ACME request being handled was sent to, `None` outside of one
+pub fn current() -> Option<String> {
+    CURRENT.with_borrow(|c| c.clone())
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TenantPolicy {
+    /// identifiers must equal or be a subdomain of one of these, an empty list allows any domain
+    pub allowed_domains: Vec<String>,
+    pub allow_wildcards: bool,
+    /// caps the validity of the certificates the tenant signs below what the profile allows
+    pub max_validity_days: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Tenant {
+    pub id: String,
+    /// path prefix every ACME resource of this tenant is served under, e.g. `/customer-a`, left
+    /// empty for `/t/<id>`
+    pub base_path: String,
+    /// principals allowed to manage this tenant, in addition to the canister controllers
+    pub admins: Vec<Principal>,
+    pub policy: TenantPolicy,
+    pub rate_limit: RateLimit,
+}
+
+candid_storable!(Tenant);
+
+impl Tenant {
+    fn validate(&self) -> ApiResult<()> {
+        let valid_id = !self.id.is_empty()
+            && self
+                .id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-');
+
+        if !valid_id {
+            return Err(ApiError::InvalidArgument(
+                "tenant id must be non-empty and only contain alphanumerics or '-'".to_string(),
+            ));
+        }
+
+        if !self.base_path.starts_with('/')
+            || self.base_path.len() < 2
+            || self.base_path.ends_with('/')
+        {
+            return Err(ApiError::InvalidArgument(
+                "base path must start with '/' and must not end with '/'".to_string(),
+            ));
+        }
+
+        // the namespace of one tenant is never handed to another
+        if self.base_path.starts_with(TENANT_PREFIX)
+            && self.base_path != format!("{TENANT_PREFIX}{}", self.id)
+        {
+            return Err(ApiError::InvalidArgument(format!(
+                "base paths below {TENANT_PREFIX} are reserved for the tenant of that id"
+            )));
+        }
+
+        if self.policy.max_validity_days == 0 {
+            return Err(ApiError::InvalidArgument(
+                "max_validity_days must be greater than zero".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn url(&self, origin: &str, resource: &str) -> String {
+        format!("{origin}{}/{resource}", self.base_path)
+    }
+
+    /// newNonce next to every endpoint the router's `acme_routes!` table advertises, and the
+    /// RFC 9773 `renewalInfo` all tenants share
+    pub fn directory(&self, origin: &str) -> Directory {
+        let mut resources: BTreeMap<_, _> = [("newNonce", NEW_NONCE)]
+            .into_iter()
+            .chain(router::directory_resources())
+            .map(|(field, path)| {
+                let url = self.url(origin, path.trim_start_matches('/'));
+
+                (field.to_string(), url)
+            })
+            .collect();
+
+        resources.insert(
+            "renewalInfo".to_string(),
+            format!("{origin}{}", RENEWAL_INFO_PATH.trim_end_matches('/')),
+        );
+
+        Directory {
+            resources,
+            meta: Some(Config::directory_meta()),
+        }
+    }
+
+    /// version of the tenant's key that signs its leaves, bumped by [`TenantRegistry::rotate_key`]
+    pub fn key_version(&self) -> u32 {
+        TENANTS
+            .with_borrow(|r| r.key_versions.get(&self.id))
+            .unwrap_or(INITIAL_KEY_VERSION)
+    }
+
+    /// every version of the tenant's key that may have issued leaves, the active one first
+    pub fn key_versions(&self) -> Vec<u32> {
+        (INITIAL_KEY_VERSION..=self.key_version()).rev().collect()
+    }
+
+    pub fn key(&self, version: u32) -> AcmeKey {
+        AcmeKey::new_root()
+            .with_tenant(&self.id)
+            .with_version(version)
+    }
+
+    /// the issuing key of this tenant
+    pub fn issuer_key(&self) -> AcmeKey {
+        self.key(self.key_version())
+    }
+
+    /// the self-signed root over [`Self::issuer_key`] the tenant's leaves are chained to, created
+    /// on first use
+    pub async fn root_pem(&self) -> anyhow::Result<String> {
+        self.root_pem_of(self.key_version()).await
+    }
+
+    async fn root_pem_of(&self, version: u32) -> anyhow::Result<String> {
+        let id = (self.id.clone(), version);
+
+        if let Some(pem) = TENANTS.with_borrow(|r| r.roots.get(&id)) {
+            return anyhow::Ok(pem);
+        }
+
+        let key = self.key(version);
+        let pem = Certificate::build_ca(&key, &key, ROOT_SERIAL_NUMBER).await?;
+
+        // another call may have stored a root while this one was waiting for its signature
+        anyhow::Ok(TENANTS.with_borrow_mut(|r| match r.roots.get(&id) {
+            Some(stored) => stored,
+            None => {
+                r.roots.insert(id, pem.clone());
+                pem
+            }
+        }))
+    }
+
+    /// the subject of the root of key `version`, the issuer its leaves name
+    pub fn issuer_name(&self, version: u32) -> Name {
+        self.root_name(version)
+            .unwrap_or_else(Certificate::root_name)
+    }
+
+    /// the subject of the root of key `version`, `None` until it was created
+    pub fn root_name(&self, version: u32) -> Option<Name> {
+        TENANTS
+            .with_borrow(|r| r.roots.get(&(self.id.clone(), version)))
+            .and_then(|pem| x509_cert::Certificate::load_pem_chain(pem.as_bytes()).ok())
+            .and_then(|chain| chain.into_iter().next())
+            .map(|root| root.tbs_certificate.subject)
+    }
+
+    pub fn is_admin(&self, principal: &Principal) -> bool {
+        ic_cdk::api::is_controller(principal) || self.admins.contains(principal)
+    }
+
+    fn owns_path(&self, path: &str) -> bool {
+        match path.strip_prefix(self.base_path.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    pub fn permits(&self, identifier: &Identifier) -> anyhow::Result<()> {
+        let value = identifier.value.to_ascii_lowercase();
+
+        let domain = match value.strip_prefix("*.") {
+            Some(_) if !self.policy.allow_wildcards => {
+                return Err(anyhow!("wildcard identifiers are not allowed"));
+            }
+            Some(domain) => domain,
+            None => value.as_str(),
+        };
+
+        if self.policy.allowed_domains.is_empty() {
+            return anyhow::Ok(());
+        }
+
+        let allowed = self.policy.allowed_domains.iter().any(|allowed| {
+            let allowed = allowed.to_ascii_lowercase();
+
+            domain == allowed || domain.ends_with(&format!(".{allowed}"))
+        });
+
+        if !allowed {
+            return Err(anyhow!("{value} is not allowed by the tenant policy"));
+        }
+
+        anyhow::Ok(())
+    }
+}
+
+/// memory markers for the root certificate of every tenant key, by tenant id and key version,
+/// and for the active key version of every tenant
+pub struct TenantRoots;
+pub struct TenantKeyVersions;
+
+pub struct TenantRegistry {
+    tenants: Repository<String, Tenant>,
+    /// kept when a tenant is deleted, a tenant created again under the same id has the same key
+    roots: Repository<(String, u32), String>,
+    /// absent until the tenant's key is first rotated, kept when a tenant is deleted
+    key_versions: Repository<String, u32>,
+}
+
+impl TenantRegistry {
+    fn init() -> Self {
+        Self {
+            tenants: Repository::init::<Self>(),
+            roots: Repository::init::<TenantRoots>(),
+            key_versions: Repository::init::<TenantKeyVersions>(),
+        }
+    }
+
+    fn _ensure_unique_path(&self, tenant: &Tenant) -> ApiResult<()> {
+        let taken = self
+            .tenants
+            .iter()
+            .any(|(id, other)| id != tenant.id && other.base_path == tenant.base_path);
+
+        if taken {
+            return Err(ApiError::InvalidArgument(format!(
+                "base path {} is already used by another tenant",
+                tenant.base_path
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// registers `tenant`, under `/t/<id>` when it comes without a base path
+    pub fn create(mut tenant: Tenant) -> ApiResult<Tenant> {
+        if tenant.base_path.is_empty() {
+            tenant.base_path = format!("{TENANT_PREFIX}{}", tenant.id);
+        }
+
+        tenant.validate()?;
+
+        TENANTS.with_borrow_mut(|r| {
+            if r.tenants.contains(&tenant.id) {
+                return Err(ApiError::InvalidArgument(format!(
+                    "tenant {} already exists",
+                    tenant.id
+                )));
+            }
+
+            r._ensure_unique_path(&tenant)?;
+            r.tenants.insert(tenant.id.clone(), tenant.clone());
+
+            Ok(tenant)
+        })
+    }
+
+    pub fn update(tenant: Tenant) -> ApiResult<()> {
+        tenant.validate()?;
+
+        TENANTS.with_borrow_mut(|r| {
+            if !r.tenants.contains(&tenant.id) {
+                return Err(ApiError::NotFound(format!("tenant {}", tenant.id)));
+            }
+
+            r._ensure_unique_path(&tenant)?;
+            r.tenants.insert(tenant.id.clone(), tenant);
+
+            Ok(())
+        })
+    }
+
+    pub fn remove(id: &str) -> ApiResult<Tenant> {
+        TENANTS
+            .with_borrow_mut(|r| r.tenants.remove(&id.to_string()))
+            .ok_or_else(|| ApiError::NotFound(format!("tenant {id}")))
+    }
+
+    pub fn get(id: &str) -> Option<Tenant> {
+        TENANTS.with_borrow(|r| r.tenants.get(&id.to_string()))
+    }
+
+    pub fn list() -> Vec<Tenant> {
+        TENANTS.with_borrow(|r| r.tenants.values().collect())
+    }
+
+    /// the tenant whose base path is the longest prefix of `path`, if any
+    pub fn resolve(path: &str) -> Option<Tenant> {
+        TENANTS.with_borrow(|r| {
+            r.tenants
+                .values()
+                .filter(|t| t.owns_path(path))
+                .max_by_key(|t| t.base_path.len())
+        })
+    }
+
+    /// Switches tenant `id` to the next version of its key, once the root of that version was
+    /// created. The tenant's later leaves chain to the new root, leaves of older versions keep
+    /// being answered by OCSP and listed on the CRL of their own key. Tenant roots are not
+    /// cross-signed, relying parties have to trust the new root before its first leaf is issued.
+    pub async fn rotate_key(id: &str, principal: &Principal) -> ApiResult<u32> {
+        let tenant = Self::ensure_admin(id, principal)?;
+        let version = tenant.key_version() + 1;
+
+        tenant
+            .root_pem_of(version)
+            .await
+            .map_err(|e| ApiError::Internal(e.to_string()))?;
+
+        // a concurrent rotation may have moved on while the root was signed
+        TENANTS.with_borrow_mut(|r| {
+            let active = r
+                .key_versions
+                .get(&tenant.id)
+                .unwrap_or(INITIAL_KEY_VERSION);
+            r.key_versions
+                .insert(tenant.id.clone(), active.max(version));
+        });
+
+        AuditLog::record(
+            AuditActor::Principal(*principal),
+            AuditAction::KeyRotationCompleted,
+            vec![tenant.id.clone()],
+            AuditOutcome::Success,
+            None,
+        );
+
+        Ok(version)
+    }
+
+    /// the tenant identified by `id`, if `principal` is allowed to manage it
+    pub fn ensure_admin(id: &str, principal: &Principal) -> ApiResult<Tenant> {
+        let tenant = Self::get(id).ok_or_else(|| ApiError::NotFound(format!("tenant {id}")))?;
+
+        if !tenant.is_admin(principal) {
+            return Err(ApiError::Unauthorized);
+        }
+
+        Ok(tenant)
+    }
+}
@@ -0,0 +1,457 @@
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use proptest::prelude::*;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use signature::Signer;
+
+use crate::{
+    challenge,
+    handler::types::{
+        Ed25519PublicKey, Es256kPublicKey, GeneralRequest, JwkHeader, JwkPublicKey, RawJwkPublicKey,
+    },
+    thumbprint,
+};
+
+/// RFC 8037 §A.1, the Ed25519 key of the examples
+pub const RFC8037_D: &str = "nWGxne_9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A";
+pub const RFC8037_X: &str = "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo";
+/// RFC 8037 §A.3
+pub const RFC8037_THUMBPRINT: &str = "kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k";
+/// RFC 8037 §A.4, the signing input of `Example of Ed25519 signing` under `{"alg":"EdDSA"}`
+pub const RFC8037_SIGNING_INPUT: &str = "eyJhbGciOiJFZERTQSJ9.RXhhbXBsZSBvZiBFZDI1NTE5IHNpZ25pbmc";
+pub const RFC8037_SIGNATURE: &str =
+    "hgyY0il_MGCjP0JzlnLWG1PPOt7-09PGcvMg3AIbQR6dWbhijcNR4ki4iylGjg5BhVsPt9g7sVvpAr_MuM0KAg";
+
+/// RFC 7638 §3.1, the RSA key of the example and its thumbprint
+pub const RFC7638_N: &str = "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw";
+pub const RFC7638_E: &str = "AQAB";
+pub const RFC7638_THUMBPRINT: &str = "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs";
+
+/// An account key envelopes are signed with. RSA keys are left out, generating them is too slow
+/// for property tests.
+#[derive(Clone, Debug)]
+pub enum TestKey {
+    Es256k(k256::ecdsa::SigningKey),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+impl TestKey {
+    /// `None` for seeds outside the scalar field
+    pub fn es256k(seed: [u8; 32]) -> Option<Self> {
+        k256::ecdsa::SigningKey::from_slice(&seed)
+            .ok()
+            .map(Self::Es256k)
+    }
+
+    pub fn ed25519(seed: [u8; 32]) -> Self {
+        Self::Ed25519(ed25519_dalek::SigningKey::from_bytes(&seed))
+    }
+
+    /// RFC 8037 §A.1
+    pub fn rfc8037() -> Self {
+        let seed = BASE64_URL_SAFE_NO_PAD
+            .decode(RFC8037_D)
+            .expect("the RFC key is base64url");
+
+        Self::ed25519(seed.try_into().expect("the RFC key is 32 bytes"))
+    }
+
+    pub fn alg(&self) -> &'static str {
+        self.public().alg()
+    }
+
+    pub fn public(&self) -> RawJwkPublicKey {
+        match self {
+            Self::Es256k(key) => {
+                RawJwkPublicKey::ES256K(Es256kPublicKey(key.verifying_key().into()))
+            }
+            Self::Ed25519(key) => RawJwkPublicKey::Ed25519(Ed25519PublicKey(key.verifying_key())),
+        }
+    }
+
+    pub fn jwk(&self) -> JwkPublicKey {
+        self.public().to_jwk()
+    }
+
+    /// `r || s` for ES256K as RFC 7518 §3.4 lays it out, the 64 byte signature for EdDSA
+    pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Es256k(key) => {
+                let signature: k256::ecdsa::Signature = key.sign(msg);
+                signature.to_bytes().to_vec()
+            }
+            Self::Ed25519(key) => key.sign(msg).to_bytes().to_vec(),
+        }
+    }
+}
+
+/// What is wrong with an envelope, as real clients and attackers get it wrong.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Defect {
+    /// a nonce the server never handed out
+    WrongNonce,
+    /// signed for another URL than the one it is sent to
+    WrongUrl,
+    /// a signature over other bytes
+    BadSignature,
+    /// another algorithm the server accepts than the one of the key
+    AlgConfusion,
+    /// RFC 7518 §3.6 unsecured JWS, with an empty signature
+    NoneAlg,
+    /// a MAC keyed with the canonical public JWK, the classic algorithm confusion
+    HmacAlg,
+    /// both `jwk` and `kid` in the protected header
+    JwkAndKid,
+}
+
+impl Defect {
+    pub const ALL: [Defect; 7] = [
+        Self::WrongNonce,
+        Self::WrongUrl,
+        Self::BadSignature,
+        Self::AlgConfusion,
+        Self::NoneAlg,
+        Self::HmacAlg,
+        Self::JwkAndKid,
+    ];
+
+    /// whether the envelope stays valid on its own and only the endpoint can tell it apart
+    pub fn needs_context(&self) -> bool {
+        matches!(self, Self::WrongNonce | Self::WrongUrl)
+    }
+}
+
+/// A flattened JWS as an ACME client sends it, RFC 8555 §6.2.
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    pub key: TestKey,
+    pub url: String,
+    pub nonce: String,
+    /// signed with the account URL when set, with the embedded `jwk` otherwise
+    pub kid: Option<String>,
+    /// sent as is, empty for a POST-as-GET
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn sign(&self) -> GeneralRequest {
+        let header = self.header(self.key.alg());
+
+        self.seal(&header, |input| self.key.sign(input))
+    }
+
+    pub fn with_defect(&self, defect: Defect) -> GeneralRequest {
+        match defect {
+            Defect::WrongNonce => Self {
+                nonce: format!("stale-{}", self.nonce),
+                ..self.clone()
+            }
+            .sign(),
+            Defect::WrongUrl => Self {
+                url: format!("{}/elsewhere", self.url),
+                ..self.clone()
+            }
+            .sign(),
+            Defect::BadSignature => self.seal(&self.header(self.key.alg()), |input| {
+                self.key.sign(&[input, b".".as_slice()].concat())
+            }),
+            Defect::AlgConfusion => {
+                let alg = JwkHeader::ALLOWED_ALGS
+                    .iter()
+                    .find(|alg| **alg != self.key.alg())
+                    .expect("more than one algorithm is accepted");
+
+                self.seal(&self.header(alg), |input| self.key.sign(input))
+            }
+            Defect::NoneAlg => self.seal(&self.header("none"), |_| Vec::new()),
+            Defect::HmacAlg => {
+                let secret = thumbprint::canonicalize(&self.key.jwk())
+                    .expect("test keys canonicalize")
+                    .into_bytes();
+
+                self.seal(&self.header("HS256"), |input| {
+                    let mut mac = Hmac::<Sha256>::new_from_slice(&secret)
+                        .expect("HMAC accepts keys of any length");
+                    mac.update(input);
+
+                    mac.finalize().into_bytes().to_vec()
+                })
+            }
+            Defect::JwkAndKid => {
+                let mut header = self.header(self.key.alg());
+                header["jwk"] = json!(self.key.jwk());
+                header["kid"] = json!(self.kid.clone().unwrap_or_else(|| self.url.clone()));
+
+                self.seal(&header, |input| self.key.sign(input))
+            }
+        }
+    }
+
+    fn header(&self, alg: &str) -> Value {
+        let mut header = json!({ "alg": alg, "nonce": self.nonce, "url": self.url });
+
+        match &self.kid {
+            Some(kid) => header["kid"] = json!(kid),
+            None => header["jwk"] = json!(self.key.jwk()),
+        }
+
+        header
+    }
+
+    fn seal(&self, header: &Value, sign: impl FnOnce(&[u8]) -> Vec<u8>) -> GeneralRequest {
+        let mut req = GeneralRequest {
+            protected: BASE64_URL_SAFE_NO_PAD.encode(header.to_string()),
+            payload: BASE64_URL_SAFE_NO_PAD.encode(&self.payload),
+            signature: String::new(),
+        };
+        req.signature = BASE64_URL_SAFE_NO_PAD.encode(sign(&req.signing_input()));
+
+        req
+    }
+}
+
+/// the `application/jose+json` body of `req`
+pub fn body(req: &GeneralRequest) -> Vec<u8> {
+    serde_json::to_vec(req).expect("a flattened JWS always serializes")
+}
+
+/// RFC 8555 §8.1, the token and the thumbprint of `key`
+pub fn key_authorization(token: &str, key: &TestKey) -> String {
+    format!("{token}.{}", key.public().thumbprint())
+}
+
+/// RFC 8555 §8.4, the TXT record a dns-01 challenge with `token` looks for
+pub fn dns01_txt_value(token: &str, key: &TestKey) -> String {
+    challenge::dns01_txt_value(&key_authorization(token, key))
+}
+
+/// the payload POSTed to an order's `finalize` URL
+pub fn finalize_payload(csr_der: &[u8]) -> Vec<u8> {
+    json!({ "csr": BASE64_URL_SAFE_NO_PAD.encode(csr_der) })
+        .to_string()
+        .into_bytes()
+}
+
+/// RFC 8037 §A.4
+pub fn rfc8037_jws() -> GeneralRequest {
+    let (protected, payload) = RFC8037_SIGNING_INPUT
+        .split_once('.')
+        .expect("the signing input joins two parts");
+
+    GeneralRequest {
+        protected: protected.to_string(),
+        payload: payload.to_string(),
+        signature: RFC8037_SIGNATURE.to_string(),
+    }
+}
+
+pub fn rfc7638_jwk() -> JwkPublicKey {
+    JwkPublicKey {
+        kty: "RSA".to_string(),
+        crv: None,
+        x: None,
+        y: None,
+        n: Some(RFC7638_N.to_string()),
+        e: Some(RFC7638_E.to_string()),
+    }
+}
+
+/// RFC 8555 §7.4, a `newOrder` signed with `kid` under ES256, which this server does not accept.
+/// The RFC leaves out the signature, a zeroed one stands in.
+pub fn rfc8555_new_order() -> GeneralRequest {
+    let header = json!({
+        "alg": "ES256",
+        "kid": "https://example.com/acme/acct/evOfKhNU60wg",
+        "nonce": "5XJ1L3lEkMG7tR6pA00clA",
+        "url": "https://example.com/acme/new-order",
+    });
+    let payload = json!({
+        "identifiers": [
+            { "type": "dns", "value": "www.example.org" },
+            { "type": "dns", "value": "example.org" },
+        ],
+        "notBefore": "2016-01-01T00:04:00+04:00",
+        "notAfter": "2016-01-08T00:04:00+04:00",
+    });
+
+    GeneralRequest {
+        protected: BASE64_URL_SAFE_NO_PAD.encode(header.to_string()),
+        payload: BASE64_URL_SAFE_NO_PAD.encode(payload.to_string()),
+        signature: BASE64_URL_SAFE_NO_PAD.encode([0u8; 64]),
+    }
+}
+
+pub fn any_key() -> impl Strategy<Value = TestKey> {
+    prop_oneof![
+        any::<[u8; 32]>().prop_filter_map("outside the scalar field", TestKey::es256k),
+        any::<[u8; 32]>().prop_map(TestKey::ed25519),
+    ]
+}
+
+/// JSON without floats, they don't survive a round trip bit for bit
+pub fn any_json() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        ".*".prop_map(Value::from),
+    ];
+
+    leaf.prop_recursive(4, 32, 8, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..8).prop_map(Value::from),
+            prop::collection::btree_map(".*", inner, 0..8)
+                .prop_map(|members| Value::Object(members.into_iter().collect())),
+        ]
+    })
+}
+
+pub fn any_envelope() -> impl Strategy<Value = Envelope> {
+    let url = "https://[a-z]{1,12}\\.test(/[a-z0-9-]{1,8}){0,3}";
+    let payload = prop_oneof![
+        Just(Vec::new()),
+        any_json().prop_map(|v| v.to_string().into_bytes()),
+    ];
+
+    (
+        any_key(),
+        url,
+        "[A-Za-z0-9_-]{22}",
+        prop::option::of(url),
+        payload,
+    )
+        .prop_map(|(key, url, nonce, kid, payload)| Envelope {
+            key,
+            url,
+            nonce,
+            kid,
+            payload,
+        })
+}
+
+pub fn any_defect() -> impl Strategy<Value = Defect> {
+    prop::sample::select(Defect::ALL.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handler::{parse, types, GenericError},
+        nonce::NoncePool,
+    };
+
+    /// Everything the handler layer checks before the endpoint looks at the request. Whether the
+    /// nonce was handed out needs the server's context, so any nonce is.
+    fn accept(
+        body: &[u8],
+        key: &RawJwkPublicKey,
+    ) -> Result<(GeneralRequest, JwkHeader), Box<types::Error>> {
+        let problem = |e: GenericError| Box::new(e.to_problem());
+
+        let req = parse::json::<GeneralRequest>("body", body).map_err(problem)?;
+        let header = req.jwk_header().map_err(problem)?;
+        NoncePool::hand_out(&header.nonce);
+        let key = header.jwk.clone().unwrap_or_else(|| key.clone());
+        req.verify(&header, &key).map_err(problem)?;
+
+        Ok((req, header))
+    }
+
+    proptest! {
+        #[test]
+        fn signed_envelopes_round_trip(envelope in any_envelope()) {
+            let signed = envelope.sign();
+            let (req, header) = accept(&body(&signed), &envelope.key.public()).unwrap();
+
+            let signing_input = format!("{}.{}", signed.protected, signed.payload).into_bytes();
+            prop_assert_eq!(req.signing_input(), signing_input);
+            prop_assert_eq!(&header.url, &envelope.url);
+            prop_assert_eq!(&header.nonce, &envelope.nonce);
+            prop_assert_eq!(&header.kid, &envelope.kid);
+
+            if envelope.payload.is_empty() {
+                prop_assert!(req.payload.is_empty());
+            } else {
+                let expected: Value = serde_json::from_slice(&envelope.payload).unwrap();
+                prop_assert_eq!(req.payload::<Value>().ok(), Some(expected));
+            }
+        }
+
+        #[test]
+        fn defects_are_refused(envelope in any_envelope(), defect in any_defect()) {
+            let accepted = accept(&body(&envelope.with_defect(defect)), &envelope.key.public());
+
+            match defect.needs_context() {
+                true => {
+                    let (_, header) = accepted.unwrap();
+                    prop_assert!(header.url != envelope.url || header.nonce != envelope.nonce);
+                }
+                false => prop_assert!(accepted.is_err(), "{defect:?} was accepted"),
+            }
+        }
+
+        #[test]
+        fn signatures_of_any_length_are_refused(
+            envelope in any_envelope(),
+            signature in prop::collection::vec(any::<u8>(), 0..160)
+        ) {
+            let mut req = envelope.sign();
+            req.signature = BASE64_URL_SAFE_NO_PAD.encode(signature);
+
+            prop_assert!(accept(&body(&req), &envelope.key.public()).is_err());
+        }
+
+        #[test]
+        fn arbitrary_bodies_are_refused_without_panicking(
+            bytes in prop::collection::vec(any::<u8>(), 0..512)
+        ) {
+            let key = TestKey::rfc8037().public();
+
+            prop_assert!(accept(&bytes, &key).is_err());
+        }
+    }
+
+    #[test]
+    fn rfc8037_signature_verifies() {
+        let req = rfc8037_jws();
+        let key = TestKey::rfc8037();
+
+        assert_eq!(req.signing_input(), RFC8037_SIGNING_INPUT.as_bytes());
+        assert_eq!(key.jwk().x.as_deref(), Some(RFC8037_X));
+        assert_eq!(
+            BASE64_URL_SAFE_NO_PAD.encode(key.sign(RFC8037_SIGNING_INPUT.as_bytes())),
+            RFC8037_SIGNATURE
+        );
+
+        let signature = req.raw_signature().ok().unwrap();
+        assert!(key.public().verify(&req.signing_input(), &signature));
+    }
+
+    #[test]
+    fn rfc_thumbprints() {
+        assert_eq!(
+            thumbprint::compute(&TestKey::rfc8037().jwk()).unwrap(),
+            RFC8037_THUMBPRINT
+        );
+        assert_eq!(
+            thumbprint::compute(&rfc7638_jwk()).unwrap(),
+            RFC7638_THUMBPRINT
+        );
+    }
+
+    #[test]
+    fn rfc8555_es256_header_is_accepted() {
+        let Ok(header) = rfc8555_new_order().jwk_header() else {
+            panic!("the RFC 8555 §7.4 header is refused");
+        };
+
+        assert_eq!(header.alg, "ES256");
+        assert_eq!(
+            header.kid.as_deref(),
+            Some("https://example.com/acme/acct/evOfKhNU60wg")
+        );
+        assert_eq!(header.url, "https://example.com/acme/new-order");
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use sha2::{Digest, Sha256};
+
+use crate::handler::types::{JwkPublicKey, KeyAuthorizationComputed};
+
+/// canonical JSON form of `jwk`, only containing the members required for its key type
+pub fn canonicalize(jwk: &JwkPublicKey) -> anyhow::Result<String> {
+    // BTreeMap keeps the members sorted, serde_json takes care of escaping
+    let mut members = BTreeMap::new();
+    members.insert("kty", jwk.kty.as_str());
+
+    match jwk.kty.as_str() {
+        "EC" => {
+            members.insert("crv", JwkPublicKey::member(&jwk.crv, "crv")?);
+            members.insert("x", JwkPublicKey::member(&jwk.x, "x")?);
+            members.insert("y", JwkPublicKey::member(&jwk.y, "y")?);
+        }
+        "OKP" => {
+            members.insert("crv", JwkPublicKey::member(&jwk.crv, "crv")?);
+            members.insert("x", JwkPublicKey::member(&jwk.x, "x")?);
+        }
+        "RSA" => {
+            members.insert("e", JwkPublicKey::member(&jwk.e, "e")?);
+            members.insert("n", JwkPublicKey::member(&jwk.n, "n")?);
+        }
+        kty => return Err(anyhow!("unsupported key type `{kty}`")),
+    }
+
+    serde_json::to_string(&members).map_err(|_| anyhow!("failed to serialize JWK"))
+}
+
+pub fn compute(jwk: &JwkPublicKey) -> anyhow::Result<String> {
+    let canonical = canonicalize(jwk)?;
+
+    anyhow::Ok(BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes())))
+}
+
+/// key authorization for `token` as defined in RFC 8555 §8.1
+pub fn key_authorization(
+    token: &str,
+    jwk: &JwkPublicKey,
+) -> anyhow::Result<KeyAuthorizationComputed> {
+    let thumbprint = compute(jwk)?;
+
+    anyhow::Ok(KeyAuthorizationComputed {
+        key_authorization: format!("{token}.{thumbprint}"),
+        token: token.to_string(),
+        thumbprint,
+    })
+}
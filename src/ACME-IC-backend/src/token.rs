@@ -0,0 +1,221 @@
+use std::{cell::RefCell, time::Duration};
+
+use anyhow::anyhow;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use candid::CandidType;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::StableCell;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    clock,
+    jobs::JobQueue,
+    mem::{candid_storable, Mem, Memory, Repository},
+    trace,
+};
+
+/// bytes of entropy per token, RFC 8555 §8.1 asks for at least 128 bits
+const TOKEN_BYTES: usize = 16;
+const _: () = assert!(TOKEN_BYTES * 8 >= 128);
+/// a failed `raw_rand` is tried again after this long
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+thread_local! {
+    static TOKENS: RefCell<TokenStore> = RefCell::new(TokenStore::init());
+}
+
+/// `raw_rand` seed the tokens are derived from, with how many were derived so none repeats
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct TokenSeed {
+    seed: Vec<u8>,
+    issued: u64,
+}
+
+candid_storable!(TokenSeed);
+
+/// RFC 8555 §8.1 token of one authorization, good for a single successful validation
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ChallengeToken {
+    pub order: u64,
+    pub index: u32,
+    pub token: String,
+    pub issued_at: u64,
+    /// the token can't be used after this, it expires with its order
+    pub expires_at: u64,
+    /// set by the validation that used the token up
+    pub consumed_at: Option<u64>,
+}
+
+candid_storable!(ChallengeToken);
+
+impl ChallengeToken {
+    /// why the token can't be validated with anymore, `None` while it can
+    fn unusable(&self, now: u64) -> Option<&'static str> {
+        if self.consumed_at.is_some() {
+            return Some("the challenge token was already used");
+        }
+
+        (now >= self.expires_at).then_some("the challenge token expired")
+    }
+}
+
+/// Challenge tokens of authorizations, keyed by order and the index of the name. Authorizations
+/// opened before tokens were kept have none and use [`crate::challenge::CANISTER_TOKEN`].
+pub struct TokenStore {
+    tokens: Repository<(u64, u32), ChallengeToken>,
+    seed: StableCell<TokenSeed, Memory>,
+}
+
+impl TokenStore {
+    fn init() -> Self {
+        Self {
+            tokens: Repository::init::<Self>(),
+            seed: Mem::cell::<TokenSeed, _>(TokenSeed::default()),
+        }
+    }
+
+    /// whether the seed was drawn, no token can be issued before
+    pub fn ready() -> bool {
+        TOKENS.with_borrow(|t| !t.seed.get().seed.is_empty())
+    }
+
+    /// a fresh token for the authorization of name `index` of `order`, which expires at
+    /// `expires_at`
+    pub fn issue(order: u64, index: u32, expires_at: u64) -> anyhow::Result<ChallengeToken> {
+        TOKENS.with_borrow_mut(|t| {
+            let TokenSeed { seed, issued } = t.seed.get().clone();
+
+            if seed.is_empty() {
+                return Err(anyhow!("challenge tokens are not ready yet, retry shortly"));
+            }
+
+            let mut hasher = Sha256::new();
+            hasher.update(&seed);
+            hasher.update(issued.to_be_bytes());
+
+            let token = ChallengeToken {
+                order,
+                index,
+                token: BASE64_URL_SAFE_NO_PAD.encode(&hasher.finalize()[..TOKEN_BYTES]),
+                issued_at: clock::now_nanos(),
+                expires_at,
+                consumed_at: None,
+            };
+
+            let next = TokenSeed {
+                seed,
+                issued: issued + 1,
+            };
+            if let Err(e) = t.seed.set(next) {
+                Mem::write_failed("the token seed", e);
+            }
+            t.tokens.insert((order, index), token.clone());
+
+            anyhow::Ok(token)
+        })
+    }
+
+    pub fn get(order: u64, index: u32) -> Option<ChallengeToken> {
+        TOKENS.with_borrow(|t| t.tokens.get(&(order, index)))
+    }
+
+    pub fn for_order(order: u64) -> Vec<ChallengeToken> {
+        TOKENS.with_borrow(|t| {
+            t.tokens
+                .range((order, 0)..=(order, u32::MAX))
+                .map(|(_, token)| token)
+                .collect()
+        })
+    }
+
+    /// The token a validation of the authorization has to use, `None` for one opened before
+    /// tokens were kept. A token that was used up or expired fails the validation for good.
+    ///
+    /// ACME accounts prove control with the token in every challenge type. For canister
+    /// consumers only http-01 carries it, their dns-01 and tls-alpn-01 values are bound to the
+    /// principal alone, see [`crate::challenge::verify_canister`]. The token still limits those
+    /// orders to one successful validation per name before the order expires.
+    pub fn usable(order: u64, index: u32) -> anyhow::Result<Option<String>> {
+        let Some(token) = Self::get(order, index) else {
+            return anyhow::Ok(None);
+        };
+
+        match token.unusable(clock::now_nanos()) {
+            Some(reason) => Err(anyhow!(reason)),
+            None => anyhow::Ok(Some(token.token)),
+        }
+    }
+
+    /// Forgets the tokens that expired, except those of an order whose issuance job is still
+    /// queued. That job fails on the expired token, without it the order would fall back to
+    /// [`crate::challenge::CANISTER_TOKEN`]. Validations of accounts need a token and fail
+    /// without one.
+    pub fn prune(now: u64) {
+        TOKENS.with_borrow_mut(|t| {
+            let expired = t
+                .tokens
+                .iter()
+                .filter(|(_, token)| token.expires_at <= now)
+                .map(|(key, _)| key)
+                .filter(|(order, _)| !JobQueue::contains(*order))
+                .collect::<Vec<_>>();
+
+            for key in expired {
+                t.tokens.remove(&key);
+            }
+        })
+    }
+
+    /// marks the token of the authorization used up by a successful validation
+    pub fn consume(order: u64, index: u32) {
+        let now = clock::now_nanos();
+
+        TOKENS.with_borrow_mut(|t| {
+            t.tokens.update(&(order, index), |token| {
+                token.consumed_at.get_or_insert(now);
+            })
+        });
+    }
+}
+
+async fn draw() -> anyhow::Result<()> {
+    if TokenStore::ready() {
+        return anyhow::Ok(());
+    }
+
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|(code, msg)| anyhow!("failed to draw the token seed: {code:?} {msg}"))?;
+
+    TOKENS.with_borrow_mut(|t| {
+        // a seed drawn meanwhile keeps its count, no token may be derived twice
+        if t.seed.get().seed.is_empty() {
+            t.seed
+                .set(TokenSeed {
+                    seed: bytes,
+                    issued: 0,
+                })
+                .map_err(|e| anyhow!("failed to store the token seed: {e:?}"))?;
+        }
+
+        anyhow::Ok(())
+    })
+}
+
+/// draws the seed right after install, has to be called again after every upgrade in case it
+/// never was
+pub fn start() {
+    schedule(Duration::ZERO);
+}
+
+fn schedule(delay: Duration) {
+    ic_cdk_timers::set_timer(delay, || {
+        ic_cdk::spawn(async {
+            if let Err(e) = draw().await {
+                trace::log(&e.to_string());
+                schedule(RETRY_DELAY);
+            }
+        })
+    });
+}
@@ -0,0 +1,84 @@
+use std::{cell::RefCell, future::Future, pin::pin};
+
+use ic_http_certification::{HeaderField, HttpResponse};
+
+use crate::{clock, media};
+
+/// carries the correlation id of a request, both ways
+pub const REQUEST_ID: &str = "X-Request-Id";
+/// longest correlation id taken over from a client
+const MAX_CLIENT_ID_LEN: usize = 64;
+
+thread_local! {
+    /// correlation id of the request being handled, see [`scoped`]
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// tells apart the requests handled in the same round, which all see the same time
+    static SEQUENCE: RefCell<u64> = const { RefCell::new(0) };
+}
+
+/// The `X-Request-Id` a client sent, so its own logs line up with ours, as long as it is short
+/// and plain. A new id otherwise, from the time and a sequence that replicas agree on.
+pub fn request_id(headers: &[HeaderField]) -> String {
+    let client = media::header(headers, REQUEST_ID)
+        .map(str::trim)
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_CLIENT_ID_LEN
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+        });
+
+    if let Some(id) = client {
+        return id.to_string();
+    }
+
+    let sequence = SEQUENCE.with_borrow_mut(|s| {
+        *s = s.wrapping_add(1);
+        *s
+    });
+
+    format!("{:016x}-{:04x}", clock::now_nanos(), sequence & 0xffff)
+}
+
+/// runs `f` with `id` as the correlation id [`current`] reports
+pub fn scoped<T>(id: Option<String>, f: impl FnOnce() -> T) -> T {
+    CURRENT.set(id);
+    let result = f();
+    CURRENT.set(None);
+
+    result
+}
+
+/// [`scoped`] for a request that awaits, the id is only set while `fut` runs so requests
+/// interleaving at an await each keep their own
+pub async fn traced<F: Future>(id: String, fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+
+    std::future::poll_fn(|cx| scoped(Some(id.clone()), || fut.as_mut().poll(cx))).await
+}
+
+/// correlation id of the request being handled, `None` outside of one
+pub fn current() -> Option<String> {
+    CURRENT.with_borrow(|c| c.clone())
+}
+
+/// RFC 7807 `instance` of a problem, names the request that ran into it
+pub fn instance() -> Option<String> {
+    current().map(|id| format!("urn:request:{id}"))
+}
+
+/// a line in the canister log, tagged with the request it was written for
+pub fn log(line: &str) {
+    match current() {
+        Some(id) => ic_cdk::println!("[{id}] {line}"),
+        None => ic_cdk::println!("{line}"),
+    }
+}
+
+/// echoes `id` in the `X-Request-Id` header of `resp`
+pub fn tagged(id: &str, mut resp: HttpResponse<'static>) -> HttpResponse<'static> {
+    resp.add_header((REQUEST_ID.to_string(), id.to_string()));
+
+    resp
+}
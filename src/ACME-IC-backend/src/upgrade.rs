@@ -0,0 +1,327 @@
+use std::{borrow::Cow, cell::RefCell};
+
+use anyhow::anyhow;
+use candid::{types::reserved::Reserved, CandidType};
+use ic_stable_structures::{storable::Bound, StableCell, Storable};
+use serde::Deserialize;
+
+use crate::{
+    account::{AccountManager, AccountThumbprintIndex},
+    audit::{AuditLog, AuditRetention},
+    authz::{AuthorizationStore, PendingValidations},
+    billing::{BillingAttempts, BillingPayments, BillingProfiles},
+    blocklist::KeyBlocklist,
+    capability::CapabilitySecret,
+    ceremony::{CeremonyTranscript, SignedTranscript},
+    cert_manager::{
+        CertificateDomainIndex, CertificateExpiryIndex, CertificateKeyIndex, CertificateManager,
+        CertificateOwnerSuffixIndex, CertificateStore, CertificateSuffixIndex,
+        ImportedCertificateIndex, IssuerChainStore, RootCertificateCell,
+    },
+    client::environment::{ClientAccountKeys, ClientEnvironments},
+    config::Config,
+    crl::{IssuerCrls, SignedCrl, TenantCrls},
+    ct::CtReceiptStore,
+    debug_capture::{DebugCapture, DebugCaptureData, DebugCaptureIndex},
+    events::{EventDeliveryQueue, EventSequence, ExpiryAnnouncements, SubscriptionStore},
+    expiry::{AutoRenewConsumers, ExpiringIndex, RenewalRequests},
+    handler::types::ServerConfig,
+    idempotency::{OrderRequestIndex, OrderRequests},
+    issuance_lock::IssuanceLock,
+    jobs::{JobQueue, WebhookQueue},
+    key::{KeyScheme, PublicKeyCache},
+    load_shed::{LoadShedConfig, LoadShedder},
+    maintenance::Maintenance,
+    mem::{Mem, Memory, Repository, StorageItem},
+    metrics::MetricCounters,
+    nonce::{NoncePool, NoncePoolConfig},
+    order::{OrderManager, ReplacedCertificates},
+    pickup::PickupSecret,
+    psl::PublicSuffixList,
+    quota::AccountQuotas,
+    rate_limit::RegisteredDomainLimiter,
+    revocation::RevocationRegistry,
+    rotation::KeyRotations,
+    tenant::{TenantKeyVersions, TenantRegistry, TenantRoots},
+    token::{TokenSeed, TokenStore},
+};
+
+/// layout version of every stored collection, a collection recorded at an older version is
+/// brought up to this one by the [`MIGRATIONS`] in between
+///
+/// collections that predate versioning are at version 1
+const VERSIONS: &[(&str, u32)] = &[
+    (CertificateManager::NAME, 1),
+    (TenantRegistry::NAME, 1),
+    (AccountManager::NAME, 1),
+    (AccountThumbprintIndex::NAME, 1),
+    (DebugCapture::NAME, 1),
+    (DebugCaptureIndex::NAME, 1),
+    (DebugCaptureData::NAME, 1),
+    (CertificateStore::NAME, 6),
+    (RootCertificateCell::NAME, 1),
+    (ClientEnvironments::NAME, 1),
+    (RevocationRegistry::NAME, 1),
+    (SignedCrl::NAME, 1),
+    (PublicSuffixList::NAME, 1),
+    (RegisteredDomainLimiter::NAME, 1),
+    (OrderManager::NAME, 1),
+    (PickupSecret::NAME, 1),
+    (CeremonyTranscript::NAME, 1),
+    (SignedTranscript::NAME, 1),
+    (IssuanceLock::NAME, 1),
+    (PublicKeyCache::NAME, 1),
+    (CertificateDomainIndex::NAME, 1),
+    (CertificateExpiryIndex::NAME, 1),
+    (ImportedCertificateIndex::NAME, 1),
+    (JobQueue::NAME, 2),
+    (AuditLog::NAME, 1),
+    (MetricCounters::NAME, 1),
+    (WebhookQueue::NAME, 1),
+    (IssuerChainStore::NAME, 2),
+    (AuthorizationStore::NAME, 1),
+    (ClientAccountKeys::NAME, 1),
+    (CtReceiptStore::NAME, 1),
+    (CertificateKeyIndex::NAME, 1),
+    (KeyBlocklist::NAME, 1),
+    (AccountQuotas::NAME, 1),
+    (OrderRequestIndex::NAME, 1),
+    (OrderRequests::NAME, 1),
+    (KeyScheme::NAME, 1),
+    (KeyRotations::NAME, 1),
+    (IssuerCrls::NAME, 1),
+    (BillingProfiles::NAME, 1),
+    (BillingPayments::NAME, 1),
+    (BillingAttempts::NAME, 1),
+    (SubscriptionStore::NAME, 1),
+    (EventDeliveryQueue::NAME, 1),
+    (EventSequence::NAME, 1),
+    (ExpiryAnnouncements::NAME, 1),
+    (ExpiringIndex::NAME, 1),
+    (AutoRenewConsumers::NAME, 1),
+    (RenewalRequests::NAME, 1),
+    (TenantRoots::NAME, 1),
+    (TenantKeyVersions::NAME, 1),
+    (TenantCrls::NAME, 1),
+    (ReplacedCertificates::NAME, 1),
+    (CapabilitySecret::NAME, 1),
+    (PendingValidations::NAME, 1),
+    (TokenStore::NAME, 1),
+    (TokenSeed::NAME, 1),
+    (CertificateSuffixIndex::NAME, 1),
+    (CertificateOwnerSuffixIndex::NAME, 1),
+    (Maintenance::NAME, 1),
+];
+
+/// One step from `from` to `from + 1` of a single collection.
+///
+/// Changing the layout of e.g. `StoredAccount` in a way candid can't decode from the old bytes
+/// means bumping its collection in [`VERSIONS`] and adding a step that rewrites the values with
+/// [`Repository::migrate`], decoding them as a copy of the old struct.
+pub struct Migration {
+    pub collection: &'static str,
+    pub from: u32,
+    pub run: fn() -> anyhow::Result<()>,
+}
+
+/// every migration ever shipped, they are never removed since a canister can skip releases
+const MIGRATIONS: &[Migration] = &[
+    // 2: certificates are indexed by domain and expiry
+    Migration {
+        collection: CertificateStore::NAME,
+        from: 1,
+        run: CertificateManager::index_existing,
+    },
+    // 2: jobs carry their retry state
+    Migration {
+        collection: JobQueue::NAME,
+        from: 1,
+        run: JobQueue::migrate_retry_state,
+    },
+    // 3: certificates are indexed by the hash of their key
+    Migration {
+        collection: CertificateStore::NAME,
+        from: 2,
+        run: CertificateManager::index_existing,
+    },
+    // 4: certificates keep the key identifiers of their leaf and its issuer
+    Migration {
+        collection: CertificateStore::NAME,
+        from: 3,
+        run: CertificateManager::link_existing,
+    },
+    // 5: certificates are indexed by their names with the labels reversed
+    Migration {
+        collection: CertificateStore::NAME,
+        from: 4,
+        run: CertificateManager::index_existing,
+    },
+    // 6: certificates are indexed by their owner and their reversed names
+    Migration {
+        collection: CertificateStore::NAME,
+        from: 5,
+        run: CertificateManager::index_existing,
+    },
+    // 2: chains keep the key identifier of their first certificate
+    Migration {
+        collection: IssuerChainStore::NAME,
+        from: 1,
+        run: CertificateManager::link_existing_chains,
+    },
+];
+
+thread_local! {
+    static SNAPSHOT: RefCell<StableCell<SavedSnapshot, Memory>> = RefCell::new(
+        Mem::cell::<UpgradeSnapshot, _>(SavedSnapshot::default()),
+    );
+    static SCHEMA: RefCell<Repository<String, u32>> =
+        RefCell::new(Repository::init::<SchemaVersions>());
+}
+
+/// Recorded layout version of each collection, keyed by its `mem_id!` name.
+pub struct SchemaVersions;
+
+/// Heap state that is configured at runtime rather than derived, carried across an upgrade.
+///
+/// Every field is optional, so a snapshot saved by an older build decodes with the fields it
+/// didn't have left out. New fields have to be `Option`s.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct UpgradeSnapshot {
+    pub config: Option<ServerConfig>,
+    pub load_shed: Option<LoadShedConfig>,
+    pub nonce_pool: Option<NoncePoolConfig>,
+    pub audit_retention: Option<AuditRetention>,
+}
+
+/// Which fields a snapshot holds, whatever their type. Candid decodes an `opt` value that no
+/// longer matches its type as `null`, this tells such a value apart from one that was never saved.
+#[derive(CandidType, Deserialize)]
+struct SnapshotFields {
+    config: Option<Reserved>,
+    load_shed: Option<Reserved>,
+    nonce_pool: Option<Reserved>,
+    audit_retention: Option<Reserved>,
+}
+
+/// The candid encoding of the [`UpgradeSnapshot`], decoded by [`restore`] where a failure can
+/// fail the upgrade. Empty until the first upgrade.
+#[derive(Clone, Debug, Default)]
+struct SavedSnapshot(Vec<u8>);
+
+impl Storable for SavedSnapshot {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// fresh install, every collection starts out at its current layout
+pub fn stamp_versions() {
+    SCHEMA.with_borrow_mut(|s| {
+        for (collection, version) in VERSIONS {
+            s.insert(collection.to_string(), *version);
+        }
+    })
+}
+
+/// runs the pending migrations of every collection, oldest first
+///
+/// must run before anything touches the migrated collections, their repositories are opened
+/// lazily with the new layout
+pub fn migrate() -> anyhow::Result<()> {
+    for (collection, current) in VERSIONS {
+        let mut version = SCHEMA.with_borrow(|s| s.get(&collection.to_string()).unwrap_or(1));
+
+        if version > *current {
+            return Err(anyhow!(
+                "{collection} is stored at version {version} but this build only knows {current}, downgrades are not supported"
+            ));
+        }
+
+        while version < *current {
+            let step = MIGRATIONS
+                .iter()
+                .find(|m| m.collection == *collection && m.from == version)
+                .ok_or_else(|| anyhow!("no migration for {collection} from version {version}"))?;
+
+            (step.run)().map_err(|e| anyhow!("migrating {collection} from {version}: {e}"))?;
+
+            version += 1;
+        }
+
+        SCHEMA.with_borrow_mut(|s| s.insert(collection.to_string(), version));
+    }
+
+    anyhow::Ok(())
+}
+
+pub fn save() -> anyhow::Result<()> {
+    let snapshot = UpgradeSnapshot {
+        config: Some(Config::get()),
+        load_shed: Some(LoadShedder::status().config),
+        nonce_pool: Some(NoncePool::status().config),
+        audit_retention: Some(AuditLog::retention()),
+    };
+
+    SNAPSHOT.with_borrow_mut(|cell| {
+        cell.set(SavedSnapshot(candid::encode_one(snapshot)?))
+            .map_err(|e| anyhow!("failed to store the upgrade snapshot: {e:?}"))
+    })?;
+
+    anyhow::Ok(())
+}
+
+/// Applies what [`save`] stored. A snapshot or a field of it that the new build can't decode or
+/// rejects fails the upgrade, so it rolls back and the operator's configuration is never dropped.
+pub fn restore() -> anyhow::Result<()> {
+    let SavedSnapshot(bytes) = SNAPSHOT.with_borrow(|cell| cell.get().clone());
+
+    if bytes.is_empty() {
+        return anyhow::Ok(());
+    }
+
+    let snapshot = candid::decode_one::<UpgradeSnapshot>(&bytes)
+        .map_err(|e| anyhow!("the upgrade snapshot does not decode: {e}"))?;
+    let saved = candid::decode_one::<SnapshotFields>(&bytes)
+        .map_err(|e| anyhow!("the upgrade snapshot does not decode: {e}"))?;
+
+    if let Some(config) = field("server config", saved.config, snapshot.config)? {
+        Config::set(config).map_err(|e| anyhow!("the saved server config is rejected: {e:?}"))?;
+    }
+
+    if let Some(config) = field("load shedding config", saved.load_shed, snapshot.load_shed)? {
+        LoadShedder::configure(config)
+            .map_err(|e| anyhow!("the saved load shedding config is rejected: {e:?}"))?;
+    }
+
+    if let Some(config) = field("nonce pool config", saved.nonce_pool, snapshot.nonce_pool)? {
+        NoncePool::configure(config)
+            .map_err(|e| anyhow!("the saved nonce pool config is rejected: {e:?}"))?;
+    }
+
+    if let Some(retention) = field(
+        "audit retention",
+        saved.audit_retention,
+        snapshot.audit_retention,
+    )? {
+        AuditLog::set_retention(retention)
+            .map_err(|e| anyhow!("the saved audit retention is rejected: {e:?}"))?;
+    }
+
+    anyhow::Ok(())
+}
+
+/// a field of the snapshot, an error if it was saved but doesn't decode as this build's type
+fn field<T>(name: &str, saved: Option<Reserved>, decoded: Option<T>) -> anyhow::Result<Option<T>> {
+    match (saved, decoded) {
+        (Some(_), None) => Err(anyhow!(
+            "the saved {name} does not decode with the type of this build, migrate it first"
+        )),
+        (_, decoded) => anyhow::Ok(decoded),
+    }
+}
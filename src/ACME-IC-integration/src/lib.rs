@@ -0,0 +1,609 @@
+// named after the backend canister it tests
+#![allow(non_snake_case)]
+
+use std::{
+    collections::BTreeMap, future::Future, pin::Pin, str::FromStr, sync::Arc, time::Duration,
+};
+
+use bytes::Bytes;
+use candid::{decode_one, encode_args, utils::ArgumentEncoder, CandidType, Principal};
+use http::{Request, Response};
+use http_body_util::BodyExt;
+use instant_acme::{Account, AccountBuilder, BodyWrapper, BytesResponse, HttpClient};
+use k256::{
+    ecdsa::{signature::Verifier, DerSignature, VerifyingKey},
+    pkcs8::DecodePublicKey,
+};
+use pocket_ic::{
+    common::rest::{
+        CanisterHttpReply, CanisterHttpRequest, CanisterHttpResponse, MockCanisterHttpResponse,
+    },
+    PocketIc, PocketIcBuilder,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use x509_cert::{
+    builder::{Builder, RequestBuilder},
+    der::{asn1::Ia5String, Encode},
+    ext::pkix::{name::GeneralName, SubjectAltName},
+    name::Name,
+    Certificate,
+};
+
+/// read unless `ACME_IC_BACKEND_WASM` points elsewhere, relative to this crate
+const DEFAULT_WASM: &str = "../../target/wasm32-unknown-unknown/release/ACME_IC_backend.wasm";
+/// the backend's default `hostname` and `port`
+pub const ORIGIN: &str = "https://localhost";
+/// the tenant every ACME request of the tests is sent to
+pub const TENANT: &str = "it";
+/// every lookup of the backend goes to this DNS-over-HTTPS endpoint
+const DOH_ENDPOINT: &str = "https://dns.google/resolve";
+/// how often the backend's job worker runs
+const WORKER_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(CandidType)]
+struct HttpRequest {
+    url: String,
+    method: String,
+    body: Vec<u8>,
+    headers: Vec<(String, String)>,
+    certificate_version: Option<u16>,
+}
+
+#[derive(CandidType)]
+struct HttpUpdateRequest {
+    url: String,
+    method: String,
+    body: Vec<u8>,
+    headers: Vec<(String, String)>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub upgrade: Option<bool>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body)
+            .unwrap_or_else(|e| panic!("{} is not JSON: {e}", String::from_utf8_lossy(&self.body)))
+    }
+}
+
+// the parts of the backend's Candid types the tests look at
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum ApiError {
+    Unauthorized,
+    InvalidArgument(String),
+    NotFound(String),
+    Internal(String),
+    Unavailable {
+        message: String,
+        retry_after_secs: u64,
+    },
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum OrderStatus {
+    Pending,
+    Ready,
+    Processing,
+    Valid,
+    Invalid,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StoredOrder {
+    pub id: u64,
+    pub status: OrderStatus,
+    pub domains: Vec<String>,
+    pub certificate_serial: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IssuedCertificate {
+    pub serial: u64,
+    pub domains: Vec<String>,
+    /// leaf followed by the issuing root
+    pub pem_chain: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct IssuanceOptions {
+    pub profile: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum RotationStatus {
+    Overlapping,
+    Completed,
+    Cancelled,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct KeyRotation {
+    pub id: u64,
+    pub from_version: u32,
+    pub to_version: u32,
+    pub status: RotationStatus,
+    /// the alternate chains served during the overlap
+    pub chains: Vec<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IssuerChain {
+    pub id: u64,
+    pub pem: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TenantPolicy {
+    allowed_domains: Vec<String>,
+    allow_wildcards: bool,
+    max_validity_days: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct RateLimit {
+    requests_per_minute: u32,
+    accounts_per_hour: u32,
+    challenges_per_hour: u32,
+    certificates_per_week: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Tenant {
+    id: String,
+    base_path: String,
+    admins: Vec<Principal>,
+    policy: TenantPolicy,
+    rate_limit: RateLimit,
+}
+
+/// The backend installed in a fresh PocketIC instance, with one tenant and a DNS that serves the
+/// TXT and CAA records the tests publish.
+pub struct Harness {
+    pub pic: Arc<PocketIc>,
+    pub canister: Principal,
+    pub controller: Principal,
+    /// TXT records by name, everything else resolves to no records
+    txt: BTreeMap<String, Vec<String>>,
+    /// CAA records by name in presentation format, e.g. `0 issue "ic.encrypt.icp"`
+    caa: BTreeMap<String, Vec<String>>,
+}
+
+impl Harness {
+    pub fn boot() -> Self {
+        let path =
+            std::env::var("ACME_IC_BACKEND_WASM").unwrap_or_else(|_| DEFAULT_WASM.to_string());
+        let wasm = std::fs::read(&path)
+            .unwrap_or_else(|e| panic!("failed to read {path}, build the backend first: {e}"));
+
+        // the tECDSA keys are held by the II subnet
+        let pic = PocketIcBuilder::new()
+            .with_application_subnet()
+            .with_ii_subnet()
+            .build();
+        let controller = Principal::from_slice(&[0xC0; 29]);
+        let canister = pic.create_canister_with_settings(Some(controller), None);
+        pic.add_cycles(canister, 100_000_000_000_000);
+        pic.install_canister(
+            canister,
+            wasm,
+            encode_args(()).expect("no init arguments"),
+            Some(controller),
+        );
+
+        let harness = Self {
+            pic: Arc::new(pic),
+            canister,
+            controller,
+            txt: BTreeMap::new(),
+            caa: BTreeMap::new(),
+        };
+
+        // the capability secret, the token seed and the first nonces are drawn right after install
+        harness.rounds(3);
+
+        let tenant = Tenant {
+            id: TENANT.to_string(),
+            base_path: format!("/t/{TENANT}"),
+            admins: Vec::new(),
+            policy: TenantPolicy {
+                allowed_domains: Vec::new(),
+                allow_wildcards: false,
+                max_validity_days: 90,
+            },
+            rate_limit: RateLimit {
+                requests_per_minute: 1_000,
+                accounts_per_hour: 100,
+                challenges_per_hour: 100,
+                certificates_per_week: 100,
+            },
+        };
+        let created: ApiResult<Tenant> = harness.update(controller, "create_tenant", (tenant,));
+        created.expect("the tenant is created");
+
+        harness
+    }
+
+    pub fn query<R: CandidType + DeserializeOwned>(
+        &self,
+        sender: Principal,
+        method: &str,
+        args: impl ArgumentEncoder,
+    ) -> R {
+        self.gateway().query(sender, method, args)
+    }
+
+    pub fn update<R: CandidType + DeserializeOwned>(
+        &self,
+        sender: Principal,
+        method: &str,
+        args: impl ArgumentEncoder,
+    ) -> R {
+        self.gateway().update(sender, method, args)
+    }
+
+    /// `path` is below the origin, e.g. `/t/it/directory`, see [`Gateway::http`]
+    pub fn http(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> HttpResponse {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        self.gateway().http(method, path, headers, body)
+    }
+
+    pub fn gateway(&self) -> Gateway {
+        Gateway {
+            pic: self.pic.clone(),
+            canister: self.canister,
+        }
+    }
+
+    /// an `instant-acme` account builder whose requests reach the backend through the [`Gateway`]
+    pub fn acme(&self) -> AccountBuilder {
+        Account::builder_with_http(Box::new(self.gateway()))
+    }
+
+    /// a flattened JWS POSTed to `url`, one of the URLs the backend handed out
+    pub fn post_jws(&self, url: &str, body: Vec<u8>) -> HttpResponse {
+        self.http(
+            "POST",
+            path(url),
+            &[("Content-Type", "application/jose+json")],
+            body,
+        )
+    }
+
+    /// a fresh `Replay-Nonce` from the tenant's newNonce
+    pub fn nonce(&self) -> String {
+        let resp = self.http("GET", &format!("/t/{TENANT}/new-nonce"), &[], Vec::new());
+
+        resp.header("Replay-Nonce")
+            .unwrap_or_else(|| panic!("no nonce in a {} response", resp.status_code))
+            .to_string()
+    }
+
+    pub fn publish_txt(&mut self, name: &str, value: &str) {
+        self.txt
+            .entry(name.to_string())
+            .or_default()
+            .push(value.to_string());
+    }
+
+    pub fn publish_caa(&mut self, name: &str, record: &str) {
+        self.caa
+            .entry(name.to_string())
+            .or_default()
+            .push(record.to_string());
+    }
+
+    pub fn order(&self, consumer: Principal, id: u64) -> StoredOrder {
+        let order: ApiResult<StoredOrder> = self.query(consumer, "get_order", (id,));
+
+        order.unwrap_or_else(|e| panic!("order {id}: {e:?}"))
+    }
+
+    /// One worker round: time moves on, the timers fire and every outcall they make is answered,
+    /// along with the outcalls the answers lead to.
+    pub fn round(&self) {
+        self.pic.advance_time(WORKER_INTERVAL);
+        self.pic.tick();
+
+        for _ in 0..16 {
+            let pending = self.pic.get_canister_http();
+
+            if pending.is_empty() {
+                break;
+            }
+
+            for request in pending {
+                self.answer(request);
+            }
+
+            self.pic.tick();
+        }
+    }
+
+    pub fn rounds(&self, n: usize) {
+        for _ in 0..n {
+            self.round();
+        }
+    }
+
+    /// runs rounds until `done` or `max` rounds passed, whether it got done
+    pub fn settle(&self, max: usize, mut done: impl FnMut(&Self) -> bool) -> bool {
+        for _ in 0..max {
+            if done(self) {
+                return true;
+            }
+
+            self.round();
+        }
+
+        done(self)
+    }
+
+    fn answer(&self, request: CanisterHttpRequest) {
+        let reply = match request.url.strip_prefix(DOH_ENDPOINT) {
+            Some(query) => self.resolve(query),
+            // nothing else the flow depends on is reachable, e.g. the public suffix list
+            None => CanisterHttpReply {
+                status: 404,
+                headers: Vec::new(),
+                body: Vec::new(),
+            },
+        };
+
+        self.pic
+            .mock_canister_http_response(MockCanisterHttpResponse {
+                subnet_id: request.subnet_id,
+                request_id: request.request_id,
+                response: CanisterHttpResponse::CanisterHttpReply(reply),
+                additional_responses: Vec::new(),
+            });
+    }
+
+    /// the DNS JSON API answer to `?name=<name>&type=<type>`, no CAA records leaves issuance open
+    /// to any CA
+    fn resolve(&self, query: &str) -> CanisterHttpReply {
+        let params = query
+            .trim_start_matches('?')
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .collect::<BTreeMap<_, _>>();
+        let name = params.get("name").copied().unwrap_or_default();
+
+        let answer = match params.get("type").copied() {
+            Some("16") => self
+                .txt
+                .get(name)
+                .into_iter()
+                .flatten()
+                .map(|value| json!({ "name": format!("{name}."), "type": 16, "data": format!("\"{value}\"") }))
+                .collect(),
+            Some("257") => self
+                .caa
+                .get(name)
+                .into_iter()
+                .flatten()
+                .map(|record| json!({ "name": format!("{name}."), "type": 257, "data": record }))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        CanisterHttpReply {
+            status: 200,
+            headers: Vec::new(),
+            body: json!({ "Status": 0, "Answer": answer })
+                .to_string()
+                .into_bytes(),
+        }
+    }
+}
+
+/// The backend behind the HTTP gateway, which also serves as the HTTP client of `instant-acme`.
+#[derive(Clone)]
+pub struct Gateway {
+    pic: Arc<PocketIc>,
+    canister: Principal,
+}
+
+impl Gateway {
+    pub fn query<R: CandidType + DeserializeOwned>(
+        &self,
+        sender: Principal,
+        method: &str,
+        args: impl ArgumentEncoder,
+    ) -> R {
+        let reply = self
+            .pic
+            .query_call(self.canister, sender, method, encode_args(args).unwrap())
+            .unwrap_or_else(|e| panic!("{method} was rejected: {e:?}"));
+
+        decode_one(&reply).unwrap_or_else(|e| panic!("unexpected reply to {method}: {e}"))
+    }
+
+    pub fn update<R: CandidType + DeserializeOwned>(
+        &self,
+        sender: Principal,
+        method: &str,
+        args: impl ArgumentEncoder,
+    ) -> R {
+        let reply = self
+            .pic
+            .update_call(self.canister, sender, method, encode_args(args).unwrap())
+            .unwrap_or_else(|e| panic!("{method} was rejected: {e:?}"));
+
+        decode_one(&reply).unwrap_or_else(|e| panic!("unexpected reply to {method}: {e}"))
+    }
+
+    /// As the HTTP gateway sends it, a query first and the update call when it asks to be upgraded.
+    pub fn http(
+        &self,
+        method: &str,
+        path: &str,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> HttpResponse {
+        let resp: HttpResponse = self.query(
+            Principal::anonymous(),
+            "http_request",
+            (HttpRequest {
+                url: path.to_string(),
+                method: method.to_string(),
+                body: body.clone(),
+                headers: headers.clone(),
+                certificate_version: Some(2),
+            },),
+        );
+
+        if resp.upgrade != Some(true) {
+            return resp;
+        }
+
+        self.update(
+            Principal::anonymous(),
+            "http_request_update",
+            (HttpUpdateRequest {
+                url: path.to_string(),
+                method: method.to_string(),
+                body,
+                headers,
+            },),
+        )
+    }
+}
+
+/// Answers right away, so the client's futures can be driven with `pollster::block_on` between
+/// the rounds of a test.
+impl HttpClient for Gateway {
+    fn request(
+        &self,
+        req: Request<BodyWrapper<Bytes>>,
+    ) -> Pin<Box<dyn Future<Output = Result<BytesResponse, instant_acme::Error>> + Send>> {
+        let gateway = self.clone();
+
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let Ok(body) = body.collect().await;
+            let headers = parts
+                .headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect();
+            let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+
+            let resp = gateway.http(
+                parts.method.as_str(),
+                path,
+                headers,
+                body.to_bytes().to_vec(),
+            );
+
+            let mut response = Response::builder().status(resp.status_code);
+            for (name, value) in &resp.headers {
+                response = response.header(name, value);
+            }
+
+            Ok(BytesResponse::from(
+                response.body(BodyWrapper::from(resp.body))?,
+            ))
+        })
+    }
+}
+
+/// `url` without the origin, as the gateway hands it to the canister
+pub fn path(url: &str) -> &str {
+    url.strip_prefix(ORIGIN).unwrap_or(url)
+}
+
+/// DER CSR for `domain`, signed with a fixed P-256 key
+pub fn csr(domain: &str) -> Vec<u8> {
+    let key = p256::ecdsa::SigningKey::from_slice(&[0x5e; 32]).expect("a valid scalar");
+    let subject = Name::from_str(&format!("CN={domain}")).expect("a valid subject");
+
+    let mut builder = RequestBuilder::new(subject, &key).expect("a request builder");
+    builder
+        .add_extension(&SubjectAltName(vec![GeneralName::DnsName(
+            Ia5String::new(domain).expect("an ASCII domain"),
+        )]))
+        .expect("the SAN extension");
+
+    builder
+        .build::<p256::ecdsa::DerSignature>()
+        .expect("a signed CSR")
+        .to_der()
+        .expect("a DER CSR")
+}
+
+/// `cert` names `issuer` as its issuer and carries a valid ecdsa-with-SHA256 signature by its key
+pub fn assert_signed_by(cert: &Certificate, issuer: &Certificate) {
+    assert_eq!(cert.tbs_certificate.issuer, issuer.tbs_certificate.subject);
+
+    let spki = issuer
+        .tbs_certificate
+        .subject_public_key_info
+        .to_der()
+        .unwrap();
+    let key = VerifyingKey::from_public_key_der(&spki).expect("a secp256k1 issuer key");
+    let signature = cert
+        .signature
+        .as_bytes()
+        .and_then(|sig| DerSignature::try_from(sig).ok())
+        .expect("a DER ECDSA signature");
+
+    key.verify(&cert.tbs_certificate.to_der().unwrap(), &signature)
+        .expect("the signature verifies");
+}
+
+/// `pem_chain` holds a leaf for `domain` that chains up to the self-signed root it ends with
+pub fn assert_chain(pem_chain: &str, domain: &str) {
+    let chain = Certificate::load_pem_chain(pem_chain.as_bytes()).expect("a PEM chain");
+    let (leaf, root) = match chain.as_slice() {
+        [leaf, .., root] => (leaf, root),
+        _ => panic!("the chain holds {} certificates", chain.len()),
+    };
+
+    assert_signed_by(root, root);
+
+    for pair in chain.windows(2) {
+        assert_signed_by(&pair[0], &pair[1]);
+    }
+
+    let names = match leaf.tbs_certificate.get::<SubjectAltName>() {
+        Ok(Some((_, san))) => san.0,
+        _ => panic!("the leaf has no subjectAltName"),
+    };
+
+    assert!(
+        names
+            .iter()
+            .any(|n| matches!(n, GeneralName::DnsName(name) if name.as_str() == domain)),
+        "the leaf does not name {domain}"
+    );
+}
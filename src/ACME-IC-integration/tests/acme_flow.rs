@@ -0,0 +1,128 @@
+use candid::Principal;
+use instant_acme::{
+    AuthorizationStatus, ChallengeType, Identifier, NewAccount, NewOrder, OrderStatus as AcmeStatus,
+};
+use pollster::block_on;
+use ACME_IC_integration::{
+    assert_chain, csr, ApiResult, Harness, IssuanceOptions, IssuedCertificate, OrderStatus,
+    StoredOrder, ORIGIN, TENANT,
+};
+
+const DOMAIN: &str = "it.example.org";
+const CANISTER_DOMAIN: &str = "canister.example.org";
+
+/// the flow an off-the-shelf client runs, driven with `instant-acme`
+#[test]
+fn account_issuance_and_download() {
+    let mut h = Harness::boot();
+
+    // the directory is fetched, the account registered with its `jwk` and every later request
+    // signed with its `kid`
+    let (account, _) = block_on(h.acme().create(
+        &NewAccount {
+            contact: &[],
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        },
+        format!("{ORIGIN}/t/{TENANT}/directory"),
+        None,
+    ))
+    .expect("the account is registered");
+    assert!(account
+        .id()
+        .starts_with(&format!("{ORIGIN}/t/{TENANT}/acct/")));
+
+    // newOrder, one authorization per name
+    let identifiers = [Identifier::Dns(DOMAIN.to_string())];
+    let mut order =
+        block_on(account.new_order(&NewOrder::new(&identifiers))).expect("the order is created");
+    assert_eq!(order.state().status, AcmeStatus::Pending);
+    assert_eq!(order.state().authorizations.len(), 1);
+
+    // the account proves control with the record its key authorization asks for
+    let mut authorizations = order.authorizations();
+    while let Some(authz) = block_on(authorizations.next()) {
+        let mut authz = authz.expect("the authorization is fetched");
+        assert_eq!(authz.status, AuthorizationStatus::Pending);
+
+        let mut challenge = authz
+            .challenge(ChallengeType::Dns01)
+            .expect("a dns-01 challenge");
+        assert_eq!(challenge.identifier().to_string(), DOMAIN);
+        h.publish_txt(
+            &format!("_acme-challenge.{DOMAIN}"),
+            &challenge.key_authorization().dns_value(),
+        );
+
+        block_on(challenge.set_ready()).expect("the challenge is accepted");
+    }
+
+    let ready = h.settle(60, |_| {
+        block_on(order.refresh())
+            .expect("the order is fetched")
+            .status
+            != AcmeStatus::Pending
+    });
+    assert!(ready, "the authorization was never validated");
+    assert_eq!(order.state().status, AcmeStatus::Ready);
+
+    // finalize with a CSR for exactly the order's names
+    block_on(order.finalize_csr(&csr(DOMAIN))).expect("the order is finalized");
+
+    let settled = h.settle(60, |_| {
+        block_on(order.refresh())
+            .expect("the order is fetched")
+            .status
+            != AcmeStatus::Processing
+    });
+    assert!(settled, "the order never settled");
+    let state = order.state();
+    assert_eq!(state.status, AcmeStatus::Valid, "{:?}", state.error);
+
+    // the chain is downloaded with POST-as-GET of the order's certificate URL
+    let chain = block_on(order.certificate())
+        .expect("the chain is downloaded")
+        .expect("the order is valid");
+    assert_chain(&chain, DOMAIN);
+}
+
+#[test]
+fn canister_issuance_and_download() {
+    let mut h = Harness::boot();
+
+    // canisters order through the Candid API and prove control over the domain with a dns-01
+    // record of their principal
+    let consumer = Principal::from_slice(&[0xa1; 29]);
+    let proof: String = h.query(consumer, "dns01_proof_value", ());
+    h.publish_txt(&format!("_acme-challenge.{CANISTER_DOMAIN}"), &proof);
+
+    let submitted: ApiResult<StoredOrder> = h.update(
+        consumer,
+        "submit_order",
+        (
+            vec![CANISTER_DOMAIN.to_string()],
+            csr(CANISTER_DOMAIN),
+            None::<String>,
+            None::<IssuanceOptions>,
+        ),
+    );
+    let submitted = submitted.expect("the order is accepted");
+    assert_eq!(submitted.domains, vec![CANISTER_DOMAIN.to_string()]);
+
+    let settled = h.settle(60, |h| {
+        matches!(
+            h.order(consumer, submitted.id).status,
+            OrderStatus::Valid | OrderStatus::Invalid
+        )
+    });
+    assert!(settled, "order {} never settled", submitted.id);
+
+    let order = h.order(consumer, submitted.id);
+    assert_eq!(order.status, OrderStatus::Valid, "{:?}", order.error);
+
+    let serial = order.certificate_serial.expect("a certificate serial");
+    let cert: ApiResult<IssuedCertificate> = h.query(consumer, "get_certificate", (serial,));
+    let cert = cert.expect("the certificate is served");
+    assert_eq!(cert.serial, serial);
+    assert_chain(&cert.pem_chain, CANISTER_DOMAIN);
+}
@@ -0,0 +1,156 @@
+use ACME_IC_backend::testing::{self, Envelope, TestKey};
+use ACME_IC_integration::{Harness, HttpResponse, TENANT};
+
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+/// retries on `badNonce` before giving up, a retry with the nonce of the error always suffices
+const MAX_RETRIES: usize = 3;
+
+fn new_account_url(h: &Harness) -> String {
+    let directory = h.http("GET", &format!("/t/{TENANT}/directory"), &[], Vec::new());
+
+    directory.json()["newAccount"]
+        .as_str()
+        .expect("newAccount in the directory")
+        .to_string()
+}
+
+fn register(key: &TestKey, url: &str, nonce: String) -> Vec<u8> {
+    let envelope = Envelope {
+        key: key.clone(),
+        url: url.to_string(),
+        nonce,
+        kid: None,
+        payload: br#"{"termsOfServiceAgreed":true}"#.to_vec(),
+    };
+
+    testing::body(&envelope.sign())
+}
+
+fn is_bad_nonce(resp: &HttpResponse) -> bool {
+    resp.status_code == 400 && resp.json()["type"] == BAD_NONCE
+}
+
+/// As certbot and most ACME clients do, a `badNonce` is signed again with the `Replay-Nonce` of
+/// the error. Returns the last response and how many requests were sent.
+fn post_retrying(
+    h: &Harness,
+    url: &str,
+    mut nonce: String,
+    sign: impl Fn(String) -> Vec<u8>,
+) -> (HttpResponse, usize) {
+    for attempt in 1..=MAX_RETRIES + 1 {
+        let resp = h.post_jws(url, sign(nonce));
+
+        if !is_bad_nonce(&resp) || attempt > MAX_RETRIES {
+            return (resp, attempt);
+        }
+
+        assert_eq!(resp.header("Retry-After"), Some("0"));
+        nonce = resp
+            .header("Replay-Nonce")
+            .expect("a fresh nonce with the badNonce")
+            .to_string();
+    }
+
+    unreachable!()
+}
+
+#[test]
+fn unknown_nonce_is_retried_with_the_nonce_of_the_error() {
+    let h = Harness::boot();
+    let url = new_account_url(&h);
+    let key = TestKey::ed25519([11; 32]);
+
+    let (resp, sent) = post_retrying(&h, &url, "bm90LWlzc3VlZA".to_string(), |nonce| {
+        register(&key, &url, nonce)
+    });
+
+    assert_eq!(resp.status_code, 201);
+    assert_eq!(sent, 2);
+    assert!(resp.header("Replay-Nonce").is_some());
+}
+
+#[test]
+fn used_nonce_is_refused_and_retried() {
+    let h = Harness::boot();
+    let url = new_account_url(&h);
+    let first = TestKey::ed25519([12; 32]);
+    let second = TestKey::ed25519([13; 32]);
+    let nonce = h.nonce();
+
+    let created = h.post_jws(&url, register(&first, &url, nonce.clone()));
+    assert_eq!(created.status_code, 201);
+
+    // another account signed over the same nonce
+    let reused = h.post_jws(&url, register(&second, &url, nonce.clone()));
+    assert!(is_bad_nonce(&reused));
+
+    let (resp, sent) = post_retrying(&h, &url, nonce, |nonce| register(&second, &url, nonce));
+    assert_eq!(resp.status_code, 201);
+    assert_eq!(sent, 2);
+}
+
+#[test]
+fn head_new_nonce_refetches_a_nonce_after_bad_nonce() {
+    let h = Harness::boot();
+    let url = new_account_url(&h);
+    let key = TestKey::ed25519([14; 32]);
+
+    let refused = h.post_jws(&url, register(&key, &url, "bm90LWlzc3VlZA".to_string()));
+    assert!(is_bad_nonce(&refused));
+
+    // certbot and lego ask newNonce with a HEAD instead of taking the nonce of the error
+    let head = h.http("HEAD", &format!("/t/{TENANT}/new-nonce"), &[], Vec::new());
+    assert_eq!(head.status_code, 200);
+    assert!(head.body.is_empty());
+
+    let nonce = head.header("Replay-Nonce").expect("a nonce").to_string();
+    let created = h.post_jws(&url, register(&key, &url, nonce));
+    assert_eq!(created.status_code, 201);
+}
+
+#[test]
+fn interleaved_clients_converge() {
+    let h = Harness::boot();
+    let url = new_account_url(&h);
+    let keys = (20..24)
+        .map(|seed| TestKey::ed25519([seed; 32]))
+        .collect::<Vec<_>>();
+
+    // every client holds a nonce before any of them sends, one of them twice
+    let mut nonces = keys.iter().map(|_| h.nonce()).collect::<Vec<_>>();
+    nonces[3] = nonces[2].clone();
+
+    for (key, nonce) in keys.iter().zip(nonces) {
+        let (resp, sent) = post_retrying(&h, &url, nonce, |nonce| register(key, &url, nonce));
+
+        assert_eq!(
+            resp.status_code,
+            201,
+            "{}",
+            String::from_utf8_lossy(&resp.body)
+        );
+        assert!(sent <= 2);
+    }
+}
+
+#[test]
+fn every_response_to_a_post_carries_a_fresh_nonce() {
+    let h = Harness::boot();
+    let url = new_account_url(&h);
+    let key = TestKey::ed25519([30; 32]);
+
+    let created = h.post_jws(&url, register(&key, &url, h.nonce()));
+    let refused = h.post_jws(&url, b"not a JWS".to_vec());
+
+    let nonces = [&created, &refused]
+        .iter()
+        .map(|resp| resp.header("Replay-Nonce").expect("a nonce").to_string())
+        .collect::<Vec<_>>();
+
+    assert_ne!(nonces[0], nonces[1]);
+
+    // the nonce of an error is as good as one from newNonce
+    let again = h.post_jws(&url, register(&key, &url, nonces[1].clone()));
+    assert_eq!(again.status_code, 200);
+}
@@ -0,0 +1,65 @@
+use candid::Principal;
+use ACME_IC_integration::{csr, ApiResult, Harness, IssuanceOptions, OrderStatus, StoredOrder};
+
+/// the backend's default `caa_identities`
+const CA: &str = "ic.encrypt.icp";
+
+/// the settled order of `consumer` for `domain`, control is proven with a dns-01 record
+fn order(h: &mut Harness, consumer: Principal, domain: &str) -> StoredOrder {
+    let proof: String = h.query(consumer, "dns01_proof_value", ());
+    let base = domain.strip_prefix("*.").unwrap_or(domain);
+    h.publish_txt(&format!("_acme-challenge.{base}"), &proof);
+
+    let submitted: ApiResult<StoredOrder> = h.update(
+        consumer,
+        "submit_order",
+        (
+            vec![domain.to_string()],
+            csr(domain),
+            None::<String>,
+            None::<IssuanceOptions>,
+        ),
+    );
+    let submitted = submitted.unwrap_or_else(|e| panic!("the order for {domain}: {e:?}"));
+
+    let settled = h.settle(60, |h| {
+        matches!(
+            h.order(consumer, submitted.id).status,
+            OrderStatus::Valid | OrderStatus::Invalid
+        )
+    });
+    assert!(settled, "the order for {domain} never settled");
+
+    h.order(consumer, submitted.id)
+}
+
+fn assert_issued(order: &StoredOrder) {
+    assert_eq!(order.status, OrderStatus::Valid, "{:?}", order.error);
+}
+
+fn assert_refused_by_caa(order: &StoredOrder) {
+    assert_eq!(order.status, OrderStatus::Invalid);
+    let error = order.error.as_deref().unwrap_or_default();
+    assert!(error.contains("CAA"), "{error}");
+}
+
+/// the parsing and matching rules are covered by the unit tests of `caa`, this only checks that
+/// issuance looks the records up and honours them
+#[test]
+fn relevant_set_is_found_by_climbing_the_tree() {
+    let mut h = Harness::boot();
+    let consumer = Principal::from_slice(&[0xb1; 29]);
+
+    // only the zone apex has records, they govern every name below it
+    h.publish_caa("climb.example.org", "0 issue \"letsencrypt.org\"");
+    h.publish_caa("climb.example.org", "0 issue \"pki.goog\"");
+
+    assert_refused_by_caa(&order(&mut h, consumer, "www.climb.example.org"));
+    assert_refused_by_caa(&order(&mut h, consumer, "a.b.climb.example.org"));
+
+    // the closest non-empty set wins, the apex isn't consulted for a name with its own records
+    h.publish_caa("own.climb.example.org", &format!("0 issue \"{CA}\""));
+
+    assert_issued(&order(&mut h, consumer, "own.climb.example.org"));
+    assert_issued(&order(&mut h, consumer, "www.own.climb.example.org"));
+}
@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use ACME_IC_integration::{ApiError, ApiResult, Harness, IssuerChain, KeyRotation, RotationStatus};
+
+/// the overlap the tests start rotations with
+const OVERLAP: Duration = Duration::from_secs(60);
+
+fn start(h: &Harness, overlap: Duration) -> ApiResult<KeyRotation> {
+    h.update(h.controller, "start_key_rotation", (overlap.as_secs(),))
+}
+
+fn served_chains(h: &Harness) -> Vec<u64> {
+    let chains: Vec<IssuerChain> = h.query(h.controller, "issuer_chains", ());
+
+    chains.into_iter().map(|c| c.id).collect()
+}
+
+fn crl_served(h: &Harness, path: &str) -> bool {
+    h.http("GET", path, &[], Vec::new()).status_code == 200
+}
+
+#[test]
+fn start_serves_the_cross_signed_chains_until_complete() {
+    let h = Harness::boot();
+
+    let rotation = start(&h, OVERLAP).expect("the rotation starts");
+    assert_eq!(rotation.status, RotationStatus::Overlapping);
+    assert_eq!((rotation.from_version, rotation.to_version), (1, 2));
+    assert_eq!(rotation.chains.len(), 2);
+
+    let served = served_chains(&h);
+    assert!(rotation.chains.iter().all(|id| served.contains(id)));
+
+    let again = start(&h, OVERLAP);
+    assert!(
+        matches!(again, Err(ApiError::InvalidArgument(_))),
+        "a second rotation started during the overlap: {again:?}"
+    );
+
+    let early: ApiResult<KeyRotation> = h.update(h.controller, "complete_key_rotation", ());
+    assert!(
+        matches!(early, Err(ApiError::InvalidArgument(_))),
+        "the rotation completed before its overlap ended: {early:?}"
+    );
+
+    h.pic.advance_time(OVERLAP);
+
+    let completed: ApiResult<KeyRotation> = h.update(h.controller, "complete_key_rotation", ());
+    let completed = completed.expect("the rotation completes once the overlap is over");
+    assert_eq!(completed.id, rotation.id);
+    assert_eq!(completed.status, RotationStatus::Completed);
+
+    let rotations: Vec<KeyRotation> = h.query(h.controller, "key_rotations", ());
+    assert_eq!(rotations.len(), 1);
+    assert_eq!(rotations[0].status, RotationStatus::Completed);
+
+    // the chains keep serving the leaves of the old key
+    let served = served_chains(&h);
+    assert!(rotation.chains.iter().all(|id| served.contains(id)));
+
+    // the new key signs a CRL of its own next to the one of the old key
+    assert!(
+        h.settle(10, |h| crl_served(h, "/crl/2.der")),
+        "no CRL is served for the new key"
+    );
+    assert!(crl_served(&h, "/crl.der"));
+}
+
+#[test]
+fn cancel_stops_serving_the_chains() {
+    let h = Harness::boot();
+
+    let rotation = start(&h, OVERLAP).expect("the rotation starts");
+
+    let cancelled: ApiResult<KeyRotation> = h.update(h.controller, "cancel_key_rotation", ());
+    let cancelled = cancelled.expect("the rotation is cancelled");
+    assert_eq!(cancelled.status, RotationStatus::Cancelled);
+
+    let served = served_chains(&h);
+    assert!(rotation.chains.iter().all(|id| !served.contains(id)));
+
+    let nothing: ApiResult<KeyRotation> = h.update(h.controller, "complete_key_rotation", ());
+    assert!(
+        matches!(nothing, Err(ApiError::NotFound(_))),
+        "a cancelled rotation completed: {nothing:?}"
+    );
+
+    // the old key keeps issuing, the next rotation moves to the same version
+    let next = start(&h, Duration::ZERO).expect("a rotation starts after the cancelled one");
+    assert_eq!((next.from_version, next.to_version), (1, 2));
+}